@@ -0,0 +1,300 @@
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{info, info_span, warn};
+
+use crate::error::{AppError, Result};
+use crate::models::{AgentConfig, JobRecord};
+use crate::policy;
+use crate::state::SharedState;
+use crate::workspace::WorkspaceService;
+
+/// How often the agent checks whether it was enabled while it is off.
+const IDLE_TICK: Duration = Duration::from_secs(60);
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Commands issued longer ago (or further in the future) than this are refused; together with
+/// the job history this keeps a captured command from being replayed.
+const MAX_COMMAND_AGE_SECS: i64 = 300;
+
+/// A booted layer reboots the machine, so the reboot is scheduled this far out to let the
+/// result reach the console first.
+const BOOT_DELAY_SECS: i64 = 60;
+
+/// A command as the console delivers it: `payload` is the JSON of an [`AgentCommand`] and
+/// `signature` the hex HMAC-SHA256 of exactly that text under the shared key, so neither side
+/// has to agree on a canonical JSON form.
+#[derive(Deserialize)]
+struct SignedCommand {
+    payload: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct AgentCommand {
+    id: String,
+    /// Agent the command is addressed to, so a command cannot be redirected to another machine.
+    agent: String,
+    issued_at: DateTime<Utc>,
+    #[serde(flatten)]
+    action: AgentAction,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum AgentAction {
+    Scan,
+    CreateFromRecipe { recipe_id: String, name: String },
+    Boot { node_id: String, version: u64 },
+    Delete { node_id: String, version: u64 },
+}
+
+impl AgentAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AgentAction::Scan => "scan",
+            AgentAction::CreateFromRecipe { .. } => "create_from_recipe",
+            AgentAction::Boot { .. } => "boot",
+            AgentAction::Delete { .. } => "delete",
+        }
+    }
+
+    /// Frontend command the action stands for, so the agent is held to the same policy.
+    fn command(&self) -> &'static str {
+        match self {
+            AgentAction::Scan => "scan_workspace",
+            AgentAction::CreateFromRecipe { .. } => "create_from_recipe",
+            AgentAction::Boot { .. } => "set_bootsequence_and_reboot",
+            AgentAction::Delete { .. } => "delete_subtree",
+        }
+    }
+
+    /// Booting and deleting cannot be undone from the console.
+    fn is_destructive(&self) -> bool {
+        matches!(self, AgentAction::Boot { .. } | AgentAction::Delete { .. })
+    }
+}
+
+#[derive(Serialize)]
+struct Registration<'a> {
+    agent: &'a str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct CommandResult<'a> {
+    agent: &'a str,
+    /// Missing when the command was refused before its payload could be trusted.
+    id: Option<&'a str>,
+    result: &'a str,
+    detail: String,
+}
+
+/// Start the agent. It stays idle until a workspace is open and agent mode is enabled in the
+/// settings, then registers with the console and polls it for signed commands.
+pub fn start(state: SharedState) {
+    thread::spawn(move || {
+        let mut registered: Option<String> = None;
+        loop {
+            let wait = poll(&state, &mut registered).unwrap_or_else(|err| {
+                warn!("agent poll failed: {err}");
+                registered = None;
+                IDLE_TICK
+            });
+            thread::sleep(wait);
+        }
+    });
+}
+
+/// Register if needed, then fetch and run pending commands. Returns how long to wait until the
+/// next poll.
+fn poll(state: &SharedState, registered: &mut Option<String>) -> Result<Duration> {
+    let config = match state.get_settings() {
+        Ok(Some(settings)) => settings.agent,
+        Ok(None) | Err(AppError::RootNotInitialized) => return Ok(IDLE_TICK),
+        Err(err) => return Err(err),
+    };
+    if !config.enabled {
+        *registered = None;
+        return Ok(IDLE_TICK);
+    }
+    let base = config.endpoint.trim().trim_end_matches('/');
+    let agent = agent_name(&config);
+    if registered.as_deref() != Some(base) {
+        post(
+            &format!("{base}/register"),
+            &Registration {
+                agent: &agent,
+                version: env!("CARGO_PKG_VERSION"),
+            },
+        )?;
+        info!("agent registered with {base} as {agent}");
+        *registered = Some(base.to_string());
+    }
+
+    let url = format!("{base}/commands");
+    let commands: Vec<SignedCommand> = ureq::get(&url)
+        .query("agent", &agent)
+        .timeout(HTTP_TIMEOUT)
+        .call()
+        .map_err(|err| http_error(&url, err))?
+        .into_json()?;
+    for signed in commands {
+        let (id, outcome) = handle(state, &config, &agent, &signed);
+        let (result, detail) = match outcome {
+            Ok(detail) => ("ok", detail),
+            Err(err) => {
+                warn!(
+                    "agent command {} failed: {err}",
+                    id.as_deref().unwrap_or("?")
+                );
+                ("failed", err.to_string())
+            }
+        };
+        post(
+            &format!("{base}/results"),
+            &CommandResult {
+                agent: &agent,
+                id: id.as_deref(),
+                result,
+                detail,
+            },
+        )?;
+    }
+    Ok(Duration::from_secs(u64::from(config.poll_seconds.max(5))))
+}
+
+/// Verify and run one command. Returns its id once the payload is trusted, and the outcome.
+fn handle(
+    state: &SharedState,
+    config: &AgentConfig,
+    agent: &str,
+    signed: &SignedCommand,
+) -> (Option<String>, Result<String>) {
+    let command = match verify(config, agent, signed) {
+        Ok(command) => command,
+        Err(err) => return (None, Err(err)),
+    };
+    let outcome = run_recorded(state, &command);
+    (Some(command.id), outcome)
+}
+
+fn verify(config: &AgentConfig, agent: &str, signed: &SignedCommand) -> Result<AgentCommand> {
+    let signature = hex::decode(signed.signature.trim())
+        .map_err(|_| AppError::Message("command signature is not hex".into()))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(signed.payload.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| AppError::Message("command signature does not match".into()))?;
+
+    let command: AgentCommand = serde_json::from_str(&signed.payload)?;
+    if !command.agent.eq_ignore_ascii_case(agent) {
+        return Err(AppError::Message(format!(
+            "command {} is addressed to {}",
+            command.id, command.agent
+        )));
+    }
+    let age = (Utc::now() - command.issued_at).num_seconds();
+    if age.abs() > MAX_COMMAND_AGE_SECS {
+        return Err(AppError::Message(format!(
+            "command {} was issued at {}, outside the accepted window",
+            command.id, command.issued_at
+        )));
+    }
+    Ok(command)
+}
+
+/// Run a verified command once, recording it in the job history under its id.
+fn run_recorded(state: &SharedState, command: &AgentCommand) -> Result<String> {
+    let db = state.db()?;
+    if db.job_recorded(&command.id)? {
+        return Err(AppError::Message(format!(
+            "command {} was already run",
+            command.id
+        )));
+    }
+    let span = info_span!("agent", action = command.action.as_str(), op_id = %command.id);
+    let _entered = span.enter();
+    let started_at = Utc::now();
+    let outcome = run_action(state, &command.action);
+    let (result, detail) = match &outcome {
+        Ok(detail) => ("ok", detail.clone()),
+        Err(err) => ("failed", err.to_string()),
+    };
+    info!("agent {} {result} {detail}", command.action.as_str());
+    db.insert_job_record(&JobRecord {
+        id: command.id.clone(),
+        kind: format!("agent.{}", command.action.as_str()),
+        started_at,
+        finished_at: Utc::now(),
+        result: result.to_string(),
+        detail: Some(detail),
+    })?;
+    outcome
+}
+
+/// Run `action` under the same policy as the frontend. A read-only workspace refuses destructive
+/// actions even while unlocked, since the PIN was entered for whoever sits at this machine.
+/// The signed command, pinned to the node version it was issued for, stands in for the
+/// confirmation token the frontend has to redeem.
+fn run_action(state: &SharedState, action: &AgentAction) -> Result<String> {
+    policy::check(action.command())?;
+    if action.is_destructive() && policy::status().read_only {
+        return Err(AppError::PolicyDenied(format!(
+            "{} is not accepted from the agent while the workspace is read-only",
+            action.as_str()
+        )));
+    }
+    let svc = WorkspaceService::new(state.clone());
+    match action {
+        AgentAction::Scan => {
+            let nodes = svc.scan()?;
+            Ok(serde_json::to_string(&nodes)?)
+        }
+        AgentAction::CreateFromRecipe { recipe_id, name } => {
+            let node = svc.create_from_recipe(recipe_id, name)?;
+            Ok(serde_json::to_string(&node)?)
+        }
+        AgentAction::Boot { node_id, version } => {
+            svc.expect_version(node_id, *version)?;
+            let at = Utc::now() + chrono::Duration::seconds(BOOT_DELAY_SECS);
+            svc.set_bootsequence_and_reboot(node_id, Some(at), false)?;
+            Ok(format!("reboot scheduled at {at}"))
+        }
+        AgentAction::Delete { node_id, version } => {
+            svc.expect_version(node_id, *version)?;
+            svc.delete_subtree(node_id)?;
+            Ok(format!("deleted {node_id}"))
+        }
+    }
+}
+
+fn agent_name(config: &AgentConfig) -> String {
+    config
+        .agent_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn post<T: Serialize>(url: &str, body: &T) -> Result<()> {
+    ureq::post(url)
+        .timeout(HTTP_TIMEOUT)
+        .send_json(body)
+        .map_err(|err| http_error(url, err))?;
+    Ok(())
+}
+
+fn http_error(url: &str, err: ureq::Error) -> AppError {
+    AppError::Message(format!("request to {url} failed: {err}"))
+}
@@ -0,0 +1,119 @@
+use chrono::{DateTime, Local, Utc};
+
+use crate::models::{AuditEntry, AuditFormat, AuditRange};
+
+/// Render recorded operations as a report for change documentation.
+pub fn render(
+    entries: &[AuditEntry],
+    range: &AuditRange,
+    workspace: &str,
+    format: AuditFormat,
+) -> String {
+    match format {
+        AuditFormat::Csv => render_csv(entries),
+        AuditFormat::Markdown => render_markdown(entries, range, workspace),
+    }
+}
+
+fn render_csv(entries: &[AuditEntry]) -> String {
+    let mut out =
+        String::from("timestamp,actor,node_id,node_name,action,result,duration_ms,detail\r\n");
+    for entry in entries {
+        let fields = [
+            entry.ts.to_rfc3339(),
+            entry.actor.clone().unwrap_or_default(),
+            entry.node_id.clone().unwrap_or_default(),
+            entry.node_name.clone().unwrap_or_default(),
+            entry.action.clone(),
+            entry.result.clone(),
+            entry
+                .duration_ms
+                .map(|ms| ms.to_string())
+                .unwrap_or_default(),
+            entry.detail.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+fn render_markdown(entries: &[AuditEntry], range: &AuditRange, workspace: &str) -> String {
+    let machine = std::env::var("COMPUTERNAME").unwrap_or_default();
+    let bound = |t: Option<DateTime<Utc>>, open: &str| {
+        t.map(local_time).unwrap_or_else(|| open.to_string())
+    };
+    let failed = entries.iter().filter(|e| e.result != "ok").count();
+
+    let mut out = String::from("# Layered System audit report\n\n");
+    out.push_str(&format!("- Machine: {}\n", md_cell(&machine)));
+    out.push_str(&format!("- Workspace: {}\n", md_cell(workspace)));
+    out.push_str(&format!(
+        "- Period: {} to {}\n",
+        bound(range.from, "beginning"),
+        bound(range.to, "now")
+    ));
+    out.push_str(&format!("- Generated: {}\n", local_time(Utc::now())));
+    out.push_str(&format!(
+        "- Operations: {} ({failed} not ok)\n\n",
+        entries.len()
+    ));
+
+    if entries.is_empty() {
+        out.push_str("No operations were recorded in this period.\n");
+        return out;
+    }
+    out.push_str("| When | Who | Layer | Action | Result | Duration | Detail |\n");
+    out.push_str("| --- | --- | --- | --- | --- | --- | --- |\n");
+    for entry in entries {
+        let layer = match (&entry.node_name, &entry.node_id) {
+            (Some(name), _) => name.clone(),
+            (None, Some(id)) => format!("{id} (deleted)"),
+            (None, None) => String::new(),
+        };
+        out.push_str(&format!(
+            "| {} | {} | {} | {} | {} | {} | {} |\n",
+            local_time(entry.ts),
+            md_cell(entry.actor.as_deref().unwrap_or("unknown")),
+            md_cell(&layer),
+            md_cell(&entry.action),
+            md_cell(&entry.result),
+            entry.duration_ms.map(format_duration).unwrap_or_default(),
+            md_cell(entry.detail.as_deref().unwrap_or_default()),
+        ));
+    }
+    out
+}
+
+fn local_time(ts: DateTime<Utc>) -> String {
+    ts.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        0 => format!("{ms} ms"),
+        1..=59 => format!("{secs} s"),
+        _ => format!("{}m {:02}s", secs / 60, secs % 60),
+    }
+}
+
+/// Quote a CSV field when it contains a separator, quote or line break (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Keep a value inside one Markdown table cell.
+fn md_cell(value: &str) -> String {
+    value
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
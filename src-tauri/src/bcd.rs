@@ -1,16 +1,91 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
-use crate::error::Result;
-use crate::sys::{run_elevated_command, CommandOutput};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
 
-/// Run bcdboot using the host's default system BCD store (omit /s and /f).
+use crate::error::{AppError, Result};
+use crate::models::{FirmwareBootInfo, FirmwareEntry};
+use crate::paths::normalize_path;
+use crate::sys::{run_elevated_command, run_powershell, CommandOutput};
+
+/// Boot target for machines that keep their boot files on another disk; empty means the
+/// host's default system store.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BcdTarget {
+    /// ESP passed to `bcdboot /s`, e.g. `Q:`.
+    pub esp: Option<String>,
+    /// Store passed to `bcdedit /store`; defaults to the BCD on `esp` when only that is set.
+    pub store: Option<String>,
+}
+
+impl BcdTarget {
+    fn esp(&self) -> Option<&str> {
+        self.esp.as_deref().map(str::trim).filter(|s| !s.is_empty())
+    }
+
+    fn store(&self) -> Option<String> {
+        if let Some(store) = self
+            .store
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            return Some(store.to_string());
+        }
+        self.esp().map(|esp| {
+            PathBuf::from(format!("{}\\", esp.trim_end_matches('\\')))
+                .join("EFI")
+                .join("Microsoft")
+                .join("Boot")
+                .join("BCD")
+                .to_string_lossy()
+                .to_string()
+        })
+    }
+}
+
+static TARGET: OnceCell<RwLock<BcdTarget>> = OnceCell::new();
+
+/// Replace the active boot target, typically after loading or saving settings.
+pub fn set_target(target: BcdTarget) {
+    let lock = TARGET.get_or_init(|| RwLock::new(BcdTarget::default()));
+    *lock.write().expect("bcd target poisoned") = target;
+}
+
+fn current_target() -> BcdTarget {
+    TARGET
+        .get()
+        .map(|lock| lock.read().expect("bcd target poisoned").clone())
+        .unwrap_or_default()
+}
+
+/// Run bcdedit against the configured store, or the system store when none is set.
+fn bcdedit(args: &[&str]) -> Result<CommandOutput> {
+    match current_target().store() {
+        Some(store) => {
+            let mut full = vec!["/store", store.as_str()];
+            full.extend_from_slice(args);
+            run_elevated_command("bcdedit", &full, None)
+        }
+        None => run_elevated_command("bcdedit", args, None),
+    }
+}
+
+/// Run bcdboot against the configured ESP, or the host's default system BCD store (omit /s and
+/// /f) when none is set.
 pub fn run_bcdboot(system_dir: &Path) -> Result<CommandOutput> {
     let sys_path = system_dir
         .to_str()
         .map(|s| s.to_string())
         .unwrap_or_else(|| system_dir.to_string_lossy().to_string());
-    let sys_arg = format!("{sys_path}\\Windows");
-    run_elevated_command("bcdboot", &[&sys_arg, "/d"], None)
+    let sys_arg = format!("{}\\Windows", sys_path.trim_end_matches('\\'));
+    match current_target().esp() {
+        Some(esp) => {
+            run_elevated_command("bcdboot", &[&sys_arg, "/s", esp, "/f", "UEFI", "/d"], None)
+        }
+        None => run_elevated_command("bcdboot", &[&sys_arg, "/d"], None),
+    }
 }
 
 /// Run bcdboot targeting a specific EFI partition while still using UEFI firmware.
@@ -19,7 +94,7 @@ pub fn run_bcdboot_to_efi(system_dir: &Path, efi_dir: &Path) -> Result<CommandOu
         .to_str()
         .map(|s| s.to_string())
         .unwrap_or_else(|| system_dir.to_string_lossy().to_string());
-    let sys_arg = format!("{sys_path}\\Windows");
+    let sys_arg = format!("{}\\Windows", sys_path.trim_end_matches('\\'));
     let efi_arg = efi_dir
         .to_str()
         .map(|s| s.to_string())
@@ -31,20 +106,242 @@ pub fn run_bcdboot_to_efi(system_dir: &Path, efi_dir: &Path) -> Result<CommandOu
     )
 }
 
+/// UEFI boot manager bcdboot copies to the ESP, relative to the ESP root.
+const BOOTMGFW: &str = r"EFI\Microsoft\Boot\bootmgfw.efi";
+
+/// Check what bcdboot produced, since its exit code alone is not a reliable signal: `guid` must
+/// be present in the store and the boot manager must exist on the ESP. Without a configured
+/// ESP the system ESP is mounted at `spare_letter` for the check.
+pub fn verify_boot_files(guid: &str, spare_letter: char) -> Result<()> {
+    let res = bcdedit_enum_entry(guid)?;
+    if !res.succeeded()
+        || !res
+            .stdout
+            .to_ascii_lowercase()
+            .contains(&guid.to_ascii_lowercase())
+    {
+        return Err(AppError::Message(format!(
+            "boot entry {guid} is not present in the store"
+        )));
+    }
+
+    let found = match current_target().esp() {
+        Some(esp) => PathBuf::from(format!("{}\\", esp.trim_end_matches('\\')))
+            .join(BOOTMGFW)
+            .exists(),
+        None => {
+            let mount = format!("{spare_letter}:");
+            let res = run_elevated_command("mountvol", &[&mount, "/s"], None)?;
+            if res.succeeded() {
+                let found = PathBuf::from(format!("{mount}\\")).join(BOOTMGFW).exists();
+                let _ = run_elevated_command("mountvol", &[&mount, "/d"], None);
+                found
+            } else {
+                // `mountvol /s` refuses while the ESP already has a letter, which is common
+                // right after bcdboot; that mount is checked instead and left in place.
+                let letter = mounted_esp()?.ok_or_else(|| {
+                    AppError::Message(format!(
+                        "cannot mount the EFI system partition: {}",
+                        res.stderr.trim()
+                    ))
+                })?;
+                PathBuf::from(format!("{letter}:\\"))
+                    .join(BOOTMGFW)
+                    .exists()
+            }
+        }
+    };
+    if !found {
+        return Err(AppError::Message(format!(
+            "{BOOTMGFW} is missing on the EFI system partition"
+        )));
+    }
+    Ok(())
+}
+
+/// Drive letter of the system ESP if it is already mounted, e.g. by an earlier `mountvol /s`.
+fn mounted_esp() -> Result<Option<char>> {
+    let res = run_powershell(
+        "Get-Partition | Where-Object { $_.IsSystem -and $_.GptType -eq '{c12a7328-f81f-11d2-ba4b-00a0c93ec93b}' } | ForEach-Object { \"$($_.DriveLetter)\" }",
+    )?;
+    Ok(parse_esp_letter(&res.stdout))
+}
+
+fn parse_esp_letter(output: &str) -> Option<char> {
+    output.lines().find_map(|line| {
+        let mut chars = line.trim().chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Some(c.to_ascii_uppercase()),
+            _ => None,
+        }
+    })
+}
+
 pub fn bcdedit_enum_all() -> Result<CommandOutput> {
-    run_elevated_command("bcdedit", &["/enum", "all", "/v"], None)
+    bcdedit(&["/enum", "all", "/v"])
+}
+
+/// Enumerate the UEFI firmware boot entries. The firmware namespace only exists in the system
+/// store, so the configured alternate store is ignored here.
+pub fn bcdedit_enum_firmware() -> Result<CommandOutput> {
+    run_elevated_command("bcdedit", &["/enum", "firmware", "/v"], None)
+}
+
+pub fn bcdedit_enum_entry(guid: &str) -> Result<CommandOutput> {
+    bcdedit(&["/enum", guid, "/v"])
 }
 
 pub fn bcdedit_boot_sequence(guid: &str) -> Result<CommandOutput> {
-    run_elevated_command("bcdedit", &["/bootsequence", guid], None)
+    bcdedit(&["/bootsequence", guid])
+}
+
+/// Drop a pending one-time boot sequence from the boot manager.
+pub fn bcdedit_clear_boot_sequence() -> Result<CommandOutput> {
+    bcdedit(&["/deletevalue", "{bootmgr}", "bootsequence"])
 }
 
 pub fn bcdedit_delete(guid: &str) -> Result<CommandOutput> {
-    run_elevated_command("bcdedit", &["/delete", guid], None)
+    bcdedit(&["/delete", guid])
 }
 
 pub fn bcdedit_set_description(guid: &str, desc: &str) -> Result<CommandOutput> {
-    run_elevated_command("bcdedit", &["/set", guid, "description", desc], None)
+    bcdedit(&["/set", guid, "description", desc])
+}
+
+/// Create a new BCD object, e.g. `/application osloader` or `/device` for ramdisk options.
+pub fn bcdedit_create(description: &str, kind: &[&str]) -> Result<CommandOutput> {
+    let mut args = vec!["/create", "/d", description];
+    args.extend_from_slice(kind);
+    bcdedit(&args)
+}
+
+pub fn bcdedit_set(guid: &str, key: &str, value: &str) -> Result<CommandOutput> {
+    bcdedit(&["/set", guid, key, value])
+}
+
+pub fn bcdedit_display_order_add_last(guid: &str) -> Result<CommandOutput> {
+    bcdedit(&["/displayorder", guid, "/addlast"])
+}
+
+/// Pull the `{guid}` out of `bcdedit /create` output (the surrounding text is localized).
+pub fn parse_created_guid(output: &str) -> Option<String> {
+    let start = output.find('{')?;
+    let end = output[start..].find('}')? + start;
+    Some(output[start..=end].to_string())
+}
+
+/// Well-known identifiers printed by `/v` in place of `{bootmgr}` and `{fwbootmgr}`.
+const BOOTMGR_GUID: &str = "{9dea862c-5cdd-4e70-acc1-f32b344d4795}";
+const FWBOOTMGR_GUID: &str = "{a5a30fa2-3d06-4e9f-b5f4-a01df9d1fcba}";
+
+/// One object block of `bcdedit /enum` output: a title line, a dashed underline and
+/// `key value` rows, where indented rows continue the previous key (e.g. `displayorder`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BcdObject {
+    pub title: String,
+    pub fields: Vec<(String, Vec<String>)>,
+}
+
+impl BcdObject {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values(key).first().map(|s| s.as_str())
+    }
+
+    pub fn values(&self, key: &str) -> &[String] {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+pub fn parse_bcd_objects(output: &str) -> Vec<BcdObject> {
+    let mut objects = Vec::new();
+    let mut current: Option<BcdObject> = None;
+    let mut pending_title: Option<String> = None;
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            objects.extend(current.take());
+            pending_title = None;
+            continue;
+        }
+        if trimmed.chars().all(|c| c == '-') {
+            objects.extend(current.take());
+            current = pending_title.take().map(|title| BcdObject {
+                title,
+                fields: Vec::new(),
+            });
+            continue;
+        }
+        let Some(obj) = current.as_mut() else {
+            pending_title = Some(trimmed.to_string());
+            continue;
+        };
+        if line.starts_with(char::is_whitespace) {
+            if let Some((_, values)) = obj.fields.last_mut() {
+                values.push(trimmed.to_string());
+            }
+            continue;
+        }
+        let (key, value) = trimmed
+            .split_once(char::is_whitespace)
+            .unwrap_or((trimmed, ""));
+        obj.fields
+            .push((key.to_string(), vec![value.trim().to_string()]));
+    }
+    objects.extend(current);
+    objects
+}
+
+/// Turn `bcdedit /enum firmware /v` output into entries listed in firmware boot order.
+pub fn parse_firmware_entries(output: &str) -> FirmwareBootInfo {
+    let objects = parse_bcd_objects(output);
+    let is_fwbootmgr = |o: &BcdObject| {
+        o.get("identifier").is_some_and(|id| {
+            id.eq_ignore_ascii_case(FWBOOTMGR_GUID) || id.eq_ignore_ascii_case("{fwbootmgr}")
+        })
+    };
+    let fwbootmgr = objects.iter().find(|o| is_fwbootmgr(o));
+    let order: Vec<String> = fwbootmgr
+        .map(|o| o.values("displayorder").to_vec())
+        .unwrap_or_default();
+    let timeout = fwbootmgr
+        .and_then(|o| o.get("timeout"))
+        .and_then(|t| t.parse().ok());
+
+    let mut entries: Vec<FirmwareEntry> = objects
+        .iter()
+        .filter(|o| !is_fwbootmgr(o))
+        .filter_map(|o| {
+            let identifier = o.get("identifier")?.to_string();
+            let position = order
+                .iter()
+                .position(|id| id.eq_ignore_ascii_case(&identifier));
+            let is_windows_boot_manager = identifier.eq_ignore_ascii_case(BOOTMGR_GUID)
+                || identifier.eq_ignore_ascii_case("{bootmgr}");
+            Some(FirmwareEntry {
+                description: o.get("description").unwrap_or(&o.title).to_string(),
+                kind: o.title.clone(),
+                device: o.get("device").map(|s| s.to_string()),
+                path: o.get("path").map(|s| s.to_string()),
+                identifier,
+                position,
+                is_windows_boot_manager,
+            })
+        })
+        .collect();
+    entries.sort_by_key(|e| e.position.unwrap_or(usize::MAX));
+
+    let boot_manager_first = entries
+        .first()
+        .is_some_and(|e| e.position == Some(0) && e.is_windows_boot_manager);
+    FirmwareBootInfo {
+        entries,
+        boot_manager_first,
+        timeout,
+    }
 }
 
 /// Extract the identifier (GUID) for an entry whose device path references the given VHD path.
@@ -92,12 +389,30 @@ pub fn extract_guid_for_partition_letter(bcd_output: &str, letter: char) -> Opti
     None
 }
 
+/// Identifier and normalized VHD path of every object that boots from a VHD.
+pub fn vhd_boot_entries(objects: &[BcdObject]) -> Vec<(String, String)> {
+    objects
+        .iter()
+        .filter_map(|obj| {
+            let guid = obj.get("identifier")?;
+            let device = obj.get("osdevice").or_else(|| obj.get("device"))?;
+            let path = parse_vhd_device(device)?;
+            Some((guid.to_string(), normalize_vhd_path(&path)))
+        })
+        .collect()
+}
+
 /// Extract raw VHD path from a device/osdevice line; strips trailing ",locate=..." if present.
 fn parse_vhd_device_path(line: &str) -> Option<String> {
     let lower = line.to_ascii_lowercase();
     if !(lower.contains("device") || lower.contains("osdevice")) {
         return None;
     }
+    parse_vhd_device(line)
+}
+
+/// Extract raw VHD path from a `vhd=...` device value.
+fn parse_vhd_device(line: &str) -> Option<String> {
     let before_comma = line.split_once(',').map(|(h, _)| h).unwrap_or(line);
     let lower_before = before_comma.to_ascii_lowercase();
     let pos = lower_before.find("vhd=")?;
@@ -110,9 +425,10 @@ fn parse_vhd_device_path(line: &str) -> Option<String> {
     }
 }
 
-/// Normalize VHD paths for comparison: remove brackets, unify separators, drop \\?\ prefix, lowercase.
+/// Normalize VHD paths for comparison: remove brackets, then the same canonical form as node
+/// paths (no verbatim prefix, 8.3 names expanded, lowercase).
 fn normalize_vhd_path(path: &str) -> String {
-    let mut normalized = path.trim().trim_start_matches("\\\\?\\").replace('/', "\\");
+    let mut normalized = path.trim().replace('/', "\\");
     if normalized.starts_with('[') {
         if let Some(end) = normalized.find(']') {
             let drive = &normalized[1..end];
@@ -120,5 +436,5 @@ fn normalize_vhd_path(path: &str) -> String {
             normalized = format!("{drive}{rest}");
         }
     }
-    normalized.replace(['[', ']'], "").to_ascii_lowercase()
+    normalize_path(&normalized.replace(['[', ']'], ""))
 }
@@ -0,0 +1,85 @@
+use crate::error::Result;
+use crate::sys::{
+    run_elevated_command, run_elevated_command_with_secrets, run_powershell, CommandOutput, Secrets,
+};
+
+#[derive(Debug, Clone)]
+pub struct BitlockerStatus {
+    pub volume_status: String,
+    pub protection_status: String,
+    pub lock_status: String,
+}
+
+impl BitlockerStatus {
+    /// Anything other than fully decrypted means the volume content is (being) encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        !self.volume_status.eq_ignore_ascii_case("FullyDecrypted")
+    }
+}
+
+/// Query BitLocker state of a mounted volume (e.g. `U:`); `None` when BitLocker is unavailable.
+pub fn volume_status(mount_point: &str) -> Result<Option<BitlockerStatus>> {
+    let script = format!(
+        r#"$ErrorActionPreference = 'Stop'
+$v = Get-BitLockerVolume -MountPoint '{mount_point}'
+Write-Output "$($v.VolumeStatus)|$($v.ProtectionStatus)|$($v.LockStatus)"
+"#,
+        mount_point = mount_point.replace('\'', "''"),
+    );
+    let res = run_powershell(&script)?;
+    if !res.succeeded() {
+        return Ok(None);
+    }
+    Ok(parse_status_line(&res.stdout))
+}
+
+/// Start encrypting a mounted volume with a recovery password protector. The output holds the
+/// new recovery password, so it is kept out of the log, the transcript and job output.
+pub fn enable(mount_point: &str) -> Result<CommandOutput> {
+    run_elevated_command_with_secrets(
+        "manage-bde",
+        &["-on", mount_point, "-RecoveryPassword", "-UsedSpaceOnly"],
+        None,
+        Secrets {
+            values: &[],
+            output: true,
+        },
+    )
+}
+
+/// Let this host unlock the volume automatically whenever the layer is attached.
+pub fn enable_auto_unlock(mount_point: &str) -> Result<CommandOutput> {
+    run_elevated_command("manage-bde", &["-autounlock", "-enable", mount_point], None)
+}
+
+/// Pull the 48-digit numerical recovery password out of `manage-bde -on` output.
+pub fn parse_recovery_password(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .find(|token| {
+            let groups: Vec<&str> = token.split('-').collect();
+            groups.len() == 8
+                && groups
+                    .iter()
+                    .all(|g| g.len() == 6 && g.chars().all(|c| c.is_ascii_digit()))
+        })
+        .map(|s| s.to_string())
+}
+
+/// `output` with the recovery password masked, for error messages.
+pub fn mask_recovery_password(output: &str) -> String {
+    match parse_recovery_password(output) {
+        Some(password) => output.replace(&password, "***"),
+        None => output.to_string(),
+    }
+}
+
+fn parse_status_line(output: &str) -> Option<BitlockerStatus> {
+    let line = output.lines().find(|l| l.contains('|'))?;
+    let mut cols = line.trim().split('|');
+    Some(BitlockerStatus {
+        volume_status: cols.next()?.to_string(),
+        protection_status: cols.next().unwrap_or_default().to_string(),
+        lock_status: cols.next().unwrap_or_default().to_string(),
+    })
+}
@@ -0,0 +1,495 @@
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::iter::once;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::FromRawHandle;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use uuid::Uuid;
+use windows_sys::Win32::Foundation::{
+    CloseHandle, GetLastError, LocalFree, ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE,
+};
+use windows_sys::Win32::Security::Authorization::{
+    ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows_sys::Win32::Security::{
+    GetTokenInformation, TokenUser, PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES, TOKEN_QUERY,
+    TOKEN_USER,
+};
+use windows_sys::Win32::Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX};
+use windows_sys::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, GetNamedPipeClientProcessId, PIPE_READMODE_BYTE,
+    PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_WAIT,
+};
+use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+use crate::jobs::{self, CancelToken, JobGuard};
+use crate::models::OutputStream;
+use crate::sys::{run_process, CommandOutput, Secrets};
+
+/// How long to wait for the user to confirm the UAC prompt of the broker.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Only SYSTEM and the user running the app get access; the medium integrity label lets the
+/// unelevated app write to a pipe owned by a high-IL process.
+fn pipe_sddl(user_sid: &str) -> String {
+    format!("D:P(A;;GA;;;SY)(A;;GRGW;;;{user_sid})S:(ML;;NW;;;ME)")
+}
+
+/// Second pipe, written only by the app, over which a running command is cancelled. A separate
+/// pipe keeps the app from writing to the one it is blocked reading.
+fn control_pipe_name(pipe_name: &str) -> String {
+    format!("{pipe_name}-control")
+}
+
+#[derive(Serialize, Deserialize)]
+struct BrokerRequest {
+    secret: String,
+    /// Job the app registered for the command; a cancellation names it.
+    job_id: String,
+    program: String,
+    args: Vec<String>,
+    workdir: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BrokerResponse {
+    output: Option<CommandOutput>,
+    error: Option<String>,
+}
+
+/// What the broker sends for a request: the output lines while the command runs, then the result.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BrokerMessage {
+    Line { stream: OutputStream, line: String },
+    Done(BrokerResponse),
+}
+
+/// Sent on the control pipe when the app cancels the job of a running command.
+#[derive(Serialize, Deserialize)]
+struct CancelRequest {
+    secret: String,
+    job_id: String,
+}
+
+struct BrokerClient {
+    reader: BufReader<File>,
+    writer: File,
+    control: File,
+    secret: String,
+}
+
+enum CallError {
+    /// The request never reached the broker, so it is safe to run the command another way.
+    NotSent(String),
+    /// The broker went away after receiving the request; the command may have run.
+    Lost(String),
+}
+
+static BROKER: Mutex<Option<BrokerClient>> = Mutex::new(None);
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Run a command through the long-lived elevated helper, starting it on first use.
+///
+/// The command is registered as a job of this process, so it is listed and can be cancelled
+/// like any other; its output is streamed back line by line. Returns `None` when the broker is
+/// not needed (already elevated) or cannot be used, in which case the caller falls back to
+/// per-call elevation. Calls are serialized over a single pipe.
+pub fn execute(
+    program: &str,
+    args: &[String],
+    workdir: Option<&Path>,
+    secrets: Secrets<'_>,
+) -> Option<std::result::Result<CommandOutput, String>> {
+    if is_elevated::is_elevated() || DISABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut guard = BROKER.lock().ok()?;
+    if guard.is_none() {
+        match start() {
+            Ok(client) => {
+                info!("elevated broker started");
+                *guard = Some(client);
+            }
+            Err(err) => {
+                warn!("elevated broker unavailable, falling back to per-call elevation: {err}");
+                DISABLED.store(true, Ordering::Relaxed);
+                return None;
+            }
+        }
+    }
+    let client = guard.as_mut()?;
+    match client.call(program, args, workdir, secrets) {
+        Ok(res) => Some(res),
+        Err(CallError::NotSent(err)) => {
+            warn!("elevated broker disconnected, restarting on next call: {err}");
+            *guard = None;
+            None
+        }
+        Err(CallError::Lost(err)) => {
+            *guard = None;
+            Some(Err(format!(
+                "elevated broker disconnected while running {program}: {err}"
+            )))
+        }
+    }
+}
+
+fn start() -> std::result::Result<BrokerClient, String> {
+    let pipe_name = format!(
+        r"\\.\pipe\layered-system-broker-{}",
+        Uuid::new_v4().simple()
+    );
+    let secret = Uuid::new_v4().to_string();
+    let user_sid = current_user_sid()?;
+    let client_pid = std::process::id();
+
+    let server = {
+        let (pipe_name, secret) = (pipe_name.clone(), secret.clone());
+        thread::spawn(move || {
+            if let Err(err) = serve(pipe_name, secret, user_sid, client_pid) {
+                warn!("elevated broker exited: {err}");
+            }
+        })
+    };
+
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let pipe = connect(&pipe_name, &server, deadline)?;
+    let control = connect(&control_pipe_name(&pipe_name), &server, deadline)?;
+    let writer = pipe.try_clone().map_err(|e| e.to_string())?;
+    Ok(BrokerClient {
+        reader: BufReader::new(pipe),
+        writer,
+        control,
+        secret,
+    })
+}
+
+/// Open `pipe_name` once the broker has created it, giving up when the broker exits or the
+/// user leaves the UAC prompt open past `deadline`.
+fn connect(
+    pipe_name: &str,
+    server: &JoinHandle<()>,
+    deadline: Instant,
+) -> std::result::Result<File, String> {
+    loop {
+        match OpenOptions::new().read(true).write(true).open(pipe_name) {
+            Ok(pipe) => return Ok(pipe),
+            Err(err) => {
+                if server.is_finished() || Instant::now() >= deadline {
+                    return Err(format!("cannot connect to {pipe_name}: {err}"));
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+        }
+    }
+}
+
+impl BrokerClient {
+    fn call(
+        &mut self,
+        program: &str,
+        args: &[String],
+        workdir: Option<&Path>,
+        secrets: Secrets<'_>,
+    ) -> std::result::Result<std::result::Result<CommandOutput, String>, CallError> {
+        let shown: Vec<String> = args.iter().map(|arg| secrets.mask(arg)).collect();
+        let job = jobs::register(program, &shown);
+        let request = BrokerRequest {
+            secret: self.secret.clone(),
+            job_id: job.id.clone(),
+            program: program.to_string(),
+            args: args.to_vec(),
+            workdir: workdir.map(|p| p.to_string_lossy().to_string()),
+        };
+        let line =
+            serde_json::to_string(&request).map_err(|e| CallError::NotSent(e.to_string()))?;
+        writeln!(self.writer, "{line}")
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| CallError::NotSent(e.to_string()))?;
+
+        let watcher = self.forward_cancel(&job);
+        let result = self.read_result(&job.id, !secrets.output);
+        if let Some(watcher) = watcher {
+            watcher.abort();
+        }
+        result
+    }
+
+    /// Pass a cancellation of `job` on to the broker while its command runs.
+    fn forward_cancel(&self, job: &JobGuard) -> Option<tauri::async_runtime::JoinHandle<()>> {
+        let mut control = self.control.try_clone().ok()?;
+        let request = CancelRequest {
+            secret: self.secret.clone(),
+            job_id: job.id.clone(),
+        };
+        let line = serde_json::to_string(&request).ok()?;
+        let cancel = job.cancel.clone();
+        Some(tauri::async_runtime::spawn(async move {
+            cancel.cancelled().await;
+            if let Err(err) = writeln!(control, "{line}").and_then(|_| control.flush()) {
+                warn!("forwarding cancellation to the elevated broker failed: {err}");
+            }
+        }))
+    }
+
+    /// Read output lines, re-emitted as output of `job_id` when `forward` is set, until the
+    /// result arrives.
+    fn read_result(
+        &mut self,
+        job_id: &str,
+        forward: bool,
+    ) -> std::result::Result<std::result::Result<CommandOutput, String>, CallError> {
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return Err(CallError::Lost("pipe closed".into())),
+                Ok(_) => {}
+                Err(err) => return Err(CallError::Lost(err.to_string())),
+            }
+            let message: BrokerMessage =
+                serde_json::from_str(&line).map_err(|e| CallError::Lost(e.to_string()))?;
+            match message {
+                BrokerMessage::Line { stream, line } => {
+                    if forward {
+                        jobs::emit_line(job_id, stream, &line);
+                    }
+                }
+                BrokerMessage::Done(response) => {
+                    return Ok(match (response.output, response.error) {
+                        (Some(output), _) => Ok(output),
+                        (None, err) => {
+                            Err(err.unwrap_or_else(|| "elevated broker returned no output".into()))
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Body of the elevated helper: serve requests from the app until it disconnects.
+#[elevated::elevated]
+fn serve(
+    pipe_name: String,
+    secret: String,
+    user_sid: String,
+    client_pid: u32,
+) -> std::result::Result<(), String> {
+    let sddl = pipe_sddl(&user_sid);
+    let pipe = create_pipe(&pipe_name, &sddl, client_pid)?;
+    // Id and cancellation token of the command being run.
+    let running: Arc<Mutex<Option<(String, CancelToken)>>> = Arc::default();
+    {
+        let (control_name, sddl, secret, running) = (
+            control_pipe_name(&pipe_name),
+            sddl.clone(),
+            secret.clone(),
+            running.clone(),
+        );
+        thread::spawn(move || {
+            serve_cancellations(&control_name, &sddl, client_pid, &secret, &running)
+        });
+    }
+
+    let writer = Mutex::new(pipe.try_clone().map_err(|e| e.to_string())?);
+    let send = |message: &BrokerMessage| {
+        let Ok(line) = serde_json::to_string(message) else {
+            return false;
+        };
+        writer
+            .lock()
+            .is_ok_and(|mut w| writeln!(w, "{line}").and_then(|_| w.flush()).is_ok())
+    };
+    for line in BufReader::new(pipe).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let response = match serde_json::from_str::<BrokerRequest>(&line) {
+            Ok(req) if req.secret == secret => {
+                let cancel = CancelToken::default();
+                if let Ok(mut running) = running.lock() {
+                    *running = Some((req.job_id.clone(), cancel.clone()));
+                }
+                let on_line = |stream, line: &str| {
+                    send(&BrokerMessage::Line {
+                        stream,
+                        line: line.to_string(),
+                    });
+                };
+                let result = tauri::async_runtime::block_on(run_process(
+                    &req.program,
+                    &req.args,
+                    req.workdir.as_deref().map(Path::new),
+                    &cancel,
+                    &on_line,
+                ));
+                if let Ok(mut running) = running.lock() {
+                    *running = None;
+                }
+                match result {
+                    Ok(output) => BrokerResponse {
+                        output: Some(output),
+                        error: None,
+                    },
+                    Err(err) => BrokerResponse {
+                        output: None,
+                        error: Some(err),
+                    },
+                }
+            }
+            Ok(_) => return Err("rejected request with a wrong secret".into()),
+            Err(err) => BrokerResponse {
+                output: None,
+                error: Some(format!("invalid request: {err}")),
+            },
+        };
+        if !send(&BrokerMessage::Done(response)) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Cancel the running command when the app asks for it on the control pipe.
+fn serve_cancellations(
+    name: &str,
+    sddl: &str,
+    client_pid: u32,
+    secret: &str,
+    running: &Mutex<Option<(String, CancelToken)>>,
+) {
+    let pipe = match create_pipe(name, sddl, client_pid) {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            warn!("elevated broker cannot accept cancellations: {err}");
+            return;
+        }
+    };
+    for line in BufReader::new(pipe).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let Ok(request) = serde_json::from_str::<CancelRequest>(&line) else {
+            continue;
+        };
+        if request.secret != secret {
+            break;
+        }
+        if let Ok(running) = running.lock() {
+            match running.as_ref() {
+                Some((job_id, cancel)) if *job_id == request.job_id => cancel.cancel(),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Create the single-instance pipe and wait for the app, and only the app, to connect.
+fn create_pipe(name: &str, sddl: &str, client_pid: u32) -> std::result::Result<File, String> {
+    let wide_name: Vec<u16> = OsStr::new(name).encode_wide().chain(once(0)).collect();
+    let sddl: Vec<u16> = OsStr::new(sddl).encode_wide().chain(once(0)).collect();
+
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    let ok = unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(format!(
+            "invalid pipe security descriptor: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let attrs = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor,
+        bInheritHandle: 0,
+    };
+    let handle = unsafe {
+        CreateNamedPipeW(
+            wide_name.as_ptr(),
+            PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            1,
+            64 * 1024,
+            64 * 1024,
+            0,
+            &attrs,
+        )
+    };
+    let create_err = std::io::Error::last_os_error();
+    unsafe { LocalFree(descriptor as _) };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(format!("cannot create {name}: {create_err}"));
+    }
+
+    let connected = unsafe { ConnectNamedPipe(handle, std::ptr::null_mut()) };
+    if connected == 0 && unsafe { GetLastError() } != ERROR_PIPE_CONNECTED {
+        let err = std::io::Error::last_os_error();
+        unsafe { CloseHandle(handle) };
+        return Err(format!("cannot accept connection on {name}: {err}"));
+    }
+    // The DACL admits every process of the user; only the app that started the broker may use it.
+    let mut pid = 0u32;
+    if unsafe { GetNamedPipeClientProcessId(handle, &mut pid) } == 0 || pid != client_pid {
+        unsafe { CloseHandle(handle) };
+        return Err(format!("rejected connection on {name} from process {pid}"));
+    }
+    Ok(unsafe { File::from_raw_handle(handle as _) })
+}
+
+/// String SID (`S-1-5-21-...`) of the user this process runs as.
+fn current_user_sid() -> std::result::Result<String, String> {
+    let mut token: HANDLE = 0;
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return Err(format!(
+            "cannot open the process token: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let mut needed = 0u32;
+    unsafe { GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut needed) };
+    // u64 elements keep the buffer aligned for the SID pointer inside `TOKEN_USER`.
+    let mut buffer = vec![0u64; (needed as usize).div_ceil(8)];
+    let ok = unsafe {
+        GetTokenInformation(
+            token,
+            TokenUser,
+            buffer.as_mut_ptr().cast(),
+            needed,
+            &mut needed,
+        )
+    };
+    let query_err = std::io::Error::last_os_error();
+    unsafe { CloseHandle(token) };
+    if ok == 0 {
+        return Err(format!("cannot read the token user: {query_err}"));
+    }
+    let user = unsafe { &*buffer.as_ptr().cast::<TOKEN_USER>() };
+    let mut text: *mut u16 = std::ptr::null_mut();
+    if unsafe { ConvertSidToStringSidW(user.User.Sid, &mut text) } == 0 {
+        return Err(format!(
+            "cannot format the user SID: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let sid = unsafe {
+        let len = (0..).take_while(|&i| *text.add(i) != 0).count();
+        String::from_utf16_lossy(std::slice::from_raw_parts(text, len))
+    };
+    unsafe { LocalFree(text as _) };
+    Ok(sid)
+}
@@ -1,15 +1,35 @@
 use std::path::PathBuf;
+use std::time::Instant;
 
+use chrono::{DateTime, Utc};
 use serde::Serialize;
 use tauri::async_runtime::spawn_blocking;
 use tauri::State;
+use tracing::field::Empty;
+use tracing::{info_span, instrument, Span};
+use uuid::Uuid;
 
 use crate::{
-    db::AppSettings,
+    db::{AppSettings, SettingsPatch},
     error::AppError,
-    models::{Node, WimImageInfo},
+    jobs, maintenance,
+    models::{
+        AttachEvent, AuditFormat, AuditRange, BackupStatus, BootEntryFlags, BootMenuPolicy,
+        ChainIssue, CompactionHint, ConfirmationRequest, CreatePreflight, CreatePreflightParams,
+        DuplicateReport, EstimateOp, FirmwareBootInfo, GraphFormat, HealthFinding, JobInfo,
+        JobRecord, LayerDriver, LayerFeature, LayerPackage, LibraryEntry, LinkRepair, LocalAdmin,
+        MaintenanceTask, MetadataImport, NetworkProfile, Node, NodeDetails, NodeOperation,
+        NodePage, NodeQuery, ProvisioningScript, Recipe, ReclaimResult, RefreshResult,
+        RegistryPreset, RegistryTweak, RepairReport, SavedView, SecureBootState, SpaceEstimate,
+        SwapFileOptions, TempCleanup, VolumeIssue, WimImageInfo, WimSource, WorkspaceStats,
+    },
+    notify::{self, JobNotification},
+    policy::{self, ReadOnlyStatus},
+    preflight,
     recents::{self, RecentStatus, RecentWorkspace},
+    reg_offline, secureboot,
     state::SharedState,
+    tools::{self, ToolPaths, ToolStatus},
     workspace::WorkspaceService,
 };
 
@@ -18,6 +38,8 @@ type CmdResult<T> = std::result::Result<T, String>;
 #[derive(Serialize)]
 pub struct InitResult {
     pub settings: AppSettings,
+    /// Preflight findings about the volume hosting the root; empty when it suits native boot.
+    pub volume_issues: Vec<VolumeIssue>,
 }
 
 async fn run_blocking_cmd<T, F>(f: F) -> CmdResult<T>
@@ -25,24 +47,64 @@ where
     T: Send + 'static,
     F: FnOnce() -> CmdResult<T> + Send + 'static,
 {
-    spawn_blocking(f)
-        .await
-        .map_err(|e| format!("failed to join async task: {e}"))?
+    // Every line logged on the blocking thread, including diskpart, DISM and bcdedit output,
+    // carries the command's span with its node id and an `op_id`. The id is reserved for the
+    // first operation the command records, so log lines match its ops row and transcript.
+    // Commands without a node get a child span for the id.
+    let op_id = Uuid::new_v4().to_string();
+    let current = Span::current();
+    let span = if current.has_field("op_id") {
+        current.record("op_id", op_id.as_str());
+        current
+    } else {
+        info_span!(parent: &current, "op", op_id = %op_id)
+    };
+    spawn_blocking(move || {
+        let _op_id = jobs::reserve_op_id(op_id);
+        span.in_scope(f)
+    })
+    .await
+    .map_err(|e| format!("failed to join async task: {e}"))?
+}
+
+/// Like `run_blocking_cmd`, reporting progress on `job://<job_id>/progress` when the frontend
+/// passed a job id. Jobs that ran a while end with a desktop notification if the window is
+/// minimized.
+async fn run_job_cmd<T, F>(job_id: Option<String>, f: F) -> CmdResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> CmdResult<T> + Send + 'static,
+{
+    // The command's span is named after the command, e.g. `create_diff_vhd`.
+    let kind = Span::current().metadata().map_or("job", |meta| meta.name());
+    run_blocking_cmd(move || {
+        let started = Instant::now();
+        let _operation = jobs::begin_operation(job_id.clone());
+        let res = f();
+        match &res {
+            Ok(_) => jobs::report_phase("done", Some(100)),
+            Err(_) => jobs::report_phase("failed", None),
+        }
+        if started.elapsed() >= notify::MIN_JOB_DURATION {
+            notify::job_finished(JobNotification {
+                job_id,
+                kind: kind.to_string(),
+                ok: res.is_ok(),
+                detail: res.as_ref().err().cloned(),
+            });
+        }
+        res
+    })
+    .await
 }
 
 #[tauri::command]
 pub async fn check_admin() -> CmdResult<bool> {
-    #[cfg(windows)]
-    {
-        Ok(is_elevated::is_elevated())
-    }
-    #[cfg(not(windows))]
-    {
-        Ok(true)
-    }
+    Ok(preflight::is_admin())
 }
 
 #[tauri::command]
+#[instrument(skip_all)]
 pub async fn init_root(
     root_path: String,
     locale: Option<String>,
@@ -71,12 +133,20 @@ pub async fn init_root(
             Some(settings.locale.clone()),
             None,
         );
-        Ok(InitResult { settings })
+        let volume_issues = match state.paths() {
+            Ok(paths) => preflight::check_layer_volumes(&paths),
+            Err(_) => preflight::check_workspace_volume(&root_path),
+        };
+        Ok(InitResult {
+            settings,
+            volume_issues,
+        })
     })
     .await
 }
 
 #[tauri::command]
+#[instrument(skip_all)]
 pub async fn get_settings(state: State<'_, SharedState>) -> CmdResult<Option<AppSettings>> {
     let state = state.inner().clone();
     run_blocking_cmd(move || match state.get_settings() {
@@ -88,9 +158,85 @@ pub async fn get_settings(state: State<'_, SharedState>) -> CmdResult<Option<App
 }
 
 #[tauri::command]
-pub async fn scan_workspace(state: State<'_, SharedState>) -> CmdResult<Vec<Node>> {
+#[instrument(skip_all)]
+pub async fn update_settings(
+    patch: SettingsPatch,
+    state: State<'_, SharedState>,
+) -> CmdResult<AppSettings> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || state.update_settings(patch).map_err(|e| e.to_string())).await
+}
+
+#[tauri::command]
+pub async fn get_read_only_status() -> CmdResult<ReadOnlyStatus> {
+    Ok(policy::status())
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn set_read_only(
+    enabled: bool,
+    pin: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<AppSettings> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        state
+            .set_read_only(enabled, pin.as_deref())
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn unlock_read_only(pin: String, state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || state.unlock_read_only(&pin).map_err(|e| e.to_string())).await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn lock_read_only() -> CmdResult<()> {
+    policy::set_unlocked(false);
+    Ok(())
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn detect_tools() -> CmdResult<Vec<ToolStatus>> {
+    run_blocking_cmd(|| Ok(tools::detect())).await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn update_tool_paths(
+    tool_paths: ToolPaths,
+    state: State<'_, SharedState>,
+) -> CmdResult<AppSettings> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
+        state
+            .update_tool_paths(tool_paths)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn check_workspace_volume(root_path: String) -> CmdResult<Vec<VolumeIssue>> {
+    run_blocking_cmd(move || Ok(preflight::check_workspace_volume(&PathBuf::from(root_path)))).await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn scan_workspace(
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<Node>> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
         let svc = WorkspaceService::new(state);
         svc.scan().map_err(|e| e.to_string())
     })
@@ -98,6 +244,7 @@ pub async fn scan_workspace(state: State<'_, SharedState>) -> CmdResult<Vec<Node
 }
 
 #[tauri::command]
+#[instrument(skip_all)]
 pub async fn list_nodes(state: State<'_, SharedState>) -> CmdResult<Vec<Node>> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
@@ -108,172 +255,1644 @@ pub async fn list_nodes(state: State<'_, SharedState>) -> CmdResult<Vec<Node>> {
 }
 
 #[tauri::command]
-pub async fn list_wim_images(
-    image_path: String,
+#[instrument(skip_all)]
+pub async fn query_nodes(query: NodeQuery, state: State<'_, SharedState>) -> CmdResult<NodePage> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.query_nodes(query).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_views(state: State<'_, SharedState>) -> CmdResult<Vec<SavedView>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_views().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn save_view(
+    view_id: Option<String>,
+    name: String,
+    query: NodeQuery,
     state: State<'_, SharedState>,
-) -> CmdResult<Vec<WimImageInfo>> {
+) -> CmdResult<SavedView> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        svc.list_wim_images(&image_path).map_err(|e| e.to_string())
+        svc.save_view(view_id.as_deref(), &name, query)
+            .map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn list_recent_workspaces(app: tauri::AppHandle) -> CmdResult<Vec<RecentWorkspace>> {
-    let app = app.clone();
-    run_blocking_cmd(move || recents::list(&app).map_err(|e| e.to_string())).await
+#[instrument(skip_all)]
+pub async fn delete_view(view_id: String, state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.delete_view(&view_id).map_err(|e| e.to_string())
+    })
+    .await
 }
 
 #[tauri::command]
-pub async fn remove_recent_workspace(path: String, app: tauri::AppHandle) -> CmdResult<()> {
-    let app = app.clone();
-    run_blocking_cmd(move || recents::remove(&app, &path).map_err(|e| e.to_string())).await
+#[instrument(skip_all)]
+pub async fn apply_view(
+    view_id: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    state: State<'_, SharedState>,
+) -> CmdResult<NodePage> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.apply_view(&view_id, offset.unwrap_or(0), limit)
+            .map_err(|e| e.to_string())
+    })
+    .await
 }
 
 #[tauri::command]
-pub async fn clear_recent_workspaces(app: tauri::AppHandle) -> CmdResult<()> {
-    let app = app.clone();
-    run_blocking_cmd(move || recents::clear(&app).map_err(|e| e.to_string())).await
+#[instrument(skip_all)]
+pub async fn nodes_digest(state: State<'_, SharedState>) -> CmdResult<String> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.nodes_digest().map_err(|e| e.to_string())
+    })
+    .await
 }
 
-#[derive(Serialize)]
-pub struct CreateNodeResponse {
-    pub node: Node,
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn get_workspace_stats(state: State<'_, SharedState>) -> CmdResult<WorkspaceStats> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_workspace_stats().map_err(|e| e.to_string())
+    })
+    .await
 }
 
 #[tauri::command]
-pub async fn create_base_vhd(
-    name: String,
-    desc: Option<String>,
-    wim_file: String,
-    wim_index: u32,
-    size_gb: u64,
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn estimate_operation(
+    op: EstimateOp,
+    node_id: String,
     state: State<'_, SharedState>,
-) -> CmdResult<CreateNodeResponse> {
+) -> CmdResult<SpaceEstimate> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        let node = svc
-            .create_base(&name, desc, &wim_file, wim_index, size_gb)
+        svc.estimate_operation(op, &node_id)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn preflight_create(
+    params: CreatePreflightParams,
+    state: State<'_, SharedState>,
+) -> CmdResult<CreatePreflight> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.preflight_create(&params).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn update_node_appearance(
+    node_id: String,
+    version: u64,
+    color: Option<String>,
+    icon: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
             .map_err(|e| e.to_string())?;
-        Ok(CreateNodeResponse { node })
+        svc.update_node_appearance(&node_id, color, icon)
+            .map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn create_diff_vhd(
-    parent_id: String,
-    name: String,
-    desc: Option<String>,
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_node_tags(
+    node_id: String,
+    version: u64,
+    tags: Vec<String>,
     state: State<'_, SharedState>,
-) -> CmdResult<CreateNodeResponse> {
+) -> CmdResult<Node> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        let node = svc
-            .create_diff(&parent_id, &name, desc)
+        svc.expect_version(&node_id, version)
             .map_err(|e| e.to_string())?;
-        Ok(CreateNodeResponse { node })
+        svc.set_node_tags(&node_id, tags).map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn set_bootsequence_and_reboot(
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_node_network(
+    node_id: String,
+    version: u64,
+    network: Option<NetworkProfile>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.set_node_network(&node_id, network)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_local_admin(
     node_id: String,
+    version: u64,
+    admin: LocalAdmin,
+    job_id: Option<String>,
     state: State<'_, SharedState>,
 ) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.set_local_admin(&node_id, &admin)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_node_expiry(
+    node_id: String,
+    version: u64,
+    expires_at: Option<DateTime<Utc>>,
+    protected: bool,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        svc.set_bootsequence_and_reboot(&node_id)
-            .map(|_| ())
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.set_node_expiry(&node_id, expires_at, protected)
             .map_err(|e| e.to_string())
     })
     .await
 }
 
-#[derive(Serialize)]
-pub struct StartVmResponse {
-    pub vm_name: String,
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn reorder_nodes(
+    ordered_ids: Vec<String>,
+    versions: Vec<u64>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        // `versions[i]` is the version of `ordered_ids[i]` as loaded by the caller.
+        if versions.len() != ordered_ids.len() {
+            return Err("reorder_nodes needs one version per node".to_string());
+        }
+        for (node_id, version) in ordered_ids.iter().zip(&versions) {
+            svc.expect_version(node_id, *version)
+                .map_err(|e| e.to_string())?;
+        }
+        svc.reorder_nodes(ordered_ids).map_err(|e| e.to_string())
+    })
+    .await
 }
 
 #[tauri::command]
-pub async fn start_vm(
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn refresh_os_info(
     node_id: String,
+    version: u64,
     state: State<'_, SharedState>,
-) -> CmdResult<StartVmResponse> {
+) -> CmdResult<Node> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        let vm_name = svc.start_vm(&node_id).map_err(|e| e.to_string())?;
-        Ok(StartVmResponse { vm_name })
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.refresh_os_info(&node_id).map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn delete_subtree(node_id: String, state: State<'_, SharedState>) -> CmdResult<()> {
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn get_layer_drivers(
+    node_id: String,
+    all: Option<bool>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<LayerDriver>> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        svc.delete_subtree(&node_id).map_err(|e| e.to_string())
+        svc.get_layer_drivers(&node_id, all.unwrap_or(false))
+            .map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn delete_bcd(node_id: String, state: State<'_, SharedState>) -> CmdResult<()> {
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn get_layer_packages(
+    node_id: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<LayerPackage>> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        svc.delete_bcd(&node_id).map_err(|e| e.to_string())
+        svc.get_layer_packages(&node_id).map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn repair_bcd(
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn get_layer_features(
     node_id: String,
     state: State<'_, SharedState>,
-) -> CmdResult<Option<String>> {
+) -> CmdResult<Vec<LayerFeature>> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        svc.repair_bcd(&node_id).map_err(|e| e.to_string())
+        svc.get_layer_features(&node_id).map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn add_bcd_entry(
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn cleanup_component_store(
     node_id: String,
-    description: Option<String>,
+    version: u64,
+    compact: Option<bool>,
+    job_id: Option<String>,
     state: State<'_, SharedState>,
-) -> CmdResult<Option<String>> {
+) -> CmdResult<ReclaimResult> {
     let state = state.inner().clone();
-    run_blocking_cmd(move || {
+    run_job_cmd(job_id, move || {
         let svc = WorkspaceService::new(state);
-        svc.add_bcd_entry(&node_id, description)
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.cleanup_component_store(&node_id, compact.unwrap_or(false))
             .map_err(|e| e.to_string())
     })
     .await
 }
 
 #[tauri::command]
-pub async fn update_bcd_description(
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_product_key(
     node_id: String,
-    description: String,
+    version: u64,
+    key: String,
     state: State<'_, SharedState>,
 ) -> CmdResult<()> {
     let state = state.inner().clone();
     run_blocking_cmd(move || {
         let svc = WorkspaceService::new(state);
-        svc.update_bcd_description(&node_id, &description)
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.set_product_key(&node_id, &key)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn add_language(
+    node_id: String,
+    version: u64,
+    package_path: String,
+    set_default: Option<bool>,
+    language: Option<String>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.add_language(
+            &node_id,
+            &package_path,
+            set_default.unwrap_or(false),
+            language,
+        )
+        .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn enable_netfx3(
+    node_id: String,
+    version: u64,
+    sources_path: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.enable_netfx3(&node_id, &sources_path)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn apply_provisioning_package(
+    node_id: String,
+    version: u64,
+    ppkg_path: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.apply_provisioning_package(&node_id, &ppkg_path)
             .map_err(|e| e.to_string())
     })
     .await
 }
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_wim_images(
+    image_path: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<WimImageInfo>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_wim_images(&image_path).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn add_wim_source(
+    path: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<WimSource> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.add_wim_source(&path).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_wim_sources(state: State<'_, SharedState>) -> CmdResult<Vec<WimSource>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_wim_sources().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn remove_wim_source(source_id: String, state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.remove_wim_source(&source_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn export_wim_index(
+    source: String,
+    index: u32,
+    dest: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.export_wim_index(&source, index, &dest)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_recent_workspaces(app: tauri::AppHandle) -> CmdResult<Vec<RecentWorkspace>> {
+    let app = app.clone();
+    run_blocking_cmd(move || recents::list(&app).map_err(|e| e.to_string())).await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn remove_recent_workspace(path: String, app: tauri::AppHandle) -> CmdResult<()> {
+    let app = app.clone();
+    run_blocking_cmd(move || recents::remove(&app, &path).map_err(|e| e.to_string())).await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn clear_recent_workspaces(app: tauri::AppHandle) -> CmdResult<()> {
+    let app = app.clone();
+    run_blocking_cmd(move || recents::clear(&app).map_err(|e| e.to_string())).await
+}
+
+#[derive(Serialize)]
+pub struct CreateNodeResponse {
+    pub node: Node,
+}
+
+/// Trim and check a requested computer name before any long-running work starts; blank means
+/// none.
+fn normalize_computer_name(computer_name: Option<String>) -> CmdResult<Option<String>> {
+    let computer_name = computer_name
+        .map(|n| n.trim().to_ascii_uppercase())
+        .filter(|n| !n.is_empty());
+    if let Some(name) = computer_name.as_deref() {
+        reg_offline::validate_computer_name(name).map_err(|e| e.to_string())?;
+    }
+    Ok(computer_name)
+}
+
+/// Apply the computer name, network profile and administrator requested at creation; the
+/// layer is kept if this fails.
+fn configure_created(
+    svc: &WorkspaceService,
+    node: &Node,
+    computer_name: Option<&str>,
+    network: Option<NetworkProfile>,
+    admin: Option<LocalAdmin>,
+    swap_files: Option<SwapFileOptions>,
+) -> CmdResult<()> {
+    if let Some(computer_name) = computer_name {
+        jobs::report_phase("computer_name", None);
+        svc.set_computer_name(&node.id, computer_name)
+            .map_err(|e| {
+                format!(
+                    "layer {} was created but setting its computer name failed: {e}",
+                    node.name
+                )
+            })?;
+    }
+    if let Some(network) = network {
+        jobs::report_phase("network", None);
+        svc.set_node_network(&node.id, Some(network)).map_err(|e| {
+            format!(
+                "layer {} was created but writing its network profile failed: {e}",
+                node.name
+            )
+        })?;
+    }
+    if let Some(admin) = admin {
+        jobs::report_phase("local_admin", None);
+        svc.set_local_admin(&node.id, &admin).map_err(|e| {
+            format!(
+                "layer {} was created but provisioning its administrator failed: {e}",
+                node.name
+            )
+        })?;
+    }
+    if let Some(swap_files) = swap_files {
+        jobs::report_phase("swap_files", None);
+        svc.disable_swap_files(&node.id, &swap_files).map_err(|e| {
+            format!(
+                "layer {} was created but disabling its pagefile and hibernation failed: {e}",
+                node.name
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Optionally smoke-boot a freshly created layer; a failed boot keeps the layer but is
+/// reported as an error so the user does not reboot into it.
+fn verify_created(
+    svc: &WorkspaceService,
+    node: Node,
+    verify_boot: Option<bool>,
+) -> CmdResult<CreateNodeResponse> {
+    if verify_boot.unwrap_or(false) {
+        jobs::report_phase("verify_boot", None);
+        svc.verify_boot_in_vm(&node.id).map_err(|e| {
+            format!(
+                "layer {} was created but failed the boot check: {e}",
+                node.name
+            )
+        })?;
+    }
+    Ok(CreateNodeResponse { node })
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn verify_boot(
+    node_id: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.verify_boot_in_vm(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_base_vhd(
+    name: String,
+    desc: Option<String>,
+    wim_file: String,
+    wim_index: u32,
+    size_gb: u64,
+    recovery: Option<bool>,
+    computer_name: Option<String>,
+    network: Option<NetworkProfile>,
+    admin: Option<LocalAdmin>,
+    verify_boot: Option<bool>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<CreateNodeResponse> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let computer_name = normalize_computer_name(computer_name)?;
+        if let Some(network) = network.as_ref() {
+            network.validate().map_err(|e| e.to_string())?;
+        }
+        if let Some(admin) = admin.as_ref() {
+            reg_offline::validate_user_name(admin.user_name.trim()).map_err(|e| e.to_string())?;
+        }
+        let svc = WorkspaceService::new(state);
+        let node = svc
+            .create_base(
+                &name,
+                desc,
+                &wim_file,
+                wim_index,
+                size_gb,
+                recovery.unwrap_or(false),
+            )
+            .map_err(|e| e.to_string())?;
+        configure_created(&svc, &node, computer_name.as_deref(), network, admin, None)?;
+        verify_created(&svc, node, verify_boot)
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_library(state: State<'_, SharedState>) -> CmdResult<Vec<LibraryEntry>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_library().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn clone_from_library(
+    entry: String,
+    name: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.clone_from_library(&entry, &name)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_templates(state: State<'_, SharedState>) -> CmdResult<Vec<LibraryEntry>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_templates().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn create_base_from_template(
+    template: String,
+    name: String,
+    desc: Option<String>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.create_base_from_template(&template, &name, desc)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn backup_node(
+    node_id: String,
+    dest: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<BackupStatus> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.backup_node(&node_id, &dest).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn restore_node_from_backup(
+    backup_id: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.restore_node_from_backup(&backup_id)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = node_id.as_deref(), op_id = Empty))]
+pub async fn get_backup_status(
+    node_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<BackupStatus>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_backup_status(node_id.as_deref())
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn capture_running_layer(
+    name: String,
+    desc: Option<String>,
+    size_gb: u64,
+    verify_boot: Option<bool>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<CreateNodeResponse> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        let node = svc
+            .capture_running_layer(&name, desc, size_gb)
+            .map_err(|e| e.to_string())?;
+        verify_created(&svc, node, verify_boot)
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %parent_id, op_id = Empty))]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_diff_vhd(
+    parent_id: String,
+    parent_version: u64,
+    name: String,
+    desc: Option<String>,
+    scripts: Option<Vec<ProvisioningScript>>,
+    computer_name: Option<String>,
+    network: Option<NetworkProfile>,
+    admin: Option<LocalAdmin>,
+    swap_files: Option<SwapFileOptions>,
+    verify_boot: Option<bool>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<CreateNodeResponse> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let computer_name = normalize_computer_name(computer_name)?;
+        if let Some(network) = network.as_ref() {
+            network.validate().map_err(|e| e.to_string())?;
+        }
+        if let Some(admin) = admin.as_ref() {
+            reg_offline::validate_user_name(admin.user_name.trim()).map_err(|e| e.to_string())?;
+        }
+        if let Some(swap_files) = swap_files.as_ref() {
+            swap_files.validate().map_err(|e| e.to_string())?;
+        }
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&parent_id, parent_version)
+            .map_err(|e| e.to_string())?;
+        let node = svc
+            .create_diff(&parent_id, &name, desc, scripts.unwrap_or_default())
+            .map_err(|e| e.to_string())?;
+        configure_created(
+            &svc,
+            &node,
+            computer_name.as_deref(),
+            network,
+            admin,
+            swap_files,
+        )?;
+        verify_created(&svc, node, verify_boot)
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn refresh_base(
+    node_id: String,
+    version: u64,
+    wim_file: String,
+    wim_index: u32,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<RefreshResult> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.refresh_base(&node_id, &wim_file, wim_index)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_recipes(state: State<'_, SharedState>) -> CmdResult<Vec<Recipe>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_recipes().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn save_recipe(recipe: Recipe, state: State<'_, SharedState>) -> CmdResult<Recipe> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.save_recipe(recipe).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn delete_recipe(recipe_id: String, state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.delete_recipe(&recipe_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn create_from_recipe(
+    recipe_id: String,
+    name: String,
+    verify_boot: Option<bool>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<CreateNodeResponse> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        let node = svc
+            .create_from_recipe(&recipe_id, &name)
+            .map_err(|e| e.to_string())?;
+        verify_created(&svc, node, verify_boot)
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_bootsequence_and_reboot(
+    node_id: String,
+    version: u64,
+    schedule_at: Option<DateTime<Utc>>,
+    force: Option<bool>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.set_bootsequence_and_reboot(&node_id, schedule_at, force.unwrap_or(false))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn validate_boot_chain(
+    node_id: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<ChainIssue>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.validate_boot_chain(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn cancel_scheduled_boot(state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.cancel_scheduled_boot().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn sysprep_layer(
+    node_id: String,
+    version: u64,
+    reboot: Option<bool>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.sysprep_layer(&node_id, reboot.unwrap_or(false))
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn inject_boot_marker(
+    node_id: String,
+    version: u64,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.inject_boot_marker(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn detect_bitlocker(node_id: String, state: State<'_, SharedState>) -> CmdResult<bool> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.detect_bitlocker(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn enable_bitlocker(
+    node_id: String,
+    version: u64,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Option<String>> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.enable_bitlocker(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn deploy_to_disk(
+    node_id: String,
+    version: u64,
+    target_volume: String,
+    efi_volume: Option<String>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.deploy_to_disk(&node_id, &target_volume, efi_volume.as_deref())
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_node_scripts(
+    node_id: String,
+    version: u64,
+    scripts: Vec<ProvisioningScript>,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.set_node_scripts(&node_id, scripts)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn list_node_scripts(
+    node_id: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<ProvisioningScript>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_node_scripts(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn apply_registry_tweaks(
+    node_id: String,
+    version: u64,
+    tweaks: Option<Vec<RegistryTweak>>,
+    presets: Option<Vec<RegistryPreset>>,
+    state: State<'_, SharedState>,
+) -> CmdResult<usize> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.apply_registry_tweaks(
+            &node_id,
+            tweaks.unwrap_or_default(),
+            presets.unwrap_or_default(),
+        )
+        .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn get_node_details(
+    node_id: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<NodeDetails> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_node_details(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn get_node_ops(
+    node_id: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<NodeOperation>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_node_ops(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn get_attach_history(
+    node_id: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<AttachEvent>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_attach_history(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn apply_node_scripts(
+    node_id: String,
+    version: u64,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.apply_node_scripts(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[derive(Serialize)]
+pub struct StartVmResponse {
+    pub vm_name: String,
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn start_vm(
+    node_id: String,
+    version: u64,
+    state: State<'_, SharedState>,
+) -> CmdResult<StartVmResponse> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        let vm_name = svc.start_vm(&node_id).map_err(|e| e.to_string())?;
+        Ok(StartVmResponse { vm_name })
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn delete_subtree(
+    node_id: String,
+    version: u64,
+    confirm_token: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Option<ConfirmationRequest>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        if let Some(request) = svc
+            .confirm_delete_subtree(&node_id, confirm_token.as_deref())
+            .map_err(|e| e.to_string())?
+        {
+            return Ok(Some(request));
+        }
+        svc.delete_subtree(&node_id).map_err(|e| e.to_string())?;
+        Ok(None)
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn shrink_vhd(
+    node_id: String,
+    version: u64,
+    new_size_gb: u64,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.shrink_vhd(&node_id, new_size_gb)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn get_compaction_advice(
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<CompactionHint>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_compaction_advice().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn compact_layer(
+    node_id: String,
+    version: u64,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<ReclaimResult> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.compact_layer(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn hash_bases(
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<String> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.hash_bases().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn find_duplicate_bases(state: State<'_, SharedState>) -> CmdResult<DuplicateReport> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.find_duplicate_bases().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn health_check(state: State<'_, SharedState>) -> CmdResult<Vec<HealthFinding>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.health_check().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn repair_all(
+    findings: Vec<HealthFinding>,
+    force_detach: Option<bool>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<RepairReport> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.repair_all(&findings, force_detach.unwrap_or(false))
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn repair_links(
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<LinkRepair> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.repair_links().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn archive_node(
+    node_id: String,
+    version: u64,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.archive_node(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn restore_archived(
+    node_id: String,
+    version: u64,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Node> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.restore_archived(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn delete_bcd(
+    node_id: String,
+    version: u64,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.delete_bcd(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn repair_bcd(
+    node_id: String,
+    version: u64,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Option<String>> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.repair_bcd(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn create_rescue_entry(
+    wim_path: Option<String>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<String> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.create_rescue_entry(wim_path.as_deref())
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn add_bcd_entry(
+    node_id: String,
+    version: u64,
+    description: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Option<String>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.add_bcd_entry(&node_id, description)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn list_firmware_entries(state: State<'_, SharedState>) -> CmdResult<FirmwareBootInfo> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.list_firmware_entries().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = node_id.as_deref(), op_id = Empty))]
+pub async fn set_boot_menu_policy(
+    node_id: Option<String>,
+    version: Option<u64>,
+    policy: BootMenuPolicy,
+    display_boot_menu: Option<bool>,
+    state: State<'_, SharedState>,
+) -> CmdResult<usize> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        // A single entry acts on one row, which needs its version like any other edit.
+        if let Some(node_id) = node_id.as_deref() {
+            let version = version.ok_or("version is required when targeting one node")?;
+            svc.expect_version(node_id, version)
+                .map_err(|e| e.to_string())?;
+        }
+        svc.set_boot_menu_policy(node_id.as_deref(), policy, display_boot_menu)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn get_secure_boot_state() -> CmdResult<SecureBootState> {
+    run_blocking_cmd(|| Ok(secureboot::state())).await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn get_entry_flags(
+    node_id: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<BootEntryFlags> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_entry_flags(&node_id).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn set_entry_flags(
+    node_id: String,
+    version: u64,
+    flags: BootEntryFlags,
+    state: State<'_, SharedState>,
+) -> CmdResult<BootEntryFlags> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.set_entry_flags(&node_id, flags)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all, fields(node_id = %node_id, op_id = Empty))]
+pub async fn update_bcd_description(
+    node_id: String,
+    version: u64,
+    description: String,
+    state: State<'_, SharedState>,
+) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.expect_version(&node_id, version)
+            .map_err(|e| e.to_string())?;
+        svc.update_bcd_description(&node_id, &description)
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn export_audit(
+    range: AuditRange,
+    format: AuditFormat,
+    state: State<'_, SharedState>,
+) -> CmdResult<String> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.export_audit(&range, format).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn export_tree_graph(
+    format: GraphFormat,
+    state: State<'_, SharedState>,
+) -> CmdResult<String> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.export_tree_graph(format).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn export_metadata(state: State<'_, SharedState>) -> CmdResult<String> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.export_metadata().map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn import_metadata(
+    file: String,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<MetadataImport> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        let svc = WorkspaceService::new(state);
+        svc.import_metadata(&file).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn reveal_log_dir(state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        let log_path = svc.ops_log_path().map_err(|e| e.to_string())?;
+        tauri_plugin_opener::reveal_item_in_dir(&log_path).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn open_ops_log(state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        let log_path = svc.ops_log_path().map_err(|e| e.to_string())?;
+        tauri_plugin_opener::open_path(&log_path, None::<&str>).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn open_failed_script(state: State<'_, SharedState>) -> CmdResult<()> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        let script = svc.last_failed_script().map_err(|e| e.to_string())?;
+        tauri_plugin_opener::open_path(&script, None::<&str>).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+pub async fn list_jobs() -> CmdResult<Vec<JobInfo>> {
+    Ok(jobs::list())
+}
+
+#[tauri::command]
+pub async fn cancel_job(job_id: String) -> CmdResult<bool> {
+    Ok(jobs::cancel(&job_id))
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn run_maintenance(
+    tasks: Option<Vec<MaintenanceTask>>,
+    job_id: Option<String>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<JobRecord>> {
+    let state = state.inner().clone();
+    run_job_cmd(job_id, move || {
+        maintenance::run_now(&state, tasks).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn get_job_history(
+    limit: Option<u32>,
+    state: State<'_, SharedState>,
+) -> CmdResult<Vec<JobRecord>> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.get_job_history(limit.unwrap_or(100))
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+#[tauri::command]
+#[instrument(skip_all)]
+pub async fn clean_temp(state: State<'_, SharedState>) -> CmdResult<TempCleanup> {
+    let state = state.inner().clone();
+    run_blocking_cmd(move || {
+        let svc = WorkspaceService::new(state);
+        svc.clean_temp().map_err(|e| e.to_string())
+    })
+    .await
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+
+use crate::error::{AppError, Result};
+
+/// How long a confirmation token stays valid after the summary was shown.
+const TOKEN_TTL: Duration = Duration::minutes(2);
+
+/// Outstanding tokens by value; each one is consumed by the first redeem attempt.
+static PENDING: Lazy<Mutex<HashMap<String, Pending>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Pending {
+    action: String,
+    target: String,
+    /// Digest of what the summary described; redeeming fails when the target changed since.
+    fingerprint: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Issue a one-time token for running `action` on `target` while it still matches
+/// `fingerprint`. Expired tokens are dropped on the way.
+pub fn issue(action: &str, target: &str, fingerprint: String) -> (String, DateTime<Utc>) {
+    let now = Utc::now();
+    let token = Uuid::new_v4().to_string();
+    let expires_at = now + TOKEN_TTL;
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, p| p.expires_at > now);
+    pending.insert(
+        token.clone(),
+        Pending {
+            action: action.to_string(),
+            target: target.to_string(),
+            fingerprint,
+            expires_at,
+        },
+    );
+    (token, expires_at)
+}
+
+/// Consume `token`; succeeds only when it was issued for the same action, target and
+/// fingerprint and has not expired.
+pub fn redeem(token: &str, action: &str, target: &str, fingerprint: &str) -> Result<()> {
+    let Some(pending) = PENDING.lock().unwrap().remove(token) else {
+        return Err(AppError::Message(
+            "confirmation token is unknown or was already used".into(),
+        ));
+    };
+    if pending.expires_at <= Utc::now() {
+        return Err(AppError::Message(
+            "confirmation token expired; review the summary again".into(),
+        ));
+    }
+    if pending.action != action || pending.target != target {
+        return Err(AppError::Message(format!(
+            "confirmation token was issued for {} on another target",
+            pending.action
+        )));
+    }
+    if pending.fingerprint != fingerprint {
+        return Err(AppError::Message(
+            "the affected layers changed since the summary was shown; review it again".into(),
+        ));
+    }
+    Ok(())
+}
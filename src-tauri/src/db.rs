@@ -1,13 +1,23 @@
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection};
-use serde::Serialize;
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 
+use crate::bcd::BcdTarget;
 use crate::error::{AppError, Result};
-use crate::models::{Node, NodeStatus};
-use crate::paths::AppPaths;
+use crate::models::{
+    AgentConfig, AttachEvent, AuditEntry, BackupStatus, JobRecord, MaintenanceSchedule,
+    NetworkProfile, Node, NodeHash, NodeOperation, NodeStatus, OsInfo, ProvisioningScript, Recipe,
+    SavedView, VhdMetrics, WimSource,
+};
+use crate::paths::{expand_env_vars, normalize_path, AppPaths, PATH_KEY_VERSION};
+use crate::tools::ToolPaths;
+use crate::transcript;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct AppSettings {
@@ -15,8 +25,75 @@ pub struct AppSettings {
     pub locale: String,
     pub seq_counter: i64,
     pub last_boot_guid: Option<String>,
+    pub tool_paths: ToolPaths,
+    pub rescue_guid: Option<String>,
+    pub max_chain_depth: u32,
+    /// Direct children a layer may have; 0 means no limit.
+    pub max_children_per_parent: u32,
+    /// Combined size (GB) of all layer files new layers may not push past; 0 means no limit.
+    pub max_total_gb: u32,
+    /// Free space (GB) that must remain on the workspace volume before creating a layer.
+    pub free_space_headroom_gb: u32,
+    /// Boot menu description pattern, e.g. `[LS] {name} ({parent}) {date}`.
+    pub bcd_description_template: Option<String>,
+    /// Alternate ESP / BCD store for all boot-entry operations.
+    pub bcd_target: BcdTarget,
+    pub maintenance: MaintenanceSchedule,
+    pub agent: AgentConfig,
+    /// Share (usually UNC) holding published base VHDXs.
+    pub library_path: Option<String>,
+    /// Folder for temp scripts and staging files instead of `meta/tmp`; may use `%VAR%`.
+    pub temp_path: Option<String>,
+    /// Replace user paths, machine names and volume serials in logs, transcripts and reports.
+    pub redact_logs: bool,
+    /// Folder for base layers instead of `disks`; may use `%VAR%`.
+    pub base_path: Option<String>,
+    /// Folder for differencing layers; follows the base folder when not set.
+    pub diff_path: Option<String>,
+    /// Apply bases from a cached single-index export of multi-index WIM/ESD sources.
+    pub wim_cache: bool,
+    /// Local folder of golden base VHDXs copied by `create_base_from_template`; may use `%VAR%`.
+    pub template_path: Option<String>,
+    /// Destructive commands first answer with a summary and a one-time token and only run when
+    /// called again with it.
+    pub confirm_destructive: bool,
+    /// Kiosk mode: mutating commands are refused until the PIN unlocks the session.
+    pub read_only: bool,
 }
 
+/// Partial settings update; `None` fields are left untouched.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SettingsPatch {
+    pub max_chain_depth: Option<u32>,
+    pub max_children_per_parent: Option<u32>,
+    pub max_total_gb: Option<u32>,
+    pub free_space_headroom_gb: Option<u32>,
+    /// An empty string clears the template.
+    pub bcd_description_template: Option<String>,
+    pub bcd_target: Option<BcdTarget>,
+    pub maintenance: Option<MaintenanceSchedule>,
+    pub agent: Option<AgentConfig>,
+    /// An empty string clears the library path.
+    pub library_path: Option<String>,
+    /// An empty string moves temp files back into the workspace.
+    pub temp_path: Option<String>,
+    pub redact_logs: Option<bool>,
+    /// An empty string moves new base layers back into the workspace.
+    pub base_path: Option<String>,
+    /// An empty string puts new differencing layers next to the bases again.
+    pub diff_path: Option<String>,
+    pub wim_cache: Option<bool>,
+    /// An empty string clears the template folder.
+    pub template_path: Option<String>,
+    pub confirm_destructive: Option<bool>,
+}
+
+const BACKUP_COLUMNS: &str =
+    "id, file, node_id, dest, backed_up_at, result, file_bytes, source_modified, sha256, detail";
+
+const NODE_COLUMNS: &str =
+    "id, parent_id, name, path, bcd_guid, desc, created_at, status, boot_files_ready, generalized, boot_verified_at, bitlocker, color, icon, os_edition, os_version, os_build, sort_key, tags, version, expires_at, protected, network";
+
 #[derive(Debug)]
 pub struct Database {
     conn: Mutex<Connection>,
@@ -73,8 +150,184 @@ impl Database {
                 detail TEXT,
                 FOREIGN KEY(node_id) REFERENCES nodes(id)
             );
+
+            CREATE TABLE IF NOT EXISTS node_scripts (
+                node_id TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                path TEXT NOT NULL,
+                args TEXT,
+                PRIMARY KEY(node_id, position),
+                FOREIGN KEY(node_id) REFERENCES nodes(id)
+            );
+
+            CREATE TABLE IF NOT EXISTS attach_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                node_id TEXT NOT NULL,
+                op_id TEXT NOT NULL,
+                action TEXT NOT NULL,
+                context TEXT NOT NULL,
+                result TEXT NOT NULL,
+                ts TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_attach_history_node ON attach_history(node_id);
+
+            CREATE TABLE IF NOT EXISTS recipes (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                spec TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS wim_sources (
+                id TEXT PRIMARY KEY,
+                path_key TEXT NOT NULL UNIQUE,
+                spec TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS saved_views (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                spec TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS job_history (
+                id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                started_at TEXT NOT NULL,
+                finished_at TEXT NOT NULL,
+                result TEXT NOT NULL,
+                detail TEXT
+            );
+            CREATE INDEX IF NOT EXISTS idx_job_history_kind ON job_history(kind, finished_at);
+
+            CREATE TABLE IF NOT EXISTS node_backups (
+                node_id TEXT NOT NULL,
+                dest TEXT NOT NULL,
+                backed_up_at TEXT NOT NULL,
+                result TEXT NOT NULL,
+                file_bytes INTEGER NOT NULL,
+                source_modified TEXT,
+                sha256 TEXT,
+                detail TEXT,
+                PRIMARY KEY(node_id, dest)
+            );
+
+            CREATE TABLE IF NOT EXISTS vhd_metrics (
+                node_id TEXT PRIMARY KEY,
+                file_bytes INTEGER NOT NULL,
+                fragmentation INTEGER NOT NULL,
+                measured_at TEXT NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS node_hashes (
+                node_id TEXT PRIMARY KEY,
+                file_bytes INTEGER NOT NULL,
+                modified TEXT,
+                sha256 TEXT NOT NULL,
+                hashed_at TEXT NOT NULL
+            );
             "#,
         )?;
+        ensure_column(&conn, "settings", "tool_paths", "TEXT")?;
+        ensure_column(&conn, "settings", "rescue_guid", "TEXT")?;
+        ensure_column(&conn, "settings", "rescue_options_guid", "TEXT")?;
+        ensure_column(
+            &conn,
+            "settings",
+            "max_chain_depth",
+            "INTEGER NOT NULL DEFAULT 8",
+        )?;
+        ensure_column(
+            &conn,
+            "settings",
+            "free_space_headroom_gb",
+            "INTEGER NOT NULL DEFAULT 10",
+        )?;
+        ensure_column(&conn, "settings", "bcd_description_template", "TEXT")?;
+        ensure_column(&conn, "settings", "bcd_target", "TEXT")?;
+        ensure_column(&conn, "settings", "maintenance", "TEXT")?;
+        ensure_column(&conn, "settings", "agent", "TEXT")?;
+        ensure_column(&conn, "settings", "library_path", "TEXT")?;
+        ensure_column(&conn, "settings", "temp_path", "TEXT")?;
+        ensure_column(
+            &conn,
+            "settings",
+            "redact_logs",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        ensure_column(&conn, "settings", "base_path", "TEXT")?;
+        ensure_column(&conn, "settings", "diff_path", "TEXT")?;
+        ensure_column(&conn, "settings", "wim_cache", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(
+            &conn,
+            "settings",
+            "max_children_per_parent",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        ensure_column(
+            &conn,
+            "settings",
+            "max_total_gb",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        ensure_column(&conn, "settings", "template_path", "TEXT")?;
+        ensure_column(
+            &conn,
+            "settings",
+            "confirm_destructive",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        ensure_column(&conn, "settings", "read_only", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "settings", "read_only_pin", "TEXT")?;
+        ensure_column(&conn, "node_backups", "id", "TEXT")?;
+        ensure_column(&conn, "node_backups", "file", "TEXT")?;
+        ensure_column(&conn, "nodes", "generalized", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "nodes", "boot_verified_at", "TEXT")?;
+        ensure_column(&conn, "nodes", "bitlocker", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "nodes", "color", "TEXT")?;
+        ensure_column(&conn, "nodes", "icon", "TEXT")?;
+        ensure_column(&conn, "nodes", "os_edition", "TEXT")?;
+        ensure_column(&conn, "nodes", "os_version", "TEXT")?;
+        ensure_column(&conn, "nodes", "os_build", "TEXT")?;
+        ensure_column(&conn, "nodes", "os_probed_at", "TEXT")?;
+        ensure_column(&conn, "nodes", "sort_key", "INTEGER")?;
+        ensure_column(&conn, "nodes", "tags", "TEXT")?;
+        ensure_column(&conn, "nodes", "path_key", "TEXT")?;
+        ensure_column(&conn, "nodes", "version", "INTEGER NOT NULL DEFAULT 1")?;
+        ensure_column(&conn, "nodes", "expires_at", "TEXT")?;
+        ensure_column(&conn, "nodes", "network", "TEXT")?;
+        ensure_column(&conn, "nodes", "protected", "INTEGER NOT NULL DEFAULT 0")?;
+        ensure_column(&conn, "ops", "duration_ms", "INTEGER")?;
+        ensure_column(&conn, "ops", "actor", "TEXT")?;
+        ensure_column(&conn, "ops", "transcript", "TEXT")?;
+        ensure_column(
+            &conn,
+            "settings",
+            "path_key_version",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        let key_version: i64 = conn.query_row(
+            "SELECT path_key_version FROM settings WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        let stale_keys = key_version < PATH_KEY_VERSION;
+        dedupe_path_keys(&conn, stale_keys)?;
+        if stale_keys {
+            recompute_wim_source_keys(&conn)?;
+            conn.execute(
+                "UPDATE settings SET path_key_version = ?1 WHERE id = 1",
+                params![PATH_KEY_VERSION],
+            )?;
+        }
+        conn.execute_batch(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_nodes_path_key ON nodes(path_key);
+            CREATE TRIGGER IF NOT EXISTS nodes_bump_version AFTER UPDATE ON nodes
+            WHEN NEW.version = OLD.version
+            BEGIN
+                UPDATE nodes SET version = OLD.version + 1 WHERE id = NEW.id;
+            END;",
+        )?;
         Ok(())
     }
 
@@ -112,6 +365,178 @@ impl Database {
         Ok(())
     }
 
+    pub fn apply_settings_patch(&self, patch: &SettingsPatch) -> Result<()> {
+        let conn = self.connection();
+        if let Some(depth) = patch.max_chain_depth {
+            conn.execute(
+                "UPDATE settings SET max_chain_depth = ?1 WHERE id = 1",
+                params![depth],
+            )?;
+        }
+        if let Some(children) = patch.max_children_per_parent {
+            conn.execute(
+                "UPDATE settings SET max_children_per_parent = ?1 WHERE id = 1",
+                params![children],
+            )?;
+        }
+        if let Some(total) = patch.max_total_gb {
+            conn.execute(
+                "UPDATE settings SET max_total_gb = ?1 WHERE id = 1",
+                params![total],
+            )?;
+        }
+        if let Some(headroom) = patch.free_space_headroom_gb {
+            conn.execute(
+                "UPDATE settings SET free_space_headroom_gb = ?1 WHERE id = 1",
+                params![headroom],
+            )?;
+        }
+        if let Some(template) = patch.bcd_description_template.as_deref() {
+            let template = Some(template.trim()).filter(|t| !t.is_empty());
+            conn.execute(
+                "UPDATE settings SET bcd_description_template = ?1 WHERE id = 1",
+                params![template],
+            )?;
+        }
+        if let Some(target) = patch.bcd_target.as_ref() {
+            conn.execute(
+                "UPDATE settings SET bcd_target = ?1 WHERE id = 1",
+                params![serde_json::to_string(target)?],
+            )?;
+        }
+        if let Some(schedule) = patch.maintenance.as_ref() {
+            if schedule.interval_hours == 0 {
+                return Err(AppError::Message(
+                    "maintenance interval must be at least one hour".into(),
+                ));
+            }
+            conn.execute(
+                "UPDATE settings SET maintenance = ?1 WHERE id = 1",
+                params![serde_json::to_string(schedule)?],
+            )?;
+        }
+        if let Some(agent) = patch.agent.as_ref() {
+            if agent.enabled {
+                let endpoint = agent.endpoint.trim();
+                if !endpoint.starts_with("https://") && !endpoint.starts_with("http://") {
+                    return Err(AppError::Message(
+                        "agent endpoint must be an http(s) URL".into(),
+                    ));
+                }
+                if agent.key.len() < 16 {
+                    return Err(AppError::Message(
+                        "agent key must be at least 16 characters".into(),
+                    ));
+                }
+            }
+            conn.execute(
+                "UPDATE settings SET agent = ?1 WHERE id = 1",
+                params![serde_json::to_string(agent)?],
+            )?;
+        }
+        if let Some(library) = patch.library_path.as_deref() {
+            let library = Some(library.trim()).filter(|l| !l.is_empty());
+            conn.execute(
+                "UPDATE settings SET library_path = ?1 WHERE id = 1",
+                params![library],
+            )?;
+        }
+        for (column, label, value) in [
+            ("temp_path", "temp folder", &patch.temp_path),
+            ("base_path", "base layer folder", &patch.base_path),
+            ("diff_path", "diff layer folder", &patch.diff_path),
+            ("template_path", "template folder", &patch.template_path),
+        ] {
+            let Some(dir) = value.as_deref() else {
+                continue;
+            };
+            let dir = Some(dir.trim()).filter(|d| !d.is_empty());
+            if let Some(dir) = dir {
+                if !Path::new(&expand_env_vars(dir)).is_absolute() {
+                    return Err(AppError::Message(format!(
+                        "{label} must be an absolute path: {dir}"
+                    )));
+                }
+            }
+            conn.execute(
+                &format!("UPDATE settings SET {column} = ?1 WHERE id = 1"),
+                params![dir],
+            )?;
+        }
+        if let Some(redact) = patch.redact_logs {
+            conn.execute(
+                "UPDATE settings SET redact_logs = ?1 WHERE id = 1",
+                params![redact],
+            )?;
+        }
+        if let Some(cache) = patch.wim_cache {
+            conn.execute(
+                "UPDATE settings SET wim_cache = ?1 WHERE id = 1",
+                params![cache],
+            )?;
+        }
+        if let Some(confirm) = patch.confirm_destructive {
+            conn.execute(
+                "UPDATE settings SET confirm_destructive = ?1 WHERE id = 1",
+                params![confirm],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn update_tool_paths(&self, tool_paths: &ToolPaths) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE settings SET tool_paths = ?1 WHERE id = 1",
+            params![serde_json::to_string(tool_paths)?],
+        )?;
+        Ok(())
+    }
+
+    /// Salted hash of the PIN that unlocks read-only mode, as written by `set_read_only`.
+    pub fn read_only_pin(&self) -> Result<Option<String>> {
+        let conn = self.connection();
+        let pin = conn.query_row(
+            "SELECT read_only_pin FROM settings WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(pin)
+    }
+
+    pub fn set_read_only(&self, read_only: bool, pin_hash: Option<&str>) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE settings SET read_only = ?1, read_only_pin = ?2 WHERE id = 1",
+            params![read_only, pin_hash],
+        )?;
+        Ok(())
+    }
+
+    /// Rescue entry and its ramdisk options object, as created by `create_rescue_entry`.
+    pub fn rescue_entry(&self) -> Result<(Option<String>, Option<String>)> {
+        let conn = self.connection();
+        let entry = conn.query_row(
+            "SELECT rescue_guid, rescue_options_guid FROM settings WHERE id = 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(entry)
+    }
+
+    pub fn update_rescue_entry(
+        &self,
+        guid: Option<&str>,
+        options_guid: Option<&str>,
+    ) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE settings SET rescue_guid = ?1, rescue_options_guid = ?2 WHERE id = 1",
+            params![guid, options_guid],
+        )?;
+        Ok(())
+    }
+
     pub fn next_seq(&self) -> Result<i64> {
         let mut conn = self.connection();
         conn.execute("UPDATE settings SET seq_counter = seq_counter + 1", [])?;
@@ -122,7 +547,7 @@ impl Database {
     pub fn get_settings(&self) -> Result<AppSettings> {
         let conn = self.connection();
         let settings = conn.query_row(
-            "SELECT root_path, locale, seq_counter, last_boot_guid FROM settings WHERE id = 1",
+            "SELECT root_path, locale, seq_counter, last_boot_guid, tool_paths, rescue_guid, max_chain_depth, free_space_headroom_gb, bcd_description_template, bcd_target, maintenance, library_path, temp_path, redact_logs, base_path, diff_path, wim_cache, max_children_per_parent, max_total_gb, template_path, agent, confirm_destructive, read_only FROM settings WHERE id = 1",
             [],
             |row| {
                 Ok(AppSettings {
@@ -130,16 +555,88 @@ impl Database {
                     locale: row.get(1)?,
                     seq_counter: row.get(2)?,
                     last_boot_guid: row.get(3)?,
+                    tool_paths: row
+                        .get::<_, Option<String>>(4)?
+                        .and_then(|s| serde_json::from_str(&s).ok())
+                        .unwrap_or_default(),
+                    rescue_guid: row.get(5)?,
+                    max_chain_depth: row.get(6)?,
+                    free_space_headroom_gb: row.get(7)?,
+                    bcd_description_template: row.get(8)?,
+                    bcd_target: row
+                        .get::<_, Option<String>>(9)?
+                        .and_then(|s| serde_json::from_str(&s).ok())
+                        .unwrap_or_default(),
+                    maintenance: row
+                        .get::<_, Option<String>>(10)?
+                        .and_then(|s| serde_json::from_str(&s).ok())
+                        .unwrap_or_default(),
+                    library_path: row.get(11)?,
+                    temp_path: row.get(12)?,
+                    redact_logs: row.get(13)?,
+                    base_path: row.get(14)?,
+                    diff_path: row.get(15)?,
+                    wim_cache: row.get(16)?,
+                    max_children_per_parent: row.get(17)?,
+                    max_total_gb: row.get(18)?,
+                    template_path: row.get(19)?,
+                    agent: row
+                        .get::<_, Option<String>>(20)?
+                        .and_then(|s| serde_json::from_str(&s).ok())
+                        .unwrap_or_default(),
+                    confirm_destructive: row.get(21)?,
+                    read_only: row.get(22)?,
                 })
             },
         )?;
         Ok(settings)
     }
 
+    /// Insert a node; fails with a readable error when another row already points at the file.
     pub fn insert_node(&self, node: &Node) -> Result<()> {
-        let mut conn = self.connection();
-        conn.execute(
-            "INSERT INTO nodes (id, parent_id, name, path, bcd_guid, desc, created_at, status, boot_files_ready) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        match self.insert_node_with("INSERT", node) {
+            Err(AppError::Db(rusqlite::Error::SqliteFailure(err, _)))
+                if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(AppError::Message(format!(
+                    "another node already points at {}",
+                    node.path
+                )))
+            }
+            other => other.map(|_| ()),
+        }
+    }
+
+    /// Write the final row of a node inserted earlier as `Creating`, as a new version of it.
+    pub fn complete_node(&self, node: &Node) -> Result<()> {
+        let version = self.fetch_node(&node.id)?.map_or(0, |n| n.version) + 1;
+        self.insert_node_with(
+            "INSERT OR REPLACE",
+            &Node {
+                version,
+                ..node.clone()
+            },
+        )
+        .map(|_| ())
+    }
+
+    /// Insert a node discovered by a scan unless its normalized path is already tracked.
+    /// Returns the id of the row owning the path and whether it was inserted.
+    pub fn upsert_scanned_node(&self, node: &Node) -> Result<(String, bool)> {
+        let inserted = self.insert_node_with("INSERT OR IGNORE", node)? > 0;
+        let conn = self.connection();
+        let id = conn.query_row(
+            "SELECT id FROM nodes WHERE path_key = ?1",
+            params![normalize_path(&node.path)],
+            |row| row.get(0),
+        )?;
+        Ok((id, inserted))
+    }
+
+    fn insert_node_with(&self, verb: &str, node: &Node) -> Result<usize> {
+        let conn = self.connection();
+        let changed = conn.execute(
+            &format!("{verb} INTO nodes (id, parent_id, name, path, bcd_guid, desc, created_at, status, boot_files_ready, generalized, boot_verified_at, bitlocker, color, icon, os_edition, os_version, os_build, sort_key, tags, path_key, version, expires_at, protected, network) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24)"),
             params![
                 node.id,
                 node.parent_id,
@@ -149,16 +646,33 @@ impl Database {
                 node.desc,
                 node.created_at.to_rfc3339(),
                 format!("{:?}", node.status),
-                node.boot_files_ready as i32
+                node.boot_files_ready as i32,
+                node.generalized as i32,
+                node.boot_verified_at.map(|t| t.to_rfc3339()),
+                node.bitlocker as i32,
+                node.color,
+                node.icon,
+                node.os_edition,
+                node.os_version,
+                node.os_build,
+                node.sort_key,
+                tags_to_column(&node.tags),
+                normalize_path(&node.path),
+                node.version.max(1),
+                node.expires_at.map(|t| t.to_rfc3339()),
+                node.protected as i32,
+                node.network
+                    .as_ref()
+                    .and_then(|n| serde_json::to_string(n).ok())
             ],
         )?;
-        Ok(())
+        Ok(changed)
     }
 
     pub fn update_node_status(&self, id: &str, status: NodeStatus) -> Result<()> {
         let mut conn = self.connection();
         conn.execute(
-            "UPDATE nodes SET status = ?1 WHERE id = ?2",
+            "UPDATE nodes SET status = ?1 WHERE id = ?2 AND status IS NOT ?1",
             params![format!("{:?}", status), id],
         )?;
         Ok(())
@@ -182,6 +696,15 @@ impl Database {
         Ok(())
     }
 
+    pub fn set_boot_files_ready(&self, id: &str, ready: bool) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET boot_files_ready = ?1 WHERE id = ?2",
+            params![ready as i32, id],
+        )?;
+        Ok(())
+    }
+
     pub fn clear_node_bcd(&self, id: &str) -> Result<()> {
         let mut conn = self.connection();
         conn.execute(
@@ -191,62 +714,154 @@ impl Database {
         Ok(())
     }
 
-    pub fn fetch_nodes(&self) -> Result<Vec<Node>> {
+    pub fn set_node_generalized(&self, id: &str, generalized: bool) -> Result<()> {
         let conn = self.connection();
-        let mut stmt = conn.prepare(
-            "SELECT id, parent_id, name, path, bcd_guid, desc, created_at, status, boot_files_ready FROM nodes",
+        conn.execute(
+            "UPDATE nodes SET generalized = ?1 WHERE id = ?2",
+            params![generalized as i32, id],
         )?;
-        let rows = stmt.query_map([], |row| {
-            let created_at: String = row.get(6)?;
-            Ok(Node {
-                id: row.get(0)?,
-                parent_id: row.get(1)?,
-                name: row.get(2)?,
-                path: row.get(3)?,
-                bcd_guid: row.get(4)?,
-                desc: row.get(5)?,
-                created_at: created_at.parse().unwrap_or_else(|_| chrono::Utc::now()),
-                status: match row.get::<_, String>(7)?.as_str() {
-                    "MissingFile" => NodeStatus::MissingFile,
-                    "MissingParent" => NodeStatus::MissingParent,
-                    "MissingBcd" => NodeStatus::MissingBcd,
-                    "Mounted" => NodeStatus::Mounted,
-                    "Error" => NodeStatus::Error,
-                    _ => NodeStatus::Normal,
-                },
-                boot_files_ready: row.get::<_, i32>(8)? != 0,
-            })
-        })?;
+        Ok(())
+    }
+
+    pub fn set_node_boot_verified(&self, id: &str, at: DateTime<Utc>) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET boot_verified_at = ?1 WHERE id = ?2",
+            params![at.to_rfc3339(), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_node_bitlocker(&self, id: &str, encrypted: bool) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET bitlocker = ?1 WHERE id = ?2",
+            params![encrypted as i32, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_node_appearance(
+        &self,
+        id: &str,
+        color: Option<&str>,
+        icon: Option<&str>,
+    ) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET color = ?1, icon = ?2 WHERE id = ?3",
+            params![color, icon, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_node_metadata(
+        &self,
+        id: &str,
+        name: &str,
+        desc: Option<&str>,
+        generalized: bool,
+    ) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET name = ?1, desc = ?2, generalized = ?3 WHERE id = ?4",
+            params![name, desc, generalized as i32, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_node_tags(&self, id: &str, tags: &[String]) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET tags = ?1 WHERE id = ?2",
+            params![tags_to_column(tags), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_node_expiry(
+        &self,
+        id: &str,
+        expires_at: Option<DateTime<Utc>>,
+        protected: bool,
+    ) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET expires_at = ?1, protected = ?2 WHERE id = ?3",
+            params![expires_at.map(|t| t.to_rfc3339()), protected as i32, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_node_network(&self, id: &str, network: Option<&NetworkProfile>) -> Result<()> {
+        let network = network.map(serde_json::to_string).transpose()?;
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET network = ?1 WHERE id = ?2",
+            params![network, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_node_os_info(&self, id: &str, info: &OsInfo) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET os_edition = ?1, os_version = ?2, os_build = ?3 WHERE id = ?4",
+            params![info.edition, info.version, info.build, id],
+        )?;
+        Ok(())
+    }
+
+    /// Remember that the scan read OS details from the node's image, whatever the outcome, so
+    /// the elevated DISM probe is not repeated on every scan.
+    pub fn set_node_os_probed(&self, id: &str, at: DateTime<Utc>) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "UPDATE nodes SET os_probed_at = ?1 WHERE id = ?2",
+            params![at.to_rfc3339(), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn node_os_probed(&self, id: &str) -> Result<bool> {
+        let conn = self.connection();
+        let probed: Option<String> = conn.query_row(
+            "SELECT MAX(os_probed_at) FROM nodes WHERE id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        Ok(probed.is_some())
+    }
+
+    /// Persist a manual order: each id gets its position as `sort_key`.
+    pub fn update_sort_keys(&self, ordered_ids: &[String]) -> Result<()> {
+        let mut conn = self.connection();
+        let tx = conn.transaction()?;
+        for (idx, id) in ordered_ids.iter().enumerate() {
+            tx.execute(
+                "UPDATE nodes SET sort_key = ?1 WHERE id = ?2",
+                params![idx as i64, id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn fetch_nodes(&self) -> Result<Vec<Node>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {NODE_COLUMNS} FROM nodes ORDER BY sort_key IS NULL, sort_key, created_at"
+        ))?;
+        let rows = stmt.query_map([], node_from_row)?;
         Ok(rows.filter_map(rusqlite::Result::ok).collect())
     }
 
     pub fn fetch_node(&self, id: &str) -> Result<Option<Node>> {
         let conn = self.connection();
-        let mut stmt = conn.prepare(
-            "SELECT id, parent_id, name, path, bcd_guid, desc, created_at, status, boot_files_ready FROM nodes WHERE id = ?1",
-        )?;
+        let mut stmt = conn.prepare(&format!("SELECT {NODE_COLUMNS} FROM nodes WHERE id = ?1"))?;
         let mut rows = stmt.query(params![id])?;
         if let Some(row) = rows.next()? {
-            let created_at: String = row.get(6)?;
-            let node = Node {
-                id: row.get(0)?,
-                parent_id: row.get(1)?,
-                name: row.get(2)?,
-                path: row.get(3)?,
-                bcd_guid: row.get(4)?,
-                desc: row.get(5)?,
-                created_at: created_at.parse().unwrap_or_else(|_| chrono::Utc::now()),
-                status: match row.get::<_, String>(7)?.as_str() {
-                    "MissingFile" => NodeStatus::MissingFile,
-                    "MissingParent" => NodeStatus::MissingParent,
-                    "MissingBcd" => NodeStatus::MissingBcd,
-                    "Mounted" => NodeStatus::Mounted,
-                    "Error" => NodeStatus::Error,
-                    _ => NodeStatus::Normal,
-                },
-                boot_files_ready: row.get::<_, i32>(8)? != 0,
-            };
-            Ok(Some(node))
+            Ok(Some(node_from_row(row)?))
         } else {
             Ok(None)
         }
@@ -259,10 +874,215 @@ impl Database {
         let conn = self.connection();
         for id in ids {
             conn.execute("DELETE FROM nodes WHERE id = ?1", params![id])?;
+            conn.execute("DELETE FROM node_hashes WHERE node_id = ?1", params![id])?;
+            conn.execute("DELETE FROM vhd_metrics WHERE node_id = ?1", params![id])?;
         }
         Ok(())
     }
 
+    pub fn set_node_scripts(&self, node_id: &str, scripts: &[ProvisioningScript]) -> Result<()> {
+        let mut conn = self.connection();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM node_scripts WHERE node_id = ?1",
+            params![node_id],
+        )?;
+        for (idx, script) in scripts.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO node_scripts (node_id, position, path, args) VALUES (?1, ?2, ?3, ?4)",
+                params![node_id, idx as i64, script.path, script.args],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn fetch_node_scripts(&self, node_id: &str) -> Result<Vec<ProvisioningScript>> {
+        let conn = self.connection();
+        let mut stmt = conn
+            .prepare("SELECT path, args FROM node_scripts WHERE node_id = ?1 ORDER BY position")?;
+        let rows = stmt.query_map(params![node_id], |row| {
+            Ok(ProvisioningScript {
+                path: row.get(0)?,
+                args: row.get(1)?,
+            })
+        })?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
+    pub fn fetch_recipes(&self) -> Result<Vec<Recipe>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare("SELECT spec FROM recipes ORDER BY name")?;
+        let specs = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(rusqlite::Result::ok)
+            .collect::<Vec<_>>();
+        Ok(specs
+            .iter()
+            .filter_map(|spec| serde_json::from_str(spec).ok())
+            .collect())
+    }
+
+    pub fn fetch_recipe(&self, id: &str) -> Result<Option<Recipe>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare("SELECT spec FROM recipes WHERE id = ?1")?;
+        let mut rows = stmt.query(params![id])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(serde_json::from_str(&row.get::<_, String>(0)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Insert or replace a recipe; the whole recipe is stored as JSON so new fields need no migration.
+    pub fn upsert_recipe(&self, recipe: &Recipe) -> Result<()> {
+        let conn = self.connection();
+        let res = conn.execute(
+            "INSERT INTO recipes (id, name, spec, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(id) DO UPDATE SET name = excluded.name, spec = excluded.spec, updated_at = excluded.updated_at",
+            params![
+                recipe.id,
+                recipe.name,
+                serde_json::to_string(recipe)?,
+                recipe.updated_at.to_rfc3339()
+            ],
+        );
+        match res {
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(AppError::Message(format!(
+                    "a recipe named \"{}\" already exists",
+                    recipe.name
+                )))
+            }
+            other => other.map(|_| ()).map_err(Into::into),
+        }
+    }
+
+    pub fn delete_recipe(&self, id: &str) -> Result<bool> {
+        let conn = self.connection();
+        Ok(conn.execute("DELETE FROM recipes WHERE id = ?1", params![id])? > 0)
+    }
+
+    pub fn fetch_wim_sources(&self) -> Result<Vec<WimSource>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare("SELECT spec FROM wim_sources ORDER BY path_key")?;
+        let specs = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(rusqlite::Result::ok)
+            .collect::<Vec<_>>();
+        Ok(specs
+            .iter()
+            .filter_map(|spec| serde_json::from_str(spec).ok())
+            .collect())
+    }
+
+    /// Register a WIM source, replacing the entry of the same file (keeping its id); stored as
+    /// JSON like recipes.
+    pub fn upsert_wim_source(&self, source: &WimSource) -> Result<WimSource> {
+        let conn = self.connection();
+        let path_key = normalize_path(&source.path);
+        let existing = {
+            let mut stmt = conn.prepare("SELECT id FROM wim_sources WHERE path_key = ?1")?;
+            let mut rows = stmt.query(params![path_key])?;
+            match rows.next()? {
+                Some(row) => Some(row.get::<_, String>(0)?),
+                None => None,
+            }
+        };
+        let id = existing.unwrap_or_else(|| source.id.clone());
+        let source = WimSource {
+            id,
+            ..source.clone()
+        };
+        conn.execute(
+            "INSERT INTO wim_sources (id, path_key, spec) VALUES (?1, ?2, ?3)
+             ON CONFLICT(path_key) DO UPDATE SET spec = excluded.spec",
+            params![source.id, path_key, serde_json::to_string(&source)?],
+        )?;
+        Ok(source)
+    }
+
+    pub fn delete_wim_source(&self, id: &str) -> Result<bool> {
+        let conn = self.connection();
+        Ok(conn.execute("DELETE FROM wim_sources WHERE id = ?1", params![id])? > 0)
+    }
+
+    pub fn fetch_saved_views(&self) -> Result<Vec<SavedView>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare("SELECT spec FROM saved_views ORDER BY name COLLATE NOCASE")?;
+        let specs = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .filter_map(rusqlite::Result::ok)
+            .collect::<Vec<_>>();
+        Ok(specs
+            .iter()
+            .filter_map(|spec| serde_json::from_str(spec).ok())
+            .collect())
+    }
+
+    pub fn upsert_saved_view(&self, view: &SavedView) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "INSERT INTO saved_views (id, name, spec) VALUES (?1, ?2, ?3)
+             ON CONFLICT(id) DO UPDATE SET name = excluded.name, spec = excluded.spec",
+            params![view.id, view.name, serde_json::to_string(view)?],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_saved_view(&self, id: &str) -> Result<bool> {
+        let conn = self.connection();
+        Ok(conn.execute("DELETE FROM saved_views WHERE id = ?1", params![id])? > 0)
+    }
+
+    /// Count operations since `since` as `(action, result, count)` rows.
+    pub fn count_ops_since(&self, since: DateTime<Utc>) -> Result<Vec<(String, String, u64)>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(
+            "SELECT action, result, COUNT(*) FROM ops WHERE ts >= ?1 GROUP BY action, result",
+        )?;
+        let rows = stmt.query_map(params![since.to_rfc3339()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? as u64))
+        })?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
+    pub fn insert_attach_event(&self, event: &AttachEvent) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "INSERT INTO attach_history (node_id, op_id, action, context, result, ts) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                event.node_id,
+                event.op_id,
+                event.action,
+                event.context,
+                event.result,
+                event.ts.to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn fetch_attach_history(&self, node_id: &str) -> Result<Vec<AttachEvent>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(
+            "SELECT node_id, op_id, action, context, result, ts FROM attach_history WHERE node_id = ?1 ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![node_id], |row| {
+            let ts: String = row.get(5)?;
+            Ok(AttachEvent {
+                node_id: row.get(0)?,
+                op_id: row.get(1)?,
+                action: row.get(2)?,
+                context: row.get(3)?,
+                result: row.get(4)?,
+                ts: ts.parse().unwrap_or_else(|_| Utc::now()),
+            })
+        })?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
     pub fn delete_ops_for_nodes(&self, node_ids: &[String]) -> Result<()> {
         if node_ids.is_empty() {
             return Ok(());
@@ -270,6 +1090,8 @@ impl Database {
         let conn = self.connection();
         for id in node_ids {
             conn.execute("DELETE FROM ops WHERE node_id = ?1", params![id])?;
+            conn.execute("DELETE FROM node_scripts WHERE node_id = ?1", params![id])?;
+            conn.execute("DELETE FROM attach_history WHERE node_id = ?1", params![id])?;
         }
         Ok(())
     }
@@ -281,13 +1103,500 @@ impl Database {
         action: &str,
         result: &str,
         detail: &str,
+    ) -> Result<()> {
+        self.insert_op_with_duration(id, node_id, action, result, detail, None)
+    }
+
+    /// Like `insert_op`, also recording how long the operation took.
+    pub fn insert_timed_op(
+        &self,
+        id: &str,
+        node_id: Option<&str>,
+        action: &str,
+        result: &str,
+        detail: &str,
+        duration: Duration,
+    ) -> Result<()> {
+        self.insert_op_with_duration(id, node_id, action, result, detail, Some(duration))
+    }
+
+    fn insert_op_with_duration(
+        &self,
+        id: &str,
+        node_id: Option<&str>,
+        action: &str,
+        result: &str,
+        detail: &str,
+        duration: Option<Duration>,
     ) -> Result<()> {
         let ts: DateTime<Utc> = Utc::now();
+        let duration_ms = duration.map(|d| d.as_millis() as i64);
+        let conn = self.connection();
+        conn.execute(
+            "INSERT INTO ops (id, node_id, ts, action, result, detail, duration_ms, actor, transcript) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                id,
+                node_id,
+                ts.to_rfc3339(),
+                action,
+                result,
+                detail,
+                duration_ms,
+                current_actor(),
+                transcript::take_for_op(id)
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// All operations recorded in `[from, to)`, oldest first, with the node name where the node
+    /// still exists.
+    pub fn fetch_ops_between(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<AuditEntry>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(
+            "SELECT o.ts, o.actor, o.node_id, n.name, o.action, o.result, o.detail, o.duration_ms
+             FROM ops o LEFT JOIN nodes n ON n.id = o.node_id
+             WHERE (?1 IS NULL OR o.ts >= ?1) AND (?2 IS NULL OR o.ts < ?2)
+             ORDER BY o.ts, o.rowid",
+        )?;
+        let rows = stmt.query_map(
+            params![from.map(|t| t.to_rfc3339()), to.map(|t| t.to_rfc3339())],
+            |row| {
+                let ts: String = row.get(0)?;
+                Ok(AuditEntry {
+                    ts: ts.parse().unwrap_or_else(|_| Utc::now()),
+                    actor: row.get(1)?,
+                    node_id: row.get(2)?,
+                    node_name: row.get(3)?,
+                    action: row.get(4)?,
+                    result: row.get(5)?,
+                    detail: row
+                        .get::<_, Option<String>>(6)?
+                        .filter(|detail| !detail.is_empty()),
+                    duration_ms: row.get::<_, Option<i64>>(7)?.map(|ms| ms.max(0) as u64),
+                })
+            },
+        )?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
+    /// Operations recorded for a node, oldest first.
+    pub fn fetch_node_ops(&self, node_id: &str) -> Result<Vec<NodeOperation>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(
+            "SELECT id, node_id, ts, action, result, detail, duration_ms, transcript FROM ops WHERE node_id = ?1 ORDER BY ts, rowid",
+        )?;
+        let rows = stmt.query_map(params![node_id], |row| {
+            let ts: String = row.get(2)?;
+            Ok(NodeOperation {
+                id: row.get(0)?,
+                node_id: row.get(1)?,
+                ts: ts.parse().unwrap_or_else(|_| Utc::now()),
+                action: row.get(3)?,
+                result: row.get(4)?,
+                detail: row
+                    .get::<_, Option<String>>(5)?
+                    .filter(|detail| !detail.is_empty()),
+                duration_ms: row.get::<_, Option<i64>>(6)?.map(|ms| ms.max(0) as u64),
+                transcript: row.get(7)?,
+            })
+        })?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
+    /// Delete operations recorded before `before`; returns how many were removed.
+    pub fn prune_ops(&self, before: DateTime<Utc>) -> Result<usize> {
+        let conn = self.connection();
+        let removed = conn.execute(
+            "DELETE FROM ops WHERE ts < ?1",
+            params![before.to_rfc3339()],
+        )?;
+        Ok(removed)
+    }
+
+    /// Forget the transcript of operations whose transcript folder was deleted.
+    pub fn clear_transcripts(&self, ids: &[String]) -> Result<()> {
         let mut conn = self.connection();
+        let tx = conn.transaction()?;
+        for id in ids {
+            tx.execute(
+                "UPDATE ops SET transcript = NULL WHERE transcript = ?1",
+                params![id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn insert_job_record(&self, record: &JobRecord) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "INSERT INTO job_history (id, kind, started_at, finished_at, result, detail) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                record.id,
+                record.kind,
+                record.started_at.to_rfc3339(),
+                record.finished_at.to_rfc3339(),
+                record.result,
+                record.detail
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Most recent background jobs, newest first.
+    /// Whether a job with this id was recorded, which keeps agent commands from running twice.
+    pub fn job_recorded(&self, id: &str) -> Result<bool> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare("SELECT 1 FROM job_history WHERE id = ?1")?;
+        let mut rows = stmt.query(params![id])?;
+        Ok(rows.next()?.is_some())
+    }
+
+    pub fn fetch_job_history(&self, limit: u32) -> Result<Vec<JobRecord>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(
+            "SELECT id, kind, started_at, finished_at, result, detail FROM job_history ORDER BY finished_at DESC, rowid DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map(params![limit], |row| {
+            let started_at: String = row.get(2)?;
+            let finished_at: String = row.get(3)?;
+            Ok(JobRecord {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                started_at: started_at.parse().unwrap_or_else(|_| Utc::now()),
+                finished_at: finished_at.parse().unwrap_or_else(|_| Utc::now()),
+                result: row.get(4)?,
+                detail: row.get(5)?,
+            })
+        })?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
+    /// Replace the recorded backup of a node to `status.dest`.
+    pub fn upsert_backup(&self, status: &BackupStatus) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "INSERT OR REPLACE INTO node_backups (id, file, node_id, dest, backed_up_at, result, file_bytes, source_modified, sha256, detail) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                status.id,
+                status.file,
+                status.node_id,
+                status.dest,
+                status.backed_up_at.to_rfc3339(),
+                status.result,
+                status.file_bytes as i64,
+                status.source_modified.map(|t| t.to_rfc3339()),
+                status.sha256,
+                status.detail
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Recorded backups, optionally of one node only. Backups of deleted nodes are kept so they
+    /// can still be restored.
+    pub fn fetch_backups(&self, node_id: Option<&str>) -> Result<Vec<BackupStatus>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {BACKUP_COLUMNS} FROM node_backups WHERE ?1 IS NULL OR node_id = ?1 ORDER BY backed_up_at DESC"
+        ))?;
+        let rows = stmt.query_map(params![node_id], backup_from_row)?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
+    pub fn fetch_backup(&self, id: &str) -> Result<Option<BackupStatus>> {
+        let conn = self.connection();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {BACKUP_COLUMNS} FROM node_backups WHERE id = ?1"
+        ))?;
+        let mut rows = stmt.query(params![id])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(backup_from_row(row)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn upsert_vhd_metrics(&self, metrics: &VhdMetrics) -> Result<()> {
+        let conn = self.connection();
+        conn.execute(
+            "INSERT OR REPLACE INTO vhd_metrics (node_id, file_bytes, fragmentation, measured_at) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                metrics.node_id,
+                metrics.file_bytes as i64,
+                metrics.fragmentation,
+                metrics.measured_at.to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn fetch_vhd_metrics(&self) -> Result<Vec<VhdMetrics>> {
+        let conn = self.connection();
+        let mut stmt = conn
+            .prepare("SELECT node_id, file_bytes, fragmentation, measured_at FROM vhd_metrics")?;
+        let rows = stmt.query_map([], |row| {
+            let measured_at: String = row.get(3)?;
+            Ok(VhdMetrics {
+                node_id: row.get(0)?,
+                file_bytes: row.get::<_, i64>(1)?.max(0) as u64,
+                fragmentation: row.get(2)?,
+                measured_at: measured_at.parse().unwrap_or_else(|_| Utc::now()),
+            })
+        })?;
+        Ok(rows.filter_map(rusqlite::Result::ok).collect())
+    }
+
+    pub fn upsert_node_hash(&self, hash: &NodeHash) -> Result<()> {
+        let conn = self.connection();
         conn.execute(
-            "INSERT INTO ops (id, node_id, ts, action, result, detail) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![id, node_id, ts.to_rfc3339(), action, result, detail],
+            "INSERT OR REPLACE INTO node_hashes (node_id, file_bytes, modified, sha256, hashed_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                hash.node_id,
+                hash.file_bytes as i64,
+                hash.modified.map(|t| t.to_rfc3339()),
+                hash.sha256,
+                hash.hashed_at.to_rfc3339()
+            ],
         )?;
         Ok(())
     }
+
+    /// Recorded content hashes by node id.
+    pub fn fetch_node_hashes(&self) -> Result<HashMap<String, NodeHash>> {
+        let conn = self.connection();
+        let mut stmt = conn
+            .prepare("SELECT node_id, file_bytes, modified, sha256, hashed_at FROM node_hashes")?;
+        let rows = stmt.query_map([], |row| {
+            let hashed_at: String = row.get(4)?;
+            Ok(NodeHash {
+                node_id: row.get(0)?,
+                file_bytes: row.get::<_, i64>(1)?.max(0) as u64,
+                modified: row
+                    .get::<_, Option<String>>(2)?
+                    .and_then(|s| s.parse().ok()),
+                sha256: row.get(3)?,
+                hashed_at: hashed_at.parse().unwrap_or_else(|_| Utc::now()),
+            })
+        })?;
+        Ok(rows
+            .filter_map(rusqlite::Result::ok)
+            .map(|hash| (hash.node_id.clone(), hash))
+            .collect())
+    }
+
+    /// When a job of `kind` last finished, whatever its result.
+    pub fn last_job_finished(&self, kind: &str) -> Result<Option<DateTime<Utc>>> {
+        let conn = self.connection();
+        let last: Option<String> = conn.query_row(
+            "SELECT MAX(finished_at) FROM job_history WHERE kind = ?1",
+            params![kind],
+            |row| row.get(0),
+        )?;
+        Ok(last.and_then(|ts| ts.parse().ok()))
+    }
+}
+
+fn backup_from_row(row: &Row<'_>) -> rusqlite::Result<BackupStatus> {
+    let backed_up_at: String = row.get(4)?;
+    Ok(BackupStatus {
+        id: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+        file: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+        node_id: row.get(2)?,
+        dest: row.get(3)?,
+        backed_up_at: backed_up_at.parse().unwrap_or_else(|_| Utc::now()),
+        result: row.get(5)?,
+        file_bytes: row.get::<_, i64>(6)?.max(0) as u64,
+        source_modified: row
+            .get::<_, Option<String>>(7)?
+            .and_then(|s| s.parse().ok()),
+        sha256: row.get(8)?,
+        detail: row.get(9)?,
+    })
+}
+
+fn node_from_row(row: &Row<'_>) -> rusqlite::Result<Node> {
+    let created_at: String = row.get(6)?;
+    Ok(Node {
+        id: row.get(0)?,
+        parent_id: row.get(1)?,
+        name: row.get(2)?,
+        path: row.get(3)?,
+        bcd_guid: row.get(4)?,
+        desc: row.get(5)?,
+        created_at: created_at.parse().unwrap_or_else(|_| chrono::Utc::now()),
+        status: parse_status(&row.get::<_, String>(7)?),
+        boot_files_ready: row.get::<_, i32>(8)? != 0,
+        generalized: row.get::<_, i32>(9)? != 0,
+        boot_verified_at: row
+            .get::<_, Option<String>>(10)?
+            .and_then(|s| s.parse().ok()),
+        bitlocker: row.get::<_, i32>(11)? != 0,
+        color: row.get(12)?,
+        icon: row.get(13)?,
+        os_edition: row.get(14)?,
+        os_version: row.get(15)?,
+        os_build: row.get(16)?,
+        sort_key: row.get(17)?,
+        tags: row
+            .get::<_, Option<String>>(18)?
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default(),
+        version: row.get(19)?,
+        expires_at: row
+            .get::<_, Option<String>>(20)?
+            .and_then(|s| s.parse().ok()),
+        protected: row.get::<_, i32>(21)? != 0,
+        network: row
+            .get::<_, Option<String>>(22)?
+            .and_then(|s| serde_json::from_str(&s).ok()),
+        ..Default::default()
+    })
+}
+
+fn tags_to_column(tags: &[String]) -> Option<String> {
+    if tags.is_empty() {
+        None
+    } else {
+        serde_json::to_string(tags).ok()
+    }
+}
+
+fn parse_status(value: &str) -> NodeStatus {
+    match value {
+        "MissingFile" => NodeStatus::MissingFile,
+        "MissingParent" => NodeStatus::MissingParent,
+        "MissingBcd" => NodeStatus::MissingBcd,
+        "Mounted" => NodeStatus::Mounted,
+        "Error" => NodeStatus::Error,
+        "Creating" => NodeStatus::Creating,
+        "Merging" => NodeStatus::Merging,
+        "Deleting" => NodeStatus::Deleting,
+        "Archived" => NodeStatus::Archived,
+        _ => NodeStatus::Normal,
+    }
+}
+
+/// Fill `path_key` for rows from older databases (every row when `recompute`, after
+/// `normalize_path` changed) and fold rows whose paths normalize to the same file into the
+/// oldest one, re-pointing children, history and scripts to it.
+fn dedupe_path_keys(conn: &Connection, recompute: bool) -> Result<()> {
+    let rows: Vec<(String, String, Option<String>)> = {
+        let mut stmt =
+            conn.prepare("SELECT id, path, path_key FROM nodes ORDER BY created_at, rowid")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.filter_map(rusqlite::Result::ok).collect()
+    };
+    let tx = conn.unchecked_transaction()?;
+    if recompute {
+        // Cleared first so a new key never collides with a stale one still stored on another row.
+        tx.execute("UPDATE nodes SET path_key = NULL", [])?;
+    }
+    let mut keepers: HashMap<String, String> = HashMap::new();
+    for (id, path, stored_key) in rows {
+        let key = match stored_key {
+            Some(key) if !recompute => key,
+            _ => normalize_path(&path),
+        };
+        match keepers.get(&key) {
+            Some(keeper) => {
+                warn!("removing duplicate node row id={id} path={path} kept={keeper} key={key}");
+                tx.execute(
+                    "UPDATE nodes SET parent_id = ?1 WHERE parent_id = ?2",
+                    params![keeper, id],
+                )?;
+                tx.execute(
+                    "UPDATE nodes SET bcd_guid = (SELECT bcd_guid FROM nodes WHERE id = ?2) WHERE id = ?1 AND bcd_guid IS NULL",
+                    params![keeper, id],
+                )?;
+                tx.execute(
+                    "UPDATE ops SET node_id = ?1 WHERE node_id = ?2",
+                    params![keeper, id],
+                )?;
+                tx.execute(
+                    "UPDATE attach_history SET node_id = ?1 WHERE node_id = ?2",
+                    params![keeper, id],
+                )?;
+                tx.execute(
+                    "UPDATE node_scripts SET node_id = ?1 WHERE node_id = ?2 AND NOT EXISTS (SELECT 1 FROM node_scripts WHERE node_id = ?1)",
+                    params![keeper, id],
+                )?;
+                tx.execute("DELETE FROM node_scripts WHERE node_id = ?1", params![id])?;
+                tx.execute("DELETE FROM nodes WHERE id = ?1", params![id])?;
+            }
+            None => {
+                tx.execute(
+                    "UPDATE nodes SET path_key = ?1 WHERE id = ?2 AND path_key IS NULL",
+                    params![key, id],
+                )?;
+                keepers.insert(key, id);
+            }
+        }
+    }
+    tx.commit()?;
+    if recompute {
+        info!("recomputed path keys of {} node rows", keepers.len());
+    }
+    Ok(())
+}
+
+/// Recompute the `path_key` of every WIM source; of sources now sharing a key the first
+/// registered one is kept.
+fn recompute_wim_source_keys(conn: &Connection) -> Result<()> {
+    let rows: Vec<(String, String)> = {
+        let mut stmt = conn.prepare("SELECT id, spec FROM wim_sources ORDER BY rowid")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.filter_map(rusqlite::Result::ok).collect()
+    };
+    let tx = conn.unchecked_transaction()?;
+    // Parked on the (unique) id first so a new key never collides with a stale one.
+    tx.execute("UPDATE wim_sources SET path_key = id", [])?;
+    let mut seen = HashSet::new();
+    for (id, spec) in rows {
+        let Ok(source) = serde_json::from_str::<WimSource>(&spec) else {
+            continue;
+        };
+        let key = normalize_path(&source.path);
+        if seen.insert(key.clone()) {
+            tx.execute(
+                "UPDATE wim_sources SET path_key = ?1 WHERE id = ?2",
+                params![key, id],
+            )?;
+        } else {
+            warn!(
+                "removing duplicate wim source id={id} path={} key={key}",
+                source.path
+            );
+            tx.execute("DELETE FROM wim_sources WHERE id = ?1", params![id])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// `DOMAIN\user` of the account performing an operation, for the audit trail.
+fn current_actor() -> Option<String> {
+    let user = std::env::var("USERNAME").ok()?;
+    Some(match std::env::var("USERDOMAIN") {
+        Ok(domain) if !domain.is_empty() => format!("{domain}\\{user}"),
+        _ => user,
+    })
+}
+
+/// Add a column to an existing table when upgrading an older state.db.
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(rusqlite::Result::ok)
+        .any(|name| name.eq_ignore_ascii_case(column));
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl};"))?;
+    }
+    Ok(())
 }
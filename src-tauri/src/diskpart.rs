@@ -1,6 +1,7 @@
 use std::path::{Path, PathBuf};
 
-use crate::error::Result;
+use crate::error::{AppError, Result};
+use crate::paths::tool_path;
 use crate::sys::{run_elevated_command, CommandOutput};
 
 #[derive(Debug, Clone)]
@@ -15,6 +16,11 @@ pub struct VolumeInfo {
 #[derive(Debug, Clone)]
 pub struct VhdDetail {
     pub parent: Option<String>,
+    /// Whether the disk is attached; `None` when the output did not say.
+    pub attached: Option<bool>,
+    /// Share of the file's blocks that are fragmented or unused, as reported by `Get-VHD`;
+    /// diskpart does not tell.
+    pub fragmentation: Option<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -26,6 +32,7 @@ pub struct PartitionInfo {
 
 /// Run a diskpart script stored at `script_path`.
 pub fn run_diskpart_script(script_path: &Path) -> Result<CommandOutput> {
+    let script_path = tool_path(script_path)?;
     run_elevated_command(
         "diskpart",
         &["/s", script_path.to_string_lossy().as_ref()],
@@ -33,93 +40,181 @@ pub fn run_diskpart_script(script_path: &Path) -> Result<CommandOutput> {
     )
 }
 
-/// Generate script to create and partition a base VHDX with GPT + EFI/MSR/Primary.
+/// Quote a path for a diskpart `file=`/`parent=` argument.
+///
+/// Diskpart has no escape sequences: everything between the quotes is taken literally, so
+/// spaces, parentheses and non-ASCII characters are fine but a quote or line break cannot be
+/// represented. Verbatim (`\\?\`) prefixes are not understood by diskpart, so paths beyond
+/// MAX_PATH are passed in their 8.3 form instead.
+pub fn quote_path(path: &Path) -> Result<String> {
+    let plain = tool_path(path)?.to_string_lossy().to_string();
+    if plain.is_empty() || plain.contains(['"', '\r', '\n']) {
+        return Err(AppError::Message(format!(
+            "path cannot be used in a diskpart script: {plain:?}"
+        )));
+    }
+    Ok(format!("\"{plain}\""))
+}
+
+/// Line-oriented diskpart script; every command line of the generated scripts goes through it
+/// so paths are always quoted the same way.
+#[derive(Default)]
+struct Script {
+    lines: Vec<String>,
+}
+
+impl Script {
+    fn line(&mut self, line: impl Into<String>) -> &mut Self {
+        self.lines.push(line.into());
+        self
+    }
+
+    fn select_vdisk(&mut self, vhd_path: &Path) -> Result<&mut Self> {
+        let file = quote_path(vhd_path)?;
+        Ok(self.line(format!("select vdisk file={file}")))
+    }
+
+    fn finish(&self) -> String {
+        let mut script = self.lines.join("\r\n");
+        script.push_str("\r\n");
+        script
+    }
+}
+
+/// Generate script to create and partition a base VHDX with GPT + EFI/MSR/Primary, plus a
+/// trailing WinRE recovery partition when `recovery_letter` is set.
 pub fn base_diskpart_script(
     vhd_path: &Path,
     size_gb: u64,
     efi_letter: char,
     sys_letter: char,
-) -> String {
+    recovery_letter: Option<char>,
+) -> Result<String> {
     let size_mb = size_gb * 1024;
-    format!(
-        r#"
-create vdisk file="{vhd}" maximum={size_mb} type=expandable
-select vdisk file="{vhd}"
-attach vdisk
-convert gpt
-create partition efi size=100
-format quick fs=fat32 label="EFI"
-assign letter={efi_letter}
-create partition msr size=16
-create partition primary
-format quick fs=ntfs label="System"
-assign letter={sys_letter}
-list volume
-list partition
-"#,
-        vhd = vhd_path.display(),
-        size_mb = size_mb,
-        efi_letter = efi_letter,
-        sys_letter = sys_letter
-    )
+    let file = quote_path(vhd_path)?;
+    let mut script = Script::default();
+    script.line(format!(
+        "create vdisk file={file} maximum={size_mb} type=expandable"
+    ));
+    script
+        .select_vdisk(vhd_path)?
+        .line("attach vdisk")
+        .line("convert gpt")
+        .line("create partition efi size=100")
+        .line(r#"format quick fs=fat32 label="EFI""#)
+        .line(format!("assign letter={efi_letter}"))
+        .line("create partition msr size=16")
+        .line("create partition primary");
+    if recovery_letter.is_some() {
+        script.line(format!(
+            "shrink desired={RECOVERY_PARTITION_MB} minimum={RECOVERY_PARTITION_MB}"
+        ));
+    }
+    script
+        .line(r#"format quick fs=ntfs label="System""#)
+        .line(format!("assign letter={sys_letter}"));
+    if let Some(letter) = recovery_letter {
+        // Recovery type and the required/no-drive-letter attributes, as Windows Setup lays it out.
+        script
+            .line("create partition primary")
+            .line(r#"format quick fs=ntfs label="Recovery""#)
+            .line(format!("assign letter={letter}"))
+            .line("set id=de94bba4-06d1-4d40-a16a-bfd50179d6ac")
+            .line("gpt attributes=0x8000000000000001");
+    }
+    script.line("list volume").line("list partition");
+    Ok(script.finish())
 }
 
-/// Script to create a differencing VHDX and list partitions (no letter assignment).
-pub fn diff_attach_list_script(child: &Path, parent: &Path) -> String {
-    format!(
-        r#"
-create vdisk file="{child}" parent="{parent}"
-select vdisk file="{child}"
-attach vdisk
-list volume
-list partition
-"#,
-        child = child.display(),
-        parent = parent.display()
-    )
+/// Size of the optional recovery partition; WinRE needs about 500 MB and grows with updates.
+pub const RECOVERY_PARTITION_MB: u64 = 1024;
+
+/// Index of the primary partition in the layout produced by `base_diskpart_script`
+/// (EFI, MSR, primary).
+pub const BASE_SYSTEM_PARTITION: u32 = 3;
+
+/// Script to create a differencing VHDX, list its partitions and assign `letter` to
+/// partition `part_idx` in the same session. Selection and assignment use `noerr` so a parent
+/// with a different layout still yields the partition listing for a follow-up assignment.
+pub fn diff_attach_assign_script(
+    child: &Path,
+    parent: &Path,
+    part_idx: u32,
+    letter: char,
+) -> Result<String> {
+    let file = quote_path(child)?;
+    let parent = quote_path(parent)?;
+    let mut script = Script::default();
+    script.line(format!("create vdisk file={file} parent={parent}"));
+    script
+        .select_vdisk(child)?
+        .line("attach vdisk")
+        .line("list partition")
+        .line(format!("select partition {part_idx} noerr"))
+        .line(format!("assign letter={letter} noerr"))
+        .line("list volume");
+    Ok(script.finish())
 }
 
-/// Attach an existing VHD and list its partitions/volumes.
-pub fn attach_list_vdisk_script(vhd_path: &Path) -> String {
-    format!(
-        r#"
-select vdisk file="{vhd}"
-attach vdisk
-list partition
-list volume
-"#,
-        vhd = vhd_path.display()
-    )
+/// Attach an existing VHD and list its partitions/volumes. A read-only attach leaves the file
+/// untouched, which keeps the differencing children of a parent layer valid.
+pub fn attach_list_vdisk_script(vhd_path: &Path, read_only: bool) -> Result<String> {
+    let mut script = Script::default();
+    script
+        .select_vdisk(vhd_path)?
+        .line(if read_only {
+            "attach vdisk readonly"
+        } else {
+            "attach vdisk"
+        })
+        .line("list partition")
+        .line("list volume");
+    Ok(script.finish())
 }
 
 /// Script to assign letters to specific partitions on the currently attached VHD.
-pub fn assign_partitions_script(vhd_path: &Path, assignments: &[(u32, char)]) -> String {
-    let mut lines = Vec::new();
-    lines.push(format!(r#"select vdisk file="{}""#, vhd_path.display()));
+pub fn assign_partitions_script(vhd_path: &Path, assignments: &[(u32, char)]) -> Result<String> {
+    let mut script = Script::default();
+    script.select_vdisk(vhd_path)?;
     for (part_idx, letter) in assignments {
-        lines.push(format!("select partition {part_idx}"));
-        lines.push(format!("assign letter={letter} noerr"));
+        script
+            .line(format!("select partition {part_idx}"))
+            .line(format!("assign letter={letter} noerr"));
     }
-    lines.push("list volume".into());
-    lines.join("\n")
+    script.line("list volume");
+    Ok(script.finish())
+}
+
+/// Like `assign_partitions_script` for a single partition, but first takes `letter` away from
+/// whatever volume an earlier attempt may have left it on.
+pub fn reassign_partition_script(vhd_path: &Path, part_idx: u32, letter: char) -> Result<String> {
+    let mut script = Script::default();
+    script
+        .line(format!("select volume {letter} noerr"))
+        .line(format!("remove letter={letter} noerr"))
+        .select_vdisk(vhd_path)?
+        .line(format!("select partition {part_idx}"))
+        .line(format!("assign letter={letter} noerr"))
+        .line("list volume");
+    Ok(script.finish())
 }
 
-pub fn detach_vdisk_script(vhd_path: &Path, letters: &[char]) -> String {
-    let mut lines = Vec::new();
-    let select_vhd = format!(r#"select vdisk file="{}""#, vhd_path.display());
-    lines.push(select_vhd.clone());
+pub fn detach_vdisk_script(vhd_path: &Path, letters: &[char]) -> Result<String> {
+    let mut script = Script::default();
+    script.select_vdisk(vhd_path)?;
     for letter in letters {
-        lines.push(format!("select volume {letter}"));
-        lines.push(format!("remove letter={letter} noerr"));
+        script
+            .line(format!("select volume {letter}"))
+            .line(format!("remove letter={letter} noerr"));
     }
-    lines.push(select_vhd);
-    lines.push("detach vdisk".into());
-    lines.join("\n")
+    script.select_vdisk(vhd_path)?.line("detach vdisk");
+    Ok(script.finish())
 }
 
-/// Parse output of `detail vdisk` to extract parent path.
-pub fn parse_detail_vdisk_parent(output: &str) -> VhdDetail {
+/// Parse output of `detail vdisk` to extract the parent path and attach state.
+pub fn parse_detail_vdisk(output: &str) -> VhdDetail {
     let mut parent = None;
+    let mut attached = None;
     for line in output.lines() {
         let lower = line.to_ascii_lowercase();
         if lower.contains("parent path")
@@ -133,8 +228,18 @@ pub fn parse_detail_vdisk_parent(output: &str) -> VhdDetail {
                 }
             }
         }
+        // `Associated disk#: 3` once attached, `Associated disk#: Not found.` otherwise.
+        if lower.trim_start().starts_with("associated disk") {
+            if let Some((_, rest)) = line.split_once(':') {
+                attached = Some(rest.trim().parse::<u32>().is_ok());
+            }
+        }
+    }
+    VhdDetail {
+        parent,
+        attached,
+        fragmentation: None,
     }
-    VhdDetail { parent }
 }
 
 /// Parse `list volume` output to collect volume info.
@@ -176,55 +281,164 @@ pub fn parse_detail_vdisk_volumes(output: &str) -> Vec<VolumeInfo> {
 }
 
 /// Parse `list partition` output.
+///
+/// Rows are found by position rather than by the `Partition` label, which diskpart localizes:
+/// every line below the dashed header rule whose second column is a number.
 pub fn parse_list_partition(output: &str) -> Vec<PartitionInfo> {
     let mut parts = Vec::new();
+    let mut in_table = false;
     for line in output.lines() {
-        let trimmed = line.trim_start();
-        if trimmed.starts_with("Partition") {
-            let cols: Vec<&str> = trimmed.split_whitespace().collect();
-            if cols.len() >= 4 {
-                let idx = cols[1].parse::<u32>().unwrap_or(0);
-                let kind = cols[2].to_string();
-                let mut size_mb = None;
-                for w in cols.iter().rev() {
-                    if let Some(val) = parse_size_mb(w) {
-                        size_mb = Some(val);
-                        break;
-                    }
-                }
-                parts.push(PartitionInfo {
-                    index: idx,
-                    kind,
-                    size_mb,
-                });
-            }
+        let trimmed = line.trim_start().trim_start_matches('*').trim_start();
+        if trimmed.starts_with("---") {
+            in_table = true;
+            continue;
+        }
+        if !in_table {
+            continue;
         }
+        let cols: Vec<&str> = trimmed.split_whitespace().collect();
+        if cols.len() < 4 {
+            in_table = !cols.is_empty();
+            continue;
+        }
+        let Ok(index) = cols[1].parse::<u32>() else {
+            continue;
+        };
+        parts.push(PartitionInfo {
+            index,
+            kind: cols[2].to_string(),
+            size_mb: cols
+                .windows(2)
+                .find_map(|pair| parse_size_mb(pair[0], pair[1])),
+        });
     }
     parts
 }
 
-fn parse_size_mb(token: &str) -> Option<u64> {
-    let lower = token.to_ascii_lowercase();
-    if lower.ends_with("mb") {
-        let num = lower.trim_end_matches("mb").trim();
-        return num.parse::<u64>().ok();
+/// Size column such as `100 MB` or `59 GB`; the units are not localized.
+fn parse_size_mb(value: &str, unit: &str) -> Option<u64> {
+    let value = value.parse::<u64>().ok()?;
+    match unit.to_ascii_uppercase().as_str() {
+        "KB" => Some(value / 1024),
+        "MB" => Some(value),
+        "GB" => Some(value * 1024),
+        "TB" => Some(value * 1024 * 1024),
+        _ => None,
     }
-    if lower.ends_with("gb") {
-        let num = lower.trim_end_matches("gb").trim();
-        if let Ok(val) = num.parse::<u64>() {
-            return Some(val * 1024);
-        }
-    }
-    None
 }
 
-pub fn detail_vdisk_script(vhd_path: &Path) -> String {
-    format!(
-        r#"
-select vdisk file="{vhd}"
-detail vdisk
-list volume
-"#,
-        vhd = vhd_path.display()
-    )
+/// Reclaim unused blocks of an expandable VHDX; it has to be attached read-only while compacting.
+pub fn compact_vdisk_script(vhd_path: &Path) -> Result<String> {
+    let mut script = Script::default();
+    script
+        .select_vdisk(vhd_path)?
+        .line("attach vdisk readonly")
+        .line("compact vdisk")
+        .line("detach vdisk");
+    Ok(script.finish())
+}
+
+/// Give a copied VHDX its own GPT disk GUID so it can be attached next to the disk it was
+/// copied from. `online disk` brings it back if Windows took it offline over the collision.
+pub fn reset_disk_id_script(vhd_path: &Path, disk_guid: &str) -> Result<String> {
+    let mut script = Script::default();
+    script
+        .select_vdisk(vhd_path)?
+        .line("attach vdisk")
+        .line("online disk noerr")
+        .line(format!("uniqueid disk id={disk_guid}"))
+        .line("detach vdisk");
+    Ok(script.finish())
+}
+
+pub fn detail_vdisk_script(vhd_path: &Path) -> Result<String> {
+    let mut script = Script::default();
+    script
+        .select_vdisk(vhd_path)?
+        .line("detail vdisk")
+        .line("list volume");
+    Ok(script.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::temp::{read_script, TempManager};
+
+    const CHILD: &str = r"D:\工作区 (测试)\diffs\子层.vhdx";
+    const PARENT: &str = r"D:\工作区 (测试)\bases\基础.vhdx";
+
+    #[test]
+    fn quote_path_keeps_spaces_parentheses_and_unicode() {
+        assert_eq!(
+            quote_path(Path::new(CHILD)).unwrap(),
+            format!("\"{CHILD}\"")
+        );
+    }
+
+    #[test]
+    fn quote_path_rejects_what_diskpart_cannot_read() {
+        assert!(quote_path(Path::new(r#"D:\a"b.vhdx"#)).is_err());
+        assert!(quote_path(Path::new("D:\\a\r\nb.vhdx")).is_err());
+        assert!(quote_path(Path::new("")).is_err());
+    }
+
+    #[test]
+    fn diff_script_quotes_unicode_paths() {
+        let script =
+            diff_attach_assign_script(Path::new(CHILD), Path::new(PARENT), 3, 'V').unwrap();
+        let expected = [
+            format!("create vdisk file=\"{CHILD}\" parent=\"{PARENT}\""),
+            format!("select vdisk file=\"{CHILD}\""),
+            "attach vdisk".into(),
+            "list partition".into(),
+            "select partition 3 noerr".into(),
+            "assign letter=V noerr".into(),
+            "list volume".into(),
+        ];
+        assert_eq!(script, format!("{}\r\n", expected.join("\r\n")));
+    }
+
+    #[test]
+    fn read_only_attach_leaves_the_file_untouched() {
+        let read_only = attach_list_vdisk_script(Path::new(PARENT), true).unwrap();
+        let read_write = attach_list_vdisk_script(Path::new(PARENT), false).unwrap();
+        assert_eq!(read_only.lines().nth(1), Some("attach vdisk readonly"));
+        assert_eq!(read_write.lines().nth(1), Some("attach vdisk"));
+    }
+
+    #[test]
+    fn detach_script_releases_letters_before_detaching() {
+        let script = detach_vdisk_script(Path::new(CHILD), &['V', 'W']).unwrap();
+        let lines: Vec<&str> = script.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                &format!("select vdisk file=\"{CHILD}\"") as &str,
+                "select volume V",
+                "remove letter=V noerr",
+                "select volume W",
+                "remove letter=W noerr",
+                &format!("select vdisk file=\"{CHILD}\""),
+                "detach vdisk",
+            ]
+        );
+    }
+
+    #[test]
+    fn scripts_are_written_as_utf16le_with_bom() {
+        let script = attach_list_vdisk_script(Path::new(CHILD), false).unwrap();
+        let temp = TempManager::new(std::env::temp_dir().join("diskpart-tests")).unwrap();
+        let path = temp.write_script("attach.txt", &script).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes[..2], [0xFF, 0xFE]);
+        let expected: Vec<u8> = script.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        assert_eq!(bytes[2..], expected);
+        // "工" is U+5DE5, stored low byte first.
+        assert!(bytes.windows(2).any(|pair| pair == [0xE5, 0x5D]));
+        assert_eq!(read_script(&path).unwrap(), script);
+    }
 }
@@ -1,6 +1,11 @@
+use std::path::Path;
+
 use crate::error::Result;
-use crate::models::WimImageInfo;
-use crate::sys::{run_command, run_elevated_command, CommandOutput};
+use crate::models::{DebloatPreset, LayerDriver, LayerFeature, LayerPackage, OsInfo, WimImageInfo};
+use crate::paths::tool_path;
+use crate::sys::{
+    run_command, run_elevated_command, run_elevated_command_with_secrets, CommandOutput, Secrets,
+};
 
 /// List images inside a WIM/ESD file via DISM /Get-WimInfo.
 pub fn list_images(image_path: &str) -> Result<Vec<WimImageInfo>> {
@@ -9,7 +14,7 @@ pub fn list_images(image_path: &str) -> Result<Vec<WimImageInfo>> {
         &[
             "/English",
             "/Get-WimInfo",
-            &format!("/WimFile:{image_path}"),
+            &format!("/WimFile:{}", image_file(image_path)?),
         ],
         None,
     )?;
@@ -23,7 +28,7 @@ pub fn apply_image(image_path: &str, index: u32, apply_dir: &str) -> Result<Comm
         &[
             "/English",
             "/Apply-Image",
-            &format!("/ImageFile:{image_path}"),
+            &format!("/ImageFile:{}", image_file(image_path)?),
             &format!("/Index:{index}"),
             &format!("/ApplyDir:{apply_dir}"),
         ],
@@ -31,6 +36,448 @@ pub fn apply_image(image_path: &str, index: u32, apply_dir: &str) -> Result<Comm
     )
 }
 
+/// Copy image `index` of a WIM/ESD into `dest` as a WIM holding just that image.
+pub fn export_image(image_path: &str, index: u32, dest: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            "/Export-Image",
+            &format!("/SourceImageFile:{}", image_file(image_path)?),
+            &format!("/SourceIndex:{index}"),
+            &format!("/DestinationImageFile:{}", image_file(dest)?),
+            "/Compress:fast",
+        ],
+        None,
+    )
+}
+
+/// Capture a directory tree into a new WIM file. `config_file` is a DISM configuration list
+/// (`[ExclusionList]` etc.); it replaces DISM's built-in exclusions.
+pub fn capture_image(
+    capture_dir: &str,
+    image_path: &str,
+    name: &str,
+    config_file: Option<&Path>,
+) -> Result<CommandOutput> {
+    let image_arg = format!("/ImageFile:{}", image_file(image_path)?);
+    let capture_arg = format!("/CaptureDir:{capture_dir}");
+    let name_arg = format!("/Name:{name}");
+    let config_arg = config_file
+        .map(|p| image_file(&p.to_string_lossy()).map(|p| format!("/ConfigFile:{p}")))
+        .transpose()?;
+    let mut args = vec![
+        "/English",
+        "/Capture-Image",
+        image_arg.as_str(),
+        capture_arg.as_str(),
+        name_arg.as_str(),
+        "/Compress:fast",
+    ];
+    if let Some(arg) = config_arg.as_deref() {
+        args.push(arg);
+    }
+    run_elevated_command("dism", &args, None)
+}
+
+/// Read OS details from the first image of a WIM/VHDX without mounting it read-write.
+pub fn image_os_info(image_path: &str) -> Result<Option<OsInfo>> {
+    let output = run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            "/Get-ImageInfo",
+            &format!("/ImageFile:{}", image_file(image_path)?),
+            "/Index:1",
+        ],
+        None,
+    )?;
+    if !output.succeeded() {
+        return Ok(None);
+    }
+    Ok(Some(parse_image_os_info(&output.stdout)))
+}
+
+/// List drivers of an offline Windows installation; `all` includes inbox drivers.
+pub fn get_drivers(image_dir: &str, all: bool) -> Result<CommandOutput> {
+    let image_arg = format!("/Image:{image_dir}");
+    let mut args = vec![
+        "/English",
+        image_arg.as_str(),
+        "/Get-Drivers",
+        "/Format:Table",
+    ];
+    if all {
+        args.push("/All");
+    }
+    run_elevated_command("dism", &args, None)
+}
+
+pub fn parse_drivers_table(text: &str) -> Vec<LayerDriver> {
+    parse_table(text)
+        .into_iter()
+        .filter(|row| row.len() >= 7)
+        .map(|row| LayerDriver {
+            published_name: row[0].clone(),
+            original_file_name: row[1].clone(),
+            inbox: row[2].eq_ignore_ascii_case("Yes"),
+            class_name: row[3].clone(),
+            provider_name: row[4].clone(),
+            date: row[5].clone(),
+            version: row[6].clone(),
+        })
+        .collect()
+}
+
+/// Remove superseded components from an offline image; `/ResetBase` makes them unrecoverable.
+pub fn cleanup_component_store(image_dir: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Cleanup-Image",
+            "/StartComponentCleanup",
+            "/ResetBase",
+        ],
+        None,
+    )
+}
+
+/// Install a product key into an offline image, switching it to the matching edition license.
+/// The key is masked wherever the command line is shown.
+pub fn set_product_key(image_dir: &str, key: &str) -> Result<CommandOutput> {
+    run_elevated_command_with_secrets(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            &format!("/Set-ProductKey:{key}"),
+        ],
+        None,
+        Secrets {
+            values: &[key],
+            output: false,
+        },
+    )
+}
+
+/// Add a .cab/.msu package (e.g. a language pack `lp.cab`) to an offline image.
+pub fn add_package(image_dir: &str, package_path: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Add-Package",
+            &format!("/PackagePath:{package_path}"),
+        ],
+        None,
+    )
+}
+
+/// Provision an .appx/.appxbundle (e.g. a Language Experience Pack) for all users of an
+/// offline image; without a license file the package is added with `/SkipLicense`.
+pub fn add_provisioned_appx(
+    image_dir: &str,
+    package_path: &str,
+    license_path: Option<&str>,
+) -> Result<CommandOutput> {
+    let image_arg = format!("/Image:{image_dir}");
+    let package_arg = format!("/PackagePath:{package_path}");
+    let license_arg = license_path.map(|p| format!("/LicensePath:{p}"));
+    let mut args = vec![
+        "/English",
+        image_arg.as_str(),
+        "/Add-ProvisionedAppxPackage",
+        package_arg.as_str(),
+    ];
+    match license_arg.as_deref() {
+        Some(arg) => args.push(arg),
+        None => args.push("/SkipLicense"),
+    }
+    run_elevated_command("dism", &args, None)
+}
+
+/// Add a driver `.inf`, or every driver below a folder, to an offline image.
+pub fn add_driver(image_dir: &str, driver_path: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Add-Driver",
+            &format!("/Driver:{driver_path}"),
+            "/Recurse",
+        ],
+        None,
+    )
+}
+
+/// List the Appx packages provisioned for new users of an offline image.
+pub fn get_provisioned_appx(image_dir: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Get-ProvisionedAppxPackages",
+        ],
+        None,
+    )
+}
+
+/// Deprovision an Appx package by its full package name.
+pub fn remove_provisioned_appx(image_dir: &str, package_name: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Remove-ProvisionedAppxPackage",
+            &format!("/PackageName:{package_name}"),
+        ],
+        None,
+    )
+}
+
+/// `(DisplayName, PackageName)` pairs from `/Get-ProvisionedAppxPackages` output.
+pub fn parse_provisioned_appx(text: &str) -> Vec<(String, String)> {
+    let mut packages = Vec::new();
+    let mut display_name = None;
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        match key.trim() {
+            "DisplayName" => display_name = Some(value.trim().to_string()),
+            "PackageName" => {
+                if let Some(name) = display_name.take() {
+                    packages.push((name, value.trim().to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    packages
+}
+
+impl DebloatPreset {
+    /// Display names of the provisioned apps the preset removes.
+    pub fn apps(&self) -> Vec<&'static str> {
+        let mut apps = vec![
+            "Clipchamp.Clipchamp",
+            "Microsoft.BingNews",
+            "Microsoft.BingWeather",
+            "Microsoft.GamingApp",
+            "Microsoft.GetHelp",
+            "Microsoft.Getstarted",
+            "Microsoft.MicrosoftOfficeHub",
+            "Microsoft.MicrosoftSolitaireCollection",
+            "Microsoft.People",
+            "Microsoft.Todos",
+            "Microsoft.WindowsFeedbackHub",
+            "Microsoft.Xbox.TCUI",
+            "Microsoft.XboxGamingOverlay",
+            "Microsoft.XboxSpeechToTextOverlay",
+        ];
+        if *self == DebloatPreset::Full {
+            apps.extend([
+                "Microsoft.549981C3F5F10",
+                "Microsoft.OutlookForWindows",
+                "Microsoft.PowerAutomateDesktop",
+                "Microsoft.WindowsAlarms",
+                "Microsoft.WindowsMaps",
+                "Microsoft.WindowsSoundRecorder",
+                "Microsoft.YourPhone",
+                "Microsoft.ZuneMusic",
+                "Microsoft.ZuneVideo",
+                "MicrosoftCorporationII.QuickAssist",
+                "MSTeams",
+            ]);
+        }
+        apps
+    }
+}
+
+/// Set UI language, system locale, user locale and input locale of an offline image.
+pub fn set_all_intl(image_dir: &str, language: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            &format!("/Set-AllIntl:{language}"),
+        ],
+        None,
+    )
+}
+
+/// Enable an optional feature (and its parents) in an offline image; `source` points DISM at
+/// the payload, e.g. the `sources\sxs` folder of the installation media.
+pub fn enable_feature(
+    image_dir: &str,
+    feature: &str,
+    source: Option<&str>,
+) -> Result<CommandOutput> {
+    let image_arg = format!("/Image:{image_dir}");
+    let feature_arg = format!("/FeatureName:{feature}");
+    let source_arg = source.map(|s| format!("/Source:{s}"));
+    let mut args = vec![
+        "/English",
+        image_arg.as_str(),
+        "/Enable-Feature",
+        feature_arg.as_str(),
+        "/All",
+    ];
+    if let Some(arg) = source_arg.as_deref() {
+        args.push(arg);
+        args.push("/LimitAccess");
+    }
+    run_elevated_command("dism", &args, None)
+}
+
+/// Bake a Windows Configuration Designer package (.ppkg) into an offline image.
+pub fn add_provisioning_package(image_dir: &str, package_path: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Add-ProvisioningPackage",
+            &format!("/PackagePath:{package_path}"),
+        ],
+        None,
+    )
+}
+
+/// List servicing packages of an offline Windows installation.
+pub fn get_packages(image_dir: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Get-Packages",
+            "/Format:Table",
+        ],
+        None,
+    )
+}
+
+/// List optional features of an offline Windows installation.
+pub fn get_features(image_dir: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "dism",
+        &[
+            "/English",
+            &format!("/Image:{image_dir}"),
+            "/Get-Features",
+            "/Format:Table",
+        ],
+        None,
+    )
+}
+
+pub fn parse_packages_table(text: &str) -> Vec<LayerPackage> {
+    parse_table(text)
+        .into_iter()
+        .filter(|row| row.len() >= 4)
+        .map(|row| LayerPackage {
+            identity: row[0].clone(),
+            state: row[1].clone(),
+            release_type: row[2].clone(),
+            install_time: row[3].clone(),
+        })
+        .collect()
+}
+
+pub fn parse_features_table(text: &str) -> Vec<LayerFeature> {
+    parse_table(text)
+        .into_iter()
+        .filter(|row| row.len() >= 2)
+        .map(|row| LayerFeature {
+            name: row[0].clone(),
+            state: row[1].clone(),
+        })
+        .collect()
+}
+
+/// Rows of a `/Format:Table` listing: the lines after the `---- | ----` separator, split on `|`.
+fn parse_table(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut in_table = false;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if !in_table {
+            in_table = trimmed.starts_with('-')
+                && trimmed.contains('|')
+                && trimmed.chars().all(|c| matches!(c, '-' | '|' | ' '));
+            continue;
+        }
+        if !trimmed.contains('|') {
+            if trimmed.is_empty() && rows.is_empty() {
+                continue;
+            }
+            break;
+        }
+        rows.push(trimmed.split('|').map(|c| c.trim().to_string()).collect());
+    }
+    rows
+}
+
+fn parse_image_os_info(text: &str) -> OsInfo {
+    let mut info = OsInfo::default();
+    let mut version = None;
+    let mut sp_build = None;
+    for line in text.lines() {
+        let Some((key, value)) = line.split_once(" : ") else {
+            continue;
+        };
+        let value = value.trim().to_string();
+        match key.trim() {
+            "Edition" => info.edition = Some(value),
+            "Version" => version = Some(value),
+            "ServicePack Build" => sp_build = Some(value),
+            _ => {}
+        }
+    }
+    // "Version : 10.0.22631" + "ServicePack Build : 2861" -> "22631.2861"
+    let build = version.as_deref().and_then(|v| v.rsplit('.').next());
+    info.version = build.and_then(display_version).map(str::to_string);
+    info.build = match (build, sp_build) {
+        (Some(build), Some(sp)) if sp != "0" => Some(format!("{build}.{sp}")),
+        (Some(build), _) => Some(build.to_string()),
+        _ => None,
+    };
+    info
+}
+
+/// DisplayVersion of a release build; DISM only reports the build number, while the registry
+/// value is only readable with the image mounted.
+fn display_version(build: &str) -> Option<&'static str> {
+    Some(match build {
+        "10240" => "1507",
+        "10586" => "1511",
+        "14393" => "1607",
+        "15063" => "1703",
+        "16299" => "1709",
+        "17134" => "1803",
+        "17763" => "1809",
+        "18362" => "1903",
+        "18363" => "1909",
+        "19041" => "2004",
+        "19042" => "20H2",
+        "19043" => "21H1",
+        "19044" | "20348" | "22000" => "21H2",
+        "19045" | "22621" => "22H2",
+        "22631" => "23H2",
+        "26100" => "24H2",
+        "26200" => "25H2",
+        _ => return None,
+    })
+}
+
 fn parse_wim_info(text: &str) -> Vec<WimImageInfo> {
     let mut result = Vec::new();
     let mut current: Option<WimImageInfo> = None;
@@ -71,3 +518,10 @@ fn parse_wim_info(text: &str) -> Vec<WimImageInfo> {
     }
     result
 }
+
+/// Image file path as DISM accepts it, shortened when it exceeds MAX_PATH.
+fn image_file(image_path: &str) -> Result<String> {
+    Ok(tool_path(Path::new(image_path))?
+        .to_string_lossy()
+        .to_string())
+}
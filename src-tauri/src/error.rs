@@ -1,3 +1,6 @@
+use std::fmt;
+
+use serde::Serialize;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, AppError>;
@@ -12,10 +15,112 @@ pub enum AppError {
     Serde(#[from] serde_json::Error),
     #[error("Root directory is not initialized")]
     RootNotInitialized,
+    /// An external tool failed; `kind` says why, as far as its status and output tell, and
+    /// `hint` suggests a fix for well-known failures.
+    #[error("{message}{}", hint.map(|h| format!("\nSuggested fix: {h}")).unwrap_or_default())]
+    Command {
+        kind: CommandErrorKind,
+        message: String,
+        hint: Option<&'static str>,
+    },
+    /// The chosen workspace root would produce layers that fail to boot; `kind` says why and
+    /// `message` suggests where to put the workspace instead.
+    #[error("{kind}: {message}")]
+    InvalidRoot {
+        kind: RootPathErrorKind,
+        message: String,
+    },
+    /// The node changed since the caller loaded it.
+    #[error("conflict: {0}")]
+    Conflict(String),
+    /// Refused by workspace policy, e.g. a mutating command while the workspace is read-only.
+    #[error("policy denied: {0}")]
+    PolicyDenied(String),
     #[error("{0}")]
     Message(String),
 }
 
+/// Reason a folder is refused as workspace root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RootPathErrorKind {
+    /// A `\\server\share` path; the boot manager cannot open VHDXs over the network.
+    UncPath,
+    /// A OneDrive (or other cloud provider) folder whose files may be placeholders.
+    CloudSynced,
+    /// Inside the Windows folder, which servicing and Windows Update rewrite.
+    SystemFolder,
+}
+
+impl RootPathErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RootPathErrorKind::UncPath => "unc_path",
+            RootPathErrorKind::CloudSynced => "cloud_synced",
+            RootPathErrorKind::SystemFolder => "system_folder",
+        }
+    }
+}
+
+impl fmt::Display for RootPathErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Cause of an external tool failure, classified from its exit status and output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandErrorKind {
+    /// Failed without a recognizable cause.
+    Failed,
+    /// No exit status came back, e.g. the elevated helper went away mid-command.
+    UnknownStatus,
+    /// Terminated by an unhandled exception (NTSTATUS error code).
+    Crashed,
+    Cancelled,
+    AccessDenied,
+    /// The file or disk is used by another process or attached elsewhere.
+    InUse,
+    NotFound,
+    AlreadyExists,
+    InvalidParameter,
+    DiskFull,
+}
+
+impl CommandErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CommandErrorKind::Failed => "failed",
+            CommandErrorKind::UnknownStatus => "unknown_status",
+            CommandErrorKind::Crashed => "crashed",
+            CommandErrorKind::Cancelled => "cancelled",
+            CommandErrorKind::AccessDenied => "access_denied",
+            CommandErrorKind::InUse => "in_use",
+            CommandErrorKind::NotFound => "not_found",
+            CommandErrorKind::AlreadyExists => "already_exists",
+            CommandErrorKind::InvalidParameter => "invalid_parameter",
+            CommandErrorKind::DiskFull => "disk_full",
+        }
+    }
+}
+
+impl fmt::Display for CommandErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl AppError {
+    /// Classified cause when this error comes from a failed external tool.
+    pub fn command_kind(&self) -> Option<CommandErrorKind> {
+        match self {
+            AppError::Command { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+}
+
 impl From<anyhow::Error> for AppError {
     fn from(err: anyhow::Error) -> Self {
         AppError::Message(err.to_string())
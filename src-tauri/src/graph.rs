@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+use crate::models::{GraphFormat, Node, NodeStatus};
+
+/// Render the node tree as Graphviz DOT or Mermaid text, one box per layer labelled with its
+/// name, status and file size, with edges from parent to child.
+pub fn render(nodes: &[Node], sizes: &HashMap<String, u64>, format: GraphFormat) -> String {
+    // Node ids are UUIDs; short positional ids keep the output readable and valid in Mermaid.
+    let ids: HashMap<&str, String> = nodes
+        .iter()
+        .enumerate()
+        .map(|(idx, node)| (node.id.as_str(), format!("n{idx}")))
+        .collect();
+    let label = |node: &Node| {
+        let size = sizes
+            .get(&node.id)
+            .map(|bytes| format!("{:.1} GB", *bytes as f64 / (1024.0 * 1024.0 * 1024.0)))
+            .unwrap_or_else(|| "missing".to_string());
+        (node.name.clone(), format!("{:?} · {size}", node.status))
+    };
+    let edges = nodes.iter().filter_map(|node| {
+        let parent = ids.get(node.parent_id.as_deref()?)?;
+        Some((parent, &ids[node.id.as_str()]))
+    });
+
+    match format {
+        GraphFormat::Dot => {
+            let mut out =
+                String::from("digraph layers {\n    rankdir=LR;\n    node [shape=box];\n");
+            for node in nodes {
+                let (name, detail) = label(node);
+                out.push_str(&format!(
+                    "    {} [label=\"{}\\n{}\"{}];\n",
+                    ids[node.id.as_str()],
+                    dot_escape(&name),
+                    dot_escape(&detail),
+                    if node.status == NodeStatus::Normal {
+                        ""
+                    } else {
+                        ", color=red"
+                    }
+                ));
+            }
+            for (parent, child) in edges {
+                out.push_str(&format!("    {parent} -> {child};\n"));
+            }
+            out.push_str("}\n");
+            out
+        }
+        GraphFormat::Mermaid => {
+            let mut out = String::from("graph LR\n");
+            for node in nodes {
+                let (name, detail) = label(node);
+                out.push_str(&format!(
+                    "    {}[\"{}<br/>{}\"]\n",
+                    ids[node.id.as_str()],
+                    mermaid_escape(&name),
+                    mermaid_escape(&detail)
+                ));
+            }
+            for (parent, child) in edges {
+                out.push_str(&format!("    {parent} --> {child}\n"));
+            }
+            let broken: Vec<&str> = nodes
+                .iter()
+                .filter(|n| n.status != NodeStatus::Normal)
+                .map(|n| ids[n.id.as_str()].as_str())
+                .collect();
+            if !broken.is_empty() {
+                out.push_str("    classDef broken stroke:#d33,stroke-width:2px\n");
+                out.push_str(&format!("    class {} broken\n", broken.join(",")));
+            }
+            out
+        }
+    }
+}
+
+fn dot_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Mermaid labels are quoted strings that take HTML entities but no escaped quotes.
+fn mermaid_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
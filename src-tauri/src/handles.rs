@@ -0,0 +1,269 @@
+use std::fmt;
+use std::fs;
+use std::iter::once;
+use std::os::windows::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use windows_sys::Win32::Foundation::{
+    CloseHandle, GetLastError, ERROR_MORE_DATA, ERROR_SUCCESS, GENERIC_READ, GENERIC_WRITE,
+    INVALID_HANDLE_VALUE,
+};
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileW, FILE_SHARE_READ, FILE_SHARE_WRITE, OPEN_EXISTING,
+};
+use windows_sys::Win32::System::RestartManager::{
+    RmEndSession, RmGetList, RmRegisterResources, RmStartSession, CCH_RM_SESSION_KEY,
+    RM_PROCESS_INFO,
+};
+use windows_sys::Win32::System::IO::DeviceIoControl;
+
+use crate::error::{AppError, Result};
+use crate::sys::run_command;
+
+/// Files of a mounted volume handed to the Restart Manager, nearest to the root first.
+const MAX_VOLUME_FILES: usize = 512;
+const MAX_VOLUME_DEPTH: usize = 3;
+
+/// `FSCTL_LOCK_VOLUME` and `FSCTL_DISMOUNT_VOLUME` (winioctl.h).
+const FSCTL_LOCK_VOLUME: u32 = 0x0009_0018;
+const FSCTL_DISMOUNT_VOLUME: u32 = 0x0009_0020;
+
+/// A process keeping a file open, as reported by the Restart Manager.
+#[derive(Debug, Clone)]
+pub struct FileHolder {
+    pub pid: u32,
+    pub name: String,
+    pub service: Option<String>,
+}
+
+impl fmt::Display for FileHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.service {
+            Some(service) => write!(f, "{} (service {service}, pid {})", self.name, self.pid),
+            None => write!(f, "{} (pid {})", self.name, self.pid),
+        }
+    }
+}
+
+/// Processes holding any of `files` open. Called from an unelevated process this misses
+/// elevated processes and those of other users.
+pub fn processes_using(files: &[PathBuf]) -> Result<Vec<FileHolder>> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut session = 0u32;
+    let mut key = [0u16; CCH_RM_SESSION_KEY as usize + 1];
+    let err = unsafe { RmStartSession(&mut session, 0, key.as_mut_ptr()) };
+    if err != ERROR_SUCCESS {
+        return Err(AppError::Message(format!(
+            "restart manager session failed: error {err}"
+        )));
+    }
+    let result = list_holders(session, files);
+    unsafe { RmEndSession(session) };
+    result
+}
+
+fn list_holders(session: u32, files: &[PathBuf]) -> Result<Vec<FileHolder>> {
+    let wide: Vec<Vec<u16>> = files
+        .iter()
+        .map(|f| f.as_os_str().encode_wide().chain(once(0)).collect())
+        .collect();
+    let names: Vec<*const u16> = wide.iter().map(|w| w.as_ptr()).collect();
+    let err = unsafe {
+        RmRegisterResources(
+            session,
+            names.len() as u32,
+            names.as_ptr(),
+            0,
+            std::ptr::null(),
+            0,
+            std::ptr::null(),
+        )
+    };
+    if err != ERROR_SUCCESS {
+        return Err(AppError::Message(format!(
+            "restart manager registration failed: error {err}"
+        )));
+    }
+    // The list can grow between the sizing call and the real one; retry a few times.
+    let mut infos: Vec<RM_PROCESS_INFO> = Vec::new();
+    for _ in 0..3 {
+        let mut needed = 0u32;
+        let mut count = infos.len() as u32;
+        let mut reasons = 0u32;
+        let err = unsafe {
+            RmGetList(
+                session,
+                &mut needed,
+                &mut count,
+                infos.as_mut_ptr(),
+                &mut reasons,
+            )
+        };
+        if err == ERROR_SUCCESS {
+            infos.truncate(count as usize);
+            return Ok(infos.iter().map(holder).collect());
+        }
+        if err != ERROR_MORE_DATA {
+            return Err(AppError::Message(format!(
+                "restart manager listing failed: error {err}"
+            )));
+        }
+        infos = vec![unsafe { std::mem::zeroed() }; needed as usize];
+    }
+    Err(AppError::Message(
+        "restart manager listing kept changing".into(),
+    ))
+}
+
+fn holder(info: &RM_PROCESS_INFO) -> FileHolder {
+    let service = from_wide(&info.strServiceShortName);
+    FileHolder {
+        pid: info.Process.dwProcessId,
+        name: from_wide(&info.strAppName),
+        service: (!service.is_empty()).then_some(service),
+    }
+}
+
+/// The VHDX itself plus files near the root of each of its mounted volumes; a handle to any of
+/// them keeps the disk from detaching.
+pub fn disk_files(vhd_path: &Path, letters: &[char]) -> Vec<PathBuf> {
+    let mut files = vec![vhd_path.to_path_buf()];
+    for letter in letters {
+        let mut dirs = vec![(PathBuf::from(format!("{letter}:\\")), 0)];
+        while let Some((dir, depth)) = dirs.pop() {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if files.len() > MAX_VOLUME_FILES {
+                    break;
+                }
+                match entry.file_type() {
+                    Ok(kind) if kind.is_dir() && depth < MAX_VOLUME_DEPTH => {
+                        dirs.insert(0, (entry.path(), depth + 1))
+                    }
+                    Ok(kind) if kind.is_file() => files.push(entry.path()),
+                    _ => {}
+                }
+            }
+        }
+    }
+    files
+}
+
+/// Folders on `letters` shown in open Explorer windows. Explorer keeps a browsed folder busy
+/// without an open file, so the Restart Manager does not report it.
+pub fn explorer_windows_on(letters: &[char]) -> Vec<String> {
+    if letters.is_empty() {
+        return Vec::new();
+    }
+    let script = "$ErrorActionPreference = 'SilentlyContinue'
+(New-Object -ComObject Shell.Application).Windows() | ForEach-Object { $_.Document.Folder.Self.Path }";
+    let Ok(res) = run_command(
+        "powershell.exe",
+        &[
+            "-NoLogo",
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            script,
+        ],
+        None,
+    ) else {
+        return Vec::new();
+    };
+    res.stdout
+        .lines()
+        .map(str::trim)
+        .filter(|path| {
+            let mut chars = path.chars();
+            chars
+                .next()
+                .is_some_and(|c| letters.iter().any(|l| l.eq_ignore_ascii_case(&c)))
+                && chars.next() == Some(':')
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Readable list of what keeps `vhd_path` or its volumes busy, or `None` when nothing was found.
+pub fn describe_holders(vhd_path: &Path, letters: &[char]) -> Option<String> {
+    let mut parts: Vec<String> = match processes_using(&disk_files(vhd_path, letters)) {
+        Ok(holders) => holders.iter().map(ToString::to_string).collect(),
+        Err(err) => {
+            tracing::warn!(
+                "listing processes holding {} failed: {err}",
+                vhd_path.display()
+            );
+            Vec::new()
+        }
+    };
+    parts.extend(
+        explorer_windows_on(letters)
+            .into_iter()
+            .map(|path| format!("Explorer window at {path}")),
+    );
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// Dismount the file system on `volume` (a `\\?\Volume{...}\` path), invalidating every handle
+/// still open on it so a detach no longer waits for their owners. The lock is only attempted:
+/// it fails while handles are open, and the dismount goes ahead regardless.
+pub fn dismount_volume(volume: &str) -> Result<()> {
+    let device = volume.trim_end_matches('\\');
+    let name: Vec<u16> = Path::new(device)
+        .as_os_str()
+        .encode_wide()
+        .chain(once(0))
+        .collect();
+    let handle = unsafe {
+        CreateFileW(
+            name.as_ptr(),
+            GENERIC_READ | GENERIC_WRITE,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        let err = unsafe { GetLastError() };
+        return Err(AppError::Message(format!(
+            "opening {device} failed: error {err}"
+        )));
+    }
+    let control = |code| {
+        let mut returned = 0u32;
+        unsafe {
+            DeviceIoControl(
+                handle,
+                code,
+                std::ptr::null(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            ) != 0
+        }
+    };
+    let locked = control(FSCTL_LOCK_VOLUME);
+    let dismounted = control(FSCTL_DISMOUNT_VOLUME);
+    let err = unsafe { GetLastError() };
+    unsafe { CloseHandle(handle) };
+    if !dismounted {
+        return Err(AppError::Message(format!(
+            "dismounting {device} failed: error {err}"
+        )));
+    }
+    tracing::info!("dismounted {device} locked={locked}");
+    Ok(())
+}
+
+fn from_wide(buffer: &[u16]) -> String {
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    String::from_utf16_lossy(&buffer[..len])
+}
@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use once_cell::sync::{Lazy, OnceCell};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::models::{JobInfo, JobOutputLine, JobProgress, OutputStream};
+
+/// Event carrying one line of output of a running job.
+pub const JOB_OUTPUT_EVENT: &str = "job-output";
+
+static APP: OnceCell<AppHandle> = OnceCell::new();
+
+thread_local! {
+    /// Operation being run on this (blocking-pool) thread, if the frontend asked for progress.
+    static OPERATION: RefCell<Option<JobProgress>> = const { RefCell::new(None) };
+    /// Id the running command put on its log span, waiting for the first ops row it records.
+    static RESERVED_OP_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+static JOBS: Lazy<Mutex<HashMap<String, (JobInfo, CancelToken)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Cooperative cancellation flag shared between a job and whoever wants to stop it.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<CancelInner>,
+}
+
+#[derive(Default)]
+struct CancelInner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolve once `cancel` has been called.
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Handle of a registered job; unregisters it when dropped.
+pub struct JobGuard {
+    pub id: String,
+    pub cancel: CancelToken,
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        if let Ok(mut jobs) = JOBS.lock() {
+            jobs.remove(&self.id);
+        }
+    }
+}
+
+/// Remember the app handle so job output can be streamed to the frontend.
+pub fn set_app_handle(app: AppHandle) {
+    let _ = APP.set(app);
+}
+
+pub fn app_handle() -> Option<&'static AppHandle> {
+    APP.get()
+}
+
+/// Register a running process as a job that can be listed and cancelled.
+pub fn register(program: &str, args: &[String]) -> JobGuard {
+    let info = JobInfo {
+        id: Uuid::new_v4().to_string(),
+        program: program.to_string(),
+        args: args.to_vec(),
+        started_at: Utc::now(),
+    };
+    let cancel = CancelToken::default();
+    if let Ok(mut jobs) = JOBS.lock() {
+        jobs.insert(info.id.clone(), (info.clone(), cancel.clone()));
+    }
+    JobGuard {
+        id: info.id,
+        cancel,
+    }
+}
+
+pub fn list() -> Vec<JobInfo> {
+    let mut jobs: Vec<JobInfo> = JOBS
+        .lock()
+        .map(|jobs| jobs.values().map(|(info, _)| info.clone()).collect())
+        .unwrap_or_default();
+    jobs.sort_by_key(|job| job.started_at);
+    jobs
+}
+
+/// Request cancellation of a running job; returns false when no such job is running.
+pub fn cancel(id: &str) -> bool {
+    let token = JOBS
+        .lock()
+        .ok()
+        .and_then(|jobs| jobs.get(id).map(|(_, token)| token.clone()));
+    match token {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Forward one output line of a job to the frontend, if a window is attached.
+pub fn emit_line(job_id: &str, stream: OutputStream, line: &str) {
+    if let Some(app) = APP.get() {
+        let _ = app.emit(
+            JOB_OUTPUT_EVENT,
+            JobOutputLine {
+                job_id: job_id.to_string(),
+                stream,
+                line: line.to_string(),
+            },
+        );
+    }
+}
+
+/// Event channel carrying `JobProgress` updates of one operation.
+pub fn progress_channel(job_id: &str) -> String {
+    format!("job://{job_id}/progress")
+}
+
+/// Marks the operation started by `begin_operation`; progress stops being reported when dropped.
+pub struct OperationGuard {
+    previous: Option<JobProgress>,
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        OPERATION.with(|op| *op.borrow_mut() = previous);
+    }
+}
+
+/// Report progress of the operation running on this thread under `job_id`. Without an id
+/// (older frontends) the reporting calls below are no-ops.
+pub fn begin_operation(job_id: Option<String>) -> OperationGuard {
+    let current = job_id.map(|job_id| JobProgress {
+        job_id,
+        phase: "started".into(),
+        percent: Some(0),
+        command: None,
+    });
+    if let Some(progress) = &current {
+        emit_progress(progress);
+    }
+    OperationGuard {
+        previous: OPERATION.with(|op| op.replace(current)),
+    }
+}
+
+/// Releases the id reserved by `reserve_op_id` when dropped.
+pub struct ReservedOpId {
+    previous: Option<String>,
+}
+
+impl Drop for ReservedOpId {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        RESERVED_OP_ID.with(|id| *id.borrow_mut() = previous);
+    }
+}
+
+/// Hand `op_id` to the first `next_op_id` call on this thread, so the ops row of a command
+/// carries the id its log lines were tagged with.
+pub fn reserve_op_id(op_id: String) -> ReservedOpId {
+    ReservedOpId {
+        previous: RESERVED_OP_ID.with(|id| id.replace(Some(op_id))),
+    }
+}
+
+/// Id for a new ops row: the id reserved by the running command the first time, a fresh one
+/// after that.
+pub fn next_op_id() -> String {
+    RESERVED_OP_ID
+        .with(|id| id.borrow_mut().take())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Enter a new phase of the current operation, e.g. `apply_image`.
+pub fn report_phase(phase: &str, percent: Option<u8>) {
+    update_operation(|progress| {
+        progress.phase = phase.to_string();
+        progress.percent = percent.map(|p| p.min(100));
+        progress.command = None;
+    });
+}
+
+/// Report the external command the current operation is about to run.
+pub fn report_command(program: &str, args: &[String]) {
+    update_operation(|progress| {
+        let mut command = program.to_string();
+        for arg in args {
+            command.push(' ');
+            command.push_str(arg);
+        }
+        progress.command = Some(command);
+    });
+}
+
+fn update_operation(f: impl FnOnce(&mut JobProgress)) {
+    let progress = OPERATION.with(|op| {
+        op.borrow_mut().as_mut().map(|progress| {
+            f(progress);
+            progress.clone()
+        })
+    });
+    if let Some(progress) = progress {
+        emit_progress(&progress);
+    }
+}
+
+fn emit_progress(progress: &JobProgress) {
+    if let Some(app) = APP.get() {
+        let _ = app.emit(&progress_channel(&progress.job_id), progress.clone());
+    }
+}
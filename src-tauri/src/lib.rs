@@ -1,47 +1,179 @@
+mod agent;
+mod audit;
 mod bcd;
+mod bitlocker;
+#[cfg(windows)]
+mod broker;
 mod commands;
+mod confirm;
 mod db;
 mod diskpart;
 mod dism;
 mod error;
+mod graph;
+#[cfg(windows)]
+mod handles;
+mod jobs;
 mod logging;
+mod maintenance;
 mod models;
+mod notify;
 mod paths;
+mod policy;
+mod preflight;
 mod recents;
+mod redact;
+mod reg_offline;
+mod secureboot;
+#[cfg(feature = "simulate")]
+mod simulate;
 mod state;
+mod storage;
 mod sys;
 mod temp;
+mod tools;
+mod transcript;
+mod vhdx;
 mod workspace;
 
+// Outside Windows there are no tools, broker or Win32 volume and handle queries to call; the
+// simulator stands in for them.
+#[cfg(all(not(windows), not(feature = "simulate")))]
+compile_error!("builds for hosts other than Windows need the `simulate` feature");
+#[cfg(not(windows))]
+use simulate::handles;
+
 use state::SharedState;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let shared_state = SharedState::default();
+    let scheduler_state = shared_state.clone();
+    let agent_state = shared_state.clone();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(shared_state)
-        .invoke_handler(tauri::generate_handler![
+        .setup(|app| {
+            #[cfg(feature = "simulate")]
+            simulate::install();
+            jobs::set_app_handle(app.handle().clone());
+            maintenance::start(scheduler_state);
+            agent::start(agent_state);
+            Ok(())
+        })
+        .invoke_handler(policy::guard(tauri::generate_handler![
             commands::check_admin,
             commands::get_settings,
+            commands::update_settings,
+            commands::get_read_only_status,
+            commands::set_read_only,
+            commands::unlock_read_only,
+            commands::lock_read_only,
+            commands::detect_tools,
+            commands::update_tool_paths,
             commands::init_root,
+            commands::check_workspace_volume,
             commands::scan_workspace,
             commands::list_nodes,
+            commands::query_nodes,
+            commands::list_views,
+            commands::save_view,
+            commands::delete_view,
+            commands::apply_view,
+            commands::nodes_digest,
+            commands::get_workspace_stats,
+            commands::estimate_operation,
+            commands::preflight_create,
+            commands::update_node_appearance,
+            commands::set_node_tags,
+            commands::set_node_expiry,
+            commands::set_node_network,
+            commands::set_local_admin,
+            commands::refresh_os_info,
+            commands::reorder_nodes,
             commands::list_wim_images,
+            commands::add_wim_source,
+            commands::list_wim_sources,
+            commands::remove_wim_source,
+            commands::export_wim_index,
+            commands::get_layer_drivers,
+            commands::get_layer_packages,
+            commands::get_layer_features,
+            commands::cleanup_component_store,
+            commands::set_product_key,
+            commands::add_language,
+            commands::enable_netfx3,
+            commands::apply_provisioning_package,
             commands::list_recent_workspaces,
             commands::remove_recent_workspace,
             commands::clear_recent_workspaces,
             commands::create_base_vhd,
             commands::create_diff_vhd,
+            commands::refresh_base,
+            commands::list_library,
+            commands::clone_from_library,
+            commands::list_templates,
+            commands::create_base_from_template,
+            commands::backup_node,
+            commands::get_backup_status,
+            commands::restore_node_from_backup,
+            commands::capture_running_layer,
+            commands::list_recipes,
+            commands::save_recipe,
+            commands::delete_recipe,
+            commands::create_from_recipe,
             commands::set_bootsequence_and_reboot,
+            commands::validate_boot_chain,
+            commands::cancel_scheduled_boot,
+            commands::sysprep_layer,
+            commands::inject_boot_marker,
+            commands::detect_bitlocker,
+            commands::enable_bitlocker,
+            commands::deploy_to_disk,
+            commands::set_node_scripts,
+            commands::list_node_scripts,
+            commands::apply_node_scripts,
+            commands::get_node_details,
+            commands::get_node_ops,
+            commands::get_attach_history,
+            commands::apply_registry_tweaks,
             commands::start_vm,
+            commands::verify_boot,
             commands::delete_subtree,
+            commands::shrink_vhd,
+            commands::get_compaction_advice,
+            commands::compact_layer,
+            commands::hash_bases,
+            commands::find_duplicate_bases,
+            commands::health_check,
+            commands::repair_all,
+            commands::repair_links,
+            commands::archive_node,
+            commands::restore_archived,
             commands::delete_bcd,
             commands::repair_bcd,
             commands::add_bcd_entry,
-            commands::update_bcd_description
-        ])
+            commands::create_rescue_entry,
+            commands::update_bcd_description,
+            commands::set_boot_menu_policy,
+            commands::list_firmware_entries,
+            commands::get_secure_boot_state,
+            commands::get_entry_flags,
+            commands::set_entry_flags,
+            commands::export_audit,
+            commands::export_tree_graph,
+            commands::export_metadata,
+            commands::import_metadata,
+            commands::reveal_log_dir,
+            commands::open_ops_log,
+            commands::open_failed_script,
+            commands::list_jobs,
+            commands::cancel_job,
+            commands::run_maintenance,
+            commands::get_job_history,
+            commands::clean_temp
+        ]))
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -1,4 +1,8 @@
-use std::{fs, path::Path, sync::Mutex};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
 use once_cell::sync::OnceCell;
 use tracing_appender::{
@@ -12,6 +16,7 @@ use tracing_subscriber::{
 };
 
 use crate::error::{AppError, Result};
+use crate::redact::RedactingWriter;
 
 type LoggingLayer<S> = fmt::Layer<S, DefaultFields, Format<Full>, NonBlocking>;
 type LogHandle = reload::Handle<LoggingLayer<Registry>, Registry>;
@@ -48,6 +53,39 @@ pub fn init_tracing(log_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Rotate the log once it is larger than `max_bytes`: `ops.log` becomes `ops.log.1`, older
+/// copies shift up and anything beyond `keep` is deleted. Returns whether it was rotated.
+pub fn rotate_log(log_path: &Path, max_bytes: u64, keep: u32) -> Result<bool> {
+    match fs::metadata(log_path) {
+        Ok(meta) if meta.len() > max_bytes => {}
+        _ => return Ok(false),
+    }
+    if keep == 0 {
+        fs::remove_file(log_path)?;
+    } else {
+        let oldest = numbered(log_path, keep);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for n in (1..keep).rev() {
+            let from = numbered(log_path, n);
+            if from.exists() {
+                fs::rename(&from, numbered(log_path, n + 1))?;
+            }
+        }
+        fs::rename(log_path, numbered(log_path, 1))?;
+    }
+    // The writer still holds the renamed file; reopen so new lines go to a fresh `ops.log`.
+    init_tracing(log_path)?;
+    Ok(true)
+}
+
+fn numbered(log_path: &Path, n: u32) -> PathBuf {
+    let mut name = log_path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
 fn build_logging_layer(log_path: &Path) -> Result<(LoggingLayer<Registry>, WorkerGuard)> {
     if let Some(parent) = log_path.parent() {
         fs::create_dir_all(parent)?;
@@ -59,9 +97,9 @@ fn build_logging_layer(log_path: &Path) -> Result<(LoggingLayer<Registry>, Worke
     let dir = log_path
         .parent()
         .map(|p| p.to_path_buf())
-        .unwrap_or_else(|| std::path::PathBuf::from("."));
+        .unwrap_or_else(|| PathBuf::from("."));
     let rolling = rolling::never(dir, file_name);
-    let (writer, guard) = tracing_appender::non_blocking(rolling);
+    let (writer, guard) = tracing_appender::non_blocking(RedactingWriter::new(rolling));
 
     let layer: LoggingLayer<Registry> = fmt::Layer::default().with_writer(writer).with_ansi(false);
 
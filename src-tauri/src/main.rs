@@ -1,7 +1,7 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-#[elevated::main]
+#[cfg_attr(windows, elevated::main)]
 fn main() {
     layered_system_lib::run()
 }
@@ -0,0 +1,188 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use tracing::{info, info_span, warn};
+use uuid::Uuid;
+
+use crate::error::{AppError, Result};
+use crate::jobs;
+use crate::logging;
+use crate::models::{JobRecord, MaintenanceSchedule, MaintenanceTask};
+use crate::state::SharedState;
+use crate::workspace::WorkspaceService;
+
+/// How often the scheduler checks whether a task is due.
+const TICK: Duration = Duration::from_secs(60);
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+impl MaintenanceTask {
+    fn as_str(self) -> &'static str {
+        match self {
+            MaintenanceTask::Rescan => "rescan",
+            MaintenanceTask::CompactIdle => "compact_idle",
+            MaintenanceTask::PruneOps => "prune_ops",
+            MaintenanceTask::RotateLogs => "rotate_logs",
+            MaintenanceTask::VerifyChains => "verify_chains",
+            MaintenanceTask::Backup => "backup",
+            MaintenanceTask::CleanTemp => "clean_temp",
+            MaintenanceTask::ExpireLayers => "expire_layers",
+            MaintenanceTask::HashBases => "hash_bases",
+        }
+    }
+
+    /// Kind under which runs of this task are recorded in the job history.
+    fn job_kind(self) -> String {
+        format!("maintenance.{}", self.as_str())
+    }
+}
+
+/// Held while maintenance runs so the scheduler and a manual run never overlap.
+struct RunningGuard;
+
+impl RunningGuard {
+    fn acquire() -> Option<Self> {
+        RUNNING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| RunningGuard)
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Start the background scheduler. It stays idle until a workspace is open and its schedule
+/// is enabled, then runs every task whose last run is older than the configured interval.
+pub fn start(state: SharedState) {
+    thread::spawn(move || loop {
+        thread::sleep(TICK);
+        if let Err(err) = run_due(&state) {
+            warn!("scheduled maintenance failed: {err}");
+        }
+    });
+}
+
+fn run_due(state: &SharedState) -> Result<()> {
+    let schedule = match state.get_settings() {
+        Ok(Some(settings)) => settings.maintenance,
+        Ok(None) | Err(AppError::RootNotInitialized) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !schedule.enabled {
+        return Ok(());
+    }
+    let db = state.db()?;
+    let interval = chrono::Duration::hours(i64::from(schedule.interval_hours.max(1)));
+    let mut due = Vec::new();
+    for &task in &schedule.tasks {
+        match db.last_job_finished(&task.job_kind())? {
+            Some(last) if Utc::now() - last < interval => {}
+            _ => due.push(task),
+        }
+    }
+    if due.is_empty() {
+        return Ok(());
+    }
+    let Some(_running) = RunningGuard::acquire() else {
+        return Ok(());
+    };
+    run_tasks(state, &schedule, &due)?;
+    Ok(())
+}
+
+/// Run maintenance now: the given tasks, or all tasks of the stored schedule.
+pub fn run_now(state: &SharedState, tasks: Option<Vec<MaintenanceTask>>) -> Result<Vec<JobRecord>> {
+    let schedule = state.db()?.get_settings()?.maintenance;
+    let tasks = tasks.unwrap_or_else(|| schedule.tasks.clone());
+    let _running = RunningGuard::acquire()
+        .ok_or_else(|| AppError::Message("maintenance is already running".into()))?;
+    run_tasks(state, &schedule, &tasks)
+}
+
+/// Run tasks one after another; a failing task is recorded and does not stop the others.
+fn run_tasks(
+    state: &SharedState,
+    schedule: &MaintenanceSchedule,
+    tasks: &[MaintenanceTask],
+) -> Result<Vec<JobRecord>> {
+    let db = state.db()?;
+    let svc = WorkspaceService::new(state.clone());
+    let mut records = Vec::new();
+    for &task in tasks {
+        jobs::report_phase(task.as_str(), None);
+        let id = Uuid::new_v4().to_string();
+        let span = info_span!("maintenance", task = task.as_str(), op_id = %id);
+        let _entered = span.enter();
+        let _op_id = jobs::reserve_op_id(id.clone());
+        let started_at = Utc::now();
+        let outcome = run_task(state, &svc, schedule, task);
+        let (result, detail) = match outcome {
+            Ok(detail) => {
+                info!("maintenance {} ok {detail}", task.as_str());
+                ("ok", detail)
+            }
+            Err(err) => {
+                warn!("maintenance {} failed: {err}", task.as_str());
+                ("failed", err.to_string())
+            }
+        };
+        let record = JobRecord {
+            id,
+            kind: task.job_kind(),
+            started_at,
+            finished_at: Utc::now(),
+            result: result.to_string(),
+            detail: Some(detail),
+        };
+        db.insert_job_record(&record)?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn run_task(
+    state: &SharedState,
+    svc: &WorkspaceService,
+    schedule: &MaintenanceSchedule,
+    task: MaintenanceTask,
+) -> Result<String> {
+    match task {
+        MaintenanceTask::Rescan => svc.scan().map(|nodes| format!("nodes={}", nodes.len())),
+        MaintenanceTask::CompactIdle => svc.compact_idle_layers(schedule.idle_days),
+        MaintenanceTask::PruneOps => svc.prune_ops(
+            schedule.ops_retention_days,
+            schedule.transcript_retention_days,
+        ),
+        MaintenanceTask::RotateLogs => {
+            let log_path = state.paths()?.ops_log_path();
+            let max_bytes = u64::from(schedule.log_max_mb) * 1024 * 1024;
+            let rotated = logging::rotate_log(&log_path, max_bytes, schedule.log_keep)?;
+            Ok(format!("rotated={rotated}"))
+        }
+        MaintenanceTask::VerifyChains => svc.verify_chains(),
+        MaintenanceTask::Backup => {
+            let dest = schedule
+                .backup_dest
+                .as_deref()
+                .ok_or_else(|| AppError::Message("no backup destination configured".into()))?;
+            svc.backup_changed(dest)
+        }
+        MaintenanceTask::CleanTemp => {
+            svc.clean_temp_older_than(schedule.temp_retention_days)
+                .map(|cleanup| {
+                    format!(
+                        "removed={} freed_bytes={}",
+                        cleanup.removed, cleanup.freed_bytes
+                    )
+                })
+        }
+        MaintenanceTask::ExpireLayers => svc.expire_layers(schedule.delete_expired),
+        MaintenanceTask::HashBases => svc.hash_bases(),
+    }
+}
@@ -1,7 +1,11 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::bcd::BcdObject;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum NodeStatus {
     Normal,
@@ -10,6 +14,25 @@ pub enum NodeStatus {
     MissingBcd,
     Mounted,
     Error,
+    /// A job is still creating the layer; the file may be missing or incomplete.
+    Creating,
+    /// A job is merging the layer into its parent.
+    Merging,
+    /// A job is deleting the layer.
+    Deleting,
+    /// The VHDX was compressed into `meta/archive` and must be restored before use.
+    Archived,
+}
+
+impl NodeStatus {
+    /// Whether a running job owns the node, so scans must leave it alone and it must not be
+    /// booted or used as a parent.
+    pub fn in_progress(&self) -> bool {
+        matches!(
+            self,
+            NodeStatus::Creating | NodeStatus::Merging | NodeStatus::Deleting
+        )
+    }
 }
 
 impl Default for NodeStatus {
@@ -18,7 +41,7 @@ impl Default for NodeStatus {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Node {
     pub id: String,
     pub parent_id: Option<String>,
@@ -29,6 +52,96 @@ pub struct Node {
     pub created_at: DateTime<Utc>,
     pub status: NodeStatus,
     pub boot_files_ready: bool,
+    pub generalized: bool,
+    pub boot_verified_at: Option<DateTime<Utc>>,
+    pub bitlocker: bool,
+    /// UI accent color as `#rrggbb`.
+    pub color: Option<String>,
+    /// UI icon identifier (e.g. `flask`, `shield`).
+    pub icon: Option<String>,
+    pub os_edition: Option<String>,
+    pub os_version: Option<String>,
+    pub os_build: Option<String>,
+    /// Manual position among siblings; unset nodes follow in creation order.
+    pub sort_key: Option<i64>,
+    /// Free-form lowercase labels (e.g. `driver-test`).
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Bumped on every change to the row; mutating commands pass the version they loaded so
+    /// actions based on a stale view fail instead of acting on a changed node.
+    #[serde(default)]
+    pub version: u64,
+    /// After this moment the layer counts as expired.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Never deleted by expiration or `delete_subtree`.
+    #[serde(default)]
+    pub protected: bool,
+    /// Network profile last written into the layer.
+    pub network: Option<NetworkProfile>,
+    /// Set when `expires_at` has passed or an idle rule of one of its tags matched; computed
+    /// on listing, not stored.
+    #[serde(default)]
+    pub expired: bool,
+    /// Number of ancestors; computed on listing, not stored.
+    #[serde(default)]
+    pub depth: u32,
+    #[serde(default)]
+    pub root_id: String,
+    /// Ancestors from the immediate parent up to the root.
+    #[serde(default)]
+    pub ancestor_ids: Vec<String>,
+    /// Set when `depth` is above the workspace's configured maximum.
+    #[serde(default)]
+    pub depth_exceeded: bool,
+}
+
+/// Filter and paging for `query_nodes`; unset fields match everything and set fields combine
+/// with AND.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NodeQuery {
+    /// Keep nodes whose status is one of these.
+    pub statuses: Option<Vec<NodeStatus>>,
+    /// Drop nodes whose status is one of these.
+    #[serde(default)]
+    pub exclude_statuses: Option<Vec<NodeStatus>>,
+    /// Keep nodes carrying this tag.
+    pub tag: Option<String>,
+    /// Case-insensitive substring of the node name.
+    pub name: Option<String>,
+    /// Keep nodes whose last verified boot is older than this many days, or that never booted.
+    #[serde(default)]
+    pub idle_days: Option<u32>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// A named `NodeQuery` kept in the workspace database; paging is supplied when it is applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedView {
+    pub id: String,
+    pub name: String,
+    pub query: NodeQuery,
+    pub created_at: DateTime<Utc>,
+}
+
+/// One page of `query_nodes`; `total` counts all matches before paging.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodePage {
+    pub nodes: Vec<Node>,
+    pub total: usize,
+    pub offset: usize,
+}
+
+/// Windows edition/version details read from a layer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OsInfo {
+    /// EditionID, e.g. `Professional`.
+    pub edition: Option<String>,
+    /// DisplayVersion, e.g. `23H2`.
+    pub version: Option<String>,
+    /// Build with revision, e.g. `22631.2861`.
+    pub build: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,3 +151,894 @@ pub struct WimImageInfo {
     pub description: Option<String>,
     pub size: Option<String>,
 }
+
+/// A WIM/ESD registered in the catalog with its images and hash cached, so the creation dialog
+/// needs no DISM call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WimSource {
+    pub id: String,
+    pub path: String,
+    pub size_bytes: u64,
+    /// Last write time when the file was registered.
+    pub modified_at: Option<DateTime<Utc>>,
+    pub sha256: String,
+    pub images: Vec<WimImageInfo>,
+    pub added_at: DateTime<Utc>,
+    /// Computed on listing from the file's current size and write time, not stored.
+    #[serde(default)]
+    pub status: WimSourceStatus,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WimSourceStatus {
+    #[default]
+    Ok,
+    /// The file was replaced or modified since it was registered; register it again.
+    Changed,
+    Missing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueSeverity {
+    Warning,
+    Error,
+}
+
+/// A problem with the volume hosting the workspace, found by the preflight checks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeIssue {
+    pub severity: IssueSeverity,
+    /// Stable identifier for the UI, e.g. `not_ntfs`.
+    pub code: String,
+    pub message: String,
+}
+
+/// Layer creation to check with `preflight_create`: a differencing layer when `parent_id` is
+/// set, otherwise a base applied from `wim_file`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreatePreflightParams {
+    pub name: String,
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    #[serde(default)]
+    pub wim_file: Option<String>,
+    #[serde(default)]
+    pub wim_index: Option<u32>,
+    /// The base gets a WinRE recovery partition, which takes one more drive letter.
+    #[serde(default)]
+    pub recovery: bool,
+}
+
+/// One line of the creation checklist; `severity` is `None` when the check passed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreflightCheck {
+    /// Stable identifier for the UI, e.g. `free_space`.
+    pub code: String,
+    pub severity: Option<IssueSeverity>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatePreflight {
+    /// No check failed with an error; warnings do not block the creation.
+    pub ready: bool,
+    pub checks: Vec<PreflightCheck>,
+}
+
+/// A process currently run by the backend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// What `clean_temp` removed from `meta/tmp`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TempCleanup {
+    pub removed: u32,
+    pub freed_bytes: u64,
+}
+
+/// A finished background job, as listed in the job history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: String,
+    /// e.g. `maintenance.rescan`.
+    pub kind: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// `ok` or `failed`.
+    pub result: String,
+    pub detail: Option<String>,
+}
+
+/// Housekeeping job the maintenance scheduler can run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceTask {
+    Rescan,
+    CompactIdle,
+    PruneOps,
+    RotateLogs,
+    VerifyChains,
+    Backup,
+    CleanTemp,
+    ExpireLayers,
+    HashBases,
+}
+
+/// Opt-in remote management; stored in settings. The agent registers with `endpoint` and polls
+/// it for commands, which run only when signed with `key`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AgentConfig {
+    pub enabled: bool,
+    /// Base URL of the central console, e.g. `https://lab-console:8443/api`.
+    pub endpoint: String,
+    /// Shared secret the console signs commands with (HMAC-SHA256).
+    pub key: String,
+    /// Name this machine registers under; the computer name when not set.
+    pub agent_name: Option<String>,
+    pub poll_seconds: u32,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: String::new(),
+            key: String::new(),
+            agent_name: None,
+            poll_seconds: 30,
+        }
+    }
+}
+
+/// When and how the maintenance scheduler runs; stored in settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceSchedule {
+    pub enabled: bool,
+    /// Each task runs again once this long has passed since its last run.
+    pub interval_hours: u32,
+    pub tasks: Vec<MaintenanceTask>,
+    /// Leaf layers whose VHDX was not written for this many days are compacted.
+    pub idle_days: u32,
+    /// Operations older than this many days are pruned.
+    pub ops_retention_days: u32,
+    /// Command transcripts older than this many days are pruned.
+    pub transcript_retention_days: u32,
+    /// Copies of failed scripts in `meta/tmp/failed` are kept this many days.
+    pub temp_retention_days: u32,
+    /// `ops.log` is rotated once it grows beyond this size.
+    pub log_max_mb: u32,
+    /// Rotated logs kept next to `ops.log`.
+    pub log_keep: u32,
+    /// Folder the `backup` task copies changed layers to.
+    pub backup_dest: Option<String>,
+    /// Layers carrying one of these tags expire once their VHDX was not written for the given
+    /// number of days, e.g. `experiment` -> 7.
+    pub tag_idle_days: BTreeMap<String, u32>,
+    /// Let `expire_layers` delete expired leaf layers instead of only reporting them.
+    pub delete_expired: bool,
+}
+
+impl Default for MaintenanceSchedule {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: 24,
+            tasks: vec![
+                MaintenanceTask::Rescan,
+                MaintenanceTask::PruneOps,
+                MaintenanceTask::RotateLogs,
+                MaintenanceTask::VerifyChains,
+                MaintenanceTask::CleanTemp,
+            ],
+            idle_days: 14,
+            ops_retention_days: 180,
+            transcript_retention_days: 30,
+            temp_retention_days: 14,
+            log_max_mb: 20,
+            log_keep: 5,
+            backup_dest: None,
+            tag_idle_days: BTreeMap::new(),
+            delete_expired: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Payload of the `job-output` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobOutputLine {
+    pub job_id: String,
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// Progress of a long operation, emitted on `job://<job_id>/progress`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobProgress {
+    pub job_id: String,
+    /// Current step, e.g. `apply_image` or `boot_files`; `done` or `failed` at the end.
+    pub phase: String,
+    pub percent: Option<u8>,
+    /// Command line of the external tool currently running, if any.
+    pub command: Option<String>,
+}
+
+/// Aggregated numbers for the workspace dashboard.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WorkspaceStats {
+    pub node_count: usize,
+    /// Node count keyed by status (`normal`, `missing_file`, ...).
+    pub status_counts: BTreeMap<String, usize>,
+    /// Sum of the VHDX file sizes, i.e. space actually used on disk.
+    pub file_bytes: u64,
+    /// Sum of the capacities the layers expose to Windows.
+    pub virtual_bytes: u64,
+    pub chains: Vec<ChainStats>,
+    pub volume_free_bytes: Option<u64>,
+    pub volume_total_bytes: Option<u64>,
+    pub recent_ops: RecentOpStats,
+}
+
+/// Sizes of one base layer and all of its descendants.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChainStats {
+    pub root_id: String,
+    pub root_name: String,
+    pub node_count: usize,
+    pub file_bytes: u64,
+    pub virtual_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecentOpStats {
+    pub since: DateTime<Utc>,
+    pub total: u64,
+    pub failed: u64,
+    /// Operation count keyed by action (`create_diff`, `repair_bcd`, ...).
+    pub by_action: BTreeMap<String, u64>,
+}
+
+/// A UEFI boot option as reported by `bcdedit /enum firmware`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirmwareEntry {
+    pub identifier: String,
+    pub description: String,
+    /// Object type title, e.g. `Windows Boot Manager` or `Firmware Application (101fffff)`.
+    pub kind: String,
+    pub device: Option<String>,
+    pub path: Option<String>,
+    /// Index in the firmware boot order; `None` when the entry is not in the order at all.
+    pub position: Option<usize>,
+    pub is_windows_boot_manager: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirmwareBootInfo {
+    /// Entries sorted by firmware boot order, followed by those outside the order.
+    pub entries: Vec<FirmwareEntry>,
+    /// Whether Windows Boot Manager is the first option the firmware tries.
+    pub boot_manager_first: bool,
+    pub timeout: Option<u32>,
+}
+
+/// Boot menu style used by a loader entry (`bcdedit bootmenupolicy`).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BootMenuPolicy {
+    /// Graphical Windows 8+ menu.
+    Standard,
+    /// Text menu of Windows 7, much faster to navigate by keyboard.
+    Legacy,
+}
+
+impl BootMenuPolicy {
+    pub fn as_bcd(&self) -> &'static str {
+        match self {
+            BootMenuPolicy::Standard => "Standard",
+            BootMenuPolicy::Legacy => "Legacy",
+        }
+    }
+}
+
+/// What the boot manager does after a failed boot or shutdown (`bcdedit bootstatuspolicy`).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BootStatusPolicy {
+    DisplayAll,
+    IgnoreAll,
+    IgnoreShutdown,
+    IgnoreBoot,
+}
+
+impl BootStatusPolicy {
+    pub fn as_bcd(&self) -> &'static str {
+        match self {
+            BootStatusPolicy::DisplayAll => "DisplayAllFailures",
+            BootStatusPolicy::IgnoreAll => "IgnoreAllFailures",
+            BootStatusPolicy::IgnoreShutdown => "IgnoreShutdownFailures",
+            BootStatusPolicy::IgnoreBoot => "IgnoreBootFailures",
+        }
+    }
+
+    pub fn from_bcd(value: &str) -> Option<Self> {
+        [
+            BootStatusPolicy::DisplayAll,
+            BootStatusPolicy::IgnoreAll,
+            BootStatusPolicy::IgnoreShutdown,
+            BootStatusPolicy::IgnoreBoot,
+        ]
+        .into_iter()
+        .find(|p| p.as_bcd().eq_ignore_ascii_case(value.trim()))
+    }
+}
+
+/// Recovery-related settings of a layer's loader entry; `None` means "not set" when reading and
+/// "leave unchanged" when writing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BootEntryFlags {
+    /// Whether a failed boot falls into WinRE automatic repair.
+    pub recovery_enabled: Option<bool>,
+    pub boot_status_policy: Option<BootStatusPolicy>,
+    /// Load test-signed drivers; refused while Secure Boot is on, where the entry won't boot.
+    #[serde(default)]
+    pub testsigning: Option<bool>,
+    /// Skip driver signature checks; refused while Secure Boot is on like `testsigning`.
+    #[serde(default)]
+    pub nointegritychecks: Option<bool>,
+}
+
+/// Secure Boot state of the host firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecureBootState {
+    Enabled,
+    Disabled,
+    /// Legacy BIOS boot; there is no Secure Boot to get in the way.
+    Unsupported,
+    Unknown,
+}
+
+/// Disk-hungry operations that can be estimated up front.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EstimateOp {
+    /// Fold a differencing layer into its parent.
+    Merge,
+    /// Copy a single layer file.
+    Clone,
+    /// Collapse a layer and all its ancestors into one standalone disk.
+    Flatten,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpaceEstimate {
+    pub op: EstimateOp,
+    pub node_id: String,
+    /// Additional bytes the operation is expected to write.
+    pub required_bytes: u64,
+    pub free_bytes: Option<u64>,
+    /// `None` when the free space of the workspace volume could not be read.
+    pub sufficient: Option<bool>,
+}
+
+/// File size of a layer before and after a space-reclaiming operation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReclaimResult {
+    pub node_id: String,
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+}
+
+/// Workspace tree without the VHDX payloads, for adopting it on another machine that sees a
+/// synced copy of the disks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceMetadata {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub machine: Option<String>,
+    pub nodes: Vec<NodeMetadata>,
+    #[serde(default)]
+    pub recipes: Vec<Recipe>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeMetadata {
+    pub id: String,
+    pub parent_id: Option<String>,
+    pub name: String,
+    /// Relative to the workspace root; absolute for layers stored outside it.
+    pub rel_path: String,
+    pub desc: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub generalized: bool,
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    pub sort_key: Option<i64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub scripts: Vec<ProvisioningScript>,
+    /// Boot menu description on the exporting machine; `None` when the layer had no entry.
+    pub bcd_description: Option<String>,
+}
+
+/// Outcome of `import_metadata`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetadataImport {
+    /// Ids of layers added to this workspace.
+    pub adopted: Vec<String>,
+    /// Ids of layers that were already tracked and got their metadata replaced.
+    pub updated: Vec<String>,
+    /// Exported paths with no VHDX in this workspace.
+    pub missing: Vec<String>,
+    pub recipes: usize,
+    /// Layers, boot entries or recipes that could not be imported.
+    pub errors: Vec<String>,
+}
+
+/// A `parent_id` rewritten by `repair_links`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkChange {
+    pub node_id: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// Kind of problem reported by `health_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCategory {
+    /// A tracked layer whose VHDX is gone.
+    MissingFile,
+    /// A boot entry pointing at a missing or untracked VHDX in the workspace, or a layer whose
+    /// recorded entry no longer exists.
+    OrphanEntry,
+    /// A layer whose parent record or file is missing, or whose VHDX names another parent.
+    BrokenParent,
+    /// A layer left attached although nothing runs and the system did not boot from it.
+    StaleMount,
+}
+
+/// One problem found by `health_check`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthFinding {
+    pub category: HealthCategory,
+    pub node_id: Option<String>,
+    pub bcd_guid: Option<String>,
+    pub message: String,
+}
+
+/// Reason a layer in a boot chain would keep the chain from booting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainIssueKind {
+    MissingFile,
+    MissingParentRecord,
+    /// The VHDX names another parent, or none.
+    ParentMismatch,
+    /// The parent was written after the child was created, which invalidates the child.
+    ParentModified,
+    /// Attached by something other than the running system.
+    Attached,
+}
+
+/// One problem found by `validate_boot_chain`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainIssue {
+    pub kind: ChainIssueKind,
+    pub node_id: String,
+    pub message: String,
+}
+
+/// Summary returned instead of running a destructive command while `confirm_destructive` is
+/// on; repeat the call with `token` before `expires_at` to go ahead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfirmationRequest {
+    pub action: String,
+    pub node_id: String,
+    /// Names of the layers the action removes or rewrites.
+    pub affected: Vec<String>,
+    pub total_bytes: u64,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Outcome of `repair_all`, one line per selected finding.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepairReport {
+    pub repaired: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Outcome of `repair_links`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkRepair {
+    /// Layers whose VHDX was read.
+    pub checked: usize,
+    pub changed: Vec<LinkChange>,
+    /// Ids of layers whose parent file is missing or not tracked by the workspace.
+    pub orphaned: Vec<String>,
+    /// Layers whose file is missing or could not be read.
+    pub errors: Vec<String>,
+}
+
+/// A published base VHDX in the shared library.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    /// File name inside the library folder; identifies the entry.
+    pub file: String,
+    pub path: String,
+    pub size_bytes: u64,
+    /// From the `<file>.json` manifest next to the VHDX; the file name without one.
+    pub name: String,
+    pub desc: Option<String>,
+    /// Expected SHA-256 of the VHDX; entries without one cannot be cloned.
+    pub sha256: Option<String>,
+    pub publisher: Option<String>,
+    pub published_at: Option<DateTime<Utc>>,
+    pub os_edition: Option<String>,
+    pub os_version: Option<String>,
+    pub os_build: Option<String>,
+}
+
+/// Most recent backup of a node to one destination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupStatus {
+    pub id: String,
+    /// File name of the VHDX inside `dest`.
+    pub file: String,
+    pub node_id: String,
+    pub dest: String,
+    pub backed_up_at: DateTime<Utc>,
+    /// `ok` or `failed`.
+    pub result: String,
+    pub file_bytes: u64,
+    /// Modification time of the VHDX that was copied; unchanged layers are not copied again.
+    pub source_modified: Option<DateTime<Utc>>,
+    pub sha256: Option<String>,
+    pub detail: Option<String>,
+}
+
+/// File size and fragmentation of a VHDX as last measured by a scan.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VhdMetrics {
+    pub node_id: String,
+    pub file_bytes: u64,
+    pub fragmentation: u32,
+    pub measured_at: DateTime<Utc>,
+}
+
+/// A layer worth compacting, from `get_compaction_advice`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactionHint {
+    pub node_id: String,
+    pub file_bytes: u64,
+    pub fragmentation: u32,
+    /// Estimated bytes returned to the host by compacting, from the fragmentation share.
+    pub estimated_savings: u64,
+    pub measured_at: DateTime<Utc>,
+    /// False for layers with children, which `compact_layer` refuses.
+    pub compactable: bool,
+}
+
+/// Content hash of a base VHDX, valid while its size and modification time are unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeHash {
+    pub node_id: String,
+    pub file_bytes: u64,
+    pub modified: Option<DateTime<Utc>>,
+    pub sha256: String,
+    pub hashed_at: DateTime<Utc>,
+}
+
+/// Base layers with identical content.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateBases {
+    pub sha256: String,
+    pub file_bytes: u64,
+    /// Suggested copy to keep: the base with the most children, then the oldest.
+    pub keep: String,
+    /// Bases whose children can be rebased onto `keep` before they are deleted.
+    pub duplicates: Vec<String>,
+    /// Space freed once the duplicates are gone.
+    pub reclaimable_bytes: u64,
+}
+
+/// Outcome of `find_duplicate_bases`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DuplicateReport {
+    pub groups: Vec<DuplicateBases>,
+    /// Bases sharing their size with another base but without a current hash; the `hash_bases`
+    /// maintenance task fills these in.
+    pub unhashed: Vec<String>,
+}
+
+/// Outcome of `refresh_base`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshResult {
+    pub base: Node,
+    /// Layers rebuilt on the new base, keyed by the id of the layer they replace.
+    pub recreated: Vec<RecreatedLayer>,
+    /// Layers that could not be rebuilt, including the descendants of one that failed. The old
+    /// chain is only retired when this is empty.
+    pub failed: Vec<RefreshFailure>,
+    /// Old layers tagged `retired` whose boot entries were removed; their files are kept.
+    pub retired: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecreatedLayer {
+    pub old_id: String,
+    pub node: Node,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshFailure {
+    pub old_id: String,
+    pub error: String,
+}
+
+/// Everything the detail pane shows about one node, gathered in one call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeDetails {
+    /// The node with chain depth and ancestry filled in.
+    pub node: Node,
+    /// Ancestors from the root down to the immediate parent.
+    pub chain: Vec<Node>,
+    pub children: Vec<Node>,
+    pub file_bytes: Option<u64>,
+    pub virtual_bytes: Option<u64>,
+    pub vhd: Option<VhdState>,
+    /// The node's entry as printed by `bcdedit /enum`.
+    pub bcd_entry: Option<BcdObject>,
+    /// Parts that could not be read; everything else in the response is still valid.
+    pub errors: Vec<String>,
+}
+
+/// Live state of a VHDX as reported by `diskpart detail vdisk`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VhdState {
+    /// Parent path recorded in the file itself.
+    pub parent: Option<String>,
+    pub attached: Option<bool>,
+}
+
+/// One recorded operation on a layer, as shown in its timeline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeOperation {
+    pub id: String,
+    pub node_id: String,
+    pub ts: DateTime<Utc>,
+    /// e.g. `create_diff`, `repair_bcd` or `inject_drivers`.
+    pub action: String,
+    pub result: String,
+    pub detail: Option<String>,
+    /// Wall-clock time of the operation; only recorded for long-running ones.
+    pub duration_ms: Option<u64>,
+    /// Folder under `meta/transcripts` with the full output of the commands it ran.
+    pub transcript: Option<String>,
+}
+
+/// Date range of an audit export; open ends are unbounded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditRange {
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive upper bound.
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditFormat {
+    Csv,
+    Markdown,
+}
+
+/// Text format of `export_tree_graph`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+/// One row of the audit report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub ts: DateTime<Utc>,
+    /// `DOMAIN\user` that ran the operation; unknown for operations recorded by older versions.
+    pub actor: Option<String>,
+    pub node_id: Option<String>,
+    pub node_name: Option<String>,
+    pub action: String,
+    pub result: String,
+    pub detail: Option<String>,
+    pub duration_ms: Option<u64>,
+}
+
+/// One attach or detach of a layer's VHDX performed by the app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachEvent {
+    pub node_id: String,
+    /// Operation that performed the attach; matches the `ops` id where one was recorded.
+    pub op_id: String,
+    /// `attach` or `detach`.
+    pub action: String,
+    /// What the disk was mounted for, e.g. `create_diff` or `repair`.
+    pub context: String,
+    pub result: String,
+    pub ts: DateTime<Utc>,
+}
+
+/// Driver package found in a layer's driver store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerDriver {
+    /// Name in the driver store, e.g. `oem3.inf`.
+    pub published_name: String,
+    pub original_file_name: String,
+    pub inbox: bool,
+    pub class_name: String,
+    pub provider_name: String,
+    pub date: String,
+    pub version: String,
+}
+
+/// Servicing package (update, language pack, ...) installed in a layer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerPackage {
+    pub identity: String,
+    /// `Installed`, `Staged`, `Superseded`, ...
+    pub state: String,
+    pub release_type: String,
+    pub install_time: String,
+}
+
+/// Optional Windows feature and whether it is enabled in a layer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerFeature {
+    pub name: String,
+    /// `Enabled`, `Disabled`, `Disabled with Payload Removed`, ...
+    pub state: String,
+}
+
+/// A registry value written into a layer's offline hive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryTweak {
+    /// `SYSTEM`, `SOFTWARE` or `DEFAULT`.
+    pub hive: String,
+    /// Key relative to the hive root; `CurrentControlSet` is resolved for the SYSTEM hive.
+    pub key: String,
+    pub name: String,
+    /// `REG_SZ`, `REG_DWORD`, ... as accepted by `reg add /t`.
+    pub value_type: String,
+    pub data: String,
+}
+
+/// Built-in sets of registry tweaks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "preset", rename_all = "snake_case")]
+pub enum RegistryPreset {
+    DisableHibernation,
+    /// Windows time zone id, e.g. `China Standard Time`.
+    SetTimezone {
+        timezone: String,
+    },
+    /// NetBIOS/DNS host name, so layers copied from one image do not all boot as the same
+    /// `DESKTOP-XXXXXX`.
+    ComputerName {
+        name: String,
+    },
+    /// No pagefile at all.
+    DisablePagefile,
+    /// System-managed pagefile on another volume such as `D:`, outside the layer.
+    MovePagefile {
+        drive: String,
+    },
+}
+
+/// Creation option keeping hiberfil.sys and pagefile.sys, the two files that bloat a
+/// differencing VHDX the most, out of a layer. Hibernation is always disabled; the pagefile
+/// moves to `pagefile_drive` when set and is disabled otherwise.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SwapFileOptions {
+    #[serde(default)]
+    pub pagefile_drive: Option<String>,
+}
+
+/// Built-in lists of inbox apps removed from a new layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebloatPreset {
+    /// Promotional, game and help apps.
+    Basic,
+    /// `basic` plus media, maps, phone link and similar consumer apps.
+    Full,
+}
+
+/// Everything needed to rebuild a standard environment as a new base layer in one step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recipe {
+    /// Empty when saving a new recipe.
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub desc: Option<String>,
+    pub wim_file: String,
+    pub wim_index: u32,
+    pub size_gb: u64,
+    /// Add a WinRE recovery partition to the base.
+    #[serde(default)]
+    pub recovery: bool,
+    /// Driver folders (searched recursively) or single `.inf` files.
+    #[serde(default)]
+    pub drivers: Vec<String>,
+    /// Answer file copied to `Windows\Panther\unattend.xml`.
+    pub unattend: Option<String>,
+    pub debloat: Option<DebloatPreset>,
+    #[serde(default)]
+    pub tweaks: Vec<RegistryTweak>,
+    #[serde(default)]
+    pub presets: Vec<RegistryPreset>,
+    #[serde(default)]
+    pub scripts: Vec<ProvisioningScript>,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Addressing written into a layer by a network profile.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum NetworkAddressing {
+    Dhcp,
+    Static {
+        address: String,
+        prefix_length: u8,
+        gateway: Option<String>,
+        #[serde(default)]
+        dns: Vec<String>,
+    },
+}
+
+/// Network settings a layer gets on its first logon, for labs with fixed addressing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkProfile {
+    pub addressing: NetworkAddressing,
+    /// Adapter to configure; the first connected physical adapter when not set.
+    pub interface_alias: Option<String>,
+    /// Offline domain join blob produced by `djoin /provision`.
+    pub djoin_blob: Option<String>,
+}
+
+/// Local administrator provisioned into a layer. Not `Debug`, so the password cannot be
+/// formatted into a log line by accident; the commands that write it mask it.
+#[derive(Clone, Deserialize)]
+pub struct LocalAdmin {
+    pub user_name: String,
+    pub password: String,
+    /// Log this account on automatically at every boot.
+    #[serde(default)]
+    pub autologon: bool,
+}
+
+/// Script or installer copied into a layer and run once on its first logon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisioningScript {
+    pub path: String,
+    pub args: Option<String>,
+}
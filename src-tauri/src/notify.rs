@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use serde::Serialize;
+#[cfg(windows)]
+use tauri::Emitter;
+use tauri::{AppHandle, Manager};
+#[cfg(windows)]
+use tauri_winrt_notification::Toast;
+#[cfg(windows)]
+use tracing::warn;
+
+use crate::jobs;
+
+/// Event emitted when the user clicks a job notification, so the frontend can show the job.
+#[cfg(windows)]
+pub const NOTIFICATION_ACTIVATED_EVENT: &str = "job-notification-activated";
+
+/// Jobs finishing sooner than this rarely outlast the user's attention; they get no toast.
+pub const MIN_JOB_DURATION: Duration = Duration::from_secs(20);
+
+/// Outcome of a finished job, shown in the toast and sent along when it is clicked.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobNotification {
+    pub job_id: Option<String>,
+    /// Command that ran, e.g. `create_diff_vhd`.
+    pub kind: String,
+    pub ok: bool,
+    /// Error message of a failed job.
+    pub detail: Option<String>,
+}
+
+/// Show a toast for a finished job while the main window is minimized. Clicking it restores
+/// the window and emits [`NOTIFICATION_ACTIVATED_EVENT`].
+pub fn job_finished(notification: JobNotification) {
+    let Some(app) = jobs::app_handle() else {
+        return;
+    };
+    let minimized = app
+        .get_webview_window("main")
+        .and_then(|window| window.is_minimized().ok())
+        .unwrap_or(false);
+    if minimized {
+        show_toast(app, notification);
+    }
+}
+
+#[cfg(windows)]
+fn show_toast(app: &AppHandle, notification: JobNotification) {
+    // Toasts need the AppUserModelID the installer registers; unpackaged dev builds borrow
+    // PowerShell's.
+    let app_id = if tauri::is_dev() {
+        Toast::POWERSHELL_APP_ID.to_string()
+    } else {
+        app.config().identifier.clone()
+    };
+    let title = format!(
+        "{} {}",
+        notification.kind,
+        if notification.ok {
+            "finished"
+        } else {
+            "failed"
+        }
+    );
+    let handle = app.clone();
+    let payload = notification.clone();
+    let res = Toast::new(&app_id)
+        .title(&title)
+        .text1(notification.detail.as_deref().unwrap_or_default())
+        .add_button("Details", "details")
+        .on_activated(move |_| {
+            if let Some(window) = handle.get_webview_window("main") {
+                let _ = window.unminimize();
+                let _ = window.set_focus();
+            }
+            let _ = handle.emit(NOTIFICATION_ACTIVATED_EVENT, &payload);
+            Ok(())
+        })
+        .show();
+    if let Err(err) = res {
+        warn!("job notification failed kind={}: {err}", notification.kind);
+    }
+}
+
+/// Toasts are Windows notifications; elsewhere the finished job only shows up in the job list.
+#[cfg(not(windows))]
+fn show_toast(_app: &AppHandle, _notification: JobNotification) {}
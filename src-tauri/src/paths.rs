@@ -1,19 +1,60 @@
+#[cfg(windows)]
+use std::{ffi::OsStr, iter::once, os::windows::ffi::OsStrExt};
 use std::{
     fs,
     path::{Path, PathBuf},
 };
 
-use crate::error::Result;
+#[cfg(windows)]
+use windows_sys::Win32::Storage::FileSystem::{
+    GetLongPathNameW, GetShortPathNameW, QueryDosDeviceW,
+};
+
+use crate::error::{AppError, Result};
+
+/// Longest path (including the terminating NUL) most tools and Win32 calls accept without an
+/// extended-length prefix.
+pub const MAX_PATH: usize = 260;
+
+/// Revision of `normalize_path`. Bump it whenever the function's output changes so the stored
+/// path keys of existing workspaces are recomputed when they are opened.
+pub const PATH_KEY_VERSION: i64 = 1;
 
 #[derive(Debug, Clone)]
 pub struct AppPaths {
     root: PathBuf,
+    /// Folder configured to hold temp files instead of `meta/tmp`.
+    temp_override: Option<PathBuf>,
+    /// Folders configured to hold base and differencing layers instead of `disks`.
+    base_override: Option<PathBuf>,
+    diff_override: Option<PathBuf>,
 }
 
 impl AppPaths {
     pub fn new(root: impl Into<PathBuf>) -> Self {
         let root = root.into();
-        Self { root }
+        Self {
+            root,
+            temp_override: None,
+            base_override: None,
+            diff_override: None,
+        }
+    }
+
+    /// Keep scripts and staging files under `dir` instead of the workspace volume, e.g. when
+    /// that volume is slow or nearly full.
+    pub fn with_temp_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.temp_override = dir;
+        self
+    }
+
+    /// Keep base layers and differencing layers in their own folders, e.g. bases on a large
+    /// HDD and the diffs being booted on a fast SSD. A diff folder that is not set follows the
+    /// base folder.
+    pub fn with_layer_dirs(mut self, base: Option<PathBuf>, diff: Option<PathBuf>) -> Self {
+        self.base_override = base;
+        self.diff_override = diff;
+        self
     }
 
     pub fn root(&self) -> &Path {
@@ -21,26 +62,67 @@ impl AppPaths {
     }
 
     pub fn base_dir(&self) -> PathBuf {
-        self.root.join("disks")
+        self.base_override
+            .clone()
+            .unwrap_or_else(|| self.root.join("disks"))
     }
 
-    // 必须要跟 base 目录相同，否则引导的时候会报错找不到 \Windows\System32\winload.efi
+    // 默认跟 base 目录相同，否则引导的时候会报错找不到 \Windows\System32\winload.efi；
+    // 单独配置到别的卷时由 preflight 检查该卷能否原生引导
     pub fn diff_dir(&self) -> PathBuf {
-        self.root.join("disks")
+        self.diff_override
+            .clone()
+            .unwrap_or_else(|| self.base_dir())
+    }
+
+    /// Folders to search for layers: the workspace root and any layer folder outside it.
+    pub fn layer_roots(&self) -> Vec<PathBuf> {
+        let mut roots = vec![self.root.clone()];
+        for dir in [self.base_dir(), self.diff_dir()] {
+            if !roots.iter().any(|root| is_within(&dir, root)) {
+                roots.push(dir);
+            }
+        }
+        roots
     }
 
     pub fn meta_dir(&self) -> PathBuf {
         self.root.join("meta")
     }
 
+    /// A subfolder of the configured temp location, so cleaning it never touches files of
+    /// other programs sharing e.g. `%TEMP%`.
     pub fn tmp_dir(&self) -> PathBuf {
-        self.meta_dir().join("tmp")
+        match &self.temp_override {
+            Some(dir) => dir.join("layered-system"),
+            None => self.meta_dir().join("tmp"),
+        }
     }
 
     pub fn locales_dir(&self) -> PathBuf {
         self.meta_dir().join("locales")
     }
 
+    /// Boot markers dropped by layers that booted successfully.
+    pub fn markers_dir(&self) -> PathBuf {
+        self.meta_dir().join("markers")
+    }
+
+    /// Full command transcripts, one folder per recorded operation.
+    pub fn transcripts_dir(&self) -> PathBuf {
+        self.meta_dir().join("transcripts")
+    }
+
+    /// Single-index WIMs exported from multi-index sources, reused by later base creations.
+    pub fn wim_cache_dir(&self) -> PathBuf {
+        self.meta_dir().join("wim-cache")
+    }
+
+    /// Compressed VHDXs of archived layers, named after the node id.
+    pub fn archive_dir(&self) -> PathBuf {
+        self.meta_dir().join("archive")
+    }
+
     pub fn mount_root(&self) -> PathBuf {
         self.meta_dir().join("mnt")
     }
@@ -49,6 +131,11 @@ impl AppPaths {
         self.root.join("vms")
     }
 
+    /// WinPE image and boot.sdi backing the rescue ramdisk entry.
+    pub fn rescue_dir(&self) -> PathBuf {
+        self.root.join("rescue")
+    }
+
     pub fn state_db_path(&self) -> PathBuf {
         self.meta_dir().join("state.db")
     }
@@ -67,6 +154,7 @@ impl AppPaths {
             self.tmp_dir().as_path(),
             self.locales_dir().as_path(),
             self.mount_root().as_path(),
+            self.markers_dir().as_path(),
             self.vms_dir().as_path(),
         ] {
             fs::create_dir_all(dir)?;
@@ -74,3 +162,285 @@ impl AppPaths {
         Ok(())
     }
 }
+
+/// Replace `%NAME%` references with the value of environment variable `NAME`; unknown names
+/// are left as they are.
+pub fn expand_env_vars(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('%') {
+        let Some(len) = rest[start + 1..].find('%') else {
+            break;
+        };
+        let name = &rest[start + 1..start + 1 + len];
+        out.push_str(&rest[..start]);
+        match std::env::var(name) {
+            Ok(expanded) if !name.is_empty() => out.push_str(&expanded),
+            _ => out.push_str(&rest[start..start + len + 2]),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Whether `path` is `dir` or lies below it.
+pub fn is_within(path: &Path, dir: &Path) -> bool {
+    let path = normalize_path(&path.to_string_lossy());
+    let dir = normalize_path(&dir.to_string_lossy());
+    let dir = dir.trim_end_matches('\\');
+    path == dir || path.starts_with(&format!("{dir}\\"))
+}
+
+/// Canonical, case-insensitive form of a VHDX path used to match files to node rows.
+/// Verbatim prefixes are dropped and 8.3 names (as handed to tools for long paths) are
+/// expanded, so every spelling of a file compares equal.
+pub fn normalize_path(path: &str) -> String {
+    let trimmed = plain_path(path.trim());
+    let adjusted = device_path_to_drive(&trimmed).unwrap_or(trimmed);
+    let adjusted = if adjusted.contains('~') {
+        long_name(Path::new(&adjusted)).unwrap_or(adjusted)
+    } else {
+        adjusted
+    };
+    adjusted.replace('/', "\\").to_ascii_lowercase()
+}
+
+/// `path` without a verbatim prefix: `\\?\C:\x` becomes `C:\x` and `\\?\UNC\srv\share` becomes
+/// `\\srv\share`.
+pub fn plain_path(path: &str) -> String {
+    if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{unc}")
+    } else {
+        path.strip_prefix(r"\\?\").unwrap_or(path).to_string()
+    }
+}
+
+/// Extended-length (`\\?\`) form of an absolute path for Win32 calls that would otherwise stop
+/// at MAX_PATH. `std::fs` adds the prefix on its own; this is for direct API calls.
+#[cfg(windows)]
+pub fn extended_path(path: &Path) -> PathBuf {
+    let raw = path.to_string_lossy();
+    if raw.starts_with(r"\\?\") {
+        path.to_path_buf()
+    } else if let Some(unc) = raw.strip_prefix(r"\\") {
+        PathBuf::from(format!(r"\\?\UNC\{unc}"))
+    } else if path.is_absolute() {
+        PathBuf::from(format!(r"\\?\{}", raw.replace('/', "\\")))
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// `path` in a form tools limited to MAX_PATH (diskpart, bcdedit, bcdboot) accept: unchanged
+/// when short enough, otherwise its 8.3 form. A file that does not exist yet keeps its name
+/// under the shortened folder.
+pub fn tool_path(path: &Path) -> Result<PathBuf> {
+    let plain = plain_path(&path.to_string_lossy());
+    if path_len(&plain) < MAX_PATH {
+        return Ok(PathBuf::from(plain));
+    }
+    let short = if path.exists() {
+        short_name(path)
+    } else {
+        path.parent()
+            .and_then(short_name)
+            .zip(path.file_name())
+            .map(|(dir, name)| {
+                format!("{}\\{}", dir.trim_end_matches('\\'), name.to_string_lossy())
+            })
+    };
+    match short {
+        Some(short) if path_len(&short) < MAX_PATH => Ok(PathBuf::from(short)),
+        _ => Err(AppError::Message(format!(
+            "path is longer than {} characters and has no short (8.3) form: {plain}; move the workspace to a shallower folder or enable 8.3 names on its volume",
+            MAX_PATH - 1
+        ))),
+    }
+}
+
+/// Length in UTF-16 units, the unit MAX_PATH is counted in.
+fn path_len(path: &str) -> usize {
+    path.encode_utf16().count()
+}
+
+#[cfg(windows)]
+fn short_name(path: &Path) -> Option<String> {
+    convert_name(path, GetShortPathNameW)
+}
+
+#[cfg(windows)]
+fn long_name(path: &Path) -> Option<String> {
+    convert_name(path, GetLongPathNameW)
+}
+
+/// Other hosts have no 8.3 names.
+#[cfg(not(windows))]
+fn short_name(_path: &Path) -> Option<String> {
+    None
+}
+
+#[cfg(not(windows))]
+fn long_name(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Run `GetShortPathNameW`/`GetLongPathNameW` on the extended form of `path`.
+#[cfg(windows)]
+fn convert_name(
+    path: &Path,
+    convert: unsafe extern "system" fn(*const u16, *mut u16, u32) -> u32,
+) -> Option<String> {
+    let wide: Vec<u16> = extended_path(path)
+        .as_os_str()
+        .encode_wide()
+        .chain(once(0))
+        .collect();
+    let needed = unsafe { convert(wide.as_ptr(), std::ptr::null_mut(), 0) };
+    if needed == 0 {
+        return None;
+    }
+    let mut buffer = vec![0u16; needed as usize];
+    let len = unsafe { convert(wide.as_ptr(), buffer.as_mut_ptr(), needed) };
+    if len == 0 || len >= needed {
+        return None;
+    }
+    Some(plain_path(&String::from_utf16_lossy(
+        &buffer[..len as usize],
+    )))
+}
+
+/// Convert a device path (e.g. `\Device\HarddiskVolume10\foo`) to a drive path if possible.
+fn device_path_to_drive(path: &str) -> Option<String> {
+    let lower = path.to_ascii_lowercase();
+    let mut cleaned: &str = &lower;
+    if let Some(rest) = cleaned.strip_prefix("\\??\\") {
+        cleaned = rest;
+    }
+    if let Some(rest) = cleaned.strip_prefix("\\globalroot\\") {
+        cleaned = rest;
+    }
+    if !cleaned.starts_with("\\device\\") {
+        return None;
+    }
+
+    for letter in b'A'..=b'Z' {
+        let drive = format!("{}:", letter as char);
+        if let Some(prefix) = query_dos_device(&drive) {
+            let prefix_lower = prefix.to_ascii_lowercase();
+            if cleaned.starts_with(&prefix_lower) && cleaned.len() >= prefix_lower.len() {
+                let rest = cleaned[prefix_lower.len()..].trim_start_matches(['\\', '/']);
+                return if rest.is_empty() {
+                    Some(format!("{drive}\\"))
+                } else {
+                    Some(format!(r"{drive}\{rest}"))
+                };
+            }
+        }
+    }
+    None
+}
+
+#[cfg(windows)]
+fn query_dos_device(drive: &str) -> Option<String> {
+    let wide: Vec<u16> = OsStr::new(drive).encode_wide().chain(once(0)).collect();
+    let mut buffer = vec![0u16; 512];
+    let len = unsafe { QueryDosDeviceW(wide.as_ptr(), buffer.as_mut_ptr(), buffer.len() as u32) };
+    if len == 0 {
+        return None;
+    }
+    let slice = &buffer[..len as usize];
+    let end = slice.iter().position(|&c| c == 0).unwrap_or(slice.len());
+    Some(String::from_utf16_lossy(&slice[..end]))
+}
+
+/// Other hosts have no DOS devices, so device paths never map to a drive.
+#[cfg(not(windows))]
+fn query_dos_device(_drive: &str) -> Option<String> {
+    None
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    /// Folder under the temp dir whose path is well past MAX_PATH.
+    fn deep_dir(name: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir().join(format!("paths-{name}-{}", std::process::id()));
+        while path_len(&dir.to_string_lossy()) <= MAX_PATH + 40 {
+            dir.push("a folder name long enough to need 8.3");
+        }
+        dir
+    }
+
+    #[test]
+    fn short_paths_are_passed_unchanged() {
+        let path = Path::new(r"C:\工作区\disks\base.vhdx");
+        assert_eq!(tool_path(path).unwrap(), path);
+        assert_eq!(
+            tool_path(Path::new(r"\\?\C:\ws\base.vhdx")).unwrap(),
+            Path::new(r"C:\ws\base.vhdx")
+        );
+    }
+
+    #[test]
+    fn path_length_counts_utf16_units() {
+        assert_eq!(path_len(r"C:\a"), 4);
+        assert_eq!(path_len("C:\\工"), 4);
+        assert_eq!(path_len("C:\\\u{1D11E}"), 5);
+    }
+
+    #[test]
+    fn long_path_without_short_form_is_refused() {
+        let missing = deep_dir("missing").join("base.vhdx");
+        let missing = PathBuf::from(format!(
+            r"Z:\{}",
+            missing
+                .strip_prefix(std::env::temp_dir())
+                .unwrap()
+                .display()
+        ));
+        assert!(path_len(&missing.to_string_lossy()) > MAX_PATH);
+        let err = tool_path(&missing).unwrap_err().to_string();
+        assert!(err.contains("short (8.3) form"), "{err}");
+    }
+
+    #[test]
+    fn long_path_is_shortened_to_the_same_file() {
+        let dir = deep_dir("long");
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("base.vhdx");
+        fs::write(&file, b"").unwrap();
+        let result = tool_path(&file);
+        let created = tool_path(&dir.join("new child.vhdx"));
+        let root = std::env::temp_dir().join(format!("paths-long-{}", std::process::id()));
+        fs::remove_dir_all(root).unwrap();
+        // Volumes with 8.3 names disabled have no short form; the error is covered above.
+        let (Ok(short), Ok(created)) = (result, created) else {
+            return;
+        };
+        assert!(path_len(&short.to_string_lossy()) < MAX_PATH);
+        assert_eq!(
+            normalize_path(&short.to_string_lossy()),
+            normalize_path(&file.to_string_lossy())
+        );
+        assert!(path_len(&created.to_string_lossy()) < MAX_PATH);
+        assert!(created.to_string_lossy().ends_with(r"\new child.vhdx"));
+    }
+
+    #[test]
+    fn long_paths_normalize_without_their_verbatim_prefix() {
+        let long = deep_dir("normalize").join("Base.vhdx");
+        let plain = long.to_string_lossy();
+        let verbatim = extended_path(&long);
+        assert!(verbatim.to_string_lossy().starts_with(r"\\?\"));
+        assert_eq!(
+            normalize_path(&verbatim.to_string_lossy()),
+            plain.to_ascii_lowercase()
+        );
+        assert_eq!(
+            extended_path(Path::new(r"\\server\share\base.vhdx")),
+            Path::new(r"\\?\UNC\server\share\base.vhdx")
+        );
+    }
+}
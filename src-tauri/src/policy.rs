@@ -0,0 +1,132 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tauri::ipc::Invoke;
+use tauri::Runtime;
+use uuid::Uuid;
+
+use crate::error::{AppError, Result};
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Set by entering the PIN; lasts until locked again or the app exits.
+static UNLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Commands that only read the workspace (or the machine) and stay available in read-only mode,
+/// next to the ones needed to unlock it. Everything else is refused, so new commands are denied
+/// until they are listed here.
+const READ_ONLY_COMMANDS: &[&str] = &[
+    "check_admin",
+    "get_settings",
+    "detect_tools",
+    "check_workspace_volume",
+    "list_nodes",
+    "query_nodes",
+    "list_views",
+    "apply_view",
+    "nodes_digest",
+    "get_workspace_stats",
+    "estimate_operation",
+    "list_wim_images",
+    "list_wim_sources",
+    // These attach the layer read-only, so they never write to layer disks.
+    "get_layer_drivers",
+    "get_layer_packages",
+    "get_layer_features",
+    "detect_bitlocker",
+    "list_recent_workspaces",
+    "list_library",
+    "list_templates",
+    "get_backup_status",
+    "list_recipes",
+    "list_node_scripts",
+    "get_node_details",
+    "get_node_ops",
+    "get_attach_history",
+    "validate_boot_chain",
+    "get_compaction_advice",
+    "find_duplicate_bases",
+    "health_check",
+    "list_firmware_entries",
+    "get_secure_boot_state",
+    "get_entry_flags",
+    "export_audit",
+    "export_tree_graph",
+    "export_metadata",
+    "reveal_log_dir",
+    "open_ops_log",
+    "open_failed_script",
+    "list_jobs",
+    "get_job_history",
+    "get_read_only_status",
+    "unlock_read_only",
+    "lock_read_only",
+];
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ReadOnlyStatus {
+    pub read_only: bool,
+    pub unlocked: bool,
+}
+
+/// Apply the workspace's read-only switch. Any unlock is forgotten, so opening another
+/// workspace or turning the mode on again needs the PIN anew.
+pub fn configure(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::SeqCst);
+    UNLOCKED.store(false, Ordering::SeqCst);
+}
+
+pub fn set_unlocked(unlocked: bool) {
+    UNLOCKED.store(unlocked, Ordering::SeqCst);
+}
+
+pub fn status() -> ReadOnlyStatus {
+    ReadOnlyStatus {
+        read_only: READ_ONLY.load(Ordering::SeqCst),
+        unlocked: UNLOCKED.load(Ordering::SeqCst),
+    }
+}
+
+/// Refuse `command` while the workspace is read-only and locked, unless it only reads.
+pub fn check(command: &str) -> Result<()> {
+    let status = status();
+    if !status.read_only || status.unlocked || READ_ONLY_COMMANDS.contains(&command) {
+        return Ok(());
+    }
+    Err(AppError::PolicyDenied(format!(
+        "{command} is not available while the workspace is read-only"
+    )))
+}
+
+/// Wrap the command handler so every invoke passes `check` first.
+pub fn guard<R: Runtime>(
+    handler: impl Fn(Invoke<R>) -> bool + Send + Sync + 'static,
+) -> impl Fn(Invoke<R>) -> bool + Send + Sync + 'static {
+    move |invoke: Invoke<R>| {
+        if let Err(err) = check(invoke.message.command()) {
+            invoke.resolver.reject(err.to_string());
+            return true;
+        }
+        handler(invoke)
+    }
+}
+
+/// Salted hash of an unlock PIN as stored in the settings, `salt$sha256`.
+pub fn hash_pin(pin: &str) -> String {
+    let salt = Uuid::new_v4().simple().to_string();
+    format!("{salt}${}", pin_digest(&salt, pin))
+}
+
+pub fn verify_pin(stored: &str, pin: &str) -> bool {
+    stored
+        .split_once('$')
+        .is_some_and(|(salt, digest)| pin_digest(salt, pin) == digest)
+}
+
+fn pin_digest(salt: &str, pin: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(pin.as_bytes());
+    hex::encode(hasher.finalize())
+}
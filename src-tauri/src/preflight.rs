@@ -0,0 +1,431 @@
+#[cfg(windows)]
+use std::ffi::OsStr;
+use std::fs;
+#[cfg(windows)]
+use std::iter::once;
+#[cfg(windows)]
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+
+#[cfg(windows)]
+use tracing::warn;
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+#[cfg(windows)]
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileW, FindClose, FindFirstFileW, GetDriveTypeW, GetFileAttributesW,
+    GetVolumeInformationW, GetVolumePathNameW, FILE_ATTRIBUTE_COMPRESSED,
+    FILE_ATTRIBUTE_REPARSE_POINT, FILE_READ_ATTRIBUTES, FILE_SHARE_READ, FILE_SHARE_WRITE,
+    INVALID_FILE_ATTRIBUTES, OPEN_EXISTING, WIN32_FIND_DATAW,
+};
+#[cfg(windows)]
+use windows_sys::Win32::System::IO::DeviceIoControl;
+
+#[cfg(windows)]
+use crate::bitlocker;
+use crate::error::{AppError, Result, RootPathErrorKind};
+use crate::models::{IssueSeverity, VolumeIssue};
+use crate::paths::{is_within, AppPaths};
+#[cfg(windows)]
+use crate::storage;
+
+/// `GetDriveTypeW` results (winbase.h).
+#[cfg(windows)]
+const DRIVE_REMOVABLE: u32 = 2;
+#[cfg(windows)]
+const DRIVE_REMOTE: u32 = 4;
+#[cfg(windows)]
+const DRIVE_CDROM: u32 = 5;
+#[cfg(windows)]
+const DRIVE_RAMDISK: u32 = 6;
+
+/// `FILE_VOLUME_IS_COMPRESSED` file system flag (winnt.h).
+#[cfg(windows)]
+const FILE_VOLUME_IS_COMPRESSED: u32 = 0x0000_8000;
+
+/// `FSCTL_QUERY_PERSISTENT_VOLUME_STATE` and the Dev Drive state bit (winioctl.h).
+#[cfg(windows)]
+const FSCTL_QUERY_PERSISTENT_VOLUME_STATE: u32 = 0x0009_023C;
+#[cfg(windows)]
+const PERSISTENT_VOLUME_STATE_DEV_VOLUME: u32 = 0x0000_2000;
+
+/// Cloud files attributes (winnt.h): `RECALL_ON_OPEN`, `PINNED`, `UNPINNED` and
+/// `RECALL_ON_DATA_ACCESS`.
+#[cfg(windows)]
+const CLOUD_FILE_ATTRIBUTES: u32 = 0x0004_0000 | 0x0008_0000 | 0x0010_0000 | 0x0040_0000;
+
+/// `IO_REPARSE_TAG_CLOUD` and the mask covering its `_1` .. `_F` variants (winnt.h).
+#[cfg(windows)]
+const IO_REPARSE_TAG_CLOUD: u32 = 0x9000_001A;
+#[cfg(windows)]
+const IO_REPARSE_TAG_CLOUD_MASK: u32 = 0xFFFF_0FFF;
+
+/// Boot error reported by the boot manager for VHDXs it cannot reach on such volumes.
+#[cfg(windows)]
+const BOOT_ERROR_HINT: &str = "boot entries on it often fail with error 0xc03a000f";
+
+/// Whether the app runs elevated. Simulated tools need no rights, so this is always true
+/// under the `simulate` feature.
+pub fn is_admin() -> bool {
+    #[cfg(not(feature = "simulate"))]
+    {
+        is_elevated::is_elevated()
+    }
+    #[cfg(feature = "simulate")]
+    {
+        true
+    }
+}
+
+/// Create and remove a probe file in `dir`, creating the folder first, to prove it is writable
+/// before a long operation depends on it.
+pub fn probe_writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write-probe-{}", std::process::id()));
+    fs::write(&probe, b"probe")?;
+    fs::remove_file(&probe)
+}
+
+/// Refuse workspace roots whose layers would fail to boot later without a useful error: UNC
+/// paths, cloud-synced folders and folders inside Windows.
+pub fn validate_root(root: &Path) -> Result<()> {
+    let text = root.to_string_lossy();
+    let local = text.strip_prefix(r"\\?\").unwrap_or(&text);
+    if local.starts_with(r"\\") || local.to_ascii_uppercase().starts_with("UNC\\") {
+        return Err(invalid_root(
+            RootPathErrorKind::UncPath,
+            format!(
+                "{text} is a network path; native boot needs the VHDXs on a local NTFS drive, e.g. D:\\LayeredSystem"
+            ),
+        ));
+    }
+
+    let windows = std::env::var("SystemRoot").unwrap_or_else(|_| "C:\\Windows".into());
+    if is_within(root, Path::new(&windows)) {
+        return Err(invalid_root(
+            RootPathErrorKind::SystemFolder,
+            format!(
+                "{text} is inside {windows}, which Windows servicing rewrites; use a folder of its own, e.g. C:\\LayeredSystem"
+            ),
+        ));
+    }
+
+    let synced_root = ["OneDrive", "OneDriveConsumer", "OneDriveCommercial"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|dir| !dir.trim().is_empty() && is_within(root, Path::new(dir)));
+    if synced_root.is_some() || root.ancestors().any(is_cloud_placeholder) {
+        return Err(invalid_root(
+            RootPathErrorKind::CloudSynced,
+            format!(
+                "{text} is in a cloud-synced folder whose files can be dehydrated or locked by the sync client; use a folder outside OneDrive, e.g. D:\\LayeredSystem"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check whether the volume holding the workspace root can host VHDXs for native boot.
+///
+/// Native boot fails without a clear error for VHDXs on anything but a local, uncompressed,
+/// unencrypted NTFS volume, so the problems are reported up front instead.
+pub fn check_workspace_volume(root: &Path) -> Vec<VolumeIssue> {
+    let mut issues = Vec::new();
+    if let Err(AppError::InvalidRoot { kind, message }) = validate_root(root) {
+        issues.push(issue(IssueSeverity::Error, kind.as_str(), message));
+    }
+    issues.extend(volume_issues(root));
+    issues
+}
+
+/// Problems of the volume holding `root`: its type, file system, compression, encryption and
+/// disk layout.
+#[cfg(windows)]
+fn volume_issues(root: &Path) -> Vec<VolumeIssue> {
+    let mut issues = Vec::new();
+    let Some(volume) = volume_root(root) else {
+        issues.push(issue(
+            IssueSeverity::Error,
+            "volume_unknown",
+            format!("cannot determine the volume of {}", root.display()),
+        ));
+        return issues;
+    };
+
+    match unsafe { GetDriveTypeW(wide(&volume).as_ptr()) } {
+        DRIVE_REMOTE => issues.push(issue(
+            IssueSeverity::Error,
+            "network_volume",
+            format!("{volume} is a network share; native boot needs a local volume"),
+        )),
+        DRIVE_REMOVABLE | DRIVE_CDROM | DRIVE_RAMDISK => issues.push(issue(
+            IssueSeverity::Error,
+            "removable_volume",
+            format!("{volume} is a removable drive; native boot needs a fixed disk"),
+        )),
+        _ => {}
+    }
+
+    match volume_information(&volume) {
+        Some((fs, flags)) => {
+            if !fs.eq_ignore_ascii_case("NTFS") {
+                issues.push(issue(
+                    IssueSeverity::Error,
+                    "not_ntfs",
+                    format!("{volume} is formatted as {fs}; native boot requires NTFS"),
+                ));
+            }
+            if flags & FILE_VOLUME_IS_COMPRESSED != 0 {
+                issues.push(issue(
+                    IssueSeverity::Error,
+                    "compressed_volume",
+                    format!("{volume} is compressed; VHDXs used for native boot must not be"),
+                ));
+            }
+        }
+        None => issues.push(issue(
+            IssueSeverity::Warning,
+            "volume_info_unavailable",
+            format!("cannot read the file system of {volume}"),
+        )),
+    }
+
+    let attrs = unsafe { GetFileAttributesW(wide(&root.to_string_lossy()).as_ptr()) };
+    if attrs != INVALID_FILE_ATTRIBUTES && attrs & FILE_ATTRIBUTE_COMPRESSED != 0 {
+        issues.push(issue(
+            IssueSeverity::Error,
+            "compressed_folder",
+            format!(
+                "{} has NTFS compression enabled; new VHDXs would inherit it",
+                root.display()
+            ),
+        ));
+    }
+
+    let mount_point = volume.trim_end_matches('\\');
+    match bitlocker::volume_status(mount_point) {
+        Ok(Some(status)) if status.lock_status.eq_ignore_ascii_case("Locked") => {
+            issues.push(issue(
+                IssueSeverity::Error,
+                "bitlocker_locked",
+                format!("{mount_point} is BitLocker-locked"),
+            ))
+        }
+        Ok(Some(status)) if status.is_encrypted() => issues.push(issue(
+            IssueSeverity::Warning,
+            "bitlocker_encrypted",
+            format!(
+                "{mount_point} is BitLocker-encrypted; Windows does not support native boot from VHDXs on an encrypted host volume"
+            ),
+        )),
+        Ok(_) => {}
+        Err(err) => warn!("bitlocker status failed volume={mount_point} err={err}"),
+    }
+
+    if let Some(letter) = drive_letter(&volume) {
+        issues.extend(check_disk_layout(letter));
+    }
+    if is_dev_drive(&volume) {
+        issues.push(issue(
+            IssueSeverity::Warning,
+            "dev_drive",
+            format!("{volume} is a Dev Drive; {BOOT_ERROR_HINT}"),
+        ));
+    }
+
+    issues
+}
+
+/// Simulated workspaces live on whatever the host has; there is no boot volume to check.
+#[cfg(not(windows))]
+fn volume_issues(_root: &Path) -> Vec<VolumeIssue> {
+    Vec::new()
+}
+
+/// Check the workspace volume and, when base or diff layers are kept on other volumes, those
+/// volumes too.
+pub fn check_layer_volumes(paths: &AppPaths) -> Vec<VolumeIssue> {
+    let mut issues = check_workspace_volume(paths.root());
+    let mut checked = vec![volume_root(paths.root())];
+    for dir in [paths.base_dir(), paths.diff_dir()] {
+        let volume = volume_root(&dir);
+        if !checked.contains(&volume) {
+            issues.extend(check_workspace_volume(&dir));
+            checked.push(volume);
+        }
+    }
+    issues
+}
+
+/// Flag volumes on dynamic disks or Storage Spaces, which the boot manager cannot open VHDXs
+/// from reliably.
+#[cfg(windows)]
+fn check_disk_layout(letter: char) -> Vec<VolumeIssue> {
+    let layout = match storage::disk_for_letter(letter) {
+        Ok(Some(layout)) => layout,
+        Ok(None) => return Vec::new(),
+        Err(err) => {
+            warn!("disk layout query failed letter={letter} err={err}");
+            return Vec::new();
+        }
+    };
+
+    let mut issues = Vec::new();
+    if !layout.has_partition || layout.dynamic {
+        issues.push(issue(
+            IssueSeverity::Warning,
+            "dynamic_disk",
+            format!("{letter}: is on a dynamic disk; {BOOT_ERROR_HINT}"),
+        ));
+    }
+    if layout.bus_type.eq_ignore_ascii_case("Spaces") {
+        issues.push(issue(
+            IssueSeverity::Warning,
+            "storage_spaces",
+            format!("{letter}: is a Storage Spaces volume; {BOOT_ERROR_HINT}"),
+        ));
+    }
+    issues
+}
+
+/// Whether `path` exists and is managed by a cloud files provider: a cloud reparse point or a
+/// file with pinned/recall-on-access attributes.
+#[cfg(windows)]
+fn is_cloud_placeholder(path: &Path) -> bool {
+    let attrs = unsafe { GetFileAttributesW(wide(&path.to_string_lossy()).as_ptr()) };
+    if attrs == INVALID_FILE_ATTRIBUTES {
+        return false;
+    }
+    if attrs & CLOUD_FILE_ATTRIBUTES != 0 {
+        return true;
+    }
+    if attrs & FILE_ATTRIBUTE_REPARSE_POINT == 0 {
+        return false;
+    }
+    let mut data: WIN32_FIND_DATAW = unsafe { std::mem::zeroed() };
+    let handle = unsafe { FindFirstFileW(wide(&path.to_string_lossy()).as_ptr(), &mut data) };
+    if handle == INVALID_HANDLE_VALUE {
+        return false;
+    }
+    unsafe { FindClose(handle) };
+    // dwReserved0 holds the reparse tag; cloud tags differ only in the provider nibble.
+    data.dwReserved0 & IO_REPARSE_TAG_CLOUD_MASK == IO_REPARSE_TAG_CLOUD
+}
+
+/// Whether the volume is marked as a Dev Drive (trusted developer volume).
+#[cfg(windows)]
+fn is_dev_drive(volume: &str) -> bool {
+    let device = format!(r"\\.\{}", volume.trim_end_matches('\\'));
+    let handle = unsafe {
+        CreateFileW(
+            wide(&device).as_ptr(),
+            FILE_READ_ATTRIBUTES,
+            FILE_SHARE_READ | FILE_SHARE_WRITE,
+            std::ptr::null(),
+            OPEN_EXISTING,
+            0,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return false;
+    }
+    // FILE_FS_PERSISTENT_VOLUME_INFORMATION: VolumeFlags, FlagMask, Version, Reserved.
+    let input: [u32; 4] = [0, PERSISTENT_VOLUME_STATE_DEV_VOLUME, 1, 0];
+    let mut output: [u32; 4] = [0; 4];
+    let mut returned = 0u32;
+    let ok = unsafe {
+        DeviceIoControl(
+            handle,
+            FSCTL_QUERY_PERSISTENT_VOLUME_STATE,
+            input.as_ptr().cast(),
+            std::mem::size_of_val(&input) as u32,
+            output.as_mut_ptr().cast(),
+            std::mem::size_of_val(&output) as u32,
+            &mut returned,
+            std::ptr::null_mut(),
+        )
+    };
+    unsafe { CloseHandle(handle) };
+    ok != 0 && output[0] & PERSISTENT_VOLUME_STATE_DEV_VOLUME != 0
+}
+
+/// Drive letter of a volume root such as `D:\`; `None` for folder mount points.
+#[cfg(windows)]
+fn drive_letter(volume: &str) -> Option<char> {
+    let mut chars = volume.chars();
+    let letter = chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    (chars.next() == Some(':')).then_some(letter.to_ascii_uppercase())
+}
+
+/// Other hosts have no cloud files provider.
+#[cfg(not(windows))]
+fn is_cloud_placeholder(_path: &Path) -> bool {
+    false
+}
+
+/// Other hosts are treated as a single volume.
+#[cfg(not(windows))]
+fn volume_root(_path: &Path) -> Option<String> {
+    None
+}
+
+fn invalid_root(kind: RootPathErrorKind, message: String) -> AppError {
+    AppError::InvalidRoot { kind, message }
+}
+
+fn issue(severity: IssueSeverity, code: &str, message: String) -> VolumeIssue {
+    VolumeIssue {
+        severity,
+        code: code.to_string(),
+        message,
+    }
+}
+
+/// Root of the volume containing `path`, e.g. `D:\`.
+#[cfg(windows)]
+fn volume_root(path: &Path) -> Option<String> {
+    let mut buffer = vec![0u16; 512];
+    let ok = unsafe {
+        GetVolumePathNameW(
+            wide(&path.to_string_lossy()).as_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+        )
+    };
+    if ok == 0 {
+        return None;
+    }
+    Some(from_wide(&buffer))
+}
+
+/// File system name and flags of a volume root.
+#[cfg(windows)]
+fn volume_information(volume: &str) -> Option<(String, u32)> {
+    let mut fs_name = vec![0u16; 64];
+    let mut flags = 0u32;
+    let ok = unsafe {
+        GetVolumeInformationW(
+            wide(volume).as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut flags,
+            fs_name.as_mut_ptr(),
+            fs_name.len() as u32,
+        )
+    };
+    (ok != 0).then(|| (from_wide(&fs_name), flags))
+}
+
+#[cfg(windows)]
+fn wide(value: &str) -> Vec<u16> {
+    OsStr::new(value).encode_wide().chain(once(0)).collect()
+}
+
+#[cfg(windows)]
+fn from_wide(buffer: &[u16]) -> String {
+    let end = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    String::from_utf16_lossy(&buffer[..end])
+}
@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 use tauri::{AppHandle, Manager};
 
 use crate::error::{AppError, Result};
+use crate::paths::plain_path;
 
 const MAX_RECENT: usize = 10;
 
@@ -141,9 +142,7 @@ fn recents_path(app: &AppHandle) -> Result<PathBuf> {
 }
 
 fn normalize_path(path: &PathBuf) -> String {
-    path.to_string_lossy()
-        .trim()
-        .trim_start_matches("\\\\?\\")
+    plain_path(path.to_string_lossy().trim())
         .replace('/', "\\")
         .to_ascii_lowercase()
 }
@@ -0,0 +1,194 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use once_cell::sync::Lazy;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Workspace root of the open workspace, replaced by `<workspace>`.
+static WORKSPACE: RwLock<Option<String>> = RwLock::new(None);
+
+/// Placeholder number of every volume GUID seen so far, so the same volume keeps its name
+/// across lines and files.
+static VOLUMES: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Turn redaction of logs, transcripts and reports on or off.
+pub fn configure(enabled: bool, workspace_root: Option<&str>) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+    if let Ok(mut workspace) = WORKSPACE.write() {
+        *workspace = workspace_root
+            .map(|root| root.trim_end_matches('\\').to_string())
+            .filter(|root| !root.is_empty());
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Replace user paths, the user, machine and domain names, volume GUIDs and volume serial
+/// numbers in `text` with stable placeholders when redaction is on.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    if !is_enabled() {
+        return Cow::Borrowed(text);
+    }
+    let mut out = text.to_string();
+    if let Some(root) = WORKSPACE.read().ok().and_then(|root| root.clone()) {
+        out = replace_ignore_case(&out, &root, "<workspace>");
+    }
+    if let Ok(profile) = std::env::var("USERPROFILE") {
+        out = replace_ignore_case(&out, profile.trim_end_matches('\\'), "<profile>");
+    }
+    out = redact_user_dirs(&out);
+    for (var, placeholder) in [
+        ("USERNAME", "<user>"),
+        ("COMPUTERNAME", "<host>"),
+        ("USERDOMAIN", "<domain>"),
+    ] {
+        // Very short names would turn ordinary words into placeholders.
+        if let Ok(value) = std::env::var(var).map(|v| v.trim().to_string()) {
+            if value.len() >= 3 {
+                out = replace_ignore_case(&out, &value, placeholder);
+            }
+        }
+    }
+    out = redact_volume_guids(&out);
+    Cow::Owned(redact_serials(&out))
+}
+
+/// `io::Write` adapter redacting everything written through it; the log file writer is wrapped
+/// in it, which works because the formatter writes each event in one piece.
+pub struct RedactingWriter<W> {
+    inner: W,
+}
+
+impl<W> RedactingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !is_enabled() {
+            return self.inner.write(buf);
+        }
+        let text = String::from_utf8_lossy(buf);
+        self.inner.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn replace_ignore_case(text: &str, needle: &str, replacement: &str) -> String {
+    if needle.is_empty() {
+        return text.to_string();
+    }
+    let lower = text.to_lowercase();
+    let needle_lower = needle.to_lowercase();
+    // Lowercasing can change byte lengths outside ASCII; fall back to an exact match then.
+    if lower.len() != text.len() || needle_lower.len() != needle.len() {
+        return text.replace(needle, replacement);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (at, _) in lower.match_indices(&needle_lower) {
+        out.push_str(&text[last..at]);
+        out.push_str(replacement);
+        last = at + needle.len();
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+/// `\Users\<name>\` of any profile, not only the current user's.
+fn redact_user_dirs(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (at, marker) in lower.match_indices("\\users\\") {
+        let name_start = at + marker.len();
+        if name_start < last {
+            continue;
+        }
+        let name_len = text[name_start..]
+            .find(['\\', '/', '"', '\'', ' ', '\r', '\n'])
+            .unwrap_or(text.len() - name_start);
+        let name = &lower[name_start..name_start + name_len];
+        if name_len == 0 || matches!(name, "public" | "default" | "all users") {
+            continue;
+        }
+        out.push_str(&text[last..name_start]);
+        out.push_str("<user>");
+        last = name_start + name_len;
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+/// `Volume{xxxxxxxx-...}` becomes `Volume{<volume-N>}`.
+fn redact_volume_guids(text: &str) -> String {
+    const MARKER: &str = "Volume{";
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find(MARKER) {
+        let guid_start = at + MARKER.len();
+        let Some(len) = rest[guid_start..].find('}') else {
+            break;
+        };
+        let guid = rest[guid_start..guid_start + len].to_ascii_lowercase();
+        out.push_str(&rest[..guid_start]);
+        if len == 36 {
+            let number = VOLUMES
+                .lock()
+                .map(|mut volumes| {
+                    let next = volumes.len() + 1;
+                    *volumes.entry(guid).or_insert(next)
+                })
+                .unwrap_or(0);
+            out.push_str(&format!("<volume-{number}>"));
+        } else {
+            out.push_str(&rest[guid_start..guid_start + len]);
+        }
+        rest = &rest[guid_start + len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Volume serial numbers such as `A1B2-C3D4`. Groups that are part of a longer dashed token
+/// (GUIDs, dates) are left alone.
+fn redact_serials(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let boundary = |i: Option<usize>| {
+        i.and_then(|i| bytes.get(i))
+            .is_none_or(|b| !b.is_ascii_alphanumeric() && *b != b'-')
+    };
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    let mut i = 0;
+    while i + 9 <= bytes.len() {
+        let candidate = &bytes[i..i + 9];
+        let is_serial = candidate[4] == b'-'
+            && candidate[..4].iter().all(u8::is_ascii_hexdigit)
+            && candidate[5..].iter().all(u8::is_ascii_hexdigit)
+            && boundary(i.checked_sub(1))
+            && boundary(Some(i + 9));
+        if is_serial {
+            out.push_str(&text[last..i]);
+            out.push_str("<serial>");
+            i += 9;
+            last = i;
+        } else {
+            i += 1;
+        }
+    }
+    out.push_str(&text[last..]);
+    out
+}
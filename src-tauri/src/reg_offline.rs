@@ -0,0 +1,473 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::{AppError, Result};
+use crate::models::{OsInfo, RegistryPreset, RegistryTweak};
+use crate::sys::{run_elevated_command, run_elevated_command_with_secrets, CommandOutput, Secrets};
+
+/// Hive key under HKLM used while a layer's SOFTWARE hive is loaded.
+const SOFTWARE_MOUNT_KEY: &str = "LS_OFFLINE_SOFTWARE";
+
+/// Hives that tweaks may target.
+const TWEAKABLE_HIVES: [&str; 3] = ["SYSTEM", "SOFTWARE", "DEFAULT"];
+
+/// SOFTWARE key holding the automatic logon values.
+const WINLOGON: &str = "Microsoft\\Windows NT\\CurrentVersion\\Winlogon";
+
+/// SYSTEM key holding the pagefile configuration (`PagingFiles`).
+const MEMORY_MANAGEMENT: &str = "CurrentControlSet\\Control\\Session Manager\\Memory Management";
+
+const VALUE_TYPES: [&str; 6] = [
+    "REG_SZ",
+    "REG_EXPAND_SZ",
+    "REG_MULTI_SZ",
+    "REG_DWORD",
+    "REG_QWORD",
+    "REG_BINARY",
+];
+
+/// Path of a hive file (`SOFTWARE`, `SYSTEM`, ...) inside a mounted Windows volume.
+pub fn hive_path(system_root: &Path, hive: &str) -> PathBuf {
+    system_root
+        .join("Windows")
+        .join("System32")
+        .join("config")
+        .join(hive)
+}
+
+/// Load an offline hive file under `HKLM\<mount_key>`.
+pub fn load_hive(mount_key: &str, hive_file: &Path) -> Result<CommandOutput> {
+    let key = format!("HKLM\\{mount_key}");
+    run_elevated_command(
+        "reg",
+        &["load", &key, hive_file.to_string_lossy().as_ref()],
+        None,
+    )
+}
+
+/// Unload a hive previously loaded with [`load_hive`].
+pub fn unload_hive(mount_key: &str) -> Result<CommandOutput> {
+    let key = format!("HKLM\\{mount_key}");
+    run_elevated_command("reg", &["unload", &key], None)
+}
+
+/// Set a value below a loaded hive; `key` is relative to the hive root.
+pub fn set_value(
+    mount_key: &str,
+    key: &str,
+    name: &str,
+    kind: &str,
+    data: &str,
+) -> Result<CommandOutput> {
+    reg_add(mount_key, key, name, kind, data, Secrets::NONE)
+}
+
+/// [`set_value`] for data that must stay out of the log, transcripts and job progress, such
+/// as a password.
+pub fn set_secret_value(
+    mount_key: &str,
+    key: &str,
+    name: &str,
+    kind: &str,
+    data: &str,
+) -> Result<CommandOutput> {
+    let secrets = Secrets {
+        values: &[data],
+        output: false,
+    };
+    reg_add(mount_key, key, name, kind, data, secrets)
+}
+
+fn reg_add(
+    mount_key: &str,
+    key: &str,
+    name: &str,
+    kind: &str,
+    data: &str,
+    secrets: Secrets<'_>,
+) -> Result<CommandOutput> {
+    let full_key = format!("HKLM\\{mount_key}\\{key}");
+    run_elevated_command_with_secrets(
+        "reg",
+        &["add", &full_key, "/v", name, "/t", kind, "/d", data, "/f"],
+        None,
+        secrets,
+    )
+}
+
+/// Read a single value below a loaded hive; `None` when the value does not exist.
+pub fn query_value(mount_key: &str, key: &str, name: &str) -> Result<Option<String>> {
+    let full_key = format!("HKLM\\{mount_key}\\{key}");
+    let res = run_elevated_command("reg", &["query", &full_key, "/v", name], None)?;
+    if !res.succeeded() {
+        return Ok(None);
+    }
+    Ok(parse_query_value(&res.stdout, name))
+}
+
+/// Load `hive` from the offline system, run `f` against its mount key and always unload it.
+pub fn with_hive<T>(
+    system_root: &Path,
+    hive: &str,
+    mount_key: &str,
+    f: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    let hive_file = hive_path(system_root, hive);
+    if !hive_file.exists() {
+        return Err(AppError::Message(format!(
+            "registry hive not found: {}",
+            hive_file.display()
+        )));
+    }
+    let load = load_hive(mount_key, &hive_file)?;
+    if !load.succeeded() {
+        return Err(AppError::Command {
+            kind: load.classify(),
+            message: format!(
+                "reg load {} failed ({}): {}",
+                hive_file.display(),
+                load.classify(),
+                load.stderr.trim()
+            ),
+            hint: load.hint(),
+        });
+    }
+    let result = f(mount_key);
+    let _ = unload_hive(mount_key);
+    result
+}
+
+/// Register a command under HKLM RunOnce of the offline system so it runs on the next logon.
+pub fn add_run_once(system_root: &Path, name: &str, command: &str) -> Result<()> {
+    with_hive(system_root, "SOFTWARE", SOFTWARE_MOUNT_KEY, |mount_key| {
+        let res = set_value(
+            mount_key,
+            "Microsoft\\Windows\\CurrentVersion\\RunOnce",
+            name,
+            "REG_SZ",
+            command,
+        )?;
+        if !res.succeeded() {
+            return Err(AppError::Command {
+                kind: res.classify(),
+                message: format!(
+                    "reg add RunOnce {name} failed ({}): {}",
+                    res.classify(),
+                    res.stderr.trim()
+                ),
+                hint: res.hint(),
+            });
+        }
+        Ok(())
+    })
+}
+
+impl RegistryPreset {
+    pub fn tweaks(&self) -> Vec<RegistryTweak> {
+        let tweak =
+            |hive: &str, key: &str, name: &str, value_type: &str, data: &str| RegistryTweak {
+                hive: hive.to_string(),
+                key: key.to_string(),
+                name: name.to_string(),
+                value_type: value_type.to_string(),
+                data: data.to_string(),
+            };
+        match self {
+            RegistryPreset::DisableHibernation => vec![
+                tweak(
+                    "SYSTEM",
+                    "CurrentControlSet\\Control\\Power",
+                    "HibernateEnabled",
+                    "REG_DWORD",
+                    "0",
+                ),
+                tweak(
+                    "SYSTEM",
+                    "CurrentControlSet\\Control\\Power",
+                    "HibernateEnabledDefault",
+                    "REG_DWORD",
+                    "0",
+                ),
+            ],
+            RegistryPreset::SetTimezone { timezone } => vec![tweak(
+                "SYSTEM",
+                "CurrentControlSet\\Control\\TimeZoneInformation",
+                "TimeZoneKeyName",
+                "REG_SZ",
+                timezone,
+            )],
+            RegistryPreset::ComputerName { name } => vec![
+                tweak(
+                    "SYSTEM",
+                    "CurrentControlSet\\Control\\ComputerName\\ComputerName",
+                    "ComputerName",
+                    "REG_SZ",
+                    name,
+                ),
+                tweak(
+                    "SYSTEM",
+                    "CurrentControlSet\\Services\\Tcpip\\Parameters",
+                    "Hostname",
+                    "REG_SZ",
+                    name,
+                ),
+                tweak(
+                    "SYSTEM",
+                    "CurrentControlSet\\Services\\Tcpip\\Parameters",
+                    "NV Hostname",
+                    "REG_SZ",
+                    name,
+                ),
+            ],
+            RegistryPreset::DisablePagefile => vec![tweak(
+                "SYSTEM",
+                MEMORY_MANAGEMENT,
+                "PagingFiles",
+                "REG_MULTI_SZ",
+                "",
+            )],
+            // Zero initial and maximum sizes make the pagefile system-managed.
+            RegistryPreset::MovePagefile { drive } => vec![tweak(
+                "SYSTEM",
+                MEMORY_MANAGEMENT,
+                "PagingFiles",
+                "REG_MULTI_SZ",
+                &format!(
+                    "{}\\pagefile.sys 0 0",
+                    drive.trim().trim_end_matches(['\\', '/'])
+                ),
+            )],
+        }
+    }
+}
+
+/// Check a computer name against the NetBIOS rules Windows enforces when renaming: at most
+/// 15 characters of letters, digits and hyphens, not only digits.
+pub fn validate_computer_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 15
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !name.chars().all(|c| c.is_ascii_digit())
+        && !name.starts_with('-');
+    if !valid {
+        return Err(AppError::Message(format!(
+            "invalid computer name: {name} (1-15 letters, digits or hyphens, not only digits)"
+        )));
+    }
+    Ok(())
+}
+
+/// Register `command` as an auto-start service of the offline system so it runs as SYSTEM on
+/// the next boot before anyone logs on. The command is not a real service, so the service
+/// control manager gives up on it after its start timeout; the command has to be short and
+/// should delete the service itself.
+pub fn boot_command_tweaks(service: &str, command: &str) -> Vec<RegistryTweak> {
+    let key = format!("CurrentControlSet\\Services\\{service}");
+    [
+        ("Type", "REG_DWORD", "16"),
+        ("Start", "REG_DWORD", "2"),
+        ("ErrorControl", "REG_DWORD", "0"),
+        ("ImagePath", "REG_EXPAND_SZ", command),
+        ("ObjectName", "REG_SZ", "LocalSystem"),
+    ]
+    .into_iter()
+    .map(|(name, value_type, data)| RegistryTweak {
+        hive: "SYSTEM".to_string(),
+        key: key.clone(),
+        name: name.to_string(),
+        value_type: value_type.to_string(),
+        data: data.to_string(),
+    })
+    .collect()
+}
+
+/// Winlogon values that log `user_name` on automatically, except the password, which is set
+/// with [`set_autologon_password`].
+pub fn autologon_tweaks(user_name: &str) -> Vec<RegistryTweak> {
+    [
+        ("AutoAdminLogon", "1"),
+        ("DefaultUserName", user_name),
+        ("DefaultDomainName", "."),
+    ]
+    .into_iter()
+    .map(|(name, data)| RegistryTweak {
+        hive: "SOFTWARE".to_string(),
+        key: WINLOGON.to_string(),
+        name: name.to_string(),
+        value_type: "REG_SZ".to_string(),
+        data: data.to_string(),
+    })
+    .collect()
+}
+
+/// Store the automatic logon password in the offline system. It ends up in clear text under
+/// Winlogon, as with `AutoAdminLogon` set up by hand, but is passed to `reg add` as a secret so
+/// the log, transcripts and job progress only show `***`.
+pub fn set_autologon_password(system_root: &Path, password: &str) -> Result<()> {
+    with_hive(system_root, "SOFTWARE", SOFTWARE_MOUNT_KEY, |mount_key| {
+        let res = set_secret_value(mount_key, WINLOGON, "DefaultPassword", "REG_SZ", password)?;
+        if !res.succeeded() {
+            return Err(AppError::Command {
+                kind: res.classify(),
+                message: format!(
+                    "reg add Winlogon DefaultPassword failed ({}): {}",
+                    res.classify(),
+                    res.stderr.trim()
+                ),
+                hint: res.hint(),
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Check a local account name against the rules `net user` and `New-LocalUser` enforce.
+pub fn validate_user_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= 20
+        && !name.ends_with('.')
+        && !name
+            .chars()
+            .any(|c| c.is_control() || "\"/\\[]:;|=,+*?<>@'".contains(c));
+    if !valid {
+        return Err(AppError::Message(format!(
+            "invalid user name: {name} (1-20 characters without \"/\\[]:;|=,+*?<>@')"
+        )));
+    }
+    Ok(())
+}
+
+/// Check hive names and value types before any hive is loaded.
+pub fn validate_tweaks(tweaks: &[RegistryTweak]) -> Result<()> {
+    for tweak in tweaks {
+        if !TWEAKABLE_HIVES.contains(&tweak.hive.to_ascii_uppercase().as_str()) {
+            return Err(AppError::Message(format!(
+                "unsupported hive: {} (expected one of {})",
+                tweak.hive,
+                TWEAKABLE_HIVES.join(", ")
+            )));
+        }
+        if !VALUE_TYPES.contains(&tweak.value_type.to_ascii_uppercase().as_str()) {
+            return Err(AppError::Message(format!(
+                "unsupported value type: {}",
+                tweak.value_type
+            )));
+        }
+        if tweak.key.trim().is_empty() {
+            return Err(AppError::Message("registry key must not be empty".into()));
+        }
+    }
+    Ok(())
+}
+
+/// Write `tweaks` into the offline hives, loading each hive once. Returns the number of values set.
+pub fn apply_tweaks(system_root: &Path, tweaks: &[RegistryTweak]) -> Result<usize> {
+    validate_tweaks(tweaks)?;
+    let mut applied = 0;
+    for hive in TWEAKABLE_HIVES {
+        let hive_tweaks: Vec<&RegistryTweak> = tweaks
+            .iter()
+            .filter(|t| t.hive.eq_ignore_ascii_case(hive))
+            .collect();
+        if hive_tweaks.is_empty() {
+            continue;
+        }
+        let mount_key = format!("LS_OFFLINE_{hive}");
+        applied += with_hive(system_root, hive, &mount_key, |mount_key| {
+            let control_set = if hive == "SYSTEM" {
+                Some(current_control_set(mount_key)?)
+            } else {
+                None
+            };
+            for tweak in &hive_tweaks {
+                let key = match &control_set {
+                    Some(set) => resolve_control_set(&tweak.key, set),
+                    None => tweak.key.clone(),
+                };
+                let res = set_value(
+                    mount_key,
+                    &key,
+                    &tweak.name,
+                    &tweak.value_type.to_ascii_uppercase(),
+                    &tweak.data,
+                )?;
+                if !res.succeeded() {
+                    return Err(AppError::Command {
+                        kind: res.classify(),
+                        message: format!(
+                            "reg add {hive}\\{key} {} failed ({}): {}",
+                            tweak.name,
+                            res.classify(),
+                            res.stderr.trim()
+                        ),
+                        hint: res.hint(),
+                    });
+                }
+            }
+            Ok(hive_tweaks.len())
+        })?;
+    }
+    Ok(applied)
+}
+
+/// Offline SYSTEM hives have no `CurrentControlSet`; `Select\Current` names the active one.
+fn current_control_set(mount_key: &str) -> Result<String> {
+    let current = query_value(mount_key, "Select", "Current")?
+        .and_then(|v| parse_reg_number(&v))
+        .unwrap_or(1);
+    Ok(format!("ControlSet{current:03}"))
+}
+
+fn resolve_control_set(key: &str, control_set: &str) -> String {
+    let prefix = "CurrentControlSet";
+    match key.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => {
+            format!("{control_set}{}", &key[prefix.len()..])
+        }
+        _ => key.to_string(),
+    }
+}
+
+/// Read edition, display version and build from the offline SOFTWARE hive.
+pub fn read_os_info(system_root: &Path) -> Result<OsInfo> {
+    with_hive(system_root, "SOFTWARE", SOFTWARE_MOUNT_KEY, |mount_key| {
+        let key = "Microsoft\\Windows NT\\CurrentVersion";
+        let build = query_value(mount_key, key, "CurrentBuild")?;
+        let ubr = query_value(mount_key, key, "UBR")?
+            .and_then(|v| parse_reg_number(&v))
+            .map(|v| v.to_string());
+        let version = match query_value(mount_key, key, "DisplayVersion")? {
+            Some(v) => Some(v),
+            None => query_value(mount_key, key, "ReleaseId")?,
+        };
+        Ok(OsInfo {
+            edition: query_value(mount_key, key, "EditionID")?,
+            version,
+            build: match (build, ubr) {
+                (Some(build), Some(ubr)) => Some(format!("{build}.{ubr}")),
+                (build, _) => build,
+            },
+        })
+    })
+}
+
+/// Parse `reg query` output: `    Name    REG_SZ    Value`.
+fn parse_query_value(output: &str, name: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let trimmed = line.trim();
+        let rest = trimmed.strip_prefix(name)?;
+        let mut cols = rest.trim_start().splitn(2, char::is_whitespace);
+        let kind = cols.next()?;
+        if !kind.starts_with("REG_") {
+            return None;
+        }
+        Some(cols.next().unwrap_or("").trim().to_string())
+    })
+}
+
+/// REG_DWORD values are printed as hex (`0xb2d`).
+fn parse_reg_number(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
@@ -0,0 +1,32 @@
+use once_cell::sync::OnceCell;
+use tracing::warn;
+
+use crate::models::SecureBootState;
+use crate::sys::powershell;
+
+/// Secure Boot cannot change without a reboot, so the first answer is kept.
+static STATE: OnceCell<SecureBootState> = OnceCell::new();
+
+/// Secure Boot state of this machine, from `Confirm-SecureBootUEFI` or, when the cmdlet is
+/// refused, the `UEFISecureBootEnabled` value the firmware state is mirrored to.
+pub fn state() -> SecureBootState {
+    *STATE.get_or_init(|| {
+        let detected = powershell::<SecureBootState>(
+            r"try {
+    if (Confirm-SecureBootUEFI) { 'enabled' } else { 'disabled' }
+} catch [System.PlatformNotSupportedException] {
+    'unsupported'
+} catch {
+    $v = Get-ItemProperty -Path 'HKLM:\SYSTEM\CurrentControlSet\Control\SecureBoot\State' -Name UEFISecureBootEnabled -ErrorAction SilentlyContinue
+    if ($null -eq $v) { 'unknown' } elseif ($v.UEFISecureBootEnabled -eq 1) { 'enabled' } else { 'disabled' }
+}",
+        );
+        match detected {
+            Ok(states) => states.first().copied().unwrap_or(SecureBootState::Unknown),
+            Err(err) => {
+                warn!("secure boot detection failed err={err}");
+                SecureBootState::Unknown
+            }
+        }
+    })
+}
@@ -0,0 +1,543 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::error::Result;
+use crate::paths::normalize_path;
+use crate::sys::{self, CommandOutput, CommandRunner, Secrets};
+use crate::temp::read_script;
+
+/// Well-known identifier `bcdedit /v` prints for `{bootmgr}`.
+const BOOTMGR_GUID: &str = "{9dea862c-5cdd-4e70-acc1-f32b344d4795}";
+
+/// Images every simulated WIM/ESD holds.
+const SIMULATED_IMAGES: &[&str] = &["Windows 11 Home", "Windows 11 Pro"];
+
+static RUNNER: Lazy<Arc<SimulatedRunner>> = Lazy::new(|| Arc::new(SimulatedRunner::default()));
+
+/// Answer every tool call of the process from the in-memory model instead of running it.
+pub fn install() {
+    sys::set_default_runner(RUNNER.clone());
+    info!("simulation backend enabled; no tool touches real disks or boot entries");
+}
+
+/// Drive letter bitmask (bit 0 = `A:`) as `GetLogicalDrives` would report it: `C:` plus every
+/// letter assigned to a simulated volume.
+pub fn logical_drives() -> u32 {
+    let model = RUNNER.model.lock().unwrap();
+    model
+        .disks
+        .values()
+        .flat_map(|disk| disk.letters.iter())
+        .chain(std::iter::once(&'C'))
+        .fold(0, |mask, letter| mask | 1 << (*letter as u8 - b'A'))
+}
+
+/// What `crate::handles` answers on Windows, for hosts without the Restart Manager: simulated
+/// disks are held by nobody, and their volumes dismount at once.
+#[cfg(not(windows))]
+pub mod handles {
+    use std::path::Path;
+
+    use crate::error::Result;
+
+    pub fn describe_holders(_vhd_path: &Path, _letters: &[char]) -> Option<String> {
+        None
+    }
+
+    pub fn dismount_volume(_volume: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Stand-in for diskpart, DISM, bcdedit and the helper tools, backed by a model of virtual disks
+/// and boot entries. VHDX files are created empty so file checks and scans see them; every other
+/// tool succeeds without output.
+#[derive(Default)]
+pub struct SimulatedRunner {
+    model: Mutex<Model>,
+}
+
+#[derive(Default)]
+struct Model {
+    /// Keyed by normalized path.
+    disks: BTreeMap<String, Disk>,
+    entries: Vec<Entry>,
+    boot_sequence: Option<String>,
+}
+
+struct Disk {
+    path: String,
+    parent: Option<String>,
+    size_mb: u64,
+    attached: bool,
+    letters: Vec<char>,
+}
+
+struct Entry {
+    guid: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Entry {
+    fn set(&mut self, key: &str, value: String) {
+        match self
+            .fields
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        {
+            Some((_, v)) => *v = value,
+            None => self.fields.push((key.to_string(), value)),
+        }
+    }
+}
+
+impl CommandRunner for SimulatedRunner {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        _workdir: Option<&Path>,
+        _elevated: bool,
+        _secrets: Secrets<'_>,
+    ) -> Result<CommandOutput> {
+        let mut model = self.model.lock().unwrap();
+        Ok(match tool_name(program).as_str() {
+            "diskpart" => match args.iter().position(|a| a.eq_ignore_ascii_case("/s")) {
+                Some(idx) if idx + 1 < args.len() => {
+                    model.diskpart(&read_script(Path::new(args[idx + 1]))?)
+                }
+                _ => failed("no script given"),
+            },
+            "bcdedit" => model.bcdedit(args),
+            "dism" => dism(args),
+            _ => succeeded(String::new()),
+        })
+    }
+}
+
+impl Model {
+    fn diskpart(&mut self, script: &str) -> CommandOutput {
+        let mut out = vec!["Microsoft DiskPart (simulated)".to_string()];
+        let mut selected: Option<String> = None;
+        for line in script.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let lower = line.to_ascii_lowercase();
+            let noerr = lower.ends_with(" noerr");
+            let res = if lower.starts_with("create vdisk") {
+                self.create_vdisk(line)
+            } else if lower.starts_with("select vdisk") {
+                self.select_vdisk(line).map(|key| {
+                    selected = Some(key);
+                    "DiskPart successfully selected the virtual disk file.".to_string()
+                })
+            } else if let Some(disk) = selected.as_ref().and_then(|key| self.disks.get_mut(key)) {
+                disk_command(disk, &lower)
+            } else {
+                Ok(String::new())
+            };
+            match res {
+                Ok(text) => out.push(text),
+                Err(err) if noerr => out.push(err),
+                Err(err) => return failed(&err),
+            }
+        }
+        if script.to_ascii_lowercase().contains("list volume") {
+            out.push(self.list_volume());
+        }
+        succeeded(out.join("\r\n"))
+    }
+
+    fn create_vdisk(&mut self, line: &str) -> std::result::Result<String, String> {
+        let path = arg(line, "file").ok_or("no file given")?;
+        let key = normalize_path(&path);
+        if self.disks.contains_key(&key) || Path::new(&path).exists() {
+            return Err("The file exists.".into());
+        }
+        let parent = arg(line, "parent");
+        let size_mb = match &parent {
+            Some(parent) => self
+                .disks
+                .get(&normalize_path(parent))
+                .map(|p| p.size_mb)
+                .ok_or("The system cannot find the file specified.")?,
+            None => arg(line, "maximum")
+                .and_then(|m| m.parse().ok())
+                .unwrap_or(0),
+        };
+        if let Some(dir) = Path::new(&path).parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        fs::write(&path, []).map_err(|e| e.to_string())?;
+        self.disks.insert(
+            key,
+            Disk {
+                path,
+                parent,
+                size_mb,
+                attached: false,
+                letters: Vec::new(),
+            },
+        );
+        Ok("DiskPart successfully created the virtual disk file.".into())
+    }
+
+    /// Select a disk of the model, adopting VHDX files created outside of it (e.g. copied in).
+    fn select_vdisk(&mut self, line: &str) -> std::result::Result<String, String> {
+        let path = arg(line, "file").ok_or("no file given")?;
+        let key = normalize_path(&path);
+        if !self.disks.contains_key(&key) {
+            if !Path::new(&path).exists() {
+                return Err("The system cannot find the file specified.".into());
+            }
+            self.disks.insert(
+                key.clone(),
+                Disk {
+                    path,
+                    parent: None,
+                    size_mb: 0,
+                    attached: false,
+                    letters: Vec::new(),
+                },
+            );
+        }
+        Ok(key)
+    }
+
+    fn list_volume(&self) -> String {
+        let mut out = vec![
+            "  Volume ###  Ltr  Label        Fs     Type        Size     Status".to_string(),
+            "  ----------  ---  -----------  -----  ----------  -------  ---------".to_string(),
+        ];
+        let letters = self
+            .disks
+            .values()
+            .filter(|d| d.attached)
+            .flat_map(|d| d.letters.iter());
+        for (idx, letter) in letters.enumerate() {
+            out.push(format!(
+                "  Volume {}     {letter}    System       NTFS   Partition     59 GB  Healthy",
+                idx + 2
+            ));
+        }
+        out.join("\r\n")
+    }
+
+    fn bcdedit(&mut self, args: &[&str]) -> CommandOutput {
+        let args: Vec<&str> = match args.first() {
+            Some(first) if first.eq_ignore_ascii_case("/store") => {
+                args.iter().skip(2).copied().collect()
+            }
+            _ => args.to_vec(),
+        };
+        let Some(verb) = args.first().map(|v| v.to_ascii_lowercase()) else {
+            return succeeded(self.enum_entries(None));
+        };
+        match verb.as_str() {
+            "/create" | "/copy" => {
+                let guid = format!("{{{}}}", Uuid::new_v4());
+                let description = args
+                    .iter()
+                    .position(|a| a.eq_ignore_ascii_case("/d"))
+                    .and_then(|idx| args.get(idx + 1))
+                    .unwrap_or(&"");
+                self.entries.push(Entry {
+                    guid: guid.clone(),
+                    fields: vec![("description".into(), description.to_string())],
+                });
+                succeeded(format!("The entry {guid} was successfully created."))
+            }
+            "/enum" => match args.get(1) {
+                Some(id) if id.starts_with('{') => match self.entry(id) {
+                    Some(_) => succeeded(self.enum_entries(Some(id))),
+                    None => failed("The specified entry identifier is not valid.\r\nThe parameter is incorrect."),
+                },
+                Some(kind) if kind.eq_ignore_ascii_case("firmware") => succeeded(String::new()),
+                _ => succeeded(self.enum_entries(None)),
+            },
+            "/set" => {
+                let (Some(id), Some(key)) = (args.get(1), args.get(2)) else {
+                    return failed("The parameter is incorrect.");
+                };
+                let value = args[3.min(args.len())..].join(" ");
+                match self.entry_mut(id) {
+                    Some(entry) => {
+                        entry.set(key, value);
+                        succeeded("The operation completed successfully.".into())
+                    }
+                    None if id.eq_ignore_ascii_case("{bootmgr}") => {
+                        succeeded("The operation completed successfully.".into())
+                    }
+                    None => failed("The specified entry identifier is not valid.\r\nThe parameter is incorrect."),
+                }
+            }
+            "/delete" => {
+                let before = self.entries.len();
+                if let Some(id) = args.get(1) {
+                    self.entries.retain(|e| !e.guid.eq_ignore_ascii_case(id));
+                }
+                if self.entries.len() == before {
+                    return failed("The specified entry identifier is not valid.\r\nThe parameter is incorrect.");
+                }
+                succeeded("The operation completed successfully.".into())
+            }
+            "/bootsequence" => {
+                self.boot_sequence = args.get(1).map(|g| g.to_string());
+                succeeded("The operation completed successfully.".into())
+            }
+            "/deletevalue" if args.get(2).is_some_and(|v| v.eq_ignore_ascii_case("bootsequence")) => {
+                self.boot_sequence = None;
+                succeeded("The operation completed successfully.".into())
+            }
+            _ => succeeded("The operation completed successfully.".into()),
+        }
+    }
+
+    fn entry(&self, id: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.guid.eq_ignore_ascii_case(id))
+    }
+
+    fn entry_mut(&mut self, id: &str) -> Option<&mut Entry> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.guid.eq_ignore_ascii_case(id))
+    }
+
+    /// `bcdedit /enum /v` output for one entry, or the boot manager and all entries.
+    fn enum_entries(&self, only: Option<&str>) -> String {
+        let mut blocks = Vec::new();
+        if only.is_none() {
+            let mut block = vec![
+                "Windows Boot Manager".to_string(),
+                "--------------------".to_string(),
+                format!("identifier              {BOOTMGR_GUID}"),
+            ];
+            for (idx, entry) in self.entries.iter().enumerate() {
+                let key = if idx == 0 { "displayorder" } else { "" };
+                block.push(format!("{key:<24}{}", entry.guid));
+            }
+            if let Some(guid) = &self.boot_sequence {
+                block.push(format!("bootsequence            {guid}"));
+            }
+            blocks.push(block.join("\r\n"));
+        }
+        for entry in &self.entries {
+            if only.is_some_and(|id| !id.eq_ignore_ascii_case(&entry.guid)) {
+                continue;
+            }
+            let mut block = vec![
+                "Windows Boot Loader".to_string(),
+                "-------------------".to_string(),
+                format!("identifier              {}", entry.guid),
+            ];
+            for (key, value) in &entry.fields {
+                block.push(format!("{key:<24}{value}"));
+            }
+            blocks.push(block.join("\r\n"));
+        }
+        blocks.join("\r\n\r\n")
+    }
+}
+
+/// Commands of a diskpart script that act on the selected virtual disk.
+fn disk_command(disk: &mut Disk, lower: &str) -> std::result::Result<String, String> {
+    if lower.starts_with("attach vdisk") {
+        if disk.attached {
+            return Err("The virtual disk is already attached.".into());
+        }
+        disk.attached = true;
+        Ok("DiskPart successfully attached the virtual disk file.".into())
+    } else if lower.starts_with("detach vdisk") {
+        if !disk.attached {
+            return Err("The virtual disk is already detached.".into());
+        }
+        disk.attached = false;
+        disk.letters.clear();
+        Ok("DiskPart successfully detached the virtual disk file.".into())
+    } else if let Some(rest) = lower.strip_prefix("assign letter=") {
+        let letter = rest
+            .chars()
+            .next()
+            .map(|c| c.to_ascii_uppercase())
+            .filter(char::is_ascii_uppercase)
+            .ok_or("The parameter is incorrect.")?;
+        if !disk.attached {
+            return Err("There is no volume selected.".into());
+        }
+        disk.letters.push(letter);
+        Ok("DiskPart successfully assigned the drive letter or mount point.".into())
+    } else if let Some(rest) = lower.strip_prefix("remove letter=") {
+        let letter = rest.chars().next();
+        disk.letters
+            .retain(|l| Some(l.to_ascii_lowercase()) != letter);
+        Ok("DiskPart successfully removed the drive letter or mount point.".into())
+    } else if lower.starts_with("list partition") {
+        let size_gb = (disk.size_mb / 1024).max(1);
+        Ok([
+            "  Partition ###  Type              Size     Offset".to_string(),
+            "  -------------  ----------------  -------  -------".to_string(),
+            "  Partition 1    System             100 MB  1024 KB".to_string(),
+            "  Partition 2    Reserved            16 MB   101 MB".to_string(),
+            format!("  Partition 3    Primary             {size_gb} GB   117 MB"),
+        ]
+        .join("\r\n"))
+    } else if lower.starts_with("detail vdisk") {
+        let mut lines = vec![
+            format!("Virtual size: {} GB", disk.size_mb / 1024),
+            format!("Filename: {}", disk.path),
+            format!(
+                "Is Child: {}",
+                if disk.parent.is_some() { "Yes" } else { "No" }
+            ),
+            format!("Parent Filename: {}", disk.parent.as_deref().unwrap_or("")),
+        ];
+        lines.push(if disk.attached {
+            "Associated disk#: 3".to_string()
+        } else {
+            "Associated disk#: Not found.".to_string()
+        });
+        Ok(lines.join("\r\n"))
+    } else {
+        Ok(String::new())
+    }
+}
+
+fn dism(args: &[&str]) -> CommandOutput {
+    let wants = |flag: &str| args.iter().any(|a| a.eq_ignore_ascii_case(flag));
+    if wants("/Get-WimInfo") {
+        let images: Vec<String> = SIMULATED_IMAGES
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| {
+                format!(
+                    "Index : {}\r\nName : {name}\r\nDescription : {name}\r\nSize : 16,106,127,360 bytes",
+                    idx + 1
+                )
+            })
+            .collect();
+        return succeeded(format!(
+            "Deployment Image Servicing and Management tool (simulated)\r\n\r\n{}\r\n\r\nThe operation completed successfully.",
+            images.join("\r\n\r\n")
+        ));
+    }
+    succeeded("The operation completed successfully.".into())
+}
+
+/// Value of `key=` in a diskpart line, quoted or up to the next space.
+fn arg(line: &str, key: &str) -> Option<String> {
+    let lower = line.to_ascii_lowercase();
+    let start = lower.find(&format!("{key}="))? + key.len() + 1;
+    let rest = &line[start..];
+    let value = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next()?,
+        None => rest.split_whitespace().next()?,
+    };
+    Some(value.to_string())
+}
+
+fn tool_name(program: &str) -> String {
+    let name = Path::new(program)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    name.trim_end_matches(".exe").to_string()
+}
+
+fn succeeded(stdout: String) -> CommandOutput {
+    CommandOutput {
+        exit_code: Some(0),
+        stdout,
+        stderr: String::new(),
+    }
+}
+
+fn failed(message: &str) -> CommandOutput {
+    CommandOutput {
+        exit_code: Some(1),
+        stdout: String::new(),
+        stderr: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use super::SimulatedRunner;
+    use crate::bcd;
+    use crate::diskpart;
+    use crate::paths::normalize_path;
+    use crate::sys::install_runner;
+    use crate::temp::TempManager;
+
+    #[test]
+    fn differencing_layer_records_its_parent() {
+        let _runner = install_runner(Arc::new(SimulatedRunner::default()));
+        let root = std::env::temp_dir().join(format!("simulate-{}", Uuid::new_v4()));
+        let temp = TempManager::new(root.join("tmp")).unwrap();
+        let base = root.join("base.vhdx");
+        let child = root.join("child.vhdx");
+        let run = |name: &str, script: String| {
+            let path = temp.write_script(name, &script).unwrap();
+            diskpart::run_diskpart_script(&path).unwrap()
+        };
+
+        let created = run(
+            "base.txt",
+            diskpart::base_diskpart_script(&base, 64, 'S', 'T', None).unwrap(),
+        );
+        assert!(created.succeeded(), "{}", created.stderr);
+        assert!(created.stdout.contains("    T    "));
+        let diff = run(
+            "diff.txt",
+            diskpart::diff_attach_assign_script(&child, &base, 3, 'V').unwrap(),
+        );
+        assert!(diff.succeeded(), "{}", diff.stderr);
+        let detail = run("detail.txt", diskpart::detail_vdisk_script(&child).unwrap());
+        let parent = diskpart::parse_detail_vdisk(&detail.stdout).parent;
+        assert_eq!(
+            parent.as_deref().map(normalize_path),
+            Some(normalize_path(&base.to_string_lossy()))
+        );
+        assert!(child.exists());
+        // A second create over the same file fails like diskpart does.
+        let again = run(
+            "again.txt",
+            diskpart::diff_attach_assign_script(&child, &base, 3, 'W').unwrap(),
+        );
+        assert!(!again.succeeded());
+
+        drop(temp);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn boot_entries_are_created_listed_and_deleted() {
+        let _runner = install_runner(Arc::new(SimulatedRunner::default()));
+        let created = bcd::bcdedit_create("Layer 1", &["/application", "osloader"]).unwrap();
+        let guid = bcd::parse_created_guid(&created.stdout).unwrap();
+        assert!(
+            bcd::bcdedit_set(&guid, "osdevice", r"vhd=[D:]\ws\child.vhdx")
+                .unwrap()
+                .succeeded()
+        );
+
+        let listed = bcd::bcdedit_enum_entry(&guid).unwrap();
+        let objects = bcd::parse_bcd_objects(&listed.stdout);
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].get("description"), Some("Layer 1"));
+        assert_eq!(objects[0].get("osdevice"), Some(r"vhd=[D:]\ws\child.vhdx"));
+
+        assert!(bcd::bcdedit_delete(&guid).unwrap().succeeded());
+        assert!(!bcd::bcdedit_enum_entry(&guid).unwrap().succeeded());
+    }
+}
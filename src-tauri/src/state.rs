@@ -1,13 +1,21 @@
 use std::{
+    fs,
     path::PathBuf,
     sync::{Arc, RwLock},
+    thread,
+    time::Duration,
 };
 
+use tracing::{info, warn};
+
 use crate::{
-    db::{AppSettings, Database},
+    bcd,
+    db::{AppSettings, Database, SettingsPatch},
     error::{AppError, Result},
     logging::init_tracing,
-    paths::AppPaths,
+    paths::{expand_env_vars, AppPaths},
+    policy, preflight, redact,
+    tools::{self, ToolPaths},
 };
 
 #[derive(Clone)]
@@ -31,6 +39,7 @@ impl Default for SharedState {
 
 impl SharedState {
     pub fn initialize(&self, root: PathBuf, locale: Option<String>) -> Result<AppSettings> {
+        preflight::validate_root(&root)?;
         let paths = AppPaths::new(root);
         paths.ensure_layout()?;
         init_tracing(paths.ops_log_path().as_path())?;
@@ -41,6 +50,11 @@ impl SharedState {
             db.update_locale(&locale)?;
         }
         let settings = db.get_settings()?;
+        redact::configure(settings.redact_logs, Some(&settings.root_path));
+        policy::configure(settings.read_only);
+        tools::set_overrides(settings.tool_paths.clone());
+        bcd::set_target(settings.bcd_target.clone());
+        let paths = with_dir_settings(paths, &settings);
 
         {
             let mut inner = self.inner.write().expect("state lock poisoned");
@@ -59,6 +73,60 @@ impl SharedState {
         }
     }
 
+    pub fn update_settings(&self, patch: SettingsPatch) -> Result<AppSettings> {
+        let db = self.db()?;
+        db.apply_settings_patch(&patch)?;
+        let settings = db.get_settings()?;
+        redact::configure(settings.redact_logs, Some(&settings.root_path));
+        bcd::set_target(settings.bcd_target.clone());
+        if patch.temp_path.is_some() || patch.base_path.is_some() || patch.diff_path.is_some() {
+            let paths = with_dir_settings(self.paths()?, &settings);
+            self.inner.write().expect("state lock poisoned").paths = Some(paths);
+        }
+        Ok(settings)
+    }
+
+    pub fn update_tool_paths(&self, tool_paths: ToolPaths) -> Result<AppSettings> {
+        let db = self.db()?;
+        db.update_tool_paths(&tool_paths)?;
+        tools::set_overrides(tool_paths);
+        db.get_settings()
+    }
+
+    /// Turn read-only mode on with the PIN that unlocks it, or off again. Turning it off is
+    /// only reachable from an unlocked session since the command itself is not read-only.
+    pub fn set_read_only(&self, enabled: bool, pin: Option<&str>) -> Result<AppSettings> {
+        let db = self.db()?;
+        if enabled {
+            let pin = pin.map(str::trim).unwrap_or_default();
+            if pin.len() < 4 || !pin.chars().all(|c| c.is_ascii_digit()) {
+                return Err(AppError::Message(
+                    "the unlock PIN must be at least 4 digits".into(),
+                ));
+            }
+            db.set_read_only(true, Some(&policy::hash_pin(pin)))?;
+        } else {
+            db.set_read_only(false, None)?;
+        }
+        policy::configure(enabled);
+        info!("set_read_only enabled={enabled}");
+        db.get_settings()
+    }
+
+    /// Unlock mutating commands for this session when `pin` matches.
+    pub fn unlock_read_only(&self, pin: &str) -> Result<()> {
+        let stored = self.db()?.read_only_pin()?;
+        if !stored.is_some_and(|stored| policy::verify_pin(&stored, pin.trim())) {
+            // Slow down guessing from scripts.
+            thread::sleep(Duration::from_secs(1));
+            warn!("unlock_read_only rejected");
+            return Err(AppError::PolicyDenied("wrong PIN".into()));
+        }
+        policy::set_unlocked(true);
+        info!("unlock_read_only");
+        Ok(())
+    }
+
     pub fn paths(&self) -> Result<AppPaths> {
         self.inner
             .read()
@@ -76,3 +144,22 @@ impl SharedState {
         self.inner.read().expect("state lock poisoned").db.clone()
     }
 }
+
+/// Apply the configured layer and temp folders. Temp files stay in the workspace when their
+/// folder cannot be created (e.g. a removed drive) so the workspace still opens; layer folders
+/// are kept as configured so layers are never created in a place the user did not choose.
+fn with_dir_settings(paths: AppPaths, settings: &AppSettings) -> AppPaths {
+    let dir = |value: &Option<String>| value.as_deref().map(|d| PathBuf::from(expand_env_vars(d)));
+    let paths = paths.with_layer_dirs(dir(&settings.base_path), dir(&settings.diff_path));
+    let configured = paths.clone().with_temp_dir(dir(&settings.temp_path));
+    match fs::create_dir_all(configured.tmp_dir()) {
+        Ok(()) => configured,
+        Err(err) => {
+            warn!(
+                "temp folder unavailable, using the workspace dir={} err={err}",
+                configured.tmp_dir().display()
+            );
+            paths.with_temp_dir(None)
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::path::Path;
+
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::diskpart::{PartitionInfo, VhdDetail};
+use crate::error::Result;
+use crate::sys::powershell;
+
+/// Whether the Hyper-V module providing `Get-VHD` is installed; it is missing on Home editions
+/// and wherever the Hyper-V management tools were not enabled.
+static GET_VHD_AVAILABLE: OnceCell<bool> = OnceCell::new();
+
+/// `Get-VHD` properties used to describe a layer.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VhdRecord {
+    #[serde(default)]
+    parent_path: Option<String>,
+    attached: bool,
+    #[serde(default)]
+    fragmentation_percentage: Option<u32>,
+}
+
+/// `Get-Partition` properties of one partition.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PartitionRecord {
+    partition_number: u32,
+    #[serde(rename = "Type")]
+    kind: String,
+    size: u64,
+}
+
+/// `Get-Disk` properties describing where a volume lives.
+#[cfg(windows)]
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DiskRecord {
+    pub bus_type: String,
+    /// False when no basic partition backs the drive letter (dynamic or spanned volume).
+    pub has_partition: bool,
+    pub dynamic: bool,
+}
+
+/// Where an attached VHDX shows up as a disk.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AttachedDisk {
+    pub number: u32,
+    #[serde(default)]
+    pub volumes: Vec<String>,
+}
+
+/// Parent, attach state and fragmentation of a VHDX from `Get-VHD`; `Ok(None)` when the cmdlet is not
+/// installed, so the caller can fall back to diskpart.
+pub fn vhd_detail(vhd_path: &Path) -> Result<Option<VhdDetail>> {
+    let available = *GET_VHD_AVAILABLE.get_or_init(|| {
+        powershell::<bool>("[bool](Get-Command Get-VHD -ErrorAction SilentlyContinue)")
+            .map(|found| found.first().copied().unwrap_or(false))
+            .unwrap_or(false)
+    });
+    if !available {
+        return Ok(None);
+    }
+    let records: Vec<VhdRecord> = powershell(&format!(
+        "Get-VHD -Path {} | Select-Object ParentPath, Attached, FragmentationPercentage",
+        quote(&vhd_path.to_string_lossy())
+    ))?;
+    Ok(records.into_iter().next().map(|vhd| VhdDetail {
+        parent: vhd.parent_path.filter(|p| !p.trim().is_empty()),
+        attached: Some(vhd.attached),
+        fragmentation: vhd.fragmentation_percentage,
+    }))
+}
+
+/// Partitions of an attached VHDX, from `Get-DiskImage` and `Get-Partition`. Types are the
+/// Storage module names (`System`, `Reserved`, `Basic`, `IFS`, ...), which are not localized.
+pub fn vhd_partitions(vhd_path: &Path) -> Result<Vec<PartitionInfo>> {
+    let records: Vec<PartitionRecord> = powershell(&format!(
+        "Get-DiskImage -ImagePath {} | Get-Disk | Get-Partition |
+    Select-Object PartitionNumber, @{{ n = 'Type'; e = {{ \"$($_.Type)\" }} }}, Size",
+        quote(&vhd_path.to_string_lossy())
+    ))?;
+    Ok(records
+        .into_iter()
+        .map(|p| PartitionInfo {
+            index: p.partition_number,
+            kind: p.kind,
+            size_mb: Some(p.size / (1024 * 1024)),
+        })
+        .collect())
+}
+
+/// Disk behind drive `letter`, from `Get-Partition` and `Get-Disk`.
+#[cfg(windows)]
+pub fn disk_for_letter(letter: char) -> Result<Option<DiskRecord>> {
+    let records: Vec<DiskRecord> = powershell(&format!(
+        "$p = Get-Partition -DriveLetter '{letter}' -ErrorAction SilentlyContinue
+if ($p) {{
+    $d = $p | Get-Disk
+    [ordered]@{{
+        BusType = \"$($d.BusType)\"
+        HasPartition = $true
+        Dynamic = ($p.MbrType -eq 0x42) -or (\"$($p.GptType)\" -eq '{{af9b60a0-1431-4f62-bc68-3311714a69ad}}')
+    }}
+}} else {{
+    [ordered]@{{ BusType = ''; HasPartition = $false; Dynamic = $false }}
+}}"
+    ))?;
+    Ok(records.into_iter().next())
+}
+
+/// Disk number and volume GUID paths (`\\?\Volume{...}\`) of an attached VHDX, from
+/// `Get-DiskImage`; `None` when it is not attached.
+pub fn attached_disk(vhd_path: &Path) -> Result<Option<AttachedDisk>> {
+    let records: Vec<AttachedDisk> = powershell(&format!(
+        "$img = Get-DiskImage -ImagePath {}
+if ($img.Attached) {{
+    [ordered]@{{
+        Number = $img.Number
+        Volumes = @(Get-Partition -DiskNumber $img.Number -ErrorAction SilentlyContinue |
+            ForEach-Object {{ $_.AccessPaths }} | Where-Object {{ $_ -like '\\\\?\\Volume*' }})
+    }}
+}}",
+        quote(&vhd_path.to_string_lossy())
+    ))?;
+    Ok(records.into_iter().next())
+}
+
+/// Detach the virtual disk attached as `\\.\PHYSICALDRIVE<number>`, for when diskpart no longer
+/// gets through the image path.
+pub fn dismount_disk_number(number: u32) -> Result<()> {
+    powershell::<serde_json::Value>(&format!(
+        "Dismount-DiskImage -DevicePath '\\\\.\\PHYSICALDRIVE{number}' | Out-Null"
+    ))?;
+    Ok(())
+}
+
+/// Image file the running system booted from, when it booted natively from a VHDX. `Get-Disk`
+/// reports the file path as the location of file-backed virtual disks.
+pub fn boot_vhd_path() -> Result<Option<String>> {
+    let records: Vec<String> = powershell(
+        "Get-Disk | Where-Object { $_.IsBoot -and \"$($_.BusType)\" -eq 'File Backed Virtual' } |
+    ForEach-Object { $_.Location }",
+    )?;
+    Ok(records.into_iter().next())
+}
+
+/// Quote a value as a PowerShell single-quoted string literal.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
@@ -1,9 +1,20 @@
+use std::cell::RefCell;
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
 
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tracing::info;
 
-use crate::error::{AppError, Result};
+#[cfg(windows)]
+use crate::broker;
+use crate::error::{AppError, CommandErrorKind, Result};
+use crate::jobs::{self, CancelToken};
+use crate::models::OutputStream;
+use crate::tools;
+use crate::transcript;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct CommandOutput {
@@ -12,6 +23,195 @@ pub struct CommandOutput {
     pub stderr: String,
 }
 
+/// `STATUS_CONTROL_C_EXIT`: the process was stopped with Ctrl+C or killed on cancellation.
+const STATUS_CONTROL_C_EXIT: u32 = 0xC000_013A;
+
+impl CommandOutput {
+    /// Exited with status 0. A missing status counts as failure, never as success.
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    /// Exit status for messages; NTSTATUS and HRESULT codes are shown in hex.
+    pub fn status_text(&self) -> String {
+        match self.exit_code {
+            None => "exit status unknown".into(),
+            Some(code) if code < 0 => format!("exit=0x{:08X}", code as u32),
+            Some(code) => format!("exit={code}"),
+        }
+    }
+
+    /// Why the command failed, from its exit status first and then from HRESULTs or well-known
+    /// Win32 messages in its output (diskpart and bcdedit exit with generic codes).
+    pub fn classify(&self) -> CommandErrorKind {
+        let Some(code) = self.exit_code else {
+            return CommandErrorKind::UnknownStatus;
+        };
+        let code = code as u32;
+        if code == STATUS_CONTROL_C_EXIT {
+            return CommandErrorKind::Cancelled;
+        }
+        if let Some(kind) = hresult_kind(code) {
+            return kind;
+        }
+        if code & 0xC000_0000 == 0xC000_0000 {
+            return CommandErrorKind::Crashed;
+        }
+        let text = format!("{}\n{}", self.stdout, self.stderr);
+        if let Some(kind) = hex_codes(&text).find_map(hresult_kind) {
+            return kind;
+        }
+        let text = text.to_lowercase();
+        let messages = [
+            ("access is denied", CommandErrorKind::AccessDenied),
+            ("being used by another process", CommandErrorKind::InUse),
+            ("is in use", CommandErrorKind::InUse),
+            ("cannot find the file", CommandErrorKind::NotFound),
+            ("cannot find the path", CommandErrorKind::NotFound),
+            ("cannot be found", CommandErrorKind::NotFound),
+            ("already exists", CommandErrorKind::AlreadyExists),
+            ("parameter is incorrect", CommandErrorKind::InvalidParameter),
+            ("not enough space", CommandErrorKind::DiskFull),
+        ];
+        messages
+            .iter()
+            .find(|(message, _)| text.contains(message))
+            .map_or(CommandErrorKind::Failed, |(_, kind)| *kind)
+    }
+
+    /// Actionable advice for a well-known failure, from the exit status first and then from
+    /// codes and messages in the output.
+    pub fn hint(&self) -> Option<&'static str> {
+        let by_code = |code: u32| {
+            KNOWN_CODES
+                .iter()
+                .find(|(known, _)| *known == code)
+                .map(|(_, hint)| *hint)
+        };
+        if let Some(hint) = self.exit_code.and_then(|code| by_code(code as u32)) {
+            return Some(hint);
+        }
+        let text = format!("{}\n{}", self.stdout, self.stderr).to_lowercase();
+        if let Some(hint) = hex_codes(&text).find_map(by_code) {
+            return Some(hint);
+        }
+        KNOWN_MESSAGES
+            .iter()
+            .find(|(message, _)| text.contains(message))
+            .map(|(_, hint)| *hint)
+    }
+}
+
+/// Frequent DISM, diskpart, bcdedit and virtual disk failures and what to do about them,
+/// matched by exit status or a hex code in the output.
+const KNOWN_CODES: &[(u32, &str)] = &[
+    (
+        0x800F_0906,
+        "DISM could not download the source files; pass the install media's sources\\sxs folder or allow Windows Update access.",
+    ),
+    (
+        0x800F_081F,
+        "The source files were not found; use install media of the same build as the layer.",
+    ),
+    (
+        0x800F_0922,
+        "The image has a pending servicing operation or a full system partition; boot the layer once and retry.",
+    ),
+    (
+        0x800F_082F,
+        "The image has pending operations; boot the layer once or revert them with DISM /Cleanup-Image /RevertPendingActions.",
+    ),
+    (
+        0x800F_0831,
+        "A prerequisite update is missing from the image; install the previous cumulative update first.",
+    ),
+    (
+        0xC142_0127,
+        "The image is already mounted; unmount it or run dism /Cleanup-Wim.",
+    ),
+    (
+        0xC142_0117,
+        "Files inside the mount folder are open; close Explorer windows and consoles there and retry.",
+    ),
+    (
+        0xC151_0111,
+        "The WIM is read-only (e.g. copied from an ISO); clear the read-only attribute of the file.",
+    ),
+    (
+        0xC03A_000D,
+        "The parent VHDX was not found; restore it or run repair links.",
+    ),
+    (
+        0xC03A_000E,
+        "The parent VHDX changed after this layer was created, so the chain is broken; restore the parent from a backup.",
+    ),
+    (
+        0xC03A_000F,
+        "The parent VHDX changed after this layer was created, so the chain is broken; restore the parent from a backup.",
+    ),
+    (
+        0xC03A_0014,
+        "Windows cannot open the file as a virtual disk; make sure it ends in .vhdx and is neither NTFS-compressed nor EFS-encrypted.",
+    ),
+    (
+        0x8007_02E4,
+        "The operation requires elevation; restart the app as administrator.",
+    ),
+];
+
+/// Like `KNOWN_CODES`, for tools that print only text (lowercase fragments).
+const KNOWN_MESSAGES: &[(&str, &str)] = &[
+    (
+        "the boot configuration data store could not be opened",
+        "Run the app as administrator; if it already is, another tool may hold the BCD store or the system partition is locked.",
+    ),
+    (
+        "requested operation requires elevation",
+        "The operation requires elevation; restart the app as administrator.",
+    ),
+    (
+        "error: 740",
+        "The operation requires elevation; restart the app as administrator.",
+    ),
+    (
+        "error: 87",
+        "This DISM may be older than the image; point the DISM tool path at the Windows ADK version.",
+    ),
+    (
+        "media is write protected",
+        "The target volume is write-protected or locked by BitLocker; unlock it and retry.",
+    ),
+    (
+        "file system is incompatible",
+        "Virtual disks need an NTFS or ReFS volume; move the workspace off FAT/exFAT.",
+    ),
+];
+
+/// Kind of a Win32 error wrapped in an HRESULT (`0x8007xxxx`).
+fn hresult_kind(code: u32) -> Option<CommandErrorKind> {
+    if code & 0xFFFF_0000 != 0x8007_0000 {
+        return None;
+    }
+    match code & 0xFFFF {
+        5 => Some(CommandErrorKind::AccessDenied),
+        32 | 33 => Some(CommandErrorKind::InUse),
+        2 | 3 | 1168 => Some(CommandErrorKind::NotFound),
+        80 | 183 => Some(CommandErrorKind::AlreadyExists),
+        87 => Some(CommandErrorKind::InvalidParameter),
+        39 | 112 => Some(CommandErrorKind::DiskFull),
+        1223 => Some(CommandErrorKind::Cancelled),
+        _ => None,
+    }
+}
+
+/// Every `0x` followed by eight hex digits in `text`.
+fn hex_codes(text: &str) -> impl Iterator<Item = u32> + '_ {
+    text.match_indices("0x").filter_map(move |(at, _)| {
+        let digits = text.get(at + 2..at + 10)?;
+        u32::from_str_radix(digits, 16).ok()
+    })
+}
+
 fn configure_command_common(
     cmd: &mut Command,
     workdir: Option<&Path>,
@@ -29,60 +229,468 @@ fn configure_command_common(
     }
 }
 
-pub fn run_command(program: &str, args: &[&str], workdir: Option<&Path>) -> Result<CommandOutput> {
-    let mut cmd = Command::new(program);
-    cmd.args(args);
-    configure_command_common(&mut cmd, workdir);
-    let output = cmd
-        .output()
-        .map_err(|e| AppError::Message(format!("Failed to run {program}: {e}")))?;
-    let output = CommandOutput {
-        exit_code: output.status.code(),
-        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+/// Parts of a command that must not show up in the log, transcripts or job progress.
+#[derive(Clone, Copy, Default)]
+pub struct Secrets<'a> {
+    /// Values such as passwords or product keys, shown as `***` wherever arguments are shown.
+    pub values: &'a [&'a str],
+    /// The output carries a secret (e.g. a recovery password) and is returned to the caller only.
+    pub output: bool,
+}
+
+impl Secrets<'_> {
+    pub const NONE: Secrets<'static> = Secrets {
+        values: &[],
+        output: false,
     };
-    log_command(program, args, workdir, &output);
+
+    /// `text` with every secret value replaced by `***`.
+    pub fn mask(&self, text: &str) -> String {
+        self.values
+            .iter()
+            .filter(|value| !value.is_empty())
+            .fold(text.to_string(), |text, value| text.replace(value, "***"))
+    }
+}
+
+/// Runs the external tools behind `run_command` and `run_elevated_command`.
+///
+/// Commands go to the runner installed on the calling thread (see `install_runner`), so the
+/// workspace logic can be driven by a fake instead of real disks and boot entries.
+pub trait CommandRunner: Send + Sync {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        workdir: Option<&Path>,
+        elevated: bool,
+        secrets: Secrets<'_>,
+    ) -> Result<CommandOutput>;
+}
+
+/// Runs tools for real; elevated commands go through the broker, or a UAC prompt without it.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(
+        &self,
+        program: &str,
+        args: &[&str],
+        workdir: Option<&Path>,
+        elevated: bool,
+        secrets: Secrets<'_>,
+    ) -> Result<CommandOutput> {
+        let program = &tools::resolve(program);
+        let owned_args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        jobs::report_command(program, &shown_args(args, secrets));
+        let output = if elevated {
+            run_elevated(program, owned_args, workdir, secrets)
+        } else {
+            execute_command(program, &owned_args, workdir, secrets)
+        }
+        .map_err(AppError::Message)?;
+        log_command(program, args, workdir, &output, secrets);
+        Ok(output)
+    }
+}
+
+thread_local! {
+    /// Runner used for commands issued on this thread; `SystemRunner` when none is installed.
+    static RUNNER: RefCell<Option<Arc<dyn CommandRunner>>> = const { RefCell::new(None) };
+}
+
+/// Runner for threads without one installed; `SystemRunner` unless replaced at startup.
+static DEFAULT_RUNNER: OnceCell<Arc<dyn CommandRunner>> = OnceCell::new();
+
+/// Route commands of every thread without an installed runner through `runner` for the rest
+/// of the process.
+#[cfg(feature = "simulate")]
+pub fn set_default_runner(runner: Arc<dyn CommandRunner>) {
+    let _ = DEFAULT_RUNNER.set(runner);
+}
+
+/// Runner for a thread that has none installed: the one set at startup, else `SystemRunner`.
+pub fn default_runner() -> Arc<dyn CommandRunner> {
+    DEFAULT_RUNNER
+        .get()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(SystemRunner))
+}
+
+/// Restores the previously installed runner when dropped.
+pub struct RunnerGuard {
+    previous: Option<Arc<dyn CommandRunner>>,
+}
+
+impl Drop for RunnerGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        RUNNER.with(|runner| *runner.borrow_mut() = previous);
+    }
+}
+
+/// Route every command issued on this thread through `runner` until the guard is dropped.
+pub fn install_runner(runner: Arc<dyn CommandRunner>) -> RunnerGuard {
+    RunnerGuard {
+        previous: RUNNER.with(|current| current.replace(Some(runner))),
+    }
+}
+
+fn dispatch(
+    program: &str,
+    args: &[&str],
+    workdir: Option<&Path>,
+    elevated: bool,
+    secrets: Secrets<'_>,
+) -> Result<CommandOutput> {
+    let runner = RUNNER
+        .with(|runner| runner.borrow().clone())
+        .unwrap_or_else(default_runner);
+    let output = runner.run(program, args, workdir, elevated, secrets)?;
+    transcript::record_command(program, args, &output, secrets);
     Ok(output)
 }
 
+// Besides tests only the Windows-only `handles` runs tools unelevated.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn run_command(program: &str, args: &[&str], workdir: Option<&Path>) -> Result<CommandOutput> {
+    dispatch(program, args, workdir, false, Secrets::NONE)
+}
+
 pub fn run_elevated_command(
     program: &str,
     args: &[&str],
     workdir: Option<&Path>,
 ) -> Result<CommandOutput> {
-    let output = run_elevated_command_impl(
-        program,
-        args.iter().map(|s| s.to_string()).collect(),
-        workdir,
+    dispatch(program, args, workdir, true, Secrets::NONE)
+}
+
+/// `run_elevated_command` for a command carrying `secrets`: the tool gets the real arguments,
+/// the log, the transcript, the job list and job progress get them masked.
+pub fn run_elevated_command_with_secrets(
+    program: &str,
+    args: &[&str],
+    workdir: Option<&Path>,
+    secrets: Secrets<'_>,
+) -> Result<CommandOutput> {
+    dispatch(program, args, workdir, true, secrets)
+}
+
+/// Arguments as they may be shown, with every secret value masked.
+pub fn shown_args(args: &[&str], secrets: Secrets<'_>) -> Vec<String> {
+    args.iter().map(|arg| secrets.mask(arg)).collect()
+}
+
+/// Run an inline PowerShell script elevated, without loading the user profile.
+pub fn run_powershell(script: &str) -> Result<CommandOutput> {
+    run_elevated_command(
+        "powershell.exe",
+        &[
+            "-NoLogo",
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            script,
+        ],
+        None,
     )
-    .map_err(|err| AppError::Message(err))?;
-    log_command(program, args, workdir, &output);
-    Ok(output)
 }
 
+/// Run a PowerShell pipeline elevated and deserialize the objects it outputs.
+///
+/// The output is converted with `ConvertTo-Json -InputObject @(...)`, so no object, one object
+/// and many all come back as a list. Enum properties serialize as numbers; select them as
+/// strings (`"$($_.Type)"`) when the name is wanted.
+pub fn powershell<T: DeserializeOwned>(pipeline: &str) -> Result<Vec<T>> {
+    let script = format!(
+        "$ErrorActionPreference = 'Stop'
+$ProgressPreference = 'SilentlyContinue'
+[Console]::OutputEncoding = [Text.Encoding]::UTF8
+ConvertTo-Json -InputObject @({pipeline}
+) -Depth 4 -Compress
+"
+    );
+    let res = run_powershell(&script)?;
+    if !res.succeeded() {
+        let kind = res.classify();
+        return Err(AppError::Command {
+            kind,
+            message: format!("powershell failed ({kind}): {}", res.stderr.trim()),
+            hint: res.hint(),
+        });
+    }
+    let json = res.stdout.trim();
+    if json.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Run `program` elevated: through the broker when it is running, else behind a UAC prompt.
+#[cfg(windows)]
+fn run_elevated(
+    program: &str,
+    args: Vec<String>,
+    workdir: Option<&Path>,
+    secrets: Secrets<'_>,
+) -> std::result::Result<CommandOutput, String> {
+    match broker::execute(program, &args, workdir, secrets) {
+        Some(res) => res,
+        None => run_elevated_command_impl(program, args, workdir),
+    }
+}
+
+/// Other hosts have nothing to elevate through; the simulator answers their tools anyway.
+#[cfg(not(windows))]
+fn run_elevated(
+    program: &str,
+    args: Vec<String>,
+    workdir: Option<&Path>,
+    secrets: Secrets<'_>,
+) -> std::result::Result<CommandOutput, String> {
+    execute_command(program, &args, workdir, secrets)
+}
+
+#[cfg(windows)]
 #[elevated::elevated]
 fn run_elevated_command_impl(
     program: &str,
     args: Vec<String>,
     workdir: Option<&Path>,
 ) -> std::result::Result<CommandOutput, String> {
-    let mut cmd = Command::new(program);
-    cmd.args(&args);
+    execute_command(program, &args, workdir, Secrets::NONE)
+}
+
+/// Spawn `program` in the current process context and capture its output.
+///
+/// Blocks the calling (blocking-pool) thread on the async runtime; see `execute_command_async`.
+pub fn execute_command(
+    program: &str,
+    args: &[String],
+    workdir: Option<&Path>,
+    secrets: Secrets<'_>,
+) -> std::result::Result<CommandOutput, String> {
+    tauri::async_runtime::block_on(execute_command_async(program, args, workdir, secrets))
+}
+
+/// Run `program` on the tokio runtime as a registered job: every output line is streamed to
+/// the frontend as it arrives and the process tree is killed when the job is cancelled. The job
+/// lists the arguments with `secrets` masked and does not stream a secret output.
+pub async fn execute_command_async(
+    program: &str,
+    args: &[String],
+    workdir: Option<&Path>,
+    secrets: Secrets<'_>,
+) -> std::result::Result<CommandOutput, String> {
+    let shown: Vec<String> = args.iter().map(|arg| secrets.mask(arg)).collect();
+    let job = jobs::register(program, &shown);
+    let forward = !secrets.output;
+    let on_line = |stream, line: &str| {
+        if forward {
+            jobs::emit_line(&job.id, stream, line);
+        }
+    };
+    run_process(program, args, workdir, &job.cancel, &on_line).await
+}
+
+/// Spawn `program` and collect its output, handing every line to `on_line` as it arrives. The
+/// process tree is killed when `cancel` fires.
+pub async fn run_process(
+    program: &str,
+    args: &[String],
+    workdir: Option<&Path>,
+    cancel: &CancelToken,
+    on_line: &(dyn Fn(OutputStream, &str) + Sync),
+) -> std::result::Result<CommandOutput, String> {
+    let mut cmd = console_command(program, args);
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
     configure_command_common(&mut cmd, workdir);
-    let output = cmd
-        .output()
+    let mut cmd = tokio::process::Command::from(cmd);
+    cmd.kill_on_drop(true);
+
+    let mut child = cmd
+        .spawn()
         .map_err(|e| format!("Failed to run {program}: {e}"))?;
-    let output = CommandOutput {
-        exit_code: output.status.code(),
-        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+    #[cfg(windows)]
+    let tree = ProcessTree::adopt(&child);
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let wait = async {
+        tokio::select! {
+            status = child.wait() => status.map_err(|e| format!("Failed to run {program}: {e}")),
+            _ = cancel.cancelled() => {
+                #[cfg(windows)]
+                if let Some(tree) = &tree {
+                    tree.kill();
+                }
+                let _ = child.kill().await;
+                Err(format!("{program} was cancelled"))
+            }
+        }
     };
-    Ok(output)
+    let (status, stdout, stderr) = tokio::join!(
+        wait,
+        read_stream(stdout, OutputStream::Stdout, on_line),
+        read_stream(stderr, OutputStream::Stderr, on_line),
+    );
+    Ok(CommandOutput {
+        exit_code: status?.code(),
+        stdout,
+        stderr,
+    })
+}
+
+/// Job object holding a spawned tool and every process it starts (DismHost.exe, the cmd wrapper's
+/// diskpart). Killing only the direct child would leave those running, holding the VHDX open
+/// and the output pipes unclosed; closing the handle kills whatever is still alive.
+#[cfg(windows)]
+struct ProcessTree(windows_sys::Win32::Foundation::HANDLE);
+
+#[cfg(windows)]
+impl ProcessTree {
+    fn adopt(child: &tokio::process::Child) -> Option<Self> {
+        use windows_sys::Win32::Foundation::CloseHandle;
+        use windows_sys::Win32::System::JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        };
+
+        let process = child.raw_handle()?;
+        unsafe {
+            let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+            if job == 0 {
+                return None;
+            }
+            let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+            limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            let configured = SetInformationJobObject(
+                job,
+                JobObjectExtendedLimitInformation,
+                std::ptr::addr_of!(limits).cast(),
+                std::mem::size_of_val(&limits) as u32,
+            );
+            if configured == 0 || AssignProcessToJobObject(job, process as _) == 0 {
+                CloseHandle(job);
+                return None;
+            }
+            Some(Self(job))
+        }
+    }
+
+    fn kill(&self) {
+        use windows_sys::Win32::System::JobObjects::TerminateJobObject;
+
+        unsafe { TerminateJobObject(self.0, STATUS_CONTROL_C_EXIT) };
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(self.0) };
+    }
+}
+
+/// Build the command line for `program`, asking for English output where the tool allows it.
+///
+/// diskpart and bcdedit have no `/English` switch; they pick their UI language for the console
+/// they run in, and under code page 437 a language it cannot display (Chinese, Japanese,
+/// Korean, ...) falls back to English, which is what the output parsers expect. They are started
+/// through `cmd /c chcp 437` when every argument can pass through cmd verbatim.
+fn console_command(program: &str, args: &[String]) -> Command {
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+
+        let verbatim = |arg: &String| !arg.contains(['"', '%', '\r', '\n']) && !arg.ends_with('\\');
+        if tools::localizes_by_console(program) && args.iter().all(verbatim) {
+            let mut line = format!("chcp 437 >nul & \"{program}\"");
+            for arg in args {
+                line.push_str(&format!(" \"{arg}\""));
+            }
+            let mut cmd = Command::new("cmd.exe");
+            cmd.args(["/d", "/v:off", "/s", "/c"])
+                .raw_arg(format!("\"{line}\""));
+            return cmd;
+        }
+    }
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    cmd
+}
+
+/// Collect a child pipe line by line, handing each line to `on_line`.
+async fn read_stream<R>(
+    pipe: Option<R>,
+    stream: OutputStream,
+    on_line: &(dyn Fn(OutputStream, &str) + Sync),
+) -> String
+where
+    R: AsyncRead + Unpin,
+{
+    let mut collected = String::new();
+    let Some(pipe) = pipe else {
+        return collected;
+    };
+    let mut reader = BufReader::new(pipe);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = decode_output(&buf);
+                on_line(stream, line.trim_end());
+                collected.push_str(&line);
+            }
+        }
+    }
+    collected
+}
+
+/// Decode one line of console output. Tools like diskpart write in the OEM code page when their
+/// output is redirected, so anything that is not valid UTF-8 is converted from that code page.
+fn decode_output(bytes: &[u8]) -> String {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return text.to_string();
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::Globalization::{MultiByteToWideChar, CP_OEMCP};
+
+        let len = i32::try_from(bytes.len()).unwrap_or(i32::MAX);
+        let needed = unsafe {
+            MultiByteToWideChar(CP_OEMCP, 0, bytes.as_ptr(), len, std::ptr::null_mut(), 0)
+        };
+        if needed > 0 {
+            let mut wide = vec![0u16; needed as usize];
+            let written = unsafe {
+                MultiByteToWideChar(CP_OEMCP, 0, bytes.as_ptr(), len, wide.as_mut_ptr(), needed)
+            };
+            if written > 0 {
+                return String::from_utf16_lossy(&wide[..written as usize]);
+            }
+        }
+    }
+    String::from_utf8_lossy(bytes).into_owned()
 }
 
-fn log_command(program: &str, args: &[&str], workdir: Option<&Path>, output: &CommandOutput) {
+fn log_command(
+    program: &str,
+    args: &[&str],
+    workdir: Option<&Path>,
+    output: &CommandOutput,
+    secrets: Secrets<'_>,
+) {
     let mut parts = Vec::new();
-    parts.push(format!("cmd={program} {}", args.join(" ")));
+    parts.push(format!(
+        "cmd={program} {}",
+        shown_args(args, secrets).join(" ")
+    ));
     if let Some(dir) = workdir {
         parts.push(format!("cwd={}", dir.display()));
     }
@@ -91,10 +699,124 @@ fn log_command(program: &str, args: &[&str], workdir: Option<&Path>, output: &Co
     }
     let stderr = output.stderr.trim();
     let stdout = output.stdout.trim();
-    if !stderr.is_empty() {
+    if secrets.output {
+        parts.push("output withheld".into());
+    } else if !stderr.is_empty() {
         parts.push(format!("stderr={stderr}"));
     } else if !stdout.is_empty() {
         parts.push(format!("stdout={stdout}"));
     }
     info!("{}", parts.join(" | "));
 }
+
+/// Scriptable stand-in for the real tools, for unit tests of workspace orchestration.
+#[cfg(test)]
+pub mod fake {
+    use std::collections::VecDeque;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use super::{CommandOutput, CommandRunner, Secrets};
+    use crate::error::{AppError, Result};
+    use crate::temp::read_script;
+
+    /// Answers commands from a queue of expected calls and records every call it receives.
+    #[derive(Default)]
+    pub struct ScriptedRunner {
+        script: Mutex<VecDeque<(String, CommandOutput)>>,
+        otherwise: Mutex<Option<(i32, String)>>,
+        calls: Mutex<Vec<String>>,
+        scripts: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedRunner {
+        /// Answer the next call, which must be to `program` (matched by file name, ignoring
+        /// case and `.exe`), with the given exit code and stdout.
+        pub fn expect(&self, program: &str, exit_code: i32, stdout: &str) -> &Self {
+            self.script
+                .lock()
+                .unwrap()
+                .push_back((program.to_string(), output(exit_code, stdout)));
+            self
+        }
+
+        /// Answer calls to any program other than the next expected one with the given exit
+        /// code and stdout instead of failing them; the queue is left as it is.
+        pub fn otherwise(&self, exit_code: i32, stdout: &str) -> &Self {
+            *self.otherwise.lock().unwrap() = Some((exit_code, stdout.to_string()));
+            self
+        }
+
+        /// Command lines received so far, as `program arg1 arg2 ...`.
+        pub fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        /// Contents of the diskpart scripts received so far, read when each call was made.
+        pub fn scripts(&self) -> Vec<String> {
+            self.scripts.lock().unwrap().clone()
+        }
+
+        /// Whether every expected call has been made.
+        pub fn is_done(&self) -> bool {
+            self.script.lock().unwrap().is_empty()
+        }
+    }
+
+    impl CommandRunner for ScriptedRunner {
+        fn run(
+            &self,
+            program: &str,
+            args: &[&str],
+            _workdir: Option<&Path>,
+            _elevated: bool,
+            _secrets: Secrets<'_>,
+        ) -> Result<CommandOutput> {
+            let line = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls.lock().unwrap().push(line.clone());
+            if tool_name(program) == "diskpart" {
+                if let Some(path) = args.iter().skip_while(|a| **a != "/s").nth(1) {
+                    self.scripts
+                        .lock()
+                        .unwrap()
+                        .push(read_script(Path::new(path))?);
+                }
+            }
+            let mut script = self.script.lock().unwrap();
+            let matches = script
+                .front()
+                .is_some_and(|(expected, _)| tool_name(expected) == tool_name(program));
+            if matches {
+                return Ok(script.pop_front().unwrap().1);
+            }
+            if let Some((exit_code, stdout)) = self.otherwise.lock().unwrap().as_ref() {
+                return Ok(output(*exit_code, stdout));
+            }
+            match script.front() {
+                Some((expected, _)) => Err(AppError::Message(format!(
+                    "expected a call to {expected}, got {line}"
+                ))),
+                None => Err(AppError::Message(format!("unexpected command: {line}"))),
+            }
+        }
+    }
+
+    fn output(exit_code: i32, stdout: &str) -> CommandOutput {
+        CommandOutput {
+            exit_code: Some(exit_code),
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+        }
+    }
+
+    fn tool_name(program: &str) -> String {
+        let name = Path::new(program)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        name.trim_end_matches(".exe").to_string()
+    }
+}
@@ -1,25 +1,47 @@
 use std::{
     fs,
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
+use chrono::Local;
+use tracing::warn;
+use uuid::Uuid;
+
 use crate::error::Result;
+use crate::models::TempCleanup;
+
+const UTF16LE_BOM: [u8; 2] = [0xFF, 0xFE];
+
+/// Folder under the temp root keeping copies of the scripts of failed commands.
+const FAILED_DIR: &str = "failed";
+
+/// Leftovers of operations that did not clean up after themselves (the app was closed or
+/// crashed mid-operation) are removed once they have not been touched for this long.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
 
-#[derive(Debug, Clone)]
+/// Scratch folder of one operation under `meta/tmp`; it is deleted with everything in it when
+/// the manager is dropped. Scripts of failed commands are kept by `preserve_failed`.
+#[derive(Debug)]
 pub struct TempManager {
     base: PathBuf,
 }
 
 impl TempManager {
-    pub fn new(base: impl Into<PathBuf>) -> Result<Self> {
-        let base = base.into();
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let base = root.into().join(format!("op-{}", Uuid::new_v4()));
         fs::create_dir_all(&base)?;
         Ok(Self { base })
     }
 
+    /// Write a script as UTF-16LE with a byte order mark. Diskpart reads BOM-less scripts in the
+    /// ANSI code page, which mangles paths outside of it (e.g. CJK workspace roots).
     pub fn write_script(&self, name: &str, content: &str) -> Result<PathBuf> {
         let path = self.base.join(name);
-        fs::write(&path, content)?;
+        let mut bytes = Vec::with_capacity(2 + content.len() * 2);
+        bytes.extend_from_slice(&UTF16LE_BOM);
+        bytes.extend(content.encode_utf16().flat_map(u16::to_le_bytes));
+        fs::write(&path, bytes)?;
         Ok(path)
     }
 
@@ -30,3 +52,112 @@ impl TempManager {
         Ok(())
     }
 }
+
+impl Drop for TempManager {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.base) {
+            warn!(
+                "remove temp folder failed dir={} err={err}",
+                self.base.display()
+            );
+        }
+    }
+}
+
+/// Copy a script written by a `TempManager` to `failed/<timestamp>-<name>` under the temp root,
+/// where it outlives the operation folder.
+pub fn preserve_failed(script: &Path) -> Option<PathBuf> {
+    let root = script.parent()?.parent()?;
+    let dir = root.join(FAILED_DIR);
+    fs::create_dir_all(&dir).ok()?;
+    let name = format!(
+        "{}-{}",
+        Local::now().format("%Y%m%d-%H%M%S"),
+        script.file_name()?.to_string_lossy()
+    );
+    let kept = dir.join(name);
+    fs::copy(script, &kept).ok()?;
+    Some(kept)
+}
+
+/// Remove failed scripts older than `retention_days` and stale leftovers of other operations
+/// from the temp root.
+pub fn clean(root: &Path, retention_days: u32) -> Result<TempCleanup> {
+    let retention = Duration::from_secs(u64::from(retention_days) * 24 * 60 * 60);
+    let failed = root.join(FAILED_DIR);
+    let mut cleanup = TempCleanup::default();
+    sweep(&failed, retention, None, &mut cleanup)?;
+    sweep(root, STALE_AFTER, Some(&failed), &mut cleanup)?;
+    Ok(cleanup)
+}
+
+fn sweep(dir: &Path, age: Duration, skip: Option<&Path>, cleanup: &mut TempCleanup) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries.flatten() {
+        if skip != Some(entry.path().as_path()) {
+            remove_if_older(&entry.path(), age, cleanup);
+        }
+    }
+    Ok(())
+}
+
+fn remove_if_older(path: &Path, age: Duration, cleanup: &mut TempCleanup) {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return;
+    };
+    let cutoff = SystemTime::now() - age;
+    if !meta.modified().is_ok_and(|modified| modified < cutoff) {
+        return;
+    }
+    let size = if meta.is_dir() {
+        dir_size(path)
+    } else {
+        meta.len()
+    };
+    let removed = if meta.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match removed {
+        Ok(()) => {
+            cleanup.removed += 1;
+            cleanup.freed_bytes += size;
+        }
+        Err(err) => warn!("remove temp entry failed path={} err={err}", path.display()),
+    }
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| match entry.metadata() {
+                    Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+                    Ok(meta) => meta.len(),
+                    Err(_) => 0,
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Read back a script written by `TempManager::write_script`; plain UTF-8 files are accepted too.
+pub fn read_script(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)?;
+    match bytes.strip_prefix(&UTF16LE_BOM) {
+        Some(body) => {
+            let units: Vec<u16> = body
+                .chunks_exact(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .collect();
+            Ok(String::from_utf16_lossy(&units))
+        }
+        None => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+    }
+}
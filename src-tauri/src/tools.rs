@@ -0,0 +1,173 @@
+#[cfg(windows)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+/// User overrides for the external tools; `None` means "resolve automatically".
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolPaths {
+    pub dism: Option<String>,
+    pub bcdboot: Option<String>,
+    pub bcdedit: Option<String>,
+    pub diskpart: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolSource {
+    Override,
+    System,
+    Adk,
+    Path,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolStatus {
+    pub name: String,
+    pub path: String,
+    pub source: ToolSource,
+    pub exists: bool,
+    /// Other installations found on this machine (e.g. the ADK copy of DISM).
+    pub candidates: Vec<String>,
+}
+
+const MANAGED_TOOLS: [&str; 4] = ["dism", "bcdboot", "bcdedit", "diskpart"];
+
+/// Tools without an `/English` switch whose output language follows the console code page.
+#[cfg(windows)]
+const CONSOLE_LOCALIZED_TOOLS: [&str; 2] = ["bcdedit", "diskpart"];
+
+static OVERRIDES: OnceCell<RwLock<ToolPaths>> = OnceCell::new();
+
+/// Replace the active overrides, typically after loading or saving settings.
+pub fn set_overrides(paths: ToolPaths) {
+    let lock = OVERRIDES.get_or_init(|| RwLock::new(ToolPaths::default()));
+    *lock.write().expect("tool overrides poisoned") = paths;
+}
+
+/// Map a program name to the executable that should be launched.
+pub fn resolve(program: &str) -> String {
+    let name = tool_name(program);
+    if let Some(path) = override_for(&name) {
+        return path;
+    }
+    if MANAGED_TOOLS.contains(&name.as_str()) {
+        let system = system_tool_path(&name);
+        if system.exists() {
+            return system.to_string_lossy().to_string();
+        }
+    }
+    program.to_string()
+}
+
+/// Report where each managed tool resolves to and which alternatives exist.
+pub fn detect() -> Vec<ToolStatus> {
+    MANAGED_TOOLS
+        .iter()
+        .map(|name| {
+            let system = system_tool_path(name);
+            let mut candidates = Vec::new();
+            if system.exists() {
+                candidates.push(system.to_string_lossy().to_string());
+            }
+            if *name == "dism" {
+                if let Some(adk) = adk_dism_path().filter(|p| p.exists()) {
+                    candidates.push(adk.to_string_lossy().to_string());
+                }
+            }
+
+            let (path, source) = if let Some(path) = override_for(name) {
+                let source = if is_adk_path(&path) {
+                    ToolSource::Adk
+                } else {
+                    ToolSource::Override
+                };
+                (path, source)
+            } else if system.exists() {
+                (system.to_string_lossy().to_string(), ToolSource::System)
+            } else {
+                (format!("{name}.exe"), ToolSource::Path)
+            };
+            let exists = matches!(source, ToolSource::Path) || PathBuf::from(&path).exists();
+            ToolStatus {
+                name: name.to_string(),
+                path,
+                source,
+                exists,
+                candidates,
+            }
+        })
+        .collect()
+}
+
+/// DISM shipped with the Windows ADK Deployment Tools, which understands newer image formats.
+pub fn adk_dism_path() -> Option<PathBuf> {
+    adk_root().map(|root| {
+        root.join("Deployment Tools")
+            .join(adk_arch())
+            .join("DISM")
+            .join("dism.exe")
+    })
+}
+
+/// Root of the Windows ADK installation, if present.
+pub fn adk_root() -> Option<PathBuf> {
+    let base = std::env::var("ProgramFiles(x86)")
+        .or_else(|_| std::env::var("ProgramFiles"))
+        .ok()?;
+    let root = PathBuf::from(base)
+        .join("Windows Kits")
+        .join("10")
+        .join("Assessment and Deployment Kit");
+    root.exists().then_some(root)
+}
+
+fn adk_arch() -> &'static str {
+    if cfg!(target_arch = "aarch64") {
+        "arm64"
+    } else {
+        "amd64"
+    }
+}
+
+fn is_adk_path(path: &str) -> bool {
+    path.to_ascii_lowercase()
+        .contains("assessment and deployment kit")
+}
+
+fn override_for(name: &str) -> Option<String> {
+    let lock = OVERRIDES.get()?;
+    let paths = lock.read().expect("tool overrides poisoned");
+    let value = match name {
+        "dism" => paths.dism.clone(),
+        "bcdboot" => paths.bcdboot.clone(),
+        "bcdedit" => paths.bcdedit.clone(),
+        "diskpart" => paths.diskpart.clone(),
+        _ => None,
+    };
+    value.filter(|p| !p.trim().is_empty())
+}
+
+/// Whether `program` (a name or a resolved path) picks its output language from the console.
+#[cfg(windows)]
+pub fn localizes_by_console(program: &str) -> bool {
+    let file = Path::new(program)
+        .file_name()
+        .map_or_else(|| program.to_string(), |f| f.to_string_lossy().to_string());
+    CONSOLE_LOCALIZED_TOOLS.contains(&tool_name(&file).as_str())
+}
+
+fn system_tool_path(name: &str) -> PathBuf {
+    let system_root = std::env::var("SystemRoot").unwrap_or_else(|_| "C:\\Windows".into());
+    PathBuf::from(system_root)
+        .join("System32")
+        .join(format!("{name}.exe"))
+}
+
+fn tool_name(program: &str) -> String {
+    let lower = program.to_ascii_lowercase();
+    lower.strip_suffix(".exe").unwrap_or(&lower).to_string()
+}
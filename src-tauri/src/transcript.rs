@@ -0,0 +1,189 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::error::Result;
+use crate::redact::redact;
+use crate::sys::{shown_args, CommandOutput, Secrets};
+
+thread_local! {
+    /// Transcript collecting the commands issued on this thread, if a workspace is open.
+    static CURRENT: RefCell<Option<Transcript>> = const { RefCell::new(None) };
+}
+
+/// Full record of the commands behind one operation, kept in `meta/transcripts/<op id>/` with one
+/// file per command or script. Commands are written to a pending folder until the operation is
+/// logged, which then renames it after the operation's id.
+struct Transcript {
+    root: PathBuf,
+    id: String,
+    seq: u32,
+}
+
+impl Transcript {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            id: Uuid::new_v4().to_string(),
+            seq: 0,
+        }
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.root.join(&self.id)
+    }
+
+    fn write(&mut self, name: &str, content: &str) {
+        let dir = self.dir();
+        self.seq += 1;
+        let file = dir.join(format!("{:03}-{}.txt", self.seq, file_part(name)));
+        let content = redact(content);
+        if let Err(err) =
+            fs::create_dir_all(&dir).and_then(|_| fs::write(&file, content.as_bytes()))
+        {
+            warn!("write transcript failed file={} err={err}", file.display());
+        }
+    }
+}
+
+/// Stops collecting when dropped, restoring the transcript that was active before.
+pub struct TranscriptGuard {
+    previous: Option<Transcript>,
+}
+
+impl Drop for TranscriptGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Collect transcripts of the commands issued on this thread under `root` until the guard is
+/// dropped; `None` (no workspace open) collects nothing.
+pub fn begin(root: Option<PathBuf>) -> TranscriptGuard {
+    TranscriptGuard {
+        previous: CURRENT.with(|current| current.replace(root.map(Transcript::new))),
+    }
+}
+
+/// Record a finished command with its complete output; `secrets` are masked and a secret output
+/// is left out.
+pub fn record_command(program: &str, args: &[&str], output: &CommandOutput, secrets: Secrets<'_>) {
+    let name = Path::new(program)
+        .file_stem()
+        .map_or_else(|| program.to_string(), |s| s.to_string_lossy().to_string());
+    let mut content = format!(
+        "> {program} {}\r\n{}\r\n",
+        shown_args(args, secrets).join(" "),
+        output.status_text()
+    );
+    if secrets.output {
+        content.push_str("\r\n(output withheld)\r\n");
+        with_current(|transcript| transcript.write(&name, &content));
+        return;
+    }
+    for (label, text) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        if !text.trim().is_empty() {
+            content.push_str(&format!("\r\n--- {label} ---\r\n{}\r\n", text.trim_end()));
+        }
+    }
+    with_current(|transcript| transcript.write(&name, &content));
+}
+
+/// Record a script (e.g. for diskpart) before it is run.
+pub fn record_script(path: &Path, content: &str) {
+    let name = format!(
+        "script-{}",
+        path.file_stem().unwrap_or_default().to_string_lossy()
+    );
+    let content = format!("# {}\r\n{content}", path.display());
+    with_current(|transcript| transcript.write(&name, &content));
+}
+
+/// Folder of the transcript being written, once something has been recorded in it.
+pub fn current_dir() -> Option<PathBuf> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .filter(|t| t.seq > 0)
+            .map(Transcript::dir)
+    })
+}
+
+/// Hand the commands recorded so far to operation `op_id`, moving them to
+/// `meta/transcripts/<op_id>/`, and start a new transcript for whatever runs next. Returns the
+/// folder name, or `None` when nothing was recorded; the pending folder keeps its name when it
+/// cannot be moved.
+pub fn take_for_op(op_id: &str) -> Option<String> {
+    let taken = CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let transcript = current.as_mut().filter(|t| t.seq > 0)?;
+        let next = Transcript::new(transcript.root.clone());
+        Some(std::mem::replace(transcript, next))
+    })?;
+    let target = taken.root.join(file_part(op_id));
+    match fs::rename(taken.dir(), &target) {
+        Ok(()) => {
+            info!("transcript {} saved as {}", taken.id, target.display());
+            Some(file_part(op_id))
+        }
+        Err(err) => {
+            warn!(
+                "move transcript failed from={} to={} err={err}",
+                taken.dir().display(),
+                target.display()
+            );
+            Some(taken.id)
+        }
+    }
+}
+
+/// Delete transcripts last written more than `retention_days` ago; returns the removed ids.
+pub fn prune(root: &Path, retention_days: u32) -> Result<Vec<String>> {
+    let cutoff = SystemTime::now() - Duration::from_secs(u64::from(retention_days) * 24 * 60 * 60);
+    let mut removed = Vec::new();
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(removed),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries.flatten() {
+        let modified = entry.metadata().and_then(|m| m.modified());
+        if modified.is_ok_and(|modified| modified < cutoff) {
+            match fs::remove_dir_all(entry.path()) {
+                Ok(()) => removed.push(entry.file_name().to_string_lossy().to_string()),
+                Err(err) => warn!(
+                    "remove transcript failed dir={} err={err}",
+                    entry.path().display()
+                ),
+            }
+        }
+    }
+    Ok(removed)
+}
+
+fn with_current(f: impl FnOnce(&mut Transcript)) {
+    CURRENT.with(|current| {
+        if let Some(transcript) = current.borrow_mut().as_mut() {
+            f(transcript);
+        }
+    });
+}
+
+/// Keep a tool or script name usable as part of a file name.
+fn file_part(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
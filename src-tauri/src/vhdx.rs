@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::error::{AppError, Result};
+
+/// Offset of the first region table in a VHDX file.
+const REGION_TABLE_OFFSET: u64 = 192 * 1024;
+
+/// Region GUID `8B7CA206-4790-4B9A-B8FE-575F050F886E` (metadata region), on-disk byte order.
+const METADATA_REGION_GUID: [u8; 16] = [
+    0x06, 0xA2, 0x7C, 0x8B, 0x90, 0x47, 0x9A, 0x4B, 0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E,
+];
+
+/// Metadata item GUID `2FA54224-CD1B-4876-B211-5DBED83BF4B8` (virtual disk size), on-disk byte order.
+const VIRTUAL_DISK_SIZE_GUID: [u8; 16] = [
+    0x24, 0x42, 0xA5, 0x2F, 0x1B, 0xCD, 0x76, 0x48, 0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8,
+];
+
+/// Read the virtual disk size (capacity seen by the guest) from a VHDX header without attaching it.
+pub fn virtual_size(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)?;
+
+    let mut ident = [0u8; 8];
+    file.read_exact(&mut ident)?;
+    if &ident != b"vhdxfile" {
+        return Err(invalid(path, "missing vhdxfile signature"));
+    }
+
+    let mut table = vec![0u8; 64 * 1024];
+    file.seek(SeekFrom::Start(REGION_TABLE_OFFSET))?;
+    file.read_exact(&mut table)?;
+    if &table[0..4] != b"regi" {
+        return Err(invalid(path, "missing region table"));
+    }
+    let region_count = u32_at(&table, 8) as usize;
+    let metadata_offset = (0..region_count)
+        .map(|i| 16 + i * 32)
+        .take_while(|off| off + 32 <= table.len())
+        .find(|&off| table[off..off + 16] == METADATA_REGION_GUID)
+        .map(|off| u64_at(&table, off + 16))
+        .ok_or_else(|| invalid(path, "metadata region not found"))?;
+
+    let mut metadata = vec![0u8; 64 * 1024];
+    file.seek(SeekFrom::Start(metadata_offset))?;
+    file.read_exact(&mut metadata)?;
+    if &metadata[0..8] != b"metadata" {
+        return Err(invalid(path, "missing metadata table"));
+    }
+    let entry_count = u16_at(&metadata, 10) as usize;
+    let item_offset = (0..entry_count)
+        .map(|i| 32 + i * 32)
+        .take_while(|off| off + 32 <= metadata.len())
+        .find(|&off| metadata[off..off + 16] == VIRTUAL_DISK_SIZE_GUID)
+        .map(|off| u32_at(&metadata, off + 16) as u64)
+        .ok_or_else(|| invalid(path, "virtual disk size not found"))?;
+
+    let mut size = [0u8; 8];
+    file.seek(SeekFrom::Start(metadata_offset + item_offset))?;
+    file.read_exact(&mut size)?;
+    Ok(u64::from_le_bytes(size))
+}
+
+fn invalid(path: &Path, reason: &str) -> AppError {
+    AppError::Message(format!("invalid vhdx {}: {reason}", path.display()))
+}
+
+fn u16_at(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([buf[off], buf[off + 1]])
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap_or_default())
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap_or_default())
+}
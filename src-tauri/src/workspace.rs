@@ -1,44 +1,146 @@
-use std::collections::{HashMap, VecDeque};
-use std::ffi::OsStr;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Write};
 use std::iter::once;
+use std::net::IpAddr;
+#[cfg(windows)]
 use std::os::windows::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use chrono::{DateTime, Utc};
-use tracing::info;
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 use uuid::Uuid;
 
+use crate::audit;
 use crate::bcd::{
-    bcdedit_boot_sequence, bcdedit_delete, bcdedit_enum_all, bcdedit_set_description,
-    extract_guid_for_partition_letter, extract_guid_for_vhd, run_bcdboot, run_bcdboot_to_efi,
+    bcdedit_boot_sequence, bcdedit_clear_boot_sequence, bcdedit_create, bcdedit_delete,
+    bcdedit_display_order_add_last, bcdedit_enum_all, bcdedit_enum_entry, bcdedit_enum_firmware,
+    bcdedit_set, bcdedit_set_description, extract_guid_for_partition_letter, extract_guid_for_vhd,
+    parse_bcd_objects, parse_created_guid, parse_firmware_entries, run_bcdboot, run_bcdboot_to_efi,
+    verify_boot_files, vhd_boot_entries,
 };
+use crate::bitlocker;
+use crate::confirm;
 use crate::db::Database;
 use crate::diskpart::{
-    assign_partitions_script, attach_list_vdisk_script, base_diskpart_script, detach_vdisk_script,
-    detail_vdisk_script, diff_attach_list_script, parse_detail_vdisk_parent, parse_list_partition,
-    run_diskpart_script,
+    assign_partitions_script, attach_list_vdisk_script, base_diskpart_script, compact_vdisk_script,
+    detach_vdisk_script, detail_vdisk_script, diff_attach_assign_script, parse_detail_vdisk,
+    parse_list_partition, reassign_partition_script, reset_disk_id_script, run_diskpart_script,
+    PartitionInfo, BASE_SYSTEM_PARTITION,
 };
-use crate::dism::{apply_image, list_images};
-use crate::error::{AppError, Result};
-use crate::models::{Node, NodeStatus, WimImageInfo};
-use crate::paths::AppPaths;
+use crate::dism::{
+    add_driver, add_package, add_provisioned_appx, add_provisioning_package, apply_image,
+    capture_image, cleanup_component_store as dism_cleanup_component_store, enable_feature,
+    export_image, get_drivers, get_features, get_packages, get_provisioned_appx, image_os_info,
+    list_images, parse_drivers_table, parse_features_table, parse_packages_table,
+    parse_provisioned_appx, remove_provisioned_appx, set_all_intl,
+    set_product_key as dism_set_product_key,
+};
+use crate::error::{AppError, CommandErrorKind, Result};
+use crate::graph;
+use crate::handles;
+use crate::jobs;
+use crate::models::{
+    AttachEvent, AuditFormat, AuditRange, BackupStatus, BootEntryFlags, BootMenuPolicy,
+    BootStatusPolicy, ChainIssue, ChainIssueKind, ChainStats, CompactionHint, ConfirmationRequest,
+    CreatePreflight, CreatePreflightParams, DuplicateBases, DuplicateReport, EstimateOp,
+    FirmwareBootInfo, GraphFormat, HealthCategory, HealthFinding, IssueSeverity, JobRecord,
+    LayerDriver, LayerFeature, LayerPackage, LibraryEntry, LinkChange, LinkRepair, LocalAdmin,
+    MetadataImport, NetworkAddressing, NetworkProfile, Node, NodeDetails, NodeHash, NodeMetadata,
+    NodeOperation, NodePage, NodeQuery, NodeStatus, OsInfo, PreflightCheck, ProvisioningScript,
+    Recipe, ReclaimResult, RecreatedLayer, RefreshFailure, RefreshResult, RegistryPreset,
+    RegistryTweak, RepairReport, SavedView, SecureBootState, SpaceEstimate, SwapFileOptions,
+    TempCleanup, VhdMetrics, VhdState, WimImageInfo, WimSource, WimSourceStatus, WorkspaceMetadata,
+    WorkspaceStats,
+};
+use crate::paths::{expand_env_vars, is_within, normalize_path, AppPaths};
+use crate::preflight;
+use crate::redact;
+use crate::reg_offline::{
+    add_run_once, apply_tweaks, autologon_tweaks, boot_command_tweaks, read_os_info,
+    set_autologon_password, validate_computer_name, validate_tweaks, validate_user_name,
+};
+use crate::secureboot;
 use crate::state::SharedState;
-use crate::sys::{run_elevated_command, CommandOutput};
-use crate::temp::TempManager;
-use windows_sys::Win32::Storage::FileSystem::{GetLogicalDrives, QueryDosDeviceW};
+use crate::storage;
+use crate::sys::{
+    default_runner, install_runner, run_elevated_command, run_powershell, CommandOutput,
+    CommandRunner, RunnerGuard,
+};
+use crate::temp::{self, read_script, TempManager};
+use crate::tools;
+use crate::transcript::{self, TranscriptGuard};
+use crate::vhdx;
+#[cfg(windows)]
+use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+/// Task Scheduler name used for deferred reboots into a layer.
+const SCHEDULED_BOOT_TASK: &str = "LayeredSystemScheduledBoot";
+
+/// Window counted as "recent" by the workspace statistics.
+const RECENT_OPS_DAYS: i64 = 7;
+
+/// How long a smoke-boot VM may take to write its boot marker and power off.
+const VERIFY_BOOT_TIMEOUT_SECS: u64 = 600;
+
+/// Boot menu description of the WinPE rescue entry.
+const RESCUE_DESCRIPTION: &str = "Layered System Rescue (WinPE)";
+
+/// One-shot boot service that creates the local administrator of `set_local_admin`.
+const ACCOUNT_SERVICE: &str = "LayeredSystemAccount";
+
+/// Fragmentation share from which compacting a layer is recommended.
+const COMPACT_MIN_FRAGMENTATION: u32 = 20;
+
+/// Smallest estimated saving worth a compaction hint.
+const COMPACT_MIN_SAVINGS: u64 = 1024 * 1024 * 1024;
+
+/// A parent written this long after its child was created still counts as unmodified, covering
+/// the final flush when the child is created right after the parent.
+const PARENT_WRITE_SLACK_SECS: i64 = 120;
+
+/// Waits between forced detach attempts, each after dismounting the disk's volumes again.
+const FORCE_DETACH_BACKOFF: [Duration; 4] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+    Duration::from_secs(8),
+];
+
+/// Preserved copy of the script of the most recent failed command, for bug reports.
+static LAST_FAILED_SCRIPT: Mutex<Option<PathBuf>> = Mutex::new(None);
 
 pub struct WorkspaceService {
     state: SharedState,
+    /// Keeps `runner` installed for the commands this service issues on its thread.
+    _runner: RunnerGuard,
+    /// Collects the full output of those commands for the operations they belong to.
+    _transcript: TranscriptGuard,
 }
 
 impl WorkspaceService {
     pub fn new(state: SharedState) -> Self {
-        Self { state }
+        Self::with_runner(state, default_runner())
+    }
+
+    /// Service whose external tools (diskpart, bcdedit, dism, PowerShell, ...) are run by
+    /// `runner`; lets the orchestration be exercised against a fake.
+    pub fn with_runner(state: SharedState, runner: Arc<dyn CommandRunner>) -> Self {
+        let transcripts = state.paths().ok().map(|paths| paths.transcripts_dir());
+        Self {
+            state,
+            _runner: install_runner(runner),
+            _transcript: transcript::begin(transcripts),
+        }
     }
 
-    fn db(&self) -> Result<std::sync::Arc<Database>> {
+    fn db(&self) -> Result<Arc<Database>> {
         self.state.db()
     }
 
@@ -46,6 +148,22 @@ impl WorkspaceService {
         self.state.paths()
     }
 
+    /// Fetch `node_id`, refusing with a conflict when it changed since the caller loaded
+    /// `version` (e.g. a scan re-parented it).
+    pub fn expect_version(&self, node_id: &str, version: u64) -> Result<Node> {
+        let node = self
+            .db()?
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if node.version != version {
+            return Err(AppError::Conflict(format!(
+                "{} changed since it was loaded (version {version}, now {}); reload and try again",
+                node.name, node.version
+            )));
+        }
+        Ok(node)
+    }
+
     pub fn scan(&self) -> Result<Vec<Node>> {
         let paths = self.paths()?;
         paths.ensure_layout()?;
@@ -57,7 +175,10 @@ impl WorkspaceService {
             .map(|n| (normalize_path(&n.path), n.clone()))
             .collect();
 
-        let vhd_paths = collect_vhdx_files(paths.root())?;
+        let mut vhd_paths = Vec::new();
+        for dir in paths.layer_roots() {
+            vhd_paths.extend(collect_vhdx_files(&dir)?);
+        }
         let bcd_enum = if vhd_paths.is_empty() {
             None
         } else {
@@ -72,9 +193,11 @@ impl WorkspaceService {
 
             let mut parent_normalized = None;
             let mut detail_ok = true;
+            let mut fragmentation = None;
             match self.detail_vdisk(&path_str) {
                 Ok(detail) => {
                     parent_normalized = detail.parent.map(|p| normalize_path(&p));
+                    fragmentation = detail.fragmentation;
                 }
                 Err(err) => {
                     detail_ok = false;
@@ -91,6 +214,7 @@ impl WorkspaceService {
                 normalized,
                 parent_normalized,
                 detail_ok,
+                fragmentation,
                 created_at,
                 bcd_guid,
             });
@@ -126,10 +250,19 @@ impl WorkspaceService {
                 created_at: info.created_at,
                 status: NodeStatus::Normal,
                 boot_files_ready: info.bcd_guid.is_some(),
+                ..Default::default()
             };
-            db.insert_node(&node)?;
+            let (owner_id, inserted) = db.upsert_scanned_node(&node)?;
+            if !inserted {
+                // Another row already tracks this file under a differently spelled path.
+                if let Some(owner) = db.fetch_node(&owner_id)? {
+                    existing_paths.insert(info.normalized.clone(), owner);
+                }
+                path_to_id.insert(info.normalized.clone(), owner_id);
+                continue;
+            }
             db.insert_op(
-                &Uuid::new_v4().to_string(),
+                &jobs::next_op_id(),
                 Some(&id),
                 "import_vhdx",
                 "ok",
@@ -161,6 +294,22 @@ impl WorkspaceService {
             }
         }
 
+        for info in &scanned {
+            let (Some(node_id), Some(fragmentation)) =
+                (path_to_id.get(&info.normalized), info.fragmentation)
+            else {
+                continue;
+            };
+            if let Ok(meta) = fs::metadata(&info.path) {
+                db.upsert_vhd_metrics(&VhdMetrics {
+                    node_id: node_id.clone(),
+                    file_bytes: meta.len(),
+                    fragmentation,
+                    measured_at: Utc::now(),
+                })?;
+            }
+        }
+
         let latest_nodes = db.fetch_nodes()?;
         let detail_lookup: HashMap<String, (Option<String>, bool)> = scanned
             .into_iter()
@@ -172,6 +321,9 @@ impl WorkspaceService {
             .collect();
 
         for n in latest_nodes.iter() {
+            if n.status.in_progress() || n.status == NodeStatus::Archived {
+                continue;
+            }
             let normalized = normalize_path(&n.path);
             let mut status = NodeStatus::Normal;
             if !Path::new(&n.path).exists() {
@@ -192,701 +344,6437 @@ impl WorkspaceService {
             info!("scan node={} status={:?}", n.id, status);
         }
 
-        Ok(db.fetch_nodes()?)
+        for n in latest_nodes.iter() {
+            let complete = n.os_build.is_some() && n.os_version.is_some();
+            if complete || n.status.in_progress() || !Path::new(&n.path).exists() {
+                continue;
+            }
+            if db.node_os_probed(&n.id)? {
+                continue;
+            }
+            match image_os_info(&n.path) {
+                Ok(Some(os_info)) => db.update_node_os_info(&n.id, &os_info)?,
+                Ok(None) => info!("image info unavailable node={}", n.id),
+                Err(err) => info!("image info failed node={} err={err}", n.id),
+            }
+            db.set_node_os_probed(&n.id, Utc::now())?;
+        }
+
+        self.collect_boot_markers()?;
+        let nodes = self.annotated_nodes()?;
+        warn_deep_chains(&nodes);
+        Ok(nodes)
     }
 
     /// Lightweight fetch without validation; used by UI refresh to avoid slow diskpart checks.
     pub fn list_nodes(&self) -> Result<Vec<Node>> {
-        self.db()?.fetch_nodes()
+        self.collect_boot_markers()?;
+        self.annotated_nodes()
     }
 
-    pub fn list_wim_images(&self, image_path: &str) -> Result<Vec<WimImageInfo>> {
-        list_images(image_path)
+    /// Filtered, paged view of `list_nodes` for large workspaces. Unlike `list_nodes` this does
+    /// not collect boot markers, so it is cheap enough to call on every scroll.
+    pub fn query_nodes(&self, query: NodeQuery) -> Result<NodePage> {
+        let name = query
+            .name
+            .map(|n| n.trim().to_lowercase())
+            .filter(|n| !n.is_empty());
+        let tag = query
+            .tag
+            .map(|t| t.trim().to_lowercase())
+            .filter(|t| !t.is_empty());
+        let idle_cutoff = query
+            .idle_days
+            .map(|days| Utc::now() - chrono::Duration::days(i64::from(days)));
+        let matches: Vec<Node> = self
+            .annotated_nodes()?
+            .into_iter()
+            .filter(|n| {
+                query
+                    .statuses
+                    .as_ref()
+                    .is_none_or(|statuses| statuses.contains(&n.status))
+            })
+            .filter(|n| {
+                query
+                    .exclude_statuses
+                    .as_ref()
+                    .is_none_or(|statuses| !statuses.contains(&n.status))
+            })
+            .filter(|n| tag.as_ref().is_none_or(|t| n.tags.contains(t)))
+            .filter(|n| {
+                name.as_ref()
+                    .is_none_or(|needle| n.name.to_lowercase().contains(needle))
+            })
+            .filter(|n| {
+                idle_cutoff.is_none_or(|cutoff| n.boot_verified_at.is_none_or(|at| at < cutoff))
+            })
+            .collect();
+        let total = matches.len();
+        let nodes = matches
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect();
+        Ok(NodePage {
+            nodes,
+            total,
+            offset: query.offset,
+        })
     }
 
-    pub fn create_base(
-        &self,
-        name: &str,
-        desc: Option<String>,
-        wim_file: &str,
-        wim_index: u32,
-        size_gb: u64,
-    ) -> Result<Node> {
-        let paths = self.paths()?;
-        paths.ensure_layout()?;
-        let db = self.db()?;
-        let seq = db.next_seq()?;
-        let id = Uuid::new_v4().to_string();
-        let filename = format!("{seq:04}-{slug}.vhdx", slug = name.to_lowercase());
-        let vhd_path = paths.base_dir().join(filename);
-
-        let temp = TempManager::new(paths.tmp_dir())?;
-        fs::create_dir_all(paths.mount_root())?;
-        let letters = pick_free_letters(2).ok_or_else(|| {
-            AppError::Message("no free drive letter available between S: and Z:".into())
-        })?;
-        let efi_letter = letters[0];
-        let sys_letter = letters[1];
-
-        let script = base_diskpart_script(&vhd_path, size_gb, efi_letter, sys_letter);
-        let script_path = temp.write_script("create_base.txt", &script)?;
-        log_diskpart_script(&script_path);
-        let create_res = run_diskpart_script(&script_path)?;
-        log_command("diskpart create base", &create_res, Some(&script_path));
-
-        if create_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error(
-                "diskpart create base",
-                &create_res,
-                Some(&script_path),
-            ));
-        }
+    pub fn list_views(&self) -> Result<Vec<SavedView>> {
+        self.db()?.fetch_saved_views()
+    }
 
-        let dism_res = apply_image(wim_file, wim_index, &format!("{sys_letter}:\\"))?;
-        log_command("dism apply", &dism_res, None);
-        if dism_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("dism apply", &dism_res, None));
+    /// Create a saved view, or replace the name and filter of `id`. Paging in `query` is not
+    /// kept; `apply_view` takes it per call.
+    pub fn save_view(&self, id: Option<&str>, name: &str, query: NodeQuery) -> Result<SavedView> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AppError::Message("view name cannot be empty".into()));
         }
-
-        let sys_mount = PathBuf::from(format!("{sys_letter}:"));
-        let efi_mount = PathBuf::from(format!("{efi_letter}:"));
-        let bcd_efi_res = run_bcdboot_to_efi(&sys_mount, &efi_mount)?;
-        log_command("bcdboot efi", &bcd_efi_res, None);
-        if bcd_efi_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("bcdboot", &bcd_efi_res, None));
+        let db = self.db()?;
+        let views = db.fetch_saved_views()?;
+        if views
+            .iter()
+            .any(|v| Some(v.id.as_str()) != id && v.name.eq_ignore_ascii_case(name))
+        {
+            return Err(AppError::Message(format!(
+                "a view named {name} already exists"
+            )));
         }
+        let created_at = match id {
+            Some(id) => {
+                views
+                    .iter()
+                    .find(|v| v.id == id)
+                    .ok_or_else(|| AppError::Message("view not found".into()))?
+                    .created_at
+            }
+            None => Utc::now(),
+        };
+        let view = SavedView {
+            id: id.map_or_else(|| Uuid::new_v4().to_string(), str::to_string),
+            name: name.to_string(),
+            query: NodeQuery {
+                offset: 0,
+                limit: None,
+                ..query
+            },
+            created_at,
+        };
+        db.upsert_saved_view(&view)?;
+        info!("save_view id={} name={}", view.id, view.name);
+        Ok(view)
+    }
 
-        let bcd_res = run_bcdboot(&sys_mount)?;
-        log_command("bcdboot", &bcd_res, None);
-        if bcd_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("bcdboot", &bcd_res, None));
+    pub fn delete_view(&self, view_id: &str) -> Result<()> {
+        if !self.db()?.delete_saved_view(view_id)? {
+            return Err(AppError::Message("view not found".into()));
         }
+        info!("delete_view id={view_id}");
+        Ok(())
+    }
 
-        let bcd_enum = bcdedit_enum_all()?;
-        log_command("bcdedit enum", &bcd_enum, None);
-        let guid = extract_guid_for_vhd(&bcd_enum.stdout, vhd_path.to_str().unwrap_or_default())
-            .or_else(|| extract_guid_for_partition_letter(&bcd_enum.stdout, sys_letter))
-            .unwrap_or_default();
+    /// Run a saved view's filter through `query_nodes` with the given paging.
+    pub fn apply_view(
+        &self,
+        view_id: &str,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<NodePage> {
+        let view = self
+            .db()?
+            .fetch_saved_views()?
+            .into_iter()
+            .find(|v| v.id == view_id)
+            .ok_or_else(|| AppError::Message("view not found".into()))?;
+        self.query_nodes(NodeQuery {
+            offset,
+            limit,
+            ..view.query
+        })
+    }
 
-        let detach_script = detach_vdisk_script(&vhd_path, &[sys_letter, efi_letter]);
-        let detach_path = temp.write_script("detach_base.txt", &detach_script)?;
-        log_diskpart_script(&detach_path);
-        let detach_res = run_diskpart_script(&detach_path)?;
-        log_command("diskpart detach base", &detach_res, Some(&detach_path));
+    /// Hash of every stored node row; changes whenever any node is added, removed or updated.
+    pub fn nodes_digest(&self) -> Result<String> {
+        let nodes = self.db()?.fetch_nodes()?;
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(&nodes)?.hash(&mut hasher);
+        Ok(format!("{:016x}", hasher.finish()))
+    }
 
-        let node = Node {
-            id: id.clone(),
-            parent_id: None,
-            name: name.to_string(),
-            path: vhd_path.to_string_lossy().to_string(),
-            bcd_guid: if guid.is_empty() {
-                None
-            } else {
-                Some(guid.clone())
-            },
-            desc,
-            created_at: Utc::now(),
-            status: NodeStatus::Normal,
-            boot_files_ready: !guid.is_empty(),
-        };
+    /// Replace the node's tags; tags are trimmed, lowercased and deduplicated.
+    pub fn set_node_tags(&self, node_id: &str, tags: Vec<String>) -> Result<Node> {
+        let db = self.db()?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let mut normalized: Vec<String> = Vec::new();
+        for tag in tags {
+            let tag = tag.trim().to_lowercase();
+            if tag.is_empty() {
+                continue;
+            }
+            let valid = tag.len() <= 32
+                && tag
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+            if !valid {
+                return Err(AppError::Message(format!("invalid tag: {tag}")));
+            }
+            if !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+        db.update_node_tags(node_id, &normalized)?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
+    }
 
-        db.insert_node(&node)?;
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            Some(&id),
-            "create_base",
-            "ok",
-            "",
-        )?;
-        info!("create_base id={id} path={}", node.path);
-        Ok(node)
+    /// Set or clear the node's expiration time and protected flag.
+    pub fn set_node_expiry(
+        &self,
+        node_id: &str,
+        expires_at: Option<DateTime<Utc>>,
+        protected: bool,
+    ) -> Result<Node> {
+        let db = self.db()?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        db.update_node_expiry(node_id, expires_at, protected)?;
+        info!("set_node_expiry id={node_id} expires_at={expires_at:?} protected={protected}");
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
     }
 
-    pub fn create_diff(&self, parent_id: &str, name: &str, desc: Option<String>) -> Result<Node> {
+    /// All nodes with chain depth, ancestry and expiration filled in.
+    fn annotated_nodes(&self) -> Result<Vec<Node>> {
         let db = self.db()?;
-        let parent = db
-            .fetch_node(parent_id)?
-            .ok_or_else(|| AppError::Message("parent not found".into()))?;
+        let settings = db.get_settings()?;
+        let mut nodes = db.fetch_nodes()?;
+        annotate_chains(&mut nodes, settings.max_chain_depth);
+        annotate_expiry(&mut nodes, &settings.maintenance.tag_idle_days);
+        Ok(nodes)
+    }
+
+    /// Report expired layers, or with `delete` remove the expired leaves that are not
+    /// protected. Layers with children are only reported; their children expire first.
+    pub fn expire_layers(&self, delete: bool) -> Result<String> {
+        let nodes = self.annotated_nodes()?;
+        let (mut expired, mut deleted, mut kept, mut failed) = (0, 0, 0, 0);
+        for node in nodes.iter().filter(|n| n.expired) {
+            expired += 1;
+            let leaf = !nodes
+                .iter()
+                .any(|c| c.parent_id.as_deref() == Some(&node.id));
+            if !delete || node.protected || !leaf || node.status != NodeStatus::Normal {
+                kept += 1;
+                info!("expired layer kept node={} name={}", node.id, node.name);
+                continue;
+            }
+            match self.delete_subtree(&node.id) {
+                Ok(()) => {
+                    deleted += 1;
+                    info!("expired layer deleted node={} name={}", node.id, node.name);
+                }
+                Err(err) => {
+                    failed += 1;
+                    warn!("expired layer delete failed node={} err={err}", node.id);
+                }
+            }
+        }
+        Ok(format!(
+            "expired={expired} deleted={deleted} kept={kept} failed={failed}"
+        ))
+    }
+
+    pub fn get_workspace_stats(&self) -> Result<WorkspaceStats> {
         let paths = self.paths()?;
-        paths.ensure_layout()?;
-        let seq = db.next_seq()?;
-        let id = Uuid::new_v4().to_string();
-        let filename = format!("{seq:04}-{slug}.vhdx", slug = name.to_lowercase());
+        let db = self.db()?;
+        let nodes = self.annotated_nodes()?;
 
-        let parent_path = Path::new(&parent.path);
-        let parent_dir = parent_path
-            .parent()
-            .ok_or_else(|| AppError::Message(format!("invalid parent path: {}", parent.path)))?;
-        let vhd_path = parent_dir.join(filename);
+        let mut stats = WorkspaceStats {
+            node_count: nodes.len(),
+            ..Default::default()
+        };
+        let mut chains: Vec<ChainStats> = Vec::new();
+        for node in &nodes {
+            let status = serde_json::to_value(&node.status)
+                .ok()
+                .and_then(|v| v.as_str().map(str::to_string))
+                .unwrap_or_default();
+            *stats.status_counts.entry(status).or_default() += 1;
 
-        let temp = TempManager::new(paths.tmp_dir())?;
-        let sys_letter = pick_free_letter().ok_or_else(|| {
-            AppError::Message("no free drive letter available between S: and Z:".into())
-        })?;
+            let vhd_path = Path::new(&node.path);
+            let file_bytes = fs::metadata(vhd_path).map(|m| m.len()).unwrap_or(0);
+            let virtual_bytes = if file_bytes > 0 {
+                vhdx::virtual_size(vhd_path).unwrap_or_else(|err| {
+                    info!("read virtual size failed node={} err={err}", node.id);
+                    0
+                })
+            } else {
+                0
+            };
+            stats.file_bytes += file_bytes;
+            stats.virtual_bytes += virtual_bytes;
 
-        let attach_script = diff_attach_list_script(&vhd_path, Path::new(&parent.path));
-        let attach_path = temp.write_script("create_diff.txt", &attach_script)?;
-        log_diskpart_script(&attach_path);
-        let attach_res = run_diskpart_script(&attach_path)?;
-        log_command("diskpart create diff", &attach_res, Some(&attach_path));
-        if attach_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error(
-                "diskpart create diff",
-                &attach_res,
-                Some(&attach_path),
-            ));
+            let idx = match chains.iter().position(|c| c.root_id == node.root_id) {
+                Some(idx) => idx,
+                None => {
+                    let root_name = nodes
+                        .iter()
+                        .find(|n| n.id == node.root_id)
+                        .map(|n| n.name.clone())
+                        .unwrap_or_default();
+                    chains.push(ChainStats {
+                        root_id: node.root_id.clone(),
+                        root_name,
+                        ..Default::default()
+                    });
+                    chains.len() - 1
+                }
+            };
+            let chain = &mut chains[idx];
+            chain.node_count += 1;
+            chain.file_bytes += file_bytes;
+            chain.virtual_bytes += virtual_bytes;
         }
+        stats.chains = chains;
 
-        let parts = parse_list_partition(&attach_res.stdout);
-        let sys_part = parts
-            .iter()
-            .find(|p| p.kind.eq_ignore_ascii_case("Primary"))
-            .map(|p| p.index)
-            .or_else(|| {
-                parts
-                    .iter()
-                    .find(|p| p.kind.eq_ignore_ascii_case("Basic"))
-                    .map(|p| p.index)
-            });
-        let efi_part = parts
+        if let Some((free, total)) = volume_space(paths.root()) {
+            stats.volume_free_bytes = Some(free);
+            stats.volume_total_bytes = Some(total);
+        }
+
+        let since = Utc::now() - chrono::Duration::days(RECENT_OPS_DAYS);
+        stats.recent_ops.since = since;
+        for (action, result, count) in db.count_ops_since(since)? {
+            stats.recent_ops.total += count;
+            if result != "ok" {
+                stats.recent_ops.failed += count;
+            }
+            *stats.recent_ops.by_action.entry(action).or_default() += count;
+        }
+        Ok(stats)
+    }
+
+    /// Estimate the extra space a merge, clone or flatten of `node_id` would need.
+    pub fn estimate_operation(&self, op: EstimateOp, node_id: &str) -> Result<SpaceEstimate> {
+        let paths = self.paths()?;
+        let nodes = self.annotated_nodes()?;
+        let node = nodes
             .iter()
-            .find(|p| p.kind.eq_ignore_ascii_case("System"))
-            .map(|p| p.index)
-            .or_else(|| parts.iter().find(|p| p.index == 2).map(|p| p.index));
+            .find(|n| n.id == node_id)
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let file_size = |n: &Node| fs::metadata(&n.path).map(|m| m.len()).unwrap_or(0);
 
-        let (sys_part, efi_part) = match (sys_part, efi_part) {
-            (Some(s), Some(e)) => (s, e),
-            _ => {
-                return Err(AppError::Message(
-                    "failed to detect system/EFI partitions from list partition".into(),
-                ))
+        let required_bytes = match op {
+            // Every block of the child may be new to the parent, so the parent can grow by the child's size.
+            EstimateOp::Merge => {
+                if node.parent_id.is_none() {
+                    return Err(AppError::Message(
+                        "base layer has no parent to merge into".into(),
+                    ));
+                }
+                file_size(node)
+            }
+            EstimateOp::Clone => file_size(node),
+            // The flattened disk holds the union of the chain, which never exceeds its virtual size.
+            EstimateOp::Flatten => {
+                let chain_bytes: u64 = nodes
+                    .iter()
+                    .filter(|n| n.id == node.id || node.ancestor_ids.contains(&n.id))
+                    .map(file_size)
+                    .sum();
+                match vhdx::virtual_size(Path::new(&node.path)) {
+                    Ok(virtual_bytes) => chain_bytes.min(virtual_bytes),
+                    Err(_) => chain_bytes,
+                }
             }
         };
 
-        let assign_script = assign_partitions_script(&vhd_path, &[(sys_part, sys_letter)]);
-        let assign_path = temp.write_script("assign_diff.txt", &assign_script)?;
-        log_diskpart_script(&assign_path);
-        let assign_res = run_diskpart_script(&assign_path)?;
-        log_command("diskpart assign diff", &assign_res, Some(&assign_path));
-        if assign_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error(
-                "diskpart assign diff",
-                &assign_res,
-                Some(&assign_path),
-            ));
+        let free_bytes = volume_space(&paths.diff_dir()).map(|(free, _)| free);
+        Ok(SpaceEstimate {
+            op,
+            node_id: node_id.to_string(),
+            required_bytes,
+            free_bytes,
+            sufficient: free_bytes.map(|free| free >= required_bytes),
+        })
+    }
+
+    pub fn update_node_appearance(
+        &self,
+        node_id: &str,
+        color: Option<String>,
+        icon: Option<String>,
+    ) -> Result<Node> {
+        let db = self.db()?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let color = color.filter(|c| !c.trim().is_empty());
+        let icon = icon.filter(|i| !i.trim().is_empty());
+        if let Some(color) = color.as_deref() {
+            let hex = color.strip_prefix('#').unwrap_or("");
+            if !(hex.len() == 6 || hex.len() == 3) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(AppError::Message(format!("invalid color: {color}")));
+            }
+        }
+        if let Some(icon) = icon.as_deref() {
+            let valid = icon.len() <= 32
+                && icon
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !valid {
+                return Err(AppError::Message(format!("invalid icon: {icon}")));
+            }
         }
+        db.update_node_appearance(node_id, color.as_deref(), icon.as_deref())?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
+    }
 
-        let sys_mount = PathBuf::from(format!("{sys_letter}:"));
-        let bcd_res = run_bcdboot(&sys_mount)?;
-        log_command("bcdboot", &bcd_res, None);
-        if bcd_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("bcdboot", &bcd_res, None));
+    /// Arrange siblings in the given order.
+    pub fn reorder_nodes(&self, ordered_ids: Vec<String>) -> Result<()> {
+        let db = self.db()?;
+        let nodes = db.fetch_nodes()?;
+        let mut parent: Option<Option<String>> = None;
+        for id in &ordered_ids {
+            let node = nodes
+                .iter()
+                .find(|n| &n.id == id)
+                .ok_or_else(|| AppError::Message(format!("node not found: {id}")))?;
+            match &parent {
+                None => parent = Some(node.parent_id.clone()),
+                Some(p) if *p != node.parent_id => {
+                    return Err(AppError::Message(
+                        "reorder_nodes expects siblings sharing the same parent".into(),
+                    ))
+                }
+                Some(_) => {}
+            }
         }
-        let bcd_enum = bcdedit_enum_all()?;
-        log_command("bcdedit enum", &bcd_enum, None);
-        let guid = extract_guid_for_vhd(&bcd_enum.stdout, vhd_path.to_str().unwrap_or_default())
-            .or_else(|| extract_guid_for_partition_letter(&bcd_enum.stdout, sys_letter))
-            .unwrap_or_default();
+        db.update_sort_keys(&ordered_ids)?;
+        info!("reorder_nodes count={}", ordered_ids.len());
+        Ok(())
+    }
 
-        let detach_script = detach_vdisk_script(&vhd_path, &[sys_letter]);
-        let detach_path = temp.write_script("detach_diff.txt", &detach_script)?;
-        log_diskpart_script(&detach_path);
-        let detach_res = run_diskpart_script(&detach_path)?;
-        log_command("diskpart detach diff", &detach_res, Some(&detach_path));
+    /// Re-read edition/version/build from the layer's offline registry.
+    pub fn refresh_os_info(&self, node_id: &str) -> Result<Node> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let op_id = jobs::next_op_id();
+        let os_info = self.with_read_only_layer(&node, "osinfo", &op_id, read_os_info)?;
+        db.update_node_os_info(node_id, &os_info)?;
+        info!(
+            "refresh_os_info node={node_id} edition={:?} build={:?}",
+            os_info.edition, os_info.build
+        );
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
+    }
 
-        let node = Node {
-            id: id.clone(),
-            parent_id: Some(parent_id.to_string()),
-            name: name.to_string(),
-            path: vhd_path.to_string_lossy().to_string(),
-            bcd_guid: if guid.is_empty() {
-                None
-            } else {
-                Some(guid.clone())
-            },
-            desc,
-            created_at: Utc::now(),
-            status: NodeStatus::Normal,
-            boot_files_ready: !guid.is_empty(),
-        };
-        db.insert_node(&node)?;
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            Some(&id),
-            "create_diff",
-            "ok",
-            "",
-        )?;
-        info!("create_diff id={id} parent={parent_id}");
-        Ok(node)
+    /// Driver packages in the layer's offline driver store; `all` includes inbox drivers.
+    pub fn get_layer_drivers(&self, node_id: &str, all: bool) -> Result<Vec<LayerDriver>> {
+        let node = self
+            .db()?
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let op_id = jobs::next_op_id();
+        let drivers = self.with_read_only_layer(&node, "drivers", &op_id, |sys_mount| {
+            let res = get_drivers(&sys_mount.to_string_lossy(), all)?;
+            log_command("dism get-drivers", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("dism get-drivers", &res, None));
+            }
+            Ok(parse_drivers_table(&res.stdout))
+        })?;
+        info!("get_layer_drivers node={node_id} count={}", drivers.len());
+        Ok(drivers)
     }
 
-    pub fn set_bootsequence_and_reboot(&self, node_id: &str) -> Result<CommandOutput> {
-        let db = self.db()?;
-        let node = db
+    pub fn get_layer_packages(&self, node_id: &str) -> Result<Vec<LayerPackage>> {
+        let node = self
+            .db()?
             .fetch_node(node_id)?
             .ok_or_else(|| AppError::Message("node not found".into()))?;
-        let guid = node
-            .bcd_guid
-            .clone()
-            .ok_or_else(|| AppError::Message("node missing bcd guid".into()))?;
-        let res = bcdedit_boot_sequence_and_reboot(&guid)?;
-        log_command("bcdedit bootsequence", &res, None);
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            Some(node_id),
-            "bootsequence_reboot",
-            "ok",
-            "",
-        )?;
-        info!("bootsequence node={node_id} guid={guid}");
-        Ok(res)
+        let op_id = jobs::next_op_id();
+        let packages = self.with_read_only_layer(&node, "packages", &op_id, |sys_mount| {
+            let res = get_packages(&sys_mount.to_string_lossy())?;
+            log_command("dism get-packages", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("dism get-packages", &res, None));
+            }
+            Ok(parse_packages_table(&res.stdout))
+        })?;
+        info!("get_layer_packages node={node_id} count={}", packages.len());
+        Ok(packages)
     }
 
-    pub fn start_vm(&self, node_id: &str) -> Result<String> {
-        let db = self.db()?;
-        let node = db
+    pub fn get_layer_features(&self, node_id: &str) -> Result<Vec<LayerFeature>> {
+        let node = self
+            .db()?
             .fetch_node(node_id)?
             .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let op_id = jobs::next_op_id();
+        let features = self.with_read_only_layer(&node, "features", &op_id, |sys_mount| {
+            let res = get_features(&sys_mount.to_string_lossy())?;
+            log_command("dism get-features", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("dism get-features", &res, None));
+            }
+            Ok(parse_features_table(&res.stdout))
+        })?;
+        info!("get_layer_features node={node_id} count={}", features.len());
+        Ok(features)
+    }
 
-        let vhd_path = PathBuf::from(&node.path);
-        if !vhd_path.exists() {
-            return Err(AppError::Message(format!("vhdx not found: {}", node.path)));
-        }
+    pub fn list_wim_images(&self, image_path: &str) -> Result<Vec<WimImageInfo>> {
+        list_images(image_path)
+    }
 
-        let paths = self.paths()?;
-        paths.ensure_layout()?;
-        fs::create_dir_all(paths.vms_dir())?;
+    /// Register a WIM/ESD in the catalog with its images and hash, or refresh the entry of a
+    /// file registered before.
+    pub fn add_wim_source(&self, path: &str) -> Result<WimSource> {
+        let file = Path::new(path.trim());
+        let meta = fs::metadata(file)
+            .map_err(|err| AppError::Message(format!("cannot read {}: {err}", file.display())))?;
+        jobs::report_phase("list_images", Some(5));
+        let images = list_images(&file.to_string_lossy())?;
+        if images.is_empty() {
+            return Err(AppError::Message(format!(
+                "{} holds no images DISM can read",
+                file.display()
+            )));
+        }
+        jobs::report_phase("hash", Some(20));
+        let sha256 = file_sha256(file)?;
+        let source = self.db()?.upsert_wim_source(&WimSource {
+            id: Uuid::new_v4().to_string(),
+            path: file.to_string_lossy().to_string(),
+            size_bytes: meta.len(),
+            modified_at: meta.modified().ok().map(DateTime::<Utc>::from),
+            sha256,
+            images,
+            added_at: Utc::now(),
+            status: WimSourceStatus::Ok,
+        })?;
+        info!("add_wim_source id={} path={}", source.id, source.path);
+        Ok(source)
+    }
 
-        let vm_name = format!("ls-{}", node.id);
-        let vm_dir = paths.vms_dir().join(&vm_name);
-        fs::create_dir_all(&vm_dir)?;
+    /// Registered WIM sources, each flagged when its file is gone or no longer matches the
+    /// cached size and write time.
+    pub fn list_wim_sources(&self) -> Result<Vec<WimSource>> {
+        let mut sources = self.db()?.fetch_wim_sources()?;
+        for source in &mut sources {
+            source.status = match fs::metadata(&source.path) {
+                Err(_) => WimSourceStatus::Missing,
+                Ok(meta) => {
+                    let modified = meta.modified().ok().map(DateTime::<Utc>::from);
+                    if meta.len() == source.size_bytes && modified == source.modified_at {
+                        WimSourceStatus::Ok
+                    } else {
+                        WimSourceStatus::Changed
+                    }
+                }
+            };
+        }
+        Ok(sources)
+    }
 
-        let ps_script = format!(
-            r#"$ErrorActionPreference = 'Stop'
-if (-not (Get-Command -Name 'Get-VM' -ErrorAction SilentlyContinue)) {{ throw 'Hyper-V PowerShell module is not available (Get-VM not found).'; }}
-if (-not (Get-Command -Name 'vmconnect.exe' -ErrorAction SilentlyContinue)) {{ throw 'vmconnect.exe not found in PATH.'; }}
-$vmName = '{vm_name}'
-$vmPath = '{vm_path}'
-$vhdPath = '{vhd_path}'
-if (-not (Test-Path -Path $vmPath)) {{ New-Item -ItemType Directory -Path $vmPath | Out-Null }}
-$vm = Get-VM -Name $vmName -ErrorAction SilentlyContinue
-if (-not $vm) {{
-    $vm = New-VM -Name $vmName -Generation 2 -MemoryStartupBytes 2GB -VHDPath $vhdPath -Path $vmPath
-}} else {{
-    $drive = Get-VMHardDiskDrive -VMName $vmName -ErrorAction SilentlyContinue | Select-Object -First 1
-    if ($drive) {{
-        Set-VMHardDiskDrive -VMHardDiskDrive $drive -Path $vhdPath | Out-Null
-    }} else {{
-        Add-VMHardDiskDrive -VMName $vmName -Path $vhdPath | Out-Null
-    }}
-}}
-if ($vm.State -ne 'Running') {{
-    Start-VM -Name $vmName | Out-Null
-}}
-Start-Process vmconnect.exe -ArgumentList 'localhost', $vmName | Out-Null
-"#,
-            vm_name = ps_escape_single(&vm_name),
-            vm_path = ps_escape_single(vm_dir.to_string_lossy().as_ref()),
-            vhd_path = ps_escape_single(vhd_path.to_string_lossy().as_ref()),
-        );
+    pub fn remove_wim_source(&self, source_id: &str) -> Result<()> {
+        if !self.db()?.delete_wim_source(source_id)? {
+            return Err(AppError::Message("wim source not found".into()));
+        }
+        info!("remove_wim_source id={source_id}");
+        Ok(())
+    }
 
-        let res = run_elevated_command(
-            "powershell.exe",
-            &[
-                "-NoLogo",
-                "-NoProfile",
-                "-NonInteractive",
-                "-Command",
-                &ps_script,
-            ],
-            None,
-        )?;
-        log_command("start_vm", &res, None);
-        if res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("start_vm", &res, None));
+    /// Export image `index` of `source` into a new single-index WIM at `dest`.
+    pub fn export_wim_index(&self, source: &str, index: u32, dest: &str) -> Result<()> {
+        let started = Instant::now();
+        if Path::new(dest).exists() {
+            return Err(AppError::Message(format!("{dest} already exists")));
         }
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            Some(node_id),
-            "start_vm",
+        jobs::report_phase("export_image", None);
+        let res = export_image(source, index, dest)?;
+        log_command("dism export", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("dism export", &res, None));
+        }
+        self.db()?.insert_timed_op(
+            &jobs::next_op_id(),
+            None,
+            "export_wim_index",
             "ok",
-            &format!("vm_name={vm_name}"),
+            &format!("source={source} index={index} dest={dest}"),
+            started.elapsed(),
         )?;
-        info!("start_vm node={node_id} vm_name={vm_name}");
-        Ok(vm_name)
+        info!("export_wim_index source={source} index={index} dest={dest}");
+        Ok(())
     }
 
-    pub fn delete_subtree(&self, node_id: &str) -> Result<()> {
-        let db = self.db()?;
-        let nodes = db.fetch_nodes()?;
-        let mut graph: HashMap<String, Vec<String>> = HashMap::new();
-        for n in nodes.iter() {
-            if let Some(pid) = &n.parent_id {
-                graph.entry(pid.clone()).or_default().push(n.id.clone());
-            }
+    /// Image to apply for `wim_file`/`wim_index`: with the WIM cache on, a multi-index source is
+    /// exported once to a single-index WIM that later applies read instead of the whole file.
+    /// Falls back to the source when caching is off or the export fails.
+    fn image_to_apply(&self, wim_file: &str, wim_index: u32) -> Result<(String, u32)> {
+        let source = (wim_file.to_string(), wim_index);
+        if !self.db()?.get_settings()?.wim_cache {
+            return Ok(source);
         }
-        let mut order = Vec::new();
-        let mut queue = VecDeque::new();
-        queue.push_back(node_id.to_string());
-        while let Some(id) = queue.pop_front() {
-            order.push(id.clone());
-            if let Some(children) = graph.get(&id) {
-                for c in children {
-                    queue.push_back(c.clone());
-                }
-            }
+        let meta = fs::metadata(wim_file)?;
+        let image_count = list_images(wim_file)
+            .map(|images| images.len())
+            .unwrap_or(0);
+        if image_count <= 1 {
+            return Ok(source);
         }
-        // Delete children after parents? requirement: delete subtree; we reverse to delete leaves first.
-        order.reverse();
-        for id in order.iter() {
-            if let Some(node) = db.fetch_node(id)?.clone() {
-                if let Some(guid) = node.bcd_guid.as_ref() {
-                    if let Ok(o) = bcdedit_delete(guid) {
-                        log_command("bcdedit delete", &o, None);
-                    }
-                }
-                // attempt detach
-                let temp = TempManager::new(self.paths()?.tmp_dir())?;
-                let detach_script = detach_vdisk_script(Path::new(&node.path), &[]);
-                let path = temp.write_script("detach_cleanup.txt", &detach_script)?;
-                log_diskpart_script(&path);
-                if let Ok(o) = run_diskpart_script(&path) {
-                    log_command("diskpart detach cleanup", &o, Some(&path));
+        let stem = Path::new(wim_file)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        // Size and timestamp in the name make a replaced source miss the cache.
+        let prefix = format!("{stem}-{wim_index}-");
+        let cache_dir = self.paths()?.wim_cache_dir();
+        let cached = cache_dir.join(format!("{prefix}{:x}-{modified:x}.wim", meta.len()));
+        if cached.is_file() {
+            info!("wim cache hit source={wim_file} index={wim_index}");
+            return Ok((cached.to_string_lossy().to_string(), 1));
+        }
+
+        fs::create_dir_all(&cache_dir)?;
+        for entry in fs::read_dir(&cache_dir)?.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                if let Err(err) = fs::remove_file(entry.path()) {
+                    warn!(
+                        "remove stale cached wim failed path={} err={err}",
+                        entry.path().display()
+                    );
                 }
-                // delete file
-                // let _ = fs::remove_file(&node.path);
             }
         }
-        db.delete_ops_for_nodes(&order)?;
-        db.delete_nodes(&order)?;
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            None,
-            "delete_subtree",
-            "ok",
-            &format!("node_id={}", node_id),
-        )?;
-        info!("delete_subtree node={node_id} count={}", order.len());
-        Ok(())
+        // Export under a temporary name so a cancelled export never looks like a cache hit.
+        let partial = cached.with_extension("partial.wim");
+        jobs::report_phase("export_image", Some(2));
+        let res =
+            export_image(wim_file, wim_index, &partial.to_string_lossy()).inspect_err(|_| {
+                let _ = fs::remove_file(&partial);
+            })?;
+        log_command("dism export", &res, None);
+        if !res.succeeded() || fs::rename(&partial, &cached).is_err() {
+            warn!(
+                "wim export failed, applying from the source source={wim_file} index={wim_index}"
+            );
+            let _ = fs::remove_file(&partial);
+            return Ok(source);
+        }
+        Ok((cached.to_string_lossy().to_string(), 1))
     }
 
-    pub fn delete_bcd(&self, node_id: &str) -> Result<()> {
+    /// Run the checks a base or differencing layer creation depends on without starting it, so
+    /// the UI can show a checklist first. Every check runs even when an earlier one failed.
+    pub fn preflight_create(&self, params: &CreatePreflightParams) -> Result<CreatePreflight> {
+        let paths = self.paths()?;
         let db = self.db()?;
-        let node = db
-            .fetch_node(node_id)?
-            .ok_or_else(|| AppError::Message("node not found".into()))?;
-        if let Some(guid) = node.bcd_guid.as_ref() {
-            let res = bcdedit_delete(guid)?;
-            log_command("bcdedit delete", &res, None);
-            if res.exit_code.unwrap_or(-1) != 0 {
-                return Err(command_error("bcdedit delete", &res, None));
+        let headroom = gb_to_bytes(db.get_settings()?.free_space_headroom_gb as u64);
+        let name = params.name.trim();
+        let parent_id = params.parent_id.as_deref();
+        let mut checks = Vec::new();
+        let mut check = |code: &str, severity: IssueSeverity, outcome: Result<String>| {
+            let (severity, message) = match outcome {
+                Ok(message) => (None, message),
+                Err(err) => (Some(severity), err.to_string()),
+            };
+            checks.push(PreflightCheck {
+                code: code.to_string(),
+                severity,
+                message,
+            });
+        };
+
+        check(
+            "admin",
+            IssueSeverity::Warning,
+            if preflight::is_admin() {
+                Ok("running as administrator".into())
+            } else {
+                Err(AppError::Message(
+                    "not running as administrator; every elevated step will prompt".into(),
+                ))
+            },
+        );
+
+        let missing: Vec<String> = tools::detect()
+            .into_iter()
+            .filter(|tool| !tool.exists)
+            .map(|tool| format!("{} ({})", tool.name, tool.path))
+            .collect();
+        check(
+            "tools",
+            IssueSeverity::Error,
+            if missing.is_empty() {
+                Ok("dism, bcdboot, bcdedit and diskpart found".into())
+            } else {
+                Err(AppError::Message(format!(
+                    "tools not found: {}",
+                    missing.join(", ")
+                )))
+            },
+        );
+
+        check(
+            "name",
+            IssueSeverity::Error,
+            node_slug(name)
+                .and_then(|_| ensure_unique_name(&db, parent_id, name))
+                .map(|()| format!("\"{name}\" is available")),
+        );
+
+        let (target_dir, letter_count, incoming, required) = match parent_id {
+            Some(parent_id) => {
+                check(
+                    "parent",
+                    IssueSeverity::Error,
+                    db.fetch_node(parent_id).and_then(|parent| {
+                        let parent =
+                            parent.ok_or_else(|| AppError::Message("parent not found".into()))?;
+                        if !Path::new(&parent.path).is_file() {
+                            return Err(AppError::Message(format!(
+                                "parent file is missing: {}",
+                                parent.path
+                            )));
+                        }
+                        if parent.bitlocker {
+                            return Err(AppError::Message(format!(
+                                "parent {} is BitLocker-encrypted",
+                                parent.name
+                            )));
+                        }
+                        if parent.status.in_progress() {
+                            return Err(AppError::Message(format!(
+                                "parent {} is busy ({:?})",
+                                parent.name, parent.status
+                            )));
+                        }
+                        Ok(format!("parent {} is ready", parent.name))
+                    }),
+                );
+                (paths.diff_dir(), 1, 0, headroom)
+            }
+            None => {
+                let wim_bytes = params
+                    .wim_file
+                    .as_deref()
+                    .and_then(|wim| fs::metadata(wim).ok())
+                    .map_or(0, |meta| meta.len());
+                check(
+                    "wim",
+                    IssueSeverity::Error,
+                    check_wim(params.wim_file.as_deref(), params.wim_index),
+                );
+                let letters = if params.recovery { 3 } else { 2 };
+                (paths.base_dir(), letters, wim_bytes, wim_bytes + headroom)
             }
+        };
+
+        check(
+            "quota",
+            IssueSeverity::Error,
+            ensure_quota(&db, parent_id, incoming).map(|()| "within the workspace limits".into()),
+        );
+        check(
+            "drive_letters",
+            IssueSeverity::Error,
+            pick_free_letters(letter_count)
+                .map(|letters| {
+                    let letters: Vec<String> = letters.iter().map(|l| format!("{l}:")).collect();
+                    format!("free drive letters: {}", letters.join(", "))
+                })
+                .ok_or_else(|| {
+                    AppError::Message("no free drive letter available between S: and Z:".into())
+                }),
+        );
+        check(
+            "free_space",
+            IssueSeverity::Error,
+            ensure_free_space(&target_dir, required)
+                .map(|()| format!("{} free for this layer", format_size(required))),
+        );
+        for (code, dir) in [
+            ("target_dir", target_dir),
+            ("mount_dir", paths.mount_root()),
+            ("temp_dir", paths.tmp_dir()),
+        ] {
+            check(
+                code,
+                IssueSeverity::Error,
+                preflight::probe_writable(&dir)
+                    .map(|()| format!("{} is writable", dir.display()))
+                    .map_err(|err| {
+                        AppError::Message(format!("{} is not writable: {err}", dir.display()))
+                    }),
+            );
         }
-        db.clear_node_bcd(node_id)?;
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            Some(node_id),
-            "delete_bcd",
-            "ok",
-            "",
-        )?;
-        info!("delete_bcd node={node_id}");
-        Ok(())
-    }
 
-    pub fn add_bcd_entry(
-        &self,
-        node_id: &str,
-        description: Option<String>,
-    ) -> Result<Option<String>> {
-        let guid = self.repair_bcd_inner(node_id, description.as_deref())?;
-        Ok(guid)
+        let ready = checks
+            .iter()
+            .all(|c| c.severity != Some(IssueSeverity::Error));
+        info!("preflight_create name={name} ready={ready}");
+        Ok(CreatePreflight { ready, checks })
     }
 
-    pub fn update_bcd_description(&self, node_id: &str, description: &str) -> Result<()> {
+    pub fn create_base(
+        &self,
+        name: &str,
+        desc: Option<String>,
+        wim_file: &str,
+        wim_index: u32,
+        size_gb: u64,
+        recovery: bool,
+    ) -> Result<Node> {
+        let started = Instant::now();
+        let name = name.trim();
+        let slug = node_slug(name)?;
+        let paths = self.paths()?;
+        paths.ensure_layout()?;
         let db = self.db()?;
-        let node = db
-            .fetch_node(node_id)?
-            .ok_or_else(|| AppError::Message("node not found".into()))?;
-        let guid = node
-            .bcd_guid
-            .clone()
-            .ok_or_else(|| AppError::Message("node missing bcd guid".into()))?;
-        let res = bcdedit_set_description(&guid, description)?;
-        log_command("bcdedit set description", &res, None);
-        if res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("bcdedit set description", &res, None));
-        }
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            Some(node_id),
-            "update_bcd_description",
-            "ok",
-            description,
+        ensure_unique_name(&db, None, name)?;
+        // The disk is expandable, so it needs roughly the applied image plus headroom right away.
+        let headroom = gb_to_bytes(db.get_settings()?.free_space_headroom_gb as u64);
+        let wim_bytes = fs::metadata(wim_file).map(|m| m.len()).unwrap_or(0);
+        ensure_quota(&db, None, wim_bytes)?;
+        ensure_free_space(&paths.base_dir(), wim_bytes + headroom)?;
+        let (image_file, image_index) = self.image_to_apply(wim_file, wim_index)?;
+        let id = Uuid::new_v4().to_string();
+        let op_id = jobs::next_op_id();
+        let vhd_path = next_vhd_path(&db, &paths.base_dir(), &slug)?;
+        let pending = BusyNode::creating(
+            &db,
+            Node {
+                id: id.clone(),
+                name: name.to_string(),
+                path: vhd_path.to_string_lossy().to_string(),
+                desc: desc.clone(),
+                created_at: Utc::now(),
+                ..Default::default()
+            },
         )?;
-        Ok(())
-    }
-
-    pub fn repair_bcd(&self, node_id: &str) -> Result<Option<String>> {
-        self.repair_bcd_inner(node_id, None)
-    }
 
-    fn repair_bcd_inner(&self, node_id: &str, description: Option<&str>) -> Result<Option<String>> {
-        let db = self.db()?;
-        let node = db
-            .fetch_node(node_id)?
-            .ok_or_else(|| AppError::Message("node not found".into()))?;
-        let paths = self.paths()?;
         let temp = TempManager::new(paths.tmp_dir())?;
-        let sys_letter = pick_free_letter().ok_or_else(|| {
+        fs::create_dir_all(paths.mount_root())?;
+        let letters = pick_free_letters(if recovery { 3 } else { 2 }).ok_or_else(|| {
             AppError::Message("no free drive letter available between S: and Z:".into())
         })?;
+        let efi_letter = letters[0];
+        let sys_letter = letters[1];
+        let recovery_letter = letters.get(2).copied();
+        let mut mounted = vec![sys_letter, efi_letter];
+        mounted.extend(recovery_letter);
 
-        let attach_script = crate::diskpart::attach_list_vdisk_script(Path::new(&node.path));
-        let attach_path = temp.write_script("attach_repair.txt", &attach_script)?;
-        log_diskpart_script(&attach_path);
-        let attach_res = run_diskpart_script(&attach_path)?;
-        log_command("diskpart attach repair", &attach_res, Some(&attach_path));
-        if attach_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error(
-                "diskpart attach",
+        jobs::report_phase("create_disk", Some(5));
+        let script =
+            base_diskpart_script(&vhd_path, size_gb, efi_letter, sys_letter, recovery_letter)?;
+        let script_path = temp.write_script("create_base.txt", &script)?;
+        log_diskpart_script(&script_path);
+        let create_res = run_diskpart_script(&script_path)?;
+        log_command("diskpart create base", &create_res, Some(&script_path));
+        self.record_attach(
+            &id,
+            &op_id,
+            "attach",
+            "create_base",
+            create_res.exit_code == Some(0),
+        );
+
+        if !create_res.succeeded() {
+            return Err(command_error(
+                "diskpart create base",
+                &create_res,
+                Some(&script_path),
+            ));
+        }
+
+        jobs::report_phase("apply_image", Some(10));
+        // A failed or cancelled apply must not leave the new disk attached with its letters taken.
+        let detach = || self.detach_quietly(&temp, &vhd_path, &mounted, "create_base", &id, &op_id);
+        let dism_res = apply_image(&image_file, image_index, &format!("{sys_letter}:\\"))
+            .inspect_err(|_| detach())?;
+        log_command("dism apply", &dism_res, None);
+        if !dism_res.succeeded() {
+            detach();
+            return Err(command_error("dism apply", &dism_res, None));
+        }
+
+        if let Some(letter) = recovery_letter {
+            jobs::report_phase("winre", Some(75));
+            stage_winre(sys_letter, letter).inspect_err(|_| detach())?;
+        }
+
+        let sys_mount = PathBuf::from(format!("{sys_letter}:"));
+        let efi_mount = PathBuf::from(format!("{efi_letter}:"));
+        jobs::report_phase("boot_files", Some(80));
+        let bcd_efi_res = run_bcdboot_to_efi(&sys_mount, &efi_mount)?;
+        log_command("bcdboot efi", &bcd_efi_res, None);
+        if !bcd_efi_res.succeeded() {
+            return Err(command_error("bcdboot", &bcd_efi_res, None));
+        }
+
+        let bcd_res = run_bcdboot(&sys_mount)?;
+        log_command("bcdboot", &bcd_res, None);
+        if !bcd_res.succeeded() {
+            return Err(command_error("bcdboot", &bcd_res, None));
+        }
+
+        let bcd_enum = bcdedit_enum_all()?;
+        log_command("bcdedit enum", &bcd_enum, None);
+        let guid = extract_guid_for_vhd(&bcd_enum.stdout, vhd_path.to_str().unwrap_or_default())
+            .or_else(|| extract_guid_for_partition_letter(&bcd_enum.stdout, sys_letter))
+            .unwrap_or_default();
+        if !guid.is_empty() {
+            self.apply_description_template(&guid, name, None);
+        }
+        let boot_ready = boot_files_verified(&guid);
+
+        let sys_root = PathBuf::from(format!("{sys_letter}:\\"));
+        if let Err(err) = self.install_boot_marker(&sys_root, &id) {
+            info!("install boot marker failed node={id} err={err}");
+        }
+        let os_info = read_os_info(&sys_root).unwrap_or_else(|err| {
+            info!("read os info failed node={id} err={err}");
+            OsInfo::default()
+        });
+
+        jobs::report_phase("detach", Some(95));
+        let detach_script = detach_vdisk_script(&vhd_path, &mounted)?;
+        let detach_path = temp.write_script("detach_base.txt", &detach_script)?;
+        log_diskpart_script(&detach_path);
+        let detach_res = run_diskpart_script(&detach_path)?;
+        log_command("diskpart detach base", &detach_res, Some(&detach_path));
+        self.record_attach(
+            &id,
+            &op_id,
+            "detach",
+            "create_base",
+            detach_res.exit_code == Some(0),
+        );
+        if detach_res.exit_code != Some(0) {
+            log_detach_holders(&vhd_path, &mounted);
+        }
+
+        let node = Node {
+            id: id.clone(),
+            parent_id: None,
+            name: name.to_string(),
+            path: vhd_path.to_string_lossy().to_string(),
+            bcd_guid: if guid.is_empty() {
+                None
+            } else {
+                Some(guid.clone())
+            },
+            desc,
+            created_at: Utc::now(),
+            status: NodeStatus::Normal,
+            boot_files_ready: boot_ready,
+            os_edition: os_info.edition,
+            os_version: os_info.version,
+            os_build: os_info.build,
+            ..Default::default()
+        };
+
+        db.complete_node(&node)?;
+        pending.finish();
+        db.insert_timed_op(
+            &op_id,
+            Some(&id),
+            "create_base",
+            "ok",
+            "",
+            started.elapsed(),
+        )?;
+        info!("create_base id={id} path={}", node.path);
+        Ok(node)
+    }
+
+    /// Published bases in the configured library folder, by name.
+    pub fn list_library(&self) -> Result<Vec<LibraryEntry>> {
+        list_vhdx_entries(&self.library_dir()?)
+    }
+
+    /// Golden base VHDXs in the local template folder.
+    pub fn list_templates(&self) -> Result<Vec<LibraryEntry>> {
+        list_vhdx_entries(&self.template_dir()?)
+    }
+
+    /// Create a base by copying a golden VHDX from the template folder instead of applying a
+    /// WIM. The copy gets a new disk GUID and its own boot entry.
+    pub fn create_base_from_template(
+        &self,
+        template: &str,
+        name: &str,
+        desc: Option<String>,
+    ) -> Result<Node> {
+        let started = Instant::now();
+        let name = name.trim();
+        let slug = node_slug(name)?;
+        let paths = self.paths()?;
+        paths.ensure_layout()?;
+        let db = self.db()?;
+        ensure_unique_name(&db, None, name)?;
+
+        if template.is_empty() || template.contains(['\\', '/', ':']) || template == ".." {
+            return Err(AppError::Message(format!("invalid template: {template}")));
+        }
+        let source = self.template_dir()?.join(template);
+        if !source.is_file() {
+            return Err(AppError::Message(format!(
+                "template not found: {}",
+                source.display()
+            )));
+        }
+        if let Some(parent) = self.detail_vdisk(&source.to_string_lossy())?.parent {
+            return Err(AppError::Message(format!(
+                "{template} is a differencing disk of {parent}; only base VHDXs can be templates"
+            )));
+        }
+        let manifest = read_library_manifest(&source)?.unwrap_or_default();
+
+        let source_bytes = fs::metadata(&source)?.len();
+        ensure_quota(&db, None, source_bytes)?;
+        let headroom = gb_to_bytes(db.get_settings()?.free_space_headroom_gb as u64);
+        ensure_free_space(&paths.base_dir(), source_bytes + headroom)?;
+        let id = Uuid::new_v4().to_string();
+        let vhd_path = next_vhd_path(&db, &paths.base_dir(), &slug)?;
+        let vhd_str = vhd_path.to_string_lossy().to_string();
+        let desc = desc.or_else(|| manifest.desc.clone());
+        let pending = BusyNode::creating(
+            &db,
+            Node {
+                id: id.clone(),
+                name: name.to_string(),
+                path: vhd_str.clone(),
+                desc: desc.clone(),
+                created_at: Utc::now(),
+                ..Default::default()
+            },
+        )?;
+
+        jobs::report_phase("copy", Some(5));
+        fs::copy(&source, &vhd_path)?;
+        jobs::report_phase("reset_disk_id", Some(70));
+        let reset = (|| -> Result<()> {
+            let temp = TempManager::new(paths.tmp_dir())?;
+            let script = reset_disk_id_script(&vhd_path, &Uuid::new_v4().to_string())?;
+            let script_path = temp.write_script("reset_disk_id.txt", &script)?;
+            log_diskpart_script(&script_path);
+            let res = run_diskpart_script(&script_path)?;
+            log_command("diskpart uniqueid", &res, Some(&script_path));
+            if !res.succeeded() {
+                return Err(command_error("diskpart uniqueid", &res, Some(&script_path)));
+            }
+            Ok(())
+        })();
+        if let Err(err) = reset {
+            if let Err(remove_err) = fs::remove_file(&vhd_path) {
+                warn!("remove template copy failed path={vhd_str} err={remove_err}");
+            }
+            return Err(err);
+        }
+
+        let node = Node {
+            id: id.clone(),
+            parent_id: None,
+            name: name.to_string(),
+            path: vhd_str,
+            desc,
+            created_at: Utc::now(),
+            status: NodeStatus::Normal,
+            os_edition: manifest.os_edition.clone(),
+            os_version: manifest.os_version.clone(),
+            os_build: manifest.os_build.clone(),
+            ..Default::default()
+        };
+        db.complete_node(&node)?;
+        pending.finish();
+
+        jobs::report_phase("boot_entry", Some(85));
+        if let Err(err) = self.repair_bcd(&id) {
+            warn!("create boot entry for template base failed node={id} err={err}");
+        }
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(&id),
+            "create_base_from_template",
+            "ok",
+            &format!("source={}", source.display()),
+            started.elapsed(),
+        )?;
+        info!(
+            "create_base_from_template id={id} source={}",
+            source.display()
+        );
+        Ok(db.fetch_node(&id)?.unwrap_or(node))
+    }
+
+    /// Copy a published base from the library into this workspace and register it as a new
+    /// base layer. The copy must match the SHA-256 from the entry's manifest.
+    pub fn clone_from_library(&self, entry: &str, name: &str) -> Result<Node> {
+        let started = Instant::now();
+        let name = name.trim();
+        let slug = node_slug(name)?;
+        let paths = self.paths()?;
+        paths.ensure_layout()?;
+        let db = self.db()?;
+        ensure_unique_name(&db, None, name)?;
+
+        if entry.is_empty() || entry.contains(['\\', '/', ':']) || entry == ".." {
+            return Err(AppError::Message(format!("invalid library entry: {entry}")));
+        }
+        let source = self.library_dir()?.join(entry);
+        if !source.is_file() {
+            return Err(AppError::Message(format!(
+                "library entry not found: {}",
+                source.display()
+            )));
+        }
+        let manifest = read_library_manifest(&source)?.unwrap_or_default();
+        let expected = manifest.sha256.clone().ok_or_else(|| {
+            AppError::Message(format!(
+                "library entry {entry} has no sha256 in its manifest"
+            ))
+        })?;
+
+        let source_bytes = fs::metadata(&source)?.len();
+        ensure_quota(&db, None, source_bytes)?;
+        let headroom = gb_to_bytes(db.get_settings()?.free_space_headroom_gb as u64);
+        ensure_free_space(&paths.base_dir(), source_bytes + headroom)?;
+        let id = Uuid::new_v4().to_string();
+        let vhd_path = next_vhd_path(&db, &paths.base_dir(), &slug)?;
+        let vhd_str = vhd_path.to_string_lossy().to_string();
+        let pending = BusyNode::creating(
+            &db,
+            Node {
+                id: id.clone(),
+                name: name.to_string(),
+                path: vhd_str.clone(),
+                created_at: Utc::now(),
+                ..Default::default()
+            },
+        )?;
+
+        jobs::report_phase("copy", Some(5));
+        fs::copy(&source, &vhd_path)?;
+        let verified = (|| -> Result<()> {
+            jobs::report_phase("verify_hash", Some(60));
+            let actual = file_sha256(&vhd_path)?;
+            if !actual.eq_ignore_ascii_case(expected.trim()) {
+                return Err(AppError::Message(format!(
+                    "hash mismatch for {entry}: expected {expected}, copied file has {actual}"
+                )));
+            }
+            if let Some(parent) = self.detail_vdisk(&vhd_str)?.parent {
+                return Err(AppError::Message(format!(
+                    "{entry} is a differencing disk of {parent}; only base layers can be cloned"
+                )));
+            }
+            Ok(())
+        })();
+        if let Err(err) = verified {
+            if let Err(remove_err) = fs::remove_file(&vhd_path) {
+                warn!("remove rejected library copy failed path={vhd_str} err={remove_err}");
+            }
+            return Err(err);
+        }
+
+        let node = Node {
+            id: id.clone(),
+            parent_id: None,
+            name: name.to_string(),
+            path: vhd_str,
+            desc: manifest.desc.clone(),
+            created_at: Utc::now(),
+            status: NodeStatus::Normal,
+            os_edition: manifest.os_edition.clone(),
+            os_version: manifest.os_version.clone(),
+            os_build: manifest.os_build.clone(),
+            ..Default::default()
+        };
+        db.complete_node(&node)?;
+        pending.finish();
+
+        jobs::report_phase("boot_entry", Some(80));
+        if let Err(err) = self.repair_bcd(&id) {
+            warn!("create boot entry for cloned base failed node={id} err={err}");
+        }
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(&id),
+            "clone_from_library",
+            "ok",
+            &format!("source={} sha256={expected}", source.display()),
+            started.elapsed(),
+        )?;
+        info!("clone_from_library id={id} source={}", source.display());
+        Ok(db.fetch_node(&id)?.unwrap_or(node))
+    }
+
+    /// Copy a detached layer to `dest` and write its manifest next to it.
+    ///
+    /// The copy runs robocopy in restartable mode, so an interrupted backup to a network target
+    /// resumes where it stopped, and is verified against the hash of the source. The outcome is
+    /// recorded per node and destination.
+    pub fn backup_node(&self, node_id: &str, dest: &str) -> Result<BackupStatus> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if self.detail_vdisk(&node.path)?.attached == Some(true) {
+            return Err(AppError::Message(format!(
+                "{} is attached or booted; only detached layers can be backed up",
+                node.name
+            )));
+        }
+        self.backup_detached(&node, dest)
+    }
+
+    /// Back up every detached layer whose VHDX changed since its last successful backup to
+    /// `dest`; used by the scheduled `backup` task.
+    pub fn backup_changed(&self, dest: &str) -> Result<String> {
+        let db = self.db()?;
+        let previous = db.fetch_backups(None)?;
+        let (mut copied, mut unchanged, mut attached) = (0, 0, 0);
+        let mut failures = Vec::new();
+        for node in db.fetch_nodes()? {
+            let Ok(meta) = fs::metadata(&node.path) else {
+                continue;
+            };
+            let modified = meta.modified().ok().map(DateTime::<Utc>::from);
+            let up_to_date = previous.iter().any(|b| {
+                b.node_id == node.id
+                    && b.dest == dest
+                    && b.result == "ok"
+                    && b.file_bytes == meta.len()
+                    && b.source_modified == modified
+            });
+            if up_to_date {
+                unchanged += 1;
+                continue;
+            }
+            match self.detail_vdisk(&node.path) {
+                Ok(detail) if detail.attached == Some(true) => {
+                    attached += 1;
+                    continue;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    failures.push(format!("{}: {err}", node.name));
+                    continue;
+                }
+            }
+            match self.backup_detached(&node, dest) {
+                Ok(_) => copied += 1,
+                Err(err) => failures.push(format!("{}: {err}", node.name)),
+            }
+        }
+        if !failures.is_empty() {
+            return Err(AppError::Message(format!(
+                "{} layer(s) not backed up: {}",
+                failures.len(),
+                failures.join("; ")
+            )));
+        }
+        Ok(format!(
+            "copied={copied} unchanged={unchanged} attached={attached}"
+        ))
+    }
+
+    pub fn get_backup_status(&self, node_id: Option<&str>) -> Result<Vec<BackupStatus>> {
+        self.db()?.fetch_backups(node_id)
+    }
+
+    /// Copy a backed-up VHDX back into the workspace and make it usable again.
+    ///
+    /// The copy is verified against the hash recorded at backup time before it replaces the
+    /// layer's file. A node deleted since the backup is registered again from the backup's
+    /// manifest under its old id; its parent must already be in the workspace. The boot entry
+    /// is recreated in either case.
+    pub fn restore_node_from_backup(&self, backup_id: &str) -> Result<Node> {
+        let started = Instant::now();
+        let paths = self.paths()?;
+        let db = self.db()?;
+        let backup = db
+            .fetch_backup(backup_id)?
+            .ok_or_else(|| AppError::Message("backup not found".into()))?;
+        let expected = match (&backup.sha256, backup.result.as_str()) {
+            (Some(sha256), "ok") if !backup.file.is_empty() => sha256.clone(),
+            _ => {
+                return Err(AppError::Message(format!(
+                    "backup {backup_id} did not complete and cannot be restored"
+                )))
+            }
+        };
+        let archived = Path::new(&backup.dest).join(&backup.file);
+        if !archived.is_file() {
+            return Err(AppError::Message(format!(
+                "backup file not found: {}",
+                archived.display()
+            )));
+        }
+        let manifest = read_library_manifest(&archived)?.unwrap_or_default();
+
+        let existing = db.fetch_node(&backup.node_id)?;
+        let target = match &existing {
+            Some(node) => {
+                self.ensure_no_children(node, "restoring", false)?;
+                let attached = Path::new(&node.path).exists()
+                    && self.detail_vdisk(&node.path)?.attached == Some(true);
+                if attached {
+                    return Err(AppError::Message(format!(
+                        "{} is attached or booted; detach it before restoring",
+                        node.name
+                    )));
+                }
+                PathBuf::from(&node.path)
+            }
+            None => {
+                let dir = if manifest.parent.is_some() {
+                    paths.diff_dir()
+                } else {
+                    paths.base_dir()
+                };
+                let target = dir.join(&backup.file);
+                if target.exists() {
+                    return Err(AppError::Message(format!(
+                        "{} already exists; remove it or restore into its node",
+                        target.display()
+                    )));
+                }
+                target
+            }
+        };
+        let parent_id = match (&existing, manifest.parent.as_deref()) {
+            (Some(node), _) => node.parent_id.clone(),
+            (None, None) => None,
+            (None, Some(parent_file)) => {
+                let parent = db
+                    .fetch_nodes()?
+                    .into_iter()
+                    .find(|n| {
+                        Path::new(&n.path)
+                            .file_name()
+                            .is_some_and(|f| f.to_string_lossy().eq_ignore_ascii_case(parent_file))
+                    })
+                    .ok_or_else(|| {
+                        AppError::Message(format!(
+                            "parent layer {parent_file} is not in the workspace; restore it first"
+                        ))
+                    })?;
+                Some(parent.id)
+            }
+        };
+        let name = manifest
+            .name
+            .clone()
+            .unwrap_or_else(|| backup.file.trim_end_matches(".vhdx").to_string());
+        if existing.is_none() {
+            ensure_unique_name(&db, parent_id.as_deref(), &name)?;
+        }
+
+        // Copy next to the layer first so a failed or corrupt copy never replaces it, and the
+        // final rename stays on one volume, where it replaces the old file in a single step.
+        let target_dir = target
+            .parent()
+            .ok_or_else(|| AppError::Message(format!("invalid path: {}", target.display())))?;
+        let staging = target_dir.join(format!(".restore-{backup_id}"));
+        let copied = copy_verified(&archived, &staging).and_then(|actual| {
+            if actual.eq_ignore_ascii_case(&expected) {
+                Ok(())
+            } else {
+                Err(AppError::Message(format!(
+                    "backup {backup_id} changed since it was taken: recorded {expected}, now {actual}"
+                )))
+            }
+        });
+        let restored = copied.and_then(|_| {
+            jobs::report_phase("replace", Some(85));
+            // `fs::rename` is MoveFileEx with MOVEFILE_REPLACE_EXISTING: the old file stays in
+            // place until the new one takes its name.
+            fs::rename(staging.join(&backup.file), &target)?;
+            Ok(())
+        });
+        if let Err(err) = fs::remove_dir_all(&staging) {
+            warn!(
+                "remove restore staging failed path={} err={err}",
+                staging.display()
+            );
+        }
+        restored?;
+
+        let node = match existing {
+            Some(node) => {
+                if node.bcd_guid.is_some() {
+                    if let Err(err) = self.delete_bcd(&node.id) {
+                        warn!("remove old boot entry failed node={} err={err}", node.id);
+                    }
+                }
+                db.update_node_status(&node.id, NodeStatus::Normal)?;
+                node
+            }
+            None => {
+                let node = Node {
+                    id: backup.node_id.clone(),
+                    parent_id,
+                    name,
+                    path: target.to_string_lossy().to_string(),
+                    desc: manifest.desc.clone(),
+                    created_at: Utc::now(),
+                    status: NodeStatus::Normal,
+                    os_edition: manifest.os_edition.clone(),
+                    os_version: manifest.os_version.clone(),
+                    os_build: manifest.os_build.clone(),
+                    ..Default::default()
+                };
+                db.insert_node(&node)?;
+                node
+            }
+        };
+
+        jobs::report_phase("boot_entry", Some(90));
+        if let Err(err) = self.repair_bcd(&node.id) {
+            warn!(
+                "recreate boot entry after restore failed node={} err={err}",
+                node.id
+            );
+        }
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(&node.id),
+            "restore_backup",
+            "ok",
+            &format!("backup={backup_id} source={}", archived.display()),
+            started.elapsed(),
+        )?;
+        info!("restore_backup node={} backup={backup_id}", node.id);
+        Ok(db.fetch_node(&node.id)?.unwrap_or(node))
+    }
+
+    fn backup_detached(&self, node: &Node, dest: &str) -> Result<BackupStatus> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let dest = dest.trim();
+        if dest.is_empty() {
+            return Err(AppError::Message("backup destination is empty".into()));
+        }
+        let source = Path::new(&node.path);
+        let meta = fs::metadata(source)?;
+        let source_modified = meta.modified().ok().map(DateTime::<Utc>::from);
+        let parent = match node.parent_id.as_deref() {
+            Some(parent_id) => db.fetch_node(parent_id)?,
+            None => None,
+        };
+
+        let outcome = copy_verified(source, Path::new(dest)).and_then(|sha256| {
+            let file_name = |path: &Path| path.file_name().map(|n| n.to_string_lossy().to_string());
+            let manifest = LibraryManifest {
+                name: Some(node.name.clone()),
+                desc: node.desc.clone(),
+                sha256: Some(sha256.clone()),
+                publisher: std::env::var("COMPUTERNAME").ok(),
+                published_at: Some(Utc::now()),
+                os_edition: node.os_edition.clone(),
+                os_version: node.os_version.clone(),
+                os_build: node.os_build.clone(),
+                parent: parent.as_ref().and_then(|p| file_name(Path::new(&p.path))),
+                node_id: Some(node.id.clone()),
+            };
+            let mut manifest_path = Path::new(dest).join(file_name(source).unwrap_or_default());
+            manifest_path.as_mut_os_string().push(".json");
+            fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+            Ok(sha256)
+        });
+
+        let status = BackupStatus {
+            id: Uuid::new_v4().to_string(),
+            file: source
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            node_id: node.id.clone(),
+            dest: dest.to_string(),
+            backed_up_at: Utc::now(),
+            result: if outcome.is_ok() { "ok" } else { "failed" }.to_string(),
+            file_bytes: meta.len(),
+            source_modified,
+            sha256: outcome.as_ref().ok().cloned(),
+            detail: outcome.as_ref().err().map(|err| err.to_string()),
+        };
+        db.upsert_backup(&status)?;
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(&node.id),
+            "backup",
+            &status.result,
+            &format!(
+                "dest={dest} {}",
+                status.detail.as_deref().unwrap_or_default()
+            ),
+            started.elapsed(),
+        )?;
+        outcome.map(|_| status)
+    }
+
+    fn template_dir(&self) -> Result<PathBuf> {
+        let templates = self
+            .db()?
+            .get_settings()?
+            .template_path
+            .ok_or_else(|| AppError::Message("no template folder configured".into()))?;
+        let templates = PathBuf::from(expand_env_vars(&templates));
+        if !templates.is_dir() {
+            return Err(AppError::Message(format!(
+                "template folder not found: {}",
+                templates.display()
+            )));
+        }
+        Ok(templates)
+    }
+
+    fn library_dir(&self) -> Result<PathBuf> {
+        let library = self
+            .db()?
+            .get_settings()?
+            .library_path
+            .ok_or_else(|| AppError::Message("no library path configured".into()))?;
+        let library = PathBuf::from(library);
+        if !library.is_dir() {
+            return Err(AppError::Message(format!(
+                "library not reachable: {}",
+                library.display()
+            )));
+        }
+        Ok(library)
+    }
+
+    /// Checkpoint the running system into a new base layer.
+    ///
+    /// A VSS snapshot of the system volume is captured into a temporary WIM which is then
+    /// applied to a fresh base VHDX, so the live environment never has to be shut down.
+    pub fn capture_running_layer(
+        &self,
+        name: &str,
+        desc: Option<String>,
+        size_gb: u64,
+    ) -> Result<Node> {
+        let started = Instant::now();
+        let paths = self.paths()?;
+        paths.ensure_layout()?;
+        let db = self.db()?;
+        let capture_id = Uuid::new_v4().to_string();
+        let system_drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".into());
+
+        let ps_script = format!(
+            r#"$ErrorActionPreference = 'Stop'
+$res = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create -Arguments @{{ Volume = '{volume}\'; Context = 'ClientAccessible' }}
+if ($res.ReturnValue -ne 0) {{ throw "Win32_ShadowCopy.Create failed with code $($res.ReturnValue)" }}
+$shadow = Get-CimInstance -ClassName Win32_ShadowCopy | Where-Object {{ $_.ID -eq $res.ShadowID }}
+Write-Output "$($shadow.ID)|$($shadow.DeviceObject)"
+"#,
+            volume = ps_escape_single(&system_drive),
+        );
+        jobs::report_phase("snapshot", None);
+        let shadow_res = run_powershell(&ps_script)?;
+        log_command("vss create", &shadow_res, None);
+        if !shadow_res.succeeded() {
+            return Err(command_error("vss create", &shadow_res, None));
+        }
+        let (shadow_id, device) = shadow_res
+            .stdout
+            .lines()
+            .find_map(|line| line.trim().split_once('|'))
+            .map(|(id, dev)| (id.to_string(), dev.to_string()))
+            .ok_or_else(|| AppError::Message("failed to read shadow copy device".into()))?;
+
+        let link = paths.mount_root().join(format!("shadow-{capture_id}"));
+        // The snapshot holds the whole system volume including the workspace, so the WIM
+        // is staged outside of it and every layer folder is excluded from the capture.
+        let scratch = if paths
+            .layer_roots()
+            .iter()
+            .any(|dir| is_within(&paths.tmp_dir(), dir))
+        {
+            std::env::temp_dir().join("layered-system")
+        } else {
+            paths.tmp_dir()
+        };
+        let wim_path = scratch.join(format!("capture-{capture_id}.wim"));
+        let captured = (|| {
+            if paths
+                .layer_roots()
+                .iter()
+                .any(|dir| is_within(&scratch, dir))
+            {
+                return Err(AppError::Message(format!(
+                    "no staging folder outside the workspace for the capture: {}",
+                    scratch.display()
+                )));
+            }
+            let temp = TempManager::new(&scratch)?;
+            let config =
+                temp.write_script("capture.ini", &capture_config(&paths, &system_drive))?;
+            let link_res = run_elevated_command(
+                "cmd.exe",
+                &[
+                    "/c",
+                    "mklink",
+                    "/d",
+                    link.to_string_lossy().as_ref(),
+                    &format!("{device}\\"),
+                ],
+                None,
+            )?;
+            log_command("mklink shadow", &link_res, None);
+            if !link_res.succeeded() {
+                return Err(command_error("mklink shadow", &link_res, None));
+            }
+            jobs::report_phase("capture_image", None);
+            let capture_res = capture_image(
+                link.to_string_lossy().as_ref(),
+                wim_path.to_string_lossy().as_ref(),
+                name,
+                Some(&config),
+            )?;
+            log_command("dism capture", &capture_res, None);
+            if !capture_res.succeeded() {
+                return Err(command_error("dism capture", &capture_res, None));
+            }
+            Ok(())
+        })();
+
+        let _ = fs::remove_dir(&link);
+        let cleanup_script = format!(
+            r#"Get-CimInstance -ClassName Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{id}' }} | Remove-CimInstance"#,
+            id = ps_escape_single(&shadow_id),
+        );
+        if let Ok(o) = run_powershell(&cleanup_script) {
+            log_command("vss delete", &o, None);
+        }
+
+        let result = captured.and_then(|_| {
+            self.create_base(
+                name,
+                desc,
+                wim_path.to_string_lossy().as_ref(),
+                1,
+                size_gb,
+                false,
+            )
+        });
+        let _ = fs::remove_file(&wim_path);
+        let node = result?;
+
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(&node.id),
+            "capture_running_layer",
+            "ok",
+            &format!("volume={system_drive} shadow={shadow_id}"),
+            started.elapsed(),
+        )?;
+        info!("capture_running_layer id={} shadow={shadow_id}", node.id);
+        Ok(node)
+    }
+
+    pub fn create_diff(
+        &self,
+        parent_id: &str,
+        name: &str,
+        desc: Option<String>,
+        scripts: Vec<ProvisioningScript>,
+    ) -> Result<Node> {
+        let started = Instant::now();
+        let name = name.trim();
+        let slug = node_slug(name)?;
+        let db = self.db()?;
+        let parent = db
+            .fetch_node(parent_id)?
+            .ok_or_else(|| AppError::Message("parent not found".into()))?;
+        ensure_unique_name(&db, Some(parent_id), name)?;
+        ensure_quota(&db, Some(parent_id), 0)?;
+        if parent.bitlocker {
+            return Err(AppError::Message(format!(
+                "parent {} is BitLocker-encrypted; differencing children of an encrypted layer are not supported",
+                parent.name
+            )));
+        }
+        if parent.status.in_progress() {
+            return Err(AppError::Message(format!(
+                "parent {} is busy ({:?}); wait for its job to finish",
+                parent.name, parent.status
+            )));
+        }
+        let paths = self.paths()?;
+        paths.ensure_layout()?;
+        let headroom = gb_to_bytes(db.get_settings()?.free_space_headroom_gb as u64);
+        ensure_free_space(&paths.diff_dir(), headroom)?;
+        let id = Uuid::new_v4().to_string();
+        let op_id = jobs::next_op_id();
+
+        let vhd_path = next_vhd_path(&db, &paths.diff_dir(), &slug)?;
+        let pending = BusyNode::creating(
+            &db,
+            Node {
+                id: id.clone(),
+                parent_id: Some(parent_id.to_string()),
+                name: name.to_string(),
+                path: vhd_path.to_string_lossy().to_string(),
+                desc: desc.clone(),
+                created_at: Utc::now(),
+                ..Default::default()
+            },
+        )?;
+
+        let temp = TempManager::new(paths.tmp_dir())?;
+        let sys_letter = pick_free_letter().ok_or_else(|| {
+            AppError::Message("no free drive letter available between S: and Z:".into())
+        })?;
+
+        jobs::report_phase("create_disk", Some(10));
+        let attach_script = diff_attach_assign_script(
+            &vhd_path,
+            Path::new(&parent.path),
+            BASE_SYSTEM_PARTITION,
+            sys_letter,
+        )?;
+        let attach_path = temp.write_script("create_diff.txt", &attach_script)?;
+        log_diskpart_script(&attach_path);
+        let attach_res = run_diskpart_script(&attach_path)?;
+        log_command("diskpart create diff", &attach_res, Some(&attach_path));
+        self.record_attach(
+            &id,
+            &op_id,
+            "attach",
+            "create_diff",
+            attach_res.exit_code == Some(0),
+        );
+        if !attach_res.succeeded() {
+            return Err(command_error(
+                "diskpart create diff",
+                &attach_res,
+                Some(&attach_path),
+            ));
+        }
+
+        let parts = attached_partitions(&vhd_path, &attach_res.stdout);
+        let sys_part = system_partition_index(&parts).ok_or_else(|| {
+            AppError::Message("failed to detect system partition from list partition".into())
+        })?;
+
+        // The combined session assumed the standard layout; only parents with a different
+        // layout need a second session to assign the letter to the detected partition.
+        if sys_part != BASE_SYSTEM_PARTITION || !Path::new(&format!("{sys_letter}:\\")).exists() {
+            let assign_script = reassign_partition_script(&vhd_path, sys_part, sys_letter)?;
+            let assign_path = temp.write_script("assign_diff.txt", &assign_script)?;
+            log_diskpart_script(&assign_path);
+            let assign_res = run_diskpart_script(&assign_path)?;
+            log_command("diskpart assign diff", &assign_res, Some(&assign_path));
+            if !assign_res.succeeded() {
+                return Err(command_error(
+                    "diskpart assign diff",
+                    &assign_res,
+                    Some(&assign_path),
+                ));
+            }
+        }
+
+        jobs::report_phase("boot_files", Some(50));
+        let sys_mount = PathBuf::from(format!("{sys_letter}:"));
+        let bcd_res = run_bcdboot(&sys_mount)?;
+        log_command("bcdboot", &bcd_res, None);
+        if !bcd_res.succeeded() {
+            return Err(command_error("bcdboot", &bcd_res, None));
+        }
+        let bcd_enum = bcdedit_enum_all()?;
+        log_command("bcdedit enum", &bcd_enum, None);
+        let guid = extract_guid_for_vhd(&bcd_enum.stdout, vhd_path.to_str().unwrap_or_default())
+            .or_else(|| extract_guid_for_partition_letter(&bcd_enum.stdout, sys_letter))
+            .unwrap_or_default();
+        if !guid.is_empty() {
+            self.apply_description_template(&guid, name, Some(&parent.name));
+        }
+        let boot_ready = boot_files_verified(&guid);
+
+        let sys_root = PathBuf::from(format!("{sys_letter}:\\"));
+        if let Err(err) = self.install_boot_marker(&sys_root, &id) {
+            info!("install boot marker failed node={id} err={err}");
+        }
+        let os_info = read_os_info(&sys_root).unwrap_or_else(|err| {
+            info!("read os info failed node={id} err={err}");
+            OsInfo::default()
+        });
+        let provision_res = if scripts.is_empty() {
+            None
+        } else {
+            Some(install_provisioning_scripts(&sys_root, &scripts))
+        };
+
+        jobs::report_phase("detach", Some(90));
+        let detach_script = detach_vdisk_script(&vhd_path, &[sys_letter])?;
+        let detach_path = temp.write_script("detach_diff.txt", &detach_script)?;
+        log_diskpart_script(&detach_path);
+        let detach_res = run_diskpart_script(&detach_path)?;
+        log_command("diskpart detach diff", &detach_res, Some(&detach_path));
+        self.record_attach(
+            &id,
+            &op_id,
+            "detach",
+            "create_diff",
+            detach_res.exit_code == Some(0),
+        );
+        if detach_res.exit_code != Some(0) {
+            log_detach_holders(&vhd_path, &[sys_letter]);
+        }
+
+        // A layer whose scripts could not be staged is rolled back as a whole, so a failed
+        // create never leaves a registered node behind; `pending` drops the row.
+        if let Some(Err(err)) = provision_res {
+            if !guid.is_empty() {
+                match bcdedit_delete(&guid) {
+                    Ok(res) => log_command("bcdedit delete", &res, None),
+                    Err(err) => warn!("create_diff rollback bcd delete failed node={id} err={err}"),
+                }
+            }
+            if let Err(err) = fs::remove_file(&vhd_path) {
+                warn!(
+                    "create_diff rollback remove vhdx failed path={} err={err}",
+                    vhd_path.display()
+                );
+            }
+            db.delete_ops_for_nodes(std::slice::from_ref(&id))?;
+            db.insert_timed_op(
+                &op_id,
+                None,
+                "create_diff",
+                "error",
+                &format!("name={name} provisioning failed: {err}"),
+                started.elapsed(),
+            )?;
+            return Err(err);
+        }
+
+        let node = Node {
+            id: id.clone(),
+            parent_id: Some(parent_id.to_string()),
+            name: name.to_string(),
+            path: vhd_path.to_string_lossy().to_string(),
+            bcd_guid: if guid.is_empty() {
+                None
+            } else {
+                Some(guid.clone())
+            },
+            desc,
+            created_at: Utc::now(),
+            status: NodeStatus::Normal,
+            boot_files_ready: boot_ready,
+            os_edition: os_info.edition,
+            os_version: os_info.version,
+            os_build: os_info.build,
+            ..Default::default()
+        };
+        db.complete_node(&node)?;
+        pending.finish();
+        db.insert_timed_op(
+            &op_id,
+            Some(&id),
+            "create_diff",
+            "ok",
+            "",
+            started.elapsed(),
+        )?;
+        if let Some(res) = provision_res {
+            db.set_node_scripts(&id, &scripts)?;
+            record_provisioning(&db, &id, &scripts, res)?;
+        }
+        info!("create_diff id={id} parent={parent_id}");
+        Ok(node)
+    }
+
+    /// Set the one-time boot sequence to the node's entry and reboot, either immediately or at
+    /// `schedule_at` through a scheduled task. Booting a layer writes to it, so layers with
+    /// children are refused unless `force` is set.
+    pub fn set_bootsequence_and_reboot(
+        &self,
+        node_id: &str,
+        schedule_at: Option<DateTime<Utc>>,
+        force: bool,
+    ) -> Result<CommandOutput> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "booting", force)?;
+        let guid = node
+            .bcd_guid
+            .clone()
+            .ok_or_else(|| AppError::Message("node missing bcd guid".into()))?;
+        // A broken chain stops the boot with 0xc03a0003 and leaves the user at a blue screen
+        // instead of this app, so it is refused even with `force`.
+        let issues = self.validate_boot_chain(node_id)?;
+        if !issues.is_empty() {
+            let report: Vec<&str> = issues.iter().map(|i| i.message.as_str()).collect();
+            return Err(AppError::Message(format!(
+                "{} cannot boot: {}",
+                node.name,
+                report.join("; ")
+            )));
+        }
+
+        let Some(at) = schedule_at else {
+            let res = bcdedit_boot_sequence_and_reboot(&guid)?;
+            log_command("bcdedit bootsequence", &res, None);
+            db.insert_op(
+                &jobs::next_op_id(),
+                Some(node_id),
+                "bootsequence_reboot",
+                "ok",
+                "",
+            )?;
+            info!("bootsequence node={node_id} guid={guid}");
+            return Ok(res);
+        };
+
+        if at <= Utc::now() {
+            return Err(AppError::Message(
+                "scheduled boot time must be in the future".into(),
+            ));
+        }
+        let res = bcdedit_boot_sequence(&guid)?;
+        log_command("bcdedit bootsequence", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("bcdedit bootsequence", &res, None));
+        }
+
+        let local_at = at.with_timezone(&Local).format("%Y-%m-%dT%H:%M:%S");
+        let ps_script = format!(
+            r#"$ErrorActionPreference = 'Stop'
+$action = New-ScheduledTaskAction -Execute 'shutdown.exe' -Argument '/r /t 0'
+$trigger = New-ScheduledTaskTrigger -Once -At ([datetime]'{local_at}')
+$principal = New-ScheduledTaskPrincipal -UserId 'SYSTEM' -LogonType ServiceAccount -RunLevel Highest
+Register-ScheduledTask -TaskName '{task}' -Action $action -Trigger $trigger -Principal $principal -Force | Out-Null
+"#,
+            task = SCHEDULED_BOOT_TASK,
+        );
+        let task_res = run_powershell(&ps_script)?;
+        log_command("schedule boot", &task_res, None);
+        if !task_res.succeeded() {
+            if let Ok(o) = bcdedit_clear_boot_sequence() {
+                log_command("bcdedit clear bootsequence", &o, None);
+            }
+            return Err(command_error("schedule boot", &task_res, None));
+        }
+
+        db.insert_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "bootsequence_scheduled",
+            "ok",
+            &format!("at={}", at.to_rfc3339()),
+        )?;
+        info!("bootsequence scheduled node={node_id} guid={guid} at={local_at}");
+        Ok(res)
+    }
+
+    /// Remove a pending scheduled reboot and clear the one-time boot sequence.
+    pub fn cancel_scheduled_boot(&self) -> Result<()> {
+        let db = self.db()?;
+        let ps_script = format!(
+            r#"$ErrorActionPreference = 'Stop'
+$task = Get-ScheduledTask -TaskName '{task}' -ErrorAction SilentlyContinue
+if ($task) {{ Unregister-ScheduledTask -TaskName '{task}' -Confirm:$false }}
+"#,
+            task = SCHEDULED_BOOT_TASK,
+        );
+        let task_res = run_powershell(&ps_script)?;
+        log_command("cancel scheduled boot", &task_res, None);
+        if !task_res.succeeded() {
+            return Err(command_error("cancel scheduled boot", &task_res, None));
+        }
+        let res = bcdedit_clear_boot_sequence()?;
+        log_command("bcdedit clear bootsequence", &res, None);
+
+        db.insert_op(&jobs::next_op_id(), None, "cancel_scheduled_boot", "ok", "")?;
+        info!("cancel_scheduled_boot");
+        Ok(())
+    }
+
+    pub fn start_vm(&self, node_id: &str) -> Result<String> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+
+        let vhd_path = PathBuf::from(&node.path);
+        if !vhd_path.exists() {
+            return Err(AppError::Message(format!("vhdx not found: {}", node.path)));
+        }
+
+        let paths = self.paths()?;
+        paths.ensure_layout()?;
+        fs::create_dir_all(paths.vms_dir())?;
+
+        let vm_name = format!("ls-{}", node.id);
+        let vm_dir = paths.vms_dir().join(&vm_name);
+        fs::create_dir_all(&vm_dir)?;
+
+        let ps_script = format!(
+            r#"$ErrorActionPreference = 'Stop'
+if (-not (Get-Command -Name 'Get-VM' -ErrorAction SilentlyContinue)) {{ throw 'Hyper-V PowerShell module is not available (Get-VM not found).'; }}
+if (-not (Get-Command -Name 'vmconnect.exe' -ErrorAction SilentlyContinue)) {{ throw 'vmconnect.exe not found in PATH.'; }}
+$vmName = '{vm_name}'
+$vmPath = '{vm_path}'
+$vhdPath = '{vhd_path}'
+if (-not (Test-Path -Path $vmPath)) {{ New-Item -ItemType Directory -Path $vmPath | Out-Null }}
+$vm = Get-VM -Name $vmName -ErrorAction SilentlyContinue
+if (-not $vm) {{
+    $vm = New-VM -Name $vmName -Generation 2 -MemoryStartupBytes 2GB -VHDPath $vhdPath -Path $vmPath
+}} else {{
+    $drive = Get-VMHardDiskDrive -VMName $vmName -ErrorAction SilentlyContinue | Select-Object -First 1
+    if ($drive) {{
+        Set-VMHardDiskDrive -VMHardDiskDrive $drive -Path $vhdPath | Out-Null
+    }} else {{
+        Add-VMHardDiskDrive -VMName $vmName -Path $vhdPath | Out-Null
+    }}
+}}
+if ($vm.State -ne 'Running') {{
+    Start-VM -Name $vmName | Out-Null
+}}
+Start-Process vmconnect.exe -ArgumentList 'localhost', $vmName | Out-Null
+"#,
+            vm_name = ps_escape_single(&vm_name),
+            vm_path = ps_escape_single(vm_dir.to_string_lossy().as_ref()),
+            vhd_path = ps_escape_single(vhd_path.to_string_lossy().as_ref()),
+        );
+
+        let res = run_powershell(&ps_script)?;
+        log_command("start_vm", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("start_vm", &res, None));
+        }
+        db.insert_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "start_vm",
+            "ok",
+            &format!("vm_name={vm_name}"),
+        )?;
+        info!("start_vm node={node_id} vm_name={vm_name}");
+        Ok(vm_name)
+    }
+
+    /// Boot a layer headlessly in a throwaway Hyper-V VM and wait for its post-boot marker,
+    /// catching images that apply cleanly but do not boot.
+    ///
+    /// The VM runs on a temporary differencing child, so the layer itself is never written. The
+    /// child gets the boot marker task plus a shutdown after it; once the guest powered off (or
+    /// the timeout hit) the child is mounted and checked for the marker. The VM and the child
+    /// are removed afterwards. On success `boot_verified_at` is set.
+    pub fn verify_boot_in_vm(&self, node_id: &str) -> Result<()> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let paths = self.paths()?;
+        paths.ensure_layout()?;
+
+        let vm_name = format!("ls-verify-{}", Uuid::new_v4().simple());
+        let vm_dir = paths.vms_dir().join(&vm_name);
+        let diff_path = vm_dir.join("verify.vhdx");
+        let markers_rel = relative_to_drive(&paths.markers_dir());
+        let op_id = jobs::next_op_id();
+        let child = Node {
+            path: diff_path.to_string_lossy().to_string(),
+            ..node.clone()
+        };
+
+        let verified = (|| {
+            let vhd_script = format!(
+                r#"$ErrorActionPreference = 'Stop'
+if (-not (Get-Command -Name 'Get-VM' -ErrorAction SilentlyContinue)) {{ throw 'Hyper-V PowerShell module is not available (Get-VM not found).'; }}
+New-Item -ItemType Directory -Path '{vm_path}' -Force | Out-Null
+New-VHD -Path '{diff_path}' -ParentPath '{parent_path}' -Differencing | Out-Null
+"#,
+                vm_path = ps_escape_single(vm_dir.to_string_lossy().as_ref()),
+                diff_path = ps_escape_single(diff_path.to_string_lossy().as_ref()),
+                parent_path = ps_escape_single(&node.path),
+            );
+            let res = run_powershell(&vhd_script)?;
+            log_command("verify boot new-vhd", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("verify boot new-vhd", &res, None));
+            }
+
+            // Inside the guest the layer is the system drive, so the marker task finds the
+            // markers folder there. The shutdown follows after a delay so the marker is
+            // written before the guest powers off.
+            self.with_mounted_layer(&child, "verify", &op_id, |sys_mount| {
+                fs::create_dir_all(sys_mount.join(&markers_rel))?;
+                self.install_boot_marker(sys_mount, node_id)?;
+                add_run_once(
+                    sys_mount,
+                    "LayeredSystemVerifyShutdown",
+                    "shutdown.exe /s /f /t 30",
+                )
+            })?;
+
+            let vm_script = format!(
+                r#"$ErrorActionPreference = 'Stop'
+$vmName = '{vm_name}'
+try {{
+    New-VM -Name $vmName -Generation 2 -MemoryStartupBytes 2GB -VHDPath '{diff_path}' -Path '{vm_path}' | Out-Null
+    Start-VM -Name $vmName | Out-Null
+    $deadline = (Get-Date).AddSeconds({timeout})
+    while ((Get-Date) -lt $deadline -and (Get-VM -Name $vmName).State -ne 'Off') {{
+        Start-Sleep -Seconds 5
+    }}
+}} finally {{
+    Stop-VM -Name $vmName -TurnOff -Force -ErrorAction SilentlyContinue
+    Remove-VM -Name $vmName -Force -ErrorAction SilentlyContinue
+}}
+"#,
+                vm_name = ps_escape_single(&vm_name),
+                vm_path = ps_escape_single(vm_dir.to_string_lossy().as_ref()),
+                diff_path = ps_escape_single(diff_path.to_string_lossy().as_ref()),
+                timeout = VERIFY_BOOT_TIMEOUT_SECS,
+            );
+            let res = run_powershell(&vm_script)?;
+            log_command("verify boot", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("verify boot", &res, None));
+            }
+
+            let marked = self.with_mounted_layer(&child, "verify", &op_id, |sys_mount| {
+                Ok(sys_mount
+                    .join(&markers_rel)
+                    .join(format!("{node_id}.ok"))
+                    .exists())
+            })?;
+            if !marked {
+                return Err(AppError::Message(format!(
+                    "layer did not write its boot marker within {VERIFY_BOOT_TIMEOUT_SECS} seconds"
+                )));
+            }
+            Ok(())
+        })();
+
+        if let Err(err) = fs::remove_dir_all(&vm_dir) {
+            warn!(
+                "remove verify vm folder failed dir={} err={err}",
+                vm_dir.display()
+            );
+        }
+        let detail = match &verified {
+            Ok(()) => format!("vm_name={vm_name}"),
+            Err(err) => format!("vm_name={vm_name} err={err}"),
+        };
+        db.insert_timed_op(
+            &op_id,
+            Some(node_id),
+            "verify_boot",
+            if verified.is_ok() { "ok" } else { "error" },
+            &detail,
+            started.elapsed(),
+        )?;
+        verified?;
+        db.set_node_boot_verified(node_id, Utc::now())?;
+        info!("verify_boot node={node_id} vm_name={vm_name}");
+        Ok(())
+    }
+
+    /// Gate for `delete_subtree` under the `confirm_destructive` policy. Returns a summary with a
+    /// fresh token when one is needed; `Ok(None)` means the deletion may proceed, either because
+    /// the policy is off or because `token` matched the current subtree.
+    pub fn confirm_delete_subtree(
+        &self,
+        node_id: &str,
+        token: Option<&str>,
+    ) -> Result<Option<ConfirmationRequest>> {
+        let db = self.db()?;
+        if !db.get_settings()?.confirm_destructive {
+            return Ok(None);
+        }
+        let nodes = db.fetch_nodes()?;
+        if !nodes.iter().any(|n| n.id == node_id) {
+            return Err(AppError::Message("node not found".into()));
+        }
+        let affected: Vec<&Node> = subtree_ids(&nodes, node_id)
+            .iter()
+            .filter_map(|id| nodes.iter().find(|n| &n.id == id))
+            .collect();
+        // Any added, removed or edited layer in the subtree invalidates an issued token.
+        let mut fingerprint: Vec<String> = affected
+            .iter()
+            .map(|n| format!("{}@{}", n.id, n.version))
+            .collect();
+        fingerprint.sort();
+        let fingerprint = fingerprint.join(",");
+        if let Some(token) = token {
+            confirm::redeem(token, "delete_subtree", node_id, &fingerprint)?;
+            return Ok(None);
+        }
+        let (token, expires_at) = confirm::issue("delete_subtree", node_id, fingerprint);
+        info!(
+            "confirm_delete_subtree node={node_id} count={} expires_at={expires_at}",
+            affected.len()
+        );
+        Ok(Some(ConfirmationRequest {
+            action: "delete_subtree".into(),
+            node_id: node_id.to_string(),
+            affected: affected.iter().map(|n| n.name.clone()).collect(),
+            total_bytes: affected
+                .iter()
+                .map(|n| fs::metadata(&n.path).map(|m| m.len()).unwrap_or(0))
+                .sum(),
+            token,
+            expires_at,
+        }))
+    }
+
+    pub fn delete_subtree(&self, node_id: &str) -> Result<()> {
+        let db = self.db()?;
+        let nodes = db.fetch_nodes()?;
+        let mut order = subtree_ids(&nodes, node_id);
+        // Delete children after parents? requirement: delete subtree; we reverse to delete leaves first.
+        order.reverse();
+        let mut busy = Vec::new();
+        for id in order.iter() {
+            let Some(node) = nodes.iter().find(|n| &n.id == id) else {
+                continue;
+            };
+            if node.status.in_progress() {
+                return Err(AppError::Message(format!(
+                    "{} is busy ({:?}); wait for its job to finish",
+                    node.name, node.status
+                )));
+            }
+            if node.protected {
+                return Err(AppError::Message(format!(
+                    "{} is protected; clear the flag before deleting it",
+                    node.name
+                )));
+            }
+            busy.push(BusyNode::mark(&db, node, NodeStatus::Deleting)?);
+        }
+        for id in order.iter() {
+            if let Some(node) = db.fetch_node(id)?.clone() {
+                if let Some(guid) = node.bcd_guid.as_ref() {
+                    if let Ok(o) = bcdedit_delete(guid) {
+                        log_command("bcdedit delete", &o, None);
+                    }
+                }
+                // attempt detach
+                let temp = TempManager::new(self.paths()?.tmp_dir())?;
+                if let Ok(detach_script) = detach_vdisk_script(Path::new(&node.path), &[]) {
+                    let path = temp.write_script("detach_cleanup.txt", &detach_script)?;
+                    log_diskpart_script(&path);
+                    if let Ok(o) = run_diskpart_script(&path) {
+                        log_command("diskpart detach cleanup", &o, Some(&path));
+                    }
+                }
+                // delete file
+                // let _ = fs::remove_file(&node.path);
+                if node.status == NodeStatus::Archived {
+                    let _ = fs::remove_file(archive_path(&self.paths()?, &node.id));
+                }
+            }
+        }
+        db.delete_ops_for_nodes(&order)?;
+        db.delete_nodes(&order)?;
+        busy.into_iter().for_each(BusyNode::finish);
+        db.insert_op(
+            &jobs::next_op_id(),
+            None,
+            "delete_subtree",
+            "ok",
+            &format!("node_id={}", node_id),
+        )?;
+        info!("delete_subtree node={node_id} count={}", order.len());
+        Ok(())
+    }
+
+    /// Compress a detached leaf layer into `meta/archive` with zstd, drop its boot entry and
+    /// mark it Archived. The VHDX is removed once the archive is complete.
+    pub fn archive_node(&self, node_id: &str) -> Result<Node> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if node.status != NodeStatus::Normal {
+            return Err(AppError::Message(format!(
+                "{} is {:?}; only layers in normal state can be archived",
+                node.name, node.status
+            )));
+        }
+        self.ensure_no_children(&node, "archiving", false)?;
+        let paths = self.paths()?;
+        let archive_dir = paths.archive_dir();
+        fs::create_dir_all(&archive_dir)?;
+        let vhd_path = PathBuf::from(&node.path);
+        let vhd_bytes = fs::metadata(&vhd_path)?.len();
+        let headroom = gb_to_bytes(db.get_settings()?.free_space_headroom_gb as u64);
+        ensure_free_space(&archive_dir, vhd_bytes / 2 + headroom)?;
+
+        // An attached or booted VHDX cannot be renamed, which doubles as the detach check and
+        // keeps anything from attaching it while it is being compressed.
+        let staged = vhd_path.with_extension("vhdx.archiving");
+        fs::rename(&vhd_path, &staged).map_err(|err| {
+            AppError::Message(format!(
+                "{} is attached or in use; detach it before archiving ({err})",
+                node.name
+            ))
+        })?;
+        let archive = archive_path(&paths, node_id);
+        let partial = archive.with_extension("zst.partial");
+        jobs::report_phase("compress", None);
+        let compressed = compress_file(&staged, &partial).and_then(|()| {
+            fs::rename(&partial, &archive)?;
+            Ok(())
+        });
+        if let Err(err) = compressed {
+            let _ = fs::remove_file(&partial);
+            fs::rename(&staged, &vhd_path)?;
+            return Err(err);
+        }
+
+        if let Some(guid) = node.bcd_guid.as_ref() {
+            match bcdedit_delete(guid) {
+                Ok(res) => log_command("bcdedit delete", &res, None),
+                Err(err) => warn!("archive bcd delete failed node={node_id} err={err}"),
+            }
+        }
+        db.clear_node_bcd(node_id)?;
+        db.update_node_status(node_id, NodeStatus::Archived)?;
+        fs::remove_file(&staged)?;
+        let archive_bytes = fs::metadata(&archive).map(|m| m.len()).unwrap_or(0);
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "archive_node",
+            "ok",
+            &format!("before={vhd_bytes} after={archive_bytes}"),
+            started.elapsed(),
+        )?;
+        info!("archive_node id={node_id} before={vhd_bytes} after={archive_bytes}");
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
+    }
+
+    /// Decompress an archived layer back to its original path and recreate its boot entry.
+    pub fn restore_archived(&self, node_id: &str) -> Result<Node> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if node.status != NodeStatus::Archived {
+            return Err(AppError::Message(format!("{} is not archived", node.name)));
+        }
+        let paths = self.paths()?;
+        let archive = archive_path(&paths, node_id);
+        if !archive.is_file() {
+            return Err(AppError::Message(format!(
+                "archive of {} not found: {}",
+                node.name,
+                archive.display()
+            )));
+        }
+        let vhd_path = PathBuf::from(&node.path);
+        if vhd_path.exists() {
+            return Err(AppError::Message(format!(
+                "{} already exists; move it away before restoring",
+                vhd_path.display()
+            )));
+        }
+        if let Some(dir) = vhd_path.parent() {
+            fs::create_dir_all(dir)?;
+            let headroom = gb_to_bytes(db.get_settings()?.free_space_headroom_gb as u64);
+            ensure_free_space(dir, fs::metadata(&archive)?.len() * 2 + headroom)?;
+        }
+        let partial = vhd_path.with_extension("vhdx.partial");
+        jobs::report_phase("decompress", None);
+        let restored = decompress_file(&archive, &partial).and_then(|()| {
+            fs::rename(&partial, &vhd_path)?;
+            Ok(())
+        });
+        if let Err(err) = restored {
+            let _ = fs::remove_file(&partial);
+            return Err(err);
+        }
+        db.update_node_status(node_id, NodeStatus::Normal)?;
+        fs::remove_file(&archive)?;
+
+        jobs::report_phase("bcd", None);
+        if let Err(err) = self.repair_bcd_inner(node_id, None) {
+            warn!("restore bcd entry failed node={node_id} err={err}");
+        }
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "restore_archived",
+            "ok",
+            &format!("path={}", node.path),
+            started.elapsed(),
+        )?;
+        info!("restore_archived id={node_id}");
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
+    }
+
+    pub fn delete_bcd(&self, node_id: &str) -> Result<()> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if let Some(guid) = node.bcd_guid.as_ref() {
+            let res = bcdedit_delete(guid)?;
+            log_command("bcdedit delete", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("bcdedit delete", &res, None));
+            }
+        }
+        db.clear_node_bcd(node_id)?;
+        db.insert_op(&jobs::next_op_id(), Some(node_id), "delete_bcd", "ok", "")?;
+        info!("delete_bcd node={node_id}");
+        Ok(())
+    }
+
+    pub fn add_bcd_entry(
+        &self,
+        node_id: &str,
+        description: Option<String>,
+    ) -> Result<Option<String>> {
+        let guid = self.repair_bcd_inner(node_id, description.as_deref())?;
+        Ok(guid)
+    }
+
+    pub fn update_bcd_description(&self, node_id: &str, description: &str) -> Result<()> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let guid = node
+            .bcd_guid
+            .clone()
+            .ok_or_else(|| AppError::Message("node missing bcd guid".into()))?;
+        let res = bcdedit_set_description(&guid, description)?;
+        log_command("bcdedit set description", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("bcdedit set description", &res, None));
+        }
+        db.insert_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "update_bcd_description",
+            "ok",
+            description,
+        )?;
+        Ok(())
+    }
+
+    pub fn list_firmware_entries(&self) -> Result<FirmwareBootInfo> {
+        let res = bcdedit_enum_firmware()?;
+        log_command("bcdedit enum firmware", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("bcdedit enum firmware", &res, None));
+        }
+        let info = parse_firmware_entries(&res.stdout);
+        if !info.boot_manager_first {
+            warn!(
+                "windows boot manager is not first in firmware order entries={}",
+                info.entries.len()
+            );
+        }
+        Ok(info)
+    }
+
+    /// Set the entry description from the workspace template, if one is configured.
+    fn apply_description_template(&self, guid: &str, name: &str, parent: Option<&str>) {
+        let template = match self.db().and_then(|db| db.get_settings()) {
+            Ok(settings) => settings.bcd_description_template,
+            Err(err) => {
+                warn!("read description template failed err={err}");
+                return;
+            }
+        };
+        let Some(template) = template else {
+            return;
+        };
+        let desc = render_description_template(&template, name, parent);
+        match bcdedit_set_description(guid, &desc) {
+            Ok(res) => log_command("bcdedit set description", &res, None),
+            Err(err) => warn!("set templated description failed guid={guid} err={err}"),
+        }
+    }
+
+    /// Switch `bootmenupolicy` on one managed entry, or on all of them when `node_id` is `None`.
+    /// `display_boot_menu` toggles `displaybootmenu` on the boot manager.
+    pub fn set_boot_menu_policy(
+        &self,
+        node_id: Option<&str>,
+        policy: BootMenuPolicy,
+        display_boot_menu: Option<bool>,
+    ) -> Result<usize> {
+        let db = self.db()?;
+        let targets: Vec<Node> = match node_id {
+            Some(id) => {
+                let node = db
+                    .fetch_node(id)?
+                    .ok_or_else(|| AppError::Message("node not found".into()))?;
+                if node.bcd_guid.is_none() {
+                    return Err(AppError::Message("node missing bcd guid".into()));
+                }
+                vec![node]
+            }
+            None => db
+                .fetch_nodes()?
+                .into_iter()
+                .filter(|n| n.bcd_guid.is_some())
+                .collect(),
+        };
+
+        for node in &targets {
+            let guid = node.bcd_guid.as_deref().unwrap_or_default();
+            let res = bcdedit_set(guid, "bootmenupolicy", policy.as_bcd())?;
+            log_command("bcdedit set bootmenupolicy", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("bcdedit set bootmenupolicy", &res, None));
+            }
+        }
+        if let Some(display) = display_boot_menu {
+            let res = bcdedit_set(
+                "{bootmgr}",
+                "displaybootmenu",
+                if display { "yes" } else { "no" },
+            )?;
+            log_command("bcdedit set displaybootmenu", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("bcdedit set displaybootmenu", &res, None));
+            }
+        }
+
+        db.insert_op(
+            &jobs::next_op_id(),
+            node_id,
+            "set_boot_menu_policy",
+            "ok",
+            &format!(
+                "policy={} display_boot_menu={display_boot_menu:?} count={}",
+                policy.as_bcd(),
+                targets.len()
+            ),
+        )?;
+        info!(
+            "set_boot_menu_policy policy={} count={}",
+            policy.as_bcd(),
+            targets.len()
+        );
+        Ok(targets.len())
+    }
+
+    pub fn get_entry_flags(&self, node_id: &str) -> Result<BootEntryFlags> {
+        let guid = self.node_bcd_guid(node_id)?;
+        let res = bcdedit_enum_entry(&guid)?;
+        log_command("bcdedit enum entry", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("bcdedit enum entry", &res, None));
+        }
+        let objects = parse_bcd_objects(&res.stdout);
+        let Some(entry) = objects.first() else {
+            return Ok(BootEntryFlags::default());
+        };
+        Ok(BootEntryFlags {
+            recovery_enabled: entry
+                .get("recoveryenabled")
+                .map(|v| v.eq_ignore_ascii_case("yes")),
+            boot_status_policy: entry
+                .get("bootstatuspolicy")
+                .and_then(BootStatusPolicy::from_bcd),
+            testsigning: entry
+                .get("testsigning")
+                .map(|v| v.eq_ignore_ascii_case("yes")),
+            nointegritychecks: entry
+                .get("nointegritychecks")
+                .map(|v| v.eq_ignore_ascii_case("yes")),
+        })
+    }
+
+    /// Update `recoveryenabled` / `bootstatuspolicy` so failed test boots don't end in WinRE,
+    /// and the driver signing switches, which are refused while Secure Boot is on: the firmware
+    /// rejects such an entry and the layer just never boots.
+    pub fn set_entry_flags(&self, node_id: &str, flags: BootEntryFlags) -> Result<BootEntryFlags> {
+        let guid = self.node_bcd_guid(node_id)?;
+        let unsigned = [
+            ("testsigning", flags.testsigning),
+            ("nointegritychecks", flags.nointegritychecks),
+        ];
+        let requested: Vec<&str> = unsigned
+            .iter()
+            .filter(|(_, value)| *value == Some(true))
+            .map(|(key, _)| *key)
+            .collect();
+        if !requested.is_empty() {
+            match secureboot::state() {
+                SecureBootState::Enabled => {
+                    return Err(AppError::Message(format!(
+                        "Secure Boot is enabled on this machine, so an entry with {} will not boot; turn Secure Boot off in the firmware settings first",
+                        requested.join(" and ")
+                    )))
+                }
+                SecureBootState::Unknown => warn!(
+                    "secure boot state unknown; {} may keep node={node_id} from booting",
+                    requested.join(" and ")
+                ),
+                SecureBootState::Disabled | SecureBootState::Unsupported => {}
+            }
+        }
+        if let Some(enabled) = flags.recovery_enabled {
+            let res = bcdedit_set(&guid, "recoveryenabled", if enabled { "yes" } else { "no" })?;
+            log_command("bcdedit set recoveryenabled", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("bcdedit set recoveryenabled", &res, None));
+            }
+        }
+        if let Some(policy) = flags.boot_status_policy {
+            let res = bcdedit_set(&guid, "bootstatuspolicy", policy.as_bcd())?;
+            log_command("bcdedit set bootstatuspolicy", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("bcdedit set bootstatuspolicy", &res, None));
+            }
+        }
+        for (key, value) in unsigned {
+            let Some(enabled) = value else {
+                continue;
+            };
+            let res = bcdedit_set(&guid, key, if enabled { "yes" } else { "no" })?;
+            let label = format!("bcdedit set {key}");
+            log_command(&label, &res, None);
+            if !res.succeeded() {
+                return Err(command_error(&label, &res, None));
+            }
+        }
+        self.db()?.insert_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "set_entry_flags",
+            "ok",
+            &format!(
+                "recoveryenabled={:?} bootstatuspolicy={:?} testsigning={:?} nointegritychecks={:?}",
+                flags.recovery_enabled,
+                flags.boot_status_policy.map(|p| p.as_bcd()),
+                flags.testsigning,
+                flags.nointegritychecks
+            ),
+        )?;
+        self.get_entry_flags(node_id)
+    }
+
+    fn node_bcd_guid(&self, node_id: &str) -> Result<String> {
+        let node = self
+            .db()?
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        node.bcd_guid
+            .ok_or_else(|| AppError::Message("node missing bcd guid".into()))
+    }
+
+    pub fn repair_bcd(&self, node_id: &str) -> Result<Option<String>> {
+        self.repair_bcd_inner(node_id, None)
+    }
+
+    fn repair_bcd_inner(&self, node_id: &str, description: Option<&str>) -> Result<Option<String>> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+
+        let parent_name = match node.parent_id.as_deref() {
+            Some(parent_id) => db.fetch_node(parent_id)?.map(|p| p.name),
+            None => None,
+        };
+
+        let op_id = jobs::next_op_id();
+        let guid = self.with_mounted_layer(&node, "repair", &op_id, |sys_mount| {
+            let sys_letter = drive_letter_of(sys_mount);
+            let bcd_res = run_bcdboot(sys_mount)?;
+            log_command("bcdboot", &bcd_res, None);
+            if !bcd_res.succeeded() {
+                return Err(command_error("bcdboot", &bcd_res, None));
+            }
+            let bcd_enum = bcdedit_enum_all()?;
+            log_command("bcdedit enum", &bcd_enum, None);
+            let guid = extract_guid_for_vhd(&bcd_enum.stdout, &node.path).or_else(|| {
+                sys_letter.and_then(|l| extract_guid_for_partition_letter(&bcd_enum.stdout, l))
+            });
+            if let Some(guid) = &guid {
+                db.update_node_bcd(&node.id, guid)?;
+                let letter = pick_free_letter().ok_or_else(|| {
+                    AppError::Message("no free drive letter available between S: and Z:".into())
+                })?;
+                if let Err(err) = verify_boot_files(guid, letter) {
+                    db.set_boot_files_ready(&node.id, false)?;
+                    return Err(err);
+                }
+                match description {
+                    Some(desc) => {
+                        let res = bcdedit_set_description(guid, desc)?;
+                        log_command("bcdedit set description", &res, None);
+                    }
+                    None => {
+                        self.apply_description_template(guid, &node.name, parent_name.as_deref())
+                    }
+                }
+            }
+            Ok(guid)
+        })?;
+
+        db.insert_timed_op(
+            &op_id,
+            Some(&node.id),
+            "repair_bcd",
+            "ok",
+            description.unwrap_or(""),
+            started.elapsed(),
+        )?;
+        info!(
+            "repair_bcd node={} guid={}",
+            node.id,
+            guid.clone().unwrap_or_default()
+        );
+        Ok(guid)
+    }
+
+    /// Queue `sysprep /generalize /oobe /shutdown` to run on the layer's next logon.
+    ///
+    /// The command is injected into the offline RunOnce key, so the layer is generalized the
+    /// first time it boots; with `reboot` the host immediately boots into it.
+    pub fn sysprep_layer(&self, node_id: &str, reboot: bool) -> Result<()> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "generalizing", false)?;
+
+        let op_id = jobs::next_op_id();
+        self.with_mounted_layer(&node, "sysprep", &op_id, |sys_mount| {
+            let sysprep = sys_mount
+                .join("Windows")
+                .join("System32")
+                .join("Sysprep")
+                .join("sysprep.exe");
+            if !sysprep.exists() {
+                return Err(AppError::Message(format!(
+                    "sysprep.exe not found in layer: {}",
+                    sysprep.display()
+                )));
+            }
+            add_run_once(
+                sys_mount,
+                "LayeredSystemSysprep",
+                r"%WINDIR%\System32\Sysprep\sysprep.exe /generalize /oobe /shutdown /quiet",
+            )
+        })?;
+
+        db.set_node_generalized(node_id, true)?;
+        db.insert_timed_op(
+            &op_id,
+            Some(node_id),
+            "sysprep_layer",
+            "ok",
+            &format!("reboot={reboot}"),
+            started.elapsed(),
+        )?;
+        info!("sysprep_layer node={node_id} reboot={reboot}");
+
+        if reboot {
+            self.set_bootsequence_and_reboot(node_id, None, false)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the provisioning scripts attached to a node.
+    pub fn set_node_scripts(&self, node_id: &str, scripts: Vec<ProvisioningScript>) -> Result<()> {
+        let db = self.db()?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        for script in &scripts {
+            if !Path::new(&script.path).is_file() {
+                return Err(AppError::Message(format!(
+                    "script not found: {}",
+                    script.path
+                )));
+            }
+        }
+        db.set_node_scripts(node_id, &scripts)
+    }
+
+    pub fn list_node_scripts(&self, node_id: &str) -> Result<Vec<ProvisioningScript>> {
+        self.db()?.fetch_node_scripts(node_id)
+    }
+
+    /// Replace a base layer with one built from a newer image.
+    ///
+    /// A differencing disk only makes sense on top of the exact parent it was created from, so
+    /// descendants are not re-pointed: each one is recreated on the new base with the same name,
+    /// appearance, tags and provisioning scripts, and its boot entry flags are carried over.
+    /// A layer that cannot be recreated is reported in `failed` together with its descendants,
+    /// which are skipped. Only when every layer was recreated does the old chain lose its boot
+    /// entries and get tagged `retired`; its files are kept either way.
+    pub fn refresh_base(
+        &self,
+        node_id: &str,
+        new_wim: &str,
+        wim_index: u32,
+    ) -> Result<RefreshResult> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let old_base = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if old_base.parent_id.is_some() {
+            return Err(AppError::Message(
+                "only base layers can be refreshed from a new image".into(),
+            ));
+        }
+        let size_gb = vhdx::virtual_size(Path::new(&old_base.path))?.div_ceil(1024 * 1024 * 1024);
+
+        let mut all = db.fetch_nodes()?;
+        all.sort_by(|a, b| {
+            (a.sort_key.unwrap_or(i64::MAX), a.created_at)
+                .cmp(&(b.sort_key.unwrap_or(i64::MAX), b.created_at))
+        });
+        let new_name = refreshed_base_name(&db, &old_base.name)?;
+        jobs::report_phase("new_base", None);
+        let base = self.create_base(
+            &new_name,
+            old_base.desc.clone(),
+            new_wim,
+            wim_index,
+            size_gb,
+            false,
+        )?;
+        self.copy_node_presentation(&old_base, &base.id)?;
+
+        // Recreate the old tree level by level so every parent exists before its children.
+        let mut recreated = Vec::new();
+        let mut failed = Vec::new();
+        let mut queue = VecDeque::from([(old_base.id.clone(), base.id.clone())]);
+        while let Some((old_parent, new_parent)) = queue.pop_front() {
+            for child in all
+                .iter()
+                .filter(|n| n.parent_id.as_deref() == Some(&old_parent))
+            {
+                jobs::report_phase("recreate_children", None);
+                let node = match db.fetch_node_scripts(&child.id).and_then(|scripts| {
+                    self.create_diff(&new_parent, &child.name, child.desc.clone(), scripts)
+                }) {
+                    Ok(node) => node,
+                    Err(err) => {
+                        warn!("recreate layer failed node={} err={err}", child.id);
+                        failed.push(RefreshFailure {
+                            old_id: child.id.clone(),
+                            error: err.to_string(),
+                        });
+                        failed.extend(subtree_ids(&all, &child.id).into_iter().skip(1).map(|id| {
+                            RefreshFailure {
+                                old_id: id,
+                                error: format!("parent {} was not recreated", child.name),
+                            }
+                        }));
+                        continue;
+                    }
+                };
+                if let Err(err) = self.copy_node_presentation(child, &node.id) {
+                    warn!("copy appearance failed node={} err={err}", node.id);
+                }
+                if child.bcd_guid.is_some() && node.bcd_guid.is_some() {
+                    match self.get_entry_flags(&child.id) {
+                        Ok(flags) => {
+                            if let Err(err) = self.set_entry_flags(&node.id, flags) {
+                                warn!("copy entry flags failed node={} err={err}", node.id);
+                            }
+                        }
+                        Err(err) => warn!("read entry flags failed node={} err={err}", child.id),
+                    }
+                }
+                queue.push_back((child.id.clone(), node.id.clone()));
+                recreated.push(RecreatedLayer {
+                    old_id: child.id.clone(),
+                    node,
+                });
+            }
+        }
+
+        let mut retired = Vec::new();
+        if failed.is_empty() {
+            jobs::report_phase("retire", None);
+            retired.push(old_base.id.clone());
+            retired.extend(recreated.iter().map(|r| r.old_id.clone()));
+        }
+        for id in &retired {
+            let Some(old) = all.iter().find(|n| &n.id == id) else {
+                continue;
+            };
+            if old.bcd_guid.is_some() {
+                if let Err(err) = self.delete_bcd(id) {
+                    warn!("remove boot entry of retired layer failed node={id} err={err}");
+                }
+            }
+            let mut tags = old.tags.clone();
+            if !tags.iter().any(|t| t == "retired") {
+                tags.push("retired".into());
+            }
+            db.update_node_tags(id, &tags)?;
+        }
+
+        let detail = format!(
+            "old={} new={} image={new_wim}:{wim_index} recreated={} failed={}",
+            old_base.id,
+            base.id,
+            recreated.len(),
+            failed.len()
+        );
+        let result = if failed.is_empty() { "ok" } else { "partial" };
+        for id in [&old_base.id, &base.id] {
+            db.insert_timed_op(
+                &jobs::next_op_id(),
+                Some(id),
+                "refresh_base",
+                result,
+                &detail,
+                started.elapsed(),
+            )?;
+        }
+        info!("refresh_base {result} {detail}");
+        let base = db.fetch_node(&base.id)?.unwrap_or(base);
+        Ok(RefreshResult {
+            base,
+            recreated,
+            failed,
+            retired,
+        })
+    }
+
+    /// Give a replacement layer the color, icon and tags of the layer it replaces.
+    fn copy_node_presentation(&self, from: &Node, to_id: &str) -> Result<()> {
+        let db = self.db()?;
+        db.update_node_appearance(to_id, from.color.as_deref(), from.icon.as_deref())?;
+        let tags: Vec<String> = from
+            .tags
+            .iter()
+            .filter(|t| *t != "retired")
+            .cloned()
+            .collect();
+        db.update_node_tags(to_id, &tags)
+    }
+
+    pub fn list_recipes(&self) -> Result<Vec<Recipe>> {
+        self.db()?.fetch_recipes()
+    }
+
+    /// Create or update a recipe after checking that everything it references exists.
+    pub fn save_recipe(&self, mut recipe: Recipe) -> Result<Recipe> {
+        recipe.name = recipe.name.trim().to_string();
+        if recipe.name.is_empty() {
+            return Err(AppError::Message("recipe name is required".into()));
+        }
+        validate_recipe(&recipe)?;
+        if recipe.id.is_empty() {
+            recipe.id = Uuid::new_v4().to_string();
+        }
+        recipe.updated_at = Utc::now();
+        self.db()?.upsert_recipe(&recipe)?;
+        info!("save_recipe id={} name={}", recipe.id, recipe.name);
+        Ok(recipe)
+    }
+
+    pub fn delete_recipe(&self, recipe_id: &str) -> Result<()> {
+        if !self.db()?.delete_recipe(recipe_id)? {
+            return Err(AppError::Message("recipe not found".into()));
+        }
+        info!("delete_recipe id={recipe_id}");
+        Ok(())
+    }
+
+    /// Build a new base layer from a recipe: apply the image, then add drivers, answer file,
+    /// debloat preset, registry tweaks and provisioning scripts in one mount session.
+    pub fn create_from_recipe(&self, recipe_id: &str, name: &str) -> Result<Node> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let recipe = db
+            .fetch_recipe(recipe_id)?
+            .ok_or_else(|| AppError::Message("recipe not found".into()))?;
+        validate_recipe(&recipe)?;
+
+        let node = self.create_base(
+            name,
+            recipe.desc.clone(),
+            &recipe.wim_file,
+            recipe.wim_index,
+            recipe.size_gb,
+            recipe.recovery,
+        )?;
+
+        let op_id = jobs::next_op_id();
+        let customized = self.with_mounted_layer(&node, "recipe", &op_id, |sys_mount| {
+            customize_from_recipe(sys_mount, &recipe)?;
+            if !recipe.scripts.is_empty() {
+                install_provisioning_scripts(sys_mount, &recipe.scripts)?;
+            }
+            Ok(())
+        });
+        if let Err(err) = customized {
+            db.insert_timed_op(
+                &op_id,
+                Some(&node.id),
+                "create_from_recipe",
+                "error",
+                &format!("recipe={} err={err}", recipe.name),
+                started.elapsed(),
+            )?;
+            return Err(AppError::Message(format!(
+                "layer {} was created but applying recipe {} failed: {err}",
+                node.name, recipe.name
+            )));
+        }
+        if !recipe.scripts.is_empty() {
+            db.set_node_scripts(&node.id, &recipe.scripts)?;
+            record_provisioning(&db, &node.id, &recipe.scripts, Ok(()))?;
+        }
+
+        db.insert_timed_op(
+            &op_id,
+            Some(&node.id),
+            "create_from_recipe",
+            "ok",
+            &format!("recipe={}", recipe.name),
+            started.elapsed(),
+        )?;
+        info!("create_from_recipe node={} recipe={}", node.id, recipe.name);
+        Ok(node)
+    }
+
+    /// Copy a node's attached scripts into its layer and queue them for the next logon.
+    pub fn apply_node_scripts(&self, node_id: &str) -> Result<()> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let scripts = db.fetch_node_scripts(node_id)?;
+        if scripts.is_empty() {
+            return Err(AppError::Message("node has no provisioning scripts".into()));
+        }
+        self.ensure_no_children(&node, "provisioning", false)?;
+        let op_id = jobs::next_op_id();
+        let res = self.with_mounted_layer(&node, "provision", &op_id, |sys_mount| {
+            install_provisioning_scripts(sys_mount, &scripts)
+        });
+        record_provisioning(&db, node_id, &scripts, res)
+    }
+
+    /// Write registry values (and expanded presets) into a leaf layer's offline hives.
+    pub fn apply_registry_tweaks(
+        &self,
+        node_id: &str,
+        tweaks: Vec<RegistryTweak>,
+        presets: Vec<RegistryPreset>,
+    ) -> Result<usize> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "editing the registry of", false)?;
+        let mut all: Vec<RegistryTweak> = presets.iter().flat_map(|p| p.tweaks()).collect();
+        all.extend(tweaks);
+        if all.is_empty() {
+            return Err(AppError::Message("no registry tweaks given".into()));
+        }
+        validate_tweaks(&all)?;
+
+        let op_id = jobs::next_op_id();
+        let applied = self.with_mounted_layer(&node, "registry", &op_id, |sys_mount| {
+            apply_tweaks(sys_mount, &all)
+        })?;
+        let detail = all
+            .iter()
+            .map(|t| format!("{}\\{}\\{}", t.hive, t.key, t.name))
+            .collect::<Vec<_>>()
+            .join(";");
+        db.insert_op(
+            &op_id,
+            Some(node_id),
+            "apply_registry_tweaks",
+            "ok",
+            &detail,
+        )?;
+        info!("apply_registry_tweaks node={node_id} count={applied}");
+        Ok(applied)
+    }
+
+    /// Attach a network profile to a leaf layer and write it in: addressing is applied by a
+    /// first-logon script, a domain-join blob is provisioned offline with `djoin`. `None`
+    /// only detaches the stored profile.
+    pub fn set_node_network(&self, node_id: &str, profile: Option<NetworkProfile>) -> Result<Node> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let op_id = jobs::next_op_id();
+        if let Some(profile) = profile.as_ref() {
+            profile.validate()?;
+            self.ensure_no_children(&node, "configuring the network of", false)?;
+            self.with_mounted_layer(&node, "network", &op_id, |sys_mount| {
+                install_network_profile(sys_mount, profile)
+            })?;
+        }
+        db.update_node_network(node_id, profile.as_ref())?;
+        db.insert_op(
+            &op_id,
+            Some(node_id),
+            "set_node_network",
+            "ok",
+            &serde_json::to_string(&profile)?,
+        )?;
+        info!("set_node_network node={node_id}");
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
+    }
+
+    /// Provision a local administrator in a leaf layer, optionally logged on automatically.
+    /// The account is created by a one-shot boot command running as SYSTEM, so it exists
+    /// before the first logon. Its script carries the password in clear text, so it lives in a
+    /// folder only SYSTEM and Administrators can read and deletes itself once it has run.
+    pub fn set_local_admin(&self, node_id: &str, admin: &LocalAdmin) -> Result<()> {
+        let user_name = admin.user_name.trim();
+        validate_user_name(user_name)?;
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "adding an account to", false)?;
+        let op_id = jobs::next_op_id();
+        self.with_mounted_layer(&node, "account", &op_id, |sys_mount| {
+            let dir = sys_mount
+                .join("ProgramData")
+                .join("LayeredSystem")
+                .join("account");
+            fs::create_dir_all(&dir)?;
+            restrict_to_system_and_admins(&dir)?;
+            let user = ps_escape_single(user_name);
+            let password = ps_escape_single(&admin.password);
+            let script = format!(
+                "$ErrorActionPreference = 'Continue'
+Start-Transcript -Path \"$PSScriptRoot\\account.log\" -Append | Out-Null
+$password = ConvertTo-SecureString '{password}' -AsPlainText -Force
+if (Get-LocalUser -Name '{user}' -ErrorAction SilentlyContinue) {{
+    Set-LocalUser -Name '{user}' -Password $password -PasswordNeverExpires $true
+    Enable-LocalUser -Name '{user}'
+}} else {{
+    New-LocalUser -Name '{user}' -Password $password -PasswordNeverExpires -AccountNeverExpires | Out-Null
+}}
+Add-LocalGroupMember -SID 'S-1-5-32-544' -Member '{user}' -ErrorAction SilentlyContinue
+Stop-Transcript | Out-Null
+sc.exe delete {ACCOUNT_SERVICE} | Out-Null
+Remove-Item -LiteralPath $PSCommandPath -Force
+"
+            );
+            fs::write(dir.join("account.ps1"), script.replace('\n', "\r\n"))?;
+
+            let mut tweaks = boot_command_tweaks(
+                ACCOUNT_SERVICE,
+                r"%SystemRoot%\System32\cmd.exe /c powershell.exe -NoProfile -ExecutionPolicy Bypass -File %ProgramData%\LayeredSystem\account\account.ps1",
+            );
+            if admin.autologon {
+                tweaks.extend(autologon_tweaks(user_name));
+            }
+            apply_tweaks(sys_mount, &tweaks)?;
+            if admin.autologon {
+                set_autologon_password(sys_mount, &admin.password)?;
+            }
+            Ok(())
+        })?;
+        db.insert_op(
+            &op_id,
+            Some(node_id),
+            "set_local_admin",
+            "ok",
+            &format!("user={user_name} autologon={}", admin.autologon),
+        )?;
+        info!(
+            "set_local_admin node={node_id} user={user_name} autologon={}",
+            admin.autologon
+        );
+        Ok(())
+    }
+
+    /// Give a leaf layer its own host name by writing the ComputerName keys offline.
+    pub fn set_computer_name(&self, node_id: &str, computer_name: &str) -> Result<()> {
+        let computer_name = computer_name.trim().to_ascii_uppercase();
+        validate_computer_name(&computer_name)?;
+        self.apply_registry_tweaks(
+            node_id,
+            Vec::new(),
+            vec![RegistryPreset::ComputerName {
+                name: computer_name,
+            }],
+        )?;
+        Ok(())
+    }
+
+    /// Keep hiberfil.sys and pagefile.sys out of a leaf layer by writing the power and memory
+    /// management keys offline, as `SwapFileOptions` describes.
+    pub fn disable_swap_files(&self, node_id: &str, options: &SwapFileOptions) -> Result<()> {
+        options.validate()?;
+        self.apply_registry_tweaks(node_id, Vec::new(), options.presets())?;
+        Ok(())
+    }
+
+    /// Inject the post-boot marker task into an existing layer.
+    pub fn inject_boot_marker(&self, node_id: &str) -> Result<()> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "adding the boot marker to", false)?;
+        let op_id = jobs::next_op_id();
+        self.with_mounted_layer(&node, "marker", &op_id, |sys_mount| {
+            self.install_boot_marker(sys_mount, node_id)
+        })?;
+        db.insert_op(&op_id, Some(node_id), "inject_boot_marker", "ok", "")?;
+        info!("inject_boot_marker node={node_id}");
+        Ok(())
+    }
+
+    /// Copy the marker script into a mounted layer and register it under RunOnce.
+    ///
+    /// On first logon the script looks for the workspace on every drive letter (the host volume
+    /// usually gets a different letter inside the layer) and drops `meta\markers\<id>.ok`.
+    fn install_boot_marker(&self, sys_mount: &Path, node_id: &str) -> Result<()> {
+        let paths = self.paths()?;
+        let markers_rel = relative_to_drive(&paths.markers_dir());
+        let script = [
+            "@echo off".to_string(),
+            "chcp 65001 >nul".to_string(),
+            "for %%d in (C D E F G H I J K L M N O P Q R S T U V W X Y Z) do (".to_string(),
+            format!(r#"  if exist "%%d:\{markers_rel}\" ("#),
+            format!(r#"    echo %DATE% %TIME% %COMPUTERNAME%> "%%d:\{markers_rel}\{node_id}.ok""#),
+            "  )".to_string(),
+            ")".to_string(),
+        ]
+        .join("\r\n");
+        let agent_dir = sys_mount.join("ProgramData").join("LayeredSystem");
+        fs::create_dir_all(&agent_dir)?;
+        fs::write(agent_dir.join("boot-marker.cmd"), script)?;
+        add_run_once(
+            sys_mount,
+            "LayeredSystemBootMarker",
+            r#"cmd.exe /c "%ProgramData%\LayeredSystem\boot-marker.cmd""#,
+        )
+    }
+
+    /// Turn marker files written by booted layers into `boot_verified_at` timestamps.
+    fn collect_boot_markers(&self) -> Result<()> {
+        let paths = self.paths()?;
+        let db = self.db()?;
+        let dir = paths.markers_dir();
+        if !dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let is_marker = path
+                .extension()
+                .and_then(|s| s.to_str())
+                .map(|s| s.eq_ignore_ascii_case("ok"))
+                .unwrap_or(false);
+            let Some(node_id) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if !is_marker {
+                continue;
+            }
+            if db.fetch_node(node_id)?.is_some() {
+                let verified_at = fs::metadata(&path)
+                    .and_then(|m| m.modified())
+                    .map(DateTime::<Utc>::from)
+                    .unwrap_or_else(|_| Utc::now());
+                db.set_node_boot_verified(node_id, verified_at)?;
+                db.insert_op(
+                    &jobs::next_op_id(),
+                    Some(node_id),
+                    "boot_verified",
+                    "ok",
+                    &format!("at={}", verified_at.to_rfc3339()),
+                )?;
+                info!("boot verified node={node_id}");
+            }
+            let _ = fs::remove_file(&path);
+        }
+        Ok(())
+    }
+
+    /// Turn a layer into a conventional installation on a physical partition.
+    ///
+    /// The layer's system volume (including everything inherited from its parents) is captured
+    /// into a temporary WIM, applied to `target_volume` and made bootable with bcdboot, either
+    /// against `efi_volume` (another disk's ESP) or the host's default store.
+    pub fn deploy_to_disk(
+        &self,
+        node_id: &str,
+        target_volume: &str,
+        efi_volume: Option<&str>,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let paths = self.paths()?;
+
+        let target = normalize_volume(target_volume)
+            .ok_or_else(|| AppError::Message(format!("invalid target volume: {target_volume}")))?;
+        let system_drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".into());
+        if target.eq_ignore_ascii_case(&system_drive) {
+            return Err(AppError::Message(
+                "refusing to deploy onto the running system volume".into(),
+            ));
+        }
+        if normalize_path(&paths.root().to_string_lossy()).starts_with(&target.to_ascii_lowercase())
+        {
+            return Err(AppError::Message(
+                "refusing to deploy onto the volume hosting the workspace".into(),
+            ));
+        }
+        let target_root = PathBuf::from(format!("{target}\\"));
+        if !target_root.exists() {
+            return Err(AppError::Message(format!(
+                "target volume not found: {target}"
+            )));
+        }
+        if target_root.join("Windows").exists() {
+            return Err(AppError::Message(format!(
+                "target volume {target} already contains a Windows directory"
+            )));
+        }
+        let efi = match efi_volume {
+            Some(v) => Some(
+                normalize_volume(v)
+                    .ok_or_else(|| AppError::Message(format!("invalid EFI volume: {v}")))?,
+            ),
+            None => None,
+        };
+
+        let wim_path = paths
+            .tmp_dir()
+            .join(format!("deploy-{}.wim", Uuid::new_v4()));
+        let wim_str = wim_path.to_string_lossy().to_string();
+        let op_id = jobs::next_op_id();
+        let result = self
+            .with_read_only_layer(&node, "deploy", &op_id, |sys_mount| {
+                let res = capture_image(
+                    sys_mount.to_string_lossy().as_ref(),
+                    &wim_str,
+                    &node.name,
+                    None,
+                )?;
+                log_command("dism capture", &res, None);
+                if !res.succeeded() {
+                    return Err(command_error("dism capture", &res, None));
+                }
+                Ok(())
+            })
+            .and_then(|_| {
+                jobs::report_phase("apply_image", None);
+                let res = apply_image(&wim_str, 1, target_root.to_string_lossy().as_ref())?;
+                log_command("dism apply", &res, None);
+                if !res.succeeded() {
+                    return Err(command_error("dism apply", &res, None));
+                }
+                let bcd_res = match &efi {
+                    Some(efi) => run_bcdboot_to_efi(&target_root, Path::new(efi))?,
+                    None => run_bcdboot(&target_root)?,
+                };
+                log_command("bcdboot", &bcd_res, None);
+                if !bcd_res.succeeded() {
+                    return Err(command_error("bcdboot", &bcd_res, None));
+                }
+                Ok(())
+            });
+        let _ = fs::remove_file(&wim_path);
+        result?;
+
+        db.insert_timed_op(
+            &op_id,
+            Some(node_id),
+            "deploy_to_disk",
+            "ok",
+            &format!("target={target} efi={}", efi.unwrap_or_default()),
+            started.elapsed(),
+        )?;
+        info!("deploy_to_disk node={node_id} target={target}");
+        Ok(())
+    }
+
+    /// Register a WinPE ramdisk entry in the boot menu as a way back if a layer leaves the host
+    /// unbootable.
+    ///
+    /// Without `wim_path` the WinPE image and boot.sdi from the Windows ADK are used. Running it
+    /// again replaces the previously registered entry.
+    pub fn create_rescue_entry(&self, wim_path: Option<&str>) -> Result<String> {
+        let db = self.db()?;
+        let paths = self.paths()?;
+
+        let winpe_root = tools::adk_root().map(|root| {
+            root.join("Windows Preinstallation Environment")
+                .join("amd64")
+        });
+        let source_wim = match wim_path {
+            Some(p) => PathBuf::from(p),
+            None => winpe_root
+                .as_ref()
+                .map(|root| root.join("en-us").join("winpe.wim"))
+                .filter(|p| p.exists())
+                .ok_or_else(|| {
+                    AppError::Message(
+                        "WinPE image not found; install the Windows PE add-on for the ADK or pass a WIM path".into(),
+                    )
+                })?,
+        };
+        if !source_wim.exists() {
+            return Err(AppError::Message(format!(
+                "WinPE image not found: {}",
+                source_wim.display()
+            )));
+        }
+        let system_root = std::env::var("SystemRoot").unwrap_or_else(|_| "C:\\Windows".into());
+        let source_sdi = winpe_root
+            .map(|root| root.join("Media").join("Boot").join("boot.sdi"))
+            .filter(|p| p.exists())
+            .unwrap_or_else(|| {
+                PathBuf::from(&system_root)
+                    .join("Boot")
+                    .join("DVD")
+                    .join("EFI")
+                    .join("boot.sdi")
+            });
+        if !source_sdi.exists() {
+            return Err(AppError::Message(format!(
+                "boot.sdi not found: {}",
+                source_sdi.display()
+            )));
+        }
+
+        let rescue_dir = paths.rescue_dir();
+        fs::create_dir_all(&rescue_dir)?;
+        let wim_dest = rescue_dir.join("winpe.wim");
+        let sdi_dest = rescue_dir.join("boot.sdi");
+        fs::copy(&source_wim, &wim_dest)?;
+        fs::copy(&source_sdi, &sdi_dest)?;
+
+        if let (Some(old), old_options) = db.rescue_entry()? {
+            for guid in once(old).chain(old_options) {
+                if let Ok(o) = bcdedit_delete(&guid) {
+                    log_command("bcdedit delete rescue", &o, None);
+                }
+            }
+        }
+
+        let volume = rescue_dir
+            .to_str()
+            .and_then(|p| p.get(..2))
+            .filter(|p| p.ends_with(':'))
+            .ok_or_else(|| {
+                AppError::Message("rescue directory must be on a lettered volume".into())
+            })?
+            .to_string();
+        let rel = |p: &Path| format!("\\{}", relative_to_drive(p));
+
+        let check = |name: &str, res: CommandOutput| -> Result<CommandOutput> {
+            log_command(name, &res, None);
+            if !res.succeeded() {
+                return Err(command_error(name, &res, None));
+            }
+            Ok(res)
+        };
+
+        let options_res = check(
+            "bcdedit create ramdisk options",
+            bcdedit_create(RESCUE_DESCRIPTION, &["/device"])?,
+        )?;
+        let options_guid = parse_created_guid(&options_res.stdout)
+            .ok_or_else(|| AppError::Message("failed to parse ramdisk options guid".into()))?;
+        check(
+            "bcdedit set ramdisksdidevice",
+            bcdedit_set(
+                &options_guid,
+                "ramdisksdidevice",
+                &format!("partition={volume}"),
+            )?,
+        )?;
+        check(
+            "bcdedit set ramdisksdipath",
+            bcdedit_set(&options_guid, "ramdisksdipath", &rel(&sdi_dest))?,
+        )?;
+
+        let entry_res = check(
+            "bcdedit create rescue",
+            bcdedit_create(RESCUE_DESCRIPTION, &["/application", "osloader"])?,
+        )?;
+        let guid = parse_created_guid(&entry_res.stdout)
+            .ok_or_else(|| AppError::Message("failed to parse rescue entry guid".into()))?;
+        let ramdisk = format!("ramdisk=[{volume}]{},{options_guid}", rel(&wim_dest));
+        for (key, value) in [
+            ("device", ramdisk.as_str()),
+            ("osdevice", ramdisk.as_str()),
+            ("path", "\\windows\\system32\\boot\\winload.efi"),
+            ("systemroot", "\\windows"),
+            ("winpe", "yes"),
+            ("detecthal", "yes"),
+        ] {
+            check(
+                &format!("bcdedit set {key}"),
+                bcdedit_set(&guid, key, value)?,
+            )?;
+        }
+        check(
+            "bcdedit displayorder",
+            bcdedit_display_order_add_last(&guid)?,
+        )?;
+
+        db.update_rescue_entry(Some(&guid), Some(&options_guid))?;
+        db.insert_op(
+            &jobs::next_op_id(),
+            None,
+            "create_rescue_entry",
+            "ok",
+            &format!("guid={guid} wim={}", source_wim.display()),
+        )?;
+        info!("create_rescue_entry guid={guid}");
+        Ok(guid)
+    }
+
+    /// Mount a layer to refresh its BitLocker state.
+    pub fn detect_bitlocker(&self, node_id: &str) -> Result<bool> {
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let op_id = jobs::next_op_id();
+        self.with_read_only_layer(&node, "bitlocker", &op_id, |_| Ok(()))?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        info!(
+            "detect_bitlocker node={node_id} encrypted={}",
+            node.bitlocker
+        );
+        Ok(node.bitlocker)
+    }
+
+    /// Encrypt a leaf layer and enable auto-unlock on this host, returning the recovery password.
+    ///
+    /// Encryption rewrites every block of the volume, so it is refused for layers with children.
+    /// Conversion continues in the background whenever the layer is attached or booted.
+    pub fn enable_bitlocker(&self, node_id: &str) -> Result<Option<String>> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "encrypting", false)?;
+
+        let op_id = jobs::next_op_id();
+        let recovery = self.with_mounted_layer(&node, "bitlocker", &op_id, |sys_mount| {
+            let mount_point = sys_mount
+                .to_string_lossy()
+                .trim_end_matches('\\')
+                .to_string();
+            let res = bitlocker::enable(&mount_point)?;
+            // The output holds the recovery password, which only goes back to the caller.
+            info!("manage-bde on: {}", res.status_text());
+            if !res.succeeded() {
+                let masked = CommandOutput {
+                    stdout: bitlocker::mask_recovery_password(&res.stdout),
+                    ..res
+                };
+                return Err(command_error("manage-bde on", &masked, None));
+            }
+            let unlock_res = bitlocker::enable_auto_unlock(&mount_point)?;
+            log_command("manage-bde autounlock", &unlock_res, None);
+            if !unlock_res.succeeded() {
+                return Err(command_error("manage-bde autounlock", &unlock_res, None));
+            }
+            Ok(bitlocker::parse_recovery_password(&res.stdout))
+        })?;
+
+        db.set_node_bitlocker(node_id, true)?;
+        db.insert_timed_op(
+            &op_id,
+            Some(node_id),
+            "enable_bitlocker",
+            "ok",
+            "",
+            started.elapsed(),
+        )?;
+        info!("enable_bitlocker node={node_id}");
+        Ok(recovery)
+    }
+
+    /// Run DISM component cleanup with `/ResetBase` inside a leaf layer, optionally compacting
+    /// the VHDX afterwards so the freed blocks are returned to the host.
+    pub fn cleanup_component_store(&self, node_id: &str, compact: bool) -> Result<ReclaimResult> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "cleaning up", false)?;
+        let before_bytes = fs::metadata(&node.path)?.len();
+
+        let op_id = jobs::next_op_id();
+        self.with_mounted_layer(&node, "cleanup", &op_id, |sys_mount| {
+            let res = dism_cleanup_component_store(&sys_mount.to_string_lossy())?;
+            log_command("dism cleanup-image", &res, None);
+            if !res.succeeded() {
+                return Err(command_error("dism cleanup-image", &res, None));
+            }
+            Ok(())
+        })?;
+        if compact {
+            self.compact_vhd(&node)?;
+        }
+
+        let after_bytes = fs::metadata(&node.path)?.len();
+        db.insert_timed_op(
+            &op_id,
+            Some(node_id),
+            "cleanup_component_store",
+            "ok",
+            &format!("compact={compact} before={before_bytes} after={after_bytes}"),
+            started.elapsed(),
+        )?;
+        info!("cleanup_component_store node={node_id} before={before_bytes} after={after_bytes}");
+        Ok(ReclaimResult {
+            node_id: node_id.to_string(),
+            before_bytes,
+            after_bytes,
+        })
+    }
+
+    /// Shrink a base layer's virtual disk to `new_size_gb`: the last (system) partition is
+    /// shrunk first, then the VHDX with `Resize-VHD`. Refused for layers with children, whose
+    /// differencing disks must keep the parent's geometry, and when the volume's data would
+    /// not fit with 1 GB to spare.
+    pub fn shrink_vhd(&self, node_id: &str, new_size_gb: u64) -> Result<Node> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if node.parent_id.is_some() {
+            return Err(AppError::Message(format!(
+                "{} is a differencing layer; only base layers can be shrunk",
+                node.name
+            )));
+        }
+        self.ensure_no_children(&node, "shrinking", false)?;
+        if node.status != NodeStatus::Normal {
+            return Err(AppError::Message(format!(
+                "{} is {:?}; only layers in normal state can be shrunk",
+                node.name, node.status
+            )));
+        }
+        if self.detail_vdisk(&node.path)?.attached == Some(true) {
+            return Err(AppError::Message(format!(
+                "{} is attached or booted; detach it before shrinking",
+                node.name
+            )));
+        }
+        let before = vhdx::virtual_size(Path::new(&node.path))?;
+        let target = gb_to_bytes(new_size_gb);
+        if target >= before {
+            return Err(AppError::Message(format!(
+                "{} is {} already; the new size must be smaller",
+                node.name,
+                format_size(before)
+            )));
+        }
+
+        let script = format!(
+            r#"$ErrorActionPreference = 'Stop'
+if (-not (Get-Command Resize-VHD -ErrorAction SilentlyContinue)) {{
+    throw 'Resize-VHD is not available; enable the Hyper-V management tools to shrink layers'
+}}
+$path = '{path}'
+$target = {target}
+Mount-DiskImage -ImagePath $path -NoDriveLetter | Out-Null
+try {{
+    $disk = Get-DiskImage -ImagePath $path | Get-Disk
+    $part = Get-Partition -DiskNumber $disk.Number | Sort-Object Offset | Select-Object -Last 1
+    $limit = $target - $part.Offset - 1MB
+    $min = (Get-PartitionSupportedSize -DiskNumber $disk.Number -PartitionNumber $part.PartitionNumber).SizeMin
+    if ($limit -lt $min + 1GB) {{
+        throw ('data on the volume needs {{0:N1}} GB plus 1 GB free; choose at least {{1}} GB' -f ($min / 1GB), [math]::Ceiling(($part.Offset + $min + 2GB) / 1GB))
+    }}
+    if ($part.Size -gt $limit) {{
+        Resize-Partition -DiskNumber $disk.Number -PartitionNumber $part.PartitionNumber -Size $limit
+    }}
+}} finally {{
+    Dismount-DiskImage -ImagePath $path | Out-Null
+}}
+Resize-VHD -Path $path -SizeBytes $target
+"#,
+            path = ps_escape_single(&node.path),
+        );
+        jobs::report_phase("shrink", None);
+        let res = run_powershell(&script)?;
+        log_command("shrink vhd", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("shrink vhd", &res, None));
+        }
+
+        let after = vhdx::virtual_size(Path::new(&node.path)).unwrap_or(target);
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "shrink_vhd",
+            "ok",
+            &format!("before={before} after={after}"),
+            started.elapsed(),
+        )?;
+        info!("shrink_vhd node={node_id} before={before} after={after}");
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))
+    }
+
+    /// Install a product key into a leaf layer via offline DISM.
+    pub fn set_product_key(&self, node_id: &str, key: &str) -> Result<()> {
+        let key = key.trim().to_ascii_uppercase();
+        let groups: Vec<&str> = key.split('-').collect();
+        let valid = groups.len() == 5
+            && groups
+                .iter()
+                .all(|g| g.len() == 5 && g.chars().all(|c| c.is_ascii_alphanumeric()));
+        if !valid {
+            return Err(AppError::Message(
+                "invalid product key, expected XXXXX-XXXXX-XXXXX-XXXXX-XXXXX".into(),
+            ));
+        }
+        let masked = format!("*****-*****-*****-*****-{}", groups[4]);
+        self.service_leaf_layer(node_id, "set_product_key", &masked, |image_dir| {
+            check_dism("set-productkey", dism_set_product_key(image_dir, &key)?)
+        })
+    }
+
+    /// Install a language pack (`lp.cab`) or Language Experience Pack (`.appx`) into a leaf layer
+    /// and optionally make it the default UI language. `language` overrides the tag detected
+    /// from the package file name (e.g. `de-DE`).
+    pub fn add_language(
+        &self,
+        node_id: &str,
+        package_path: &str,
+        set_default: bool,
+        language: Option<String>,
+    ) -> Result<()> {
+        let package = Path::new(package_path);
+        if !package.is_file() {
+            return Err(AppError::Message(format!(
+                "language package not found: {package_path}"
+            )));
+        }
+        let ext = package
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let is_appx = matches!(ext.as_str(), "appx" | "appxbundle" | "msix" | "msixbundle");
+        if !is_appx && ext != "cab" {
+            return Err(AppError::Message(format!(
+                "unsupported language package type: {package_path}"
+            )));
+        }
+        let language = language
+            .filter(|l| !l.trim().is_empty())
+            .or_else(|| language_tag_from_package(package));
+        if set_default && language.is_none() {
+            return Err(AppError::Message(
+                "cannot detect the language from the package name; pass it explicitly".into(),
+            ));
+        }
+        let license = package
+            .parent()
+            .map(|dir| dir.join("License.xml"))
+            .filter(|p| p.is_file());
+
+        let detail = format!(
+            "package={package_path} language={} default={set_default}",
+            language.as_deref().unwrap_or("")
+        );
+        self.service_leaf_layer(node_id, "add_language", &detail, |image_dir| {
+            if is_appx {
+                let license = license.as_ref().map(|p| p.to_string_lossy().to_string());
+                check_dism(
+                    "add-provisionedappxpackage",
+                    add_provisioned_appx(image_dir, package_path, license.as_deref())?,
+                )?;
+            } else {
+                check_dism("add-package", add_package(image_dir, package_path)?)?;
+            }
+            if let (true, Some(language)) = (set_default, language.as_deref()) {
+                check_dism("set-allintl", set_all_intl(image_dir, language)?)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Enable .NET Framework 3.5 in a leaf layer from installation media. `sources_path` may be
+    /// the media's `sources` folder or its `sources\sxs` subfolder.
+    pub fn enable_netfx3(&self, node_id: &str, sources_path: &str) -> Result<()> {
+        let sources = Path::new(sources_path);
+        let sxs = if sources.join("sxs").is_dir() {
+            sources.join("sxs")
+        } else {
+            sources.to_path_buf()
+        };
+        if !sxs.is_dir() {
+            return Err(AppError::Message(format!(
+                "sources folder not found: {sources_path}"
+            )));
+        }
+        let source = sxs.to_string_lossy().to_string();
+        let detail = format!("source={source}");
+        self.service_leaf_layer(node_id, "enable_netfx3", &detail, |image_dir| {
+            check_dism(
+                "enable-feature netfx3",
+                enable_feature(image_dir, "NetFx3", Some(&source))?,
+            )
+        })
+    }
+
+    pub fn apply_provisioning_package(&self, node_id: &str, ppkg_path: &str) -> Result<()> {
+        let package = Path::new(ppkg_path);
+        let is_ppkg = package
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("ppkg"));
+        if !package.is_file() || !is_ppkg {
+            return Err(AppError::Message(format!(
+                "provisioning package not found: {ppkg_path}"
+            )));
+        }
+        let detail = format!("package={ppkg_path}");
+        self.service_leaf_layer(
+            node_id,
+            "apply_provisioning_package",
+            &detail,
+            |image_dir| {
+                check_dism(
+                    "add-provisioningpackage",
+                    add_provisioning_package(image_dir, ppkg_path)?,
+                )
+            },
+        )
+    }
+
+    /// Mount a leaf layer, run one offline DISM servicing command against it and record the op.
+    fn service_leaf_layer(
+        &self,
+        node_id: &str,
+        action: &str,
+        detail: &str,
+        f: impl FnOnce(&str) -> Result<()>,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        self.ensure_no_children(&node, "servicing", false)?;
+        let op_id = jobs::next_op_id();
+        self.with_mounted_layer(&node, action, &op_id, |sys_mount| {
+            f(&sys_mount.to_string_lossy())
+        })?;
+        db.insert_timed_op(
+            &op_id,
+            Some(node_id),
+            action,
+            "ok",
+            detail,
+            started.elapsed(),
+        )?;
+        info!("{action} node={node_id} {detail}");
+        Ok(())
+    }
+
+    /// Compact leaf layers whose VHDX has not been written for `idle_days`. A layer that cannot
+    /// be compacted (typically because it is booted or attached) is skipped.
+    pub fn compact_idle_layers(&self, idle_days: u32) -> Result<String> {
+        let db = self.db()?;
+        let nodes = db.fetch_nodes()?;
+        let cutoff = SystemTime::now() - Duration::from_secs(u64::from(idle_days) * 24 * 60 * 60);
+        let (mut compacted, mut skipped, mut failed, mut reclaimed) = (0, 0, 0, 0u64);
+        for node in nodes.iter().filter(|n| {
+            n.status == NodeStatus::Normal
+                && !nodes.iter().any(|c| c.parent_id.as_deref() == Some(&n.id))
+        }) {
+            let Ok(meta) = fs::metadata(&node.path) else {
+                continue;
+            };
+            if meta.modified().map_or(true, |modified| modified > cutoff) {
+                continue;
+            }
+            let started = Instant::now();
+            let before = meta.len();
+            match self.compact_vhd(node) {
+                Ok(()) => {
+                    let after = fs::metadata(&node.path).map_or(before, |m| m.len());
+                    reclaimed += before.saturating_sub(after);
+                    compacted += 1;
+                    db.insert_timed_op(
+                        &jobs::next_op_id(),
+                        Some(&node.id),
+                        "compact",
+                        "ok",
+                        &format!("before={before} after={after}"),
+                        started.elapsed(),
+                    )?;
+                }
+                Err(err) if err.command_kind() == Some(CommandErrorKind::InUse) => {
+                    skipped += 1;
+                    info!("compact idle layer skipped, in use node={}", node.id);
+                }
+                Err(err) => {
+                    failed += 1;
+                    warn!("compact idle layer failed node={} err={err}", node.id);
+                }
+            }
+        }
+        Ok(format!(
+            "compacted={compacted} skipped={skipped} failed={failed} reclaimed_bytes={reclaimed}"
+        ))
+    }
+
+    /// Check the layer and all its ancestors before booting it: every file exists, each VHDX
+    /// still names its recorded parent, no parent was written after its child was created, and
+    /// no layer is attached by anything but the running system.
+    pub fn validate_boot_chain(&self, node_id: &str) -> Result<Vec<ChainIssue>> {
+        let nodes = self.db()?.fetch_nodes()?;
+        let by_id: HashMap<&str, &Node> = nodes.iter().map(|n| (n.id.as_str(), n)).collect();
+        let mut current = Some(
+            *by_id
+                .get(node_id)
+                .ok_or_else(|| AppError::Message("node not found".into()))?,
+        );
+        let in_use = booted_chain(&nodes);
+        let mut issues = Vec::new();
+        let mut issue = |kind, node: &Node, message: String| {
+            issues.push(ChainIssue {
+                kind,
+                node_id: node.id.clone(),
+                message: format!("{}: {message}", node.name),
+            })
+        };
+        let mut seen = HashSet::new();
+        while let Some(node) = current {
+            if !seen.insert(node.id.as_str()) {
+                break;
+            }
+            current = None;
+            if !Path::new(&node.path).exists() {
+                issue(
+                    ChainIssueKind::MissingFile,
+                    node,
+                    format!("{} not found", node.path),
+                );
+                continue;
+            }
+            let parent = match node.parent_id.as_deref() {
+                Some(parent_id) => match by_id.get(parent_id) {
+                    Some(parent) => Some(*parent),
+                    None => {
+                        issue(
+                            ChainIssueKind::MissingParentRecord,
+                            node,
+                            "parent record missing".into(),
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
+            let detail = self.detail_vdisk(&node.path)?;
+            if let Some(parent) = parent {
+                match detail.parent.as_deref() {
+                    Some(actual) if normalize_path(actual) == normalize_path(&parent.path) => {}
+                    Some(actual) => issue(
+                        ChainIssueKind::ParentMismatch,
+                        node,
+                        format!("parent is {actual}, expected {}", parent.path),
+                    ),
+                    None => issue(
+                        ChainIssueKind::ParentMismatch,
+                        node,
+                        "not a differencing disk".into(),
+                    ),
+                }
+                let parent_written = fs::metadata(&parent.path)
+                    .and_then(|m| m.modified())
+                    .map(DateTime::<Utc>::from);
+                if let Ok(written) = parent_written {
+                    if written
+                        > node.created_at + chrono::Duration::seconds(PARENT_WRITE_SLACK_SECS)
+                    {
+                        issue(
+                            ChainIssueKind::ParentModified,
+                            node,
+                            format!(
+                                "parent {} was written at {} after this layer was created",
+                                parent.name,
+                                written.to_rfc3339()
+                            ),
+                        );
+                    }
+                }
+                current = Some(parent);
+            }
+            if detail.attached == Some(true) && !in_use.contains(node.id.as_str()) {
+                issue(
+                    ChainIssueKind::Attached,
+                    node,
+                    "attached; detach it or wait for its job to finish".into(),
+                );
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Check that every differencing layer still points at the parent recorded for it.
+    pub fn verify_chains(&self) -> Result<String> {
+        let nodes = self.db()?.fetch_nodes()?;
+        let mut checked = 0;
+        let mut problems = Vec::new();
+        for node in &nodes {
+            let Some(parent_id) = node.parent_id.as_deref() else {
+                continue;
+            };
+            checked += 1;
+            let Some(parent) = nodes.iter().find(|p| p.id == parent_id) else {
+                problems.push(format!("{}: parent record missing", node.name));
+                continue;
+            };
+            if !Path::new(&node.path).exists() {
+                problems.push(format!("{}: vhdx not found", node.name));
+                continue;
+            }
+            match self.detail_vdisk(&node.path).map(|detail| detail.parent) {
+                Ok(Some(actual)) if normalize_path(&actual) == normalize_path(&parent.path) => {}
+                Ok(Some(actual)) => problems.push(format!(
+                    "{}: parent is {actual}, expected {}",
+                    node.name, parent.path
+                )),
+                Ok(None) => problems.push(format!("{}: not a differencing disk", node.name)),
+                Err(err) => problems.push(format!("{}: {err}", node.name)),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(AppError::Message(format!(
+                "{} of {checked} chains broken: {}",
+                problems.len(),
+                problems.join("; ")
+            )));
+        }
+        Ok(format!("checked={checked}"))
+    }
+
+    /// Drop operations older than `retention_days` from the operation log and command
+    /// transcripts older than `transcript_days`.
+    pub fn prune_ops(&self, retention_days: u32, transcript_days: u32) -> Result<String> {
+        let db = self.db()?;
+        let before = Utc::now() - chrono::Duration::days(i64::from(retention_days));
+        let removed = db.prune_ops(before)?;
+        let transcripts = transcript::prune(&self.paths()?.transcripts_dir(), transcript_days)?;
+        db.clear_transcripts(&transcripts)?;
+        Ok(format!(
+            "removed={removed} before={} transcripts_removed={}",
+            before.to_rfc3339(),
+            transcripts.len()
+        ))
+    }
+
+    /// Clear stale files from `meta/tmp`, keeping failed scripts for the configured retention.
+    pub fn clean_temp(&self) -> Result<TempCleanup> {
+        let retention = self.db()?.get_settings()?.maintenance.temp_retention_days;
+        self.clean_temp_older_than(retention)
+    }
+
+    pub fn clean_temp_older_than(&self, retention_days: u32) -> Result<TempCleanup> {
+        temp::clean(&self.paths()?.tmp_dir(), retention_days)
+    }
+
+    pub fn get_job_history(&self, limit: u32) -> Result<Vec<JobRecord>> {
+        self.db()?.fetch_job_history(limit)
+    }
+
+    fn compact_vhd(&self, node: &Node) -> Result<()> {
+        let paths = self.paths()?;
+        let temp = TempManager::new(paths.tmp_dir())?;
+        let op_id = jobs::next_op_id();
+        let script = compact_vdisk_script(Path::new(&node.path))?;
+        let script_path = temp.write_script("compact_vdisk.txt", &script)?;
+        log_diskpart_script(&script_path);
+        let res = run_diskpart_script(&script_path)?;
+        log_command("diskpart compact", &res, Some(&script_path));
+        let ok = res.exit_code == Some(0);
+        self.record_attach(&node.id, &op_id, "attach", "compact", ok);
+        self.record_attach(&node.id, &op_id, "detach", "compact", ok);
+        if !ok {
+            return Err(command_error("diskpart compact", &res, Some(&script_path)));
+        }
+        Ok(())
+    }
+
+    /// Refuse to write to a layer other layers are based on, unless explicitly forced.
+    fn ensure_no_children(&self, node: &Node, action: &str, force: bool) -> Result<()> {
+        if !self.has_children(&node.id)? {
+            return Ok(());
+        }
+        if force {
+            warn!("{action} parent layer node={} forced", node.id);
+            return Ok(());
+        }
+        Err(AppError::Message(format!(
+            "{} has child layers; {action} it would change a parent layer and corrupt every descendant",
+            node.name
+        )))
+    }
+
+    fn has_children(&self, node_id: &str) -> Result<bool> {
+        Ok(self
+            .db()?
+            .fetch_nodes()?
+            .iter()
+            .any(|n| n.parent_id.as_deref() == Some(node_id)))
+    }
+
+    /// Database record, live VHDX state, BCD entry and chain of one node in a single call.
+    /// Parts that cannot be read are reported in `errors` instead of failing the whole request.
+    pub fn get_node_details(&self, node_id: &str) -> Result<NodeDetails> {
+        let nodes = self.annotated_nodes()?;
+        let node = nodes
+            .iter()
+            .find(|n| n.id == node_id)
+            .cloned()
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        let chain = node
+            .ancestor_ids
+            .iter()
+            .rev()
+            .filter_map(|id| nodes.iter().find(|n| &n.id == id).cloned())
+            .collect();
+        let children = nodes
+            .iter()
+            .filter(|n| n.parent_id.as_deref() == Some(node_id))
+            .cloned()
+            .collect();
+
+        let mut errors = Vec::new();
+        let vhd_path = Path::new(&node.path);
+        let file_bytes = fs::metadata(vhd_path).ok().map(|m| m.len());
+        let (virtual_bytes, vhd) = if file_bytes.is_some() {
+            let virtual_bytes = match vhdx::virtual_size(vhd_path) {
+                Ok(size) => Some(size),
+                Err(err) => {
+                    errors.push(format!("virtual size: {err}"));
+                    None
+                }
+            };
+            let vhd = match self.detail_vdisk(&node.path) {
+                Ok(detail) => Some(VhdState {
+                    parent: detail.parent,
+                    attached: detail.attached,
+                }),
+                Err(err) => {
+                    errors.push(format!("vhd detail: {err}"));
+                    None
+                }
+            };
+            (virtual_bytes, vhd)
+        } else {
+            errors.push(format!("vhdx not found: {}", node.path));
+            (None, None)
+        };
+
+        let bcd_entry = match node.bcd_guid.as_deref() {
+            Some(guid) => match bcdedit_enum_entry(guid) {
+                Ok(res) if res.exit_code == Some(0) => {
+                    parse_bcd_objects(&res.stdout).into_iter().next()
+                }
+                Ok(res) => {
+                    log_command("bcdedit enum entry", &res, None);
+                    errors.push(format!("bcd entry {guid}: {}", res.stderr.trim()));
+                    None
+                }
+                Err(err) => {
+                    errors.push(format!("bcd entry {guid}: {err}"));
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Ok(NodeDetails {
+            node,
+            chain,
+            children,
+            file_bytes,
+            virtual_bytes,
+            vhd,
+            bcd_entry,
+            errors,
+        })
+    }
+
+    pub fn get_node_ops(&self, node_id: &str) -> Result<Vec<NodeOperation>> {
+        let db = self.db()?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        db.fetch_node_ops(node_id)
+    }
+
+    /// Report of all operations recorded in `range`, for documenting changes to shared machines.
+    pub fn export_audit(&self, range: &AuditRange, format: AuditFormat) -> Result<String> {
+        let paths = self.paths()?;
+        let entries = self.db()?.fetch_ops_between(range.from, range.to)?;
+        let report = audit::render(&entries, range, &paths.root().to_string_lossy(), format);
+        Ok(redact::redact(&report).into_owned())
+    }
+
+    /// The node tree as Graphviz DOT or Mermaid text, for wikis and change-review documents.
+    pub fn export_tree_graph(&self, format: GraphFormat) -> Result<String> {
+        let nodes = self.annotated_nodes()?;
+        let sizes: HashMap<String, u64> = nodes
+            .iter()
+            .filter_map(|n| Some((n.id.clone(), fs::metadata(&n.path).ok()?.len())))
+            .collect();
+        Ok(graph::render(&nodes, &sizes, format))
+    }
+
+    /// Serialize nodes, recipes, tags and boot menu descriptions of this workspace as JSON.
+    pub fn export_metadata(&self) -> Result<String> {
+        let paths = self.paths()?;
+        let db = self.db()?;
+        let descriptions: HashMap<String, String> = match bcdedit_enum_all() {
+            Ok(res) if res.exit_code == Some(0) => parse_bcd_objects(&res.stdout)
+                .into_iter()
+                .filter_map(|o| {
+                    let guid = o.get("identifier")?.to_ascii_lowercase();
+                    Some((guid, o.get("description")?.to_string()))
+                })
+                .collect(),
+            Ok(res) => {
+                log_command("bcdedit enum", &res, None);
+                HashMap::new()
+            }
+            Err(err) => {
+                warn!("read boot entries for export failed err={err}");
+                HashMap::new()
+            }
+        };
+
+        let mut nodes = Vec::new();
+        for node in db.fetch_nodes()? {
+            let bcd_description = node
+                .bcd_guid
+                .as_deref()
+                .and_then(|guid| descriptions.get(&guid.to_ascii_lowercase()).cloned());
+            nodes.push(NodeMetadata {
+                rel_path: workspace_relative(paths.root(), &node.path),
+                scripts: db.fetch_node_scripts(&node.id)?,
+                id: node.id,
+                parent_id: node.parent_id,
+                name: node.name,
+                desc: node.desc,
+                created_at: node.created_at,
+                generalized: node.generalized,
+                color: node.color,
+                icon: node.icon,
+                sort_key: node.sort_key,
+                tags: node.tags,
+                bcd_description,
+            });
+        }
+        let metadata = WorkspaceMetadata {
+            version: METADATA_VERSION,
+            exported_at: Utc::now(),
+            machine: std::env::var("COMPUTERNAME").ok(),
+            nodes,
+            recipes: db.fetch_recipes()?,
+        };
+        db.insert_op(
+            &jobs::next_op_id(),
+            None,
+            "export_metadata",
+            "ok",
+            &format!("nodes={}", metadata.nodes.len()),
+        )?;
+        Ok(serde_json::to_string_pretty(&metadata)?)
+    }
+
+    /// Adopt a tree exported by `export_metadata` on another machine.
+    ///
+    /// Layers are matched by their path inside this workspace; tracked ones get the exported
+    /// name, description, appearance, tags and scripts, untracked ones are added. Layers that
+    /// had a boot entry get one here too, with the same description.
+    pub fn import_metadata(&self, file: &str) -> Result<MetadataImport> {
+        let started = Instant::now();
+        let paths = self.paths()?;
+        let db = self.db()?;
+        let metadata: WorkspaceMetadata = serde_json::from_str(&fs::read_to_string(file)?)?;
+        if metadata.version > METADATA_VERSION {
+            return Err(AppError::Message(format!(
+                "metadata version {} is newer than supported ({METADATA_VERSION})",
+                metadata.version
+            )));
+        }
+
+        let existing = db.fetch_nodes()?;
+        let mut report = MetadataImport::default();
+        // Exported id -> id of the same layer in this workspace.
+        let mut ids: HashMap<String, String> = HashMap::new();
+        let mut pending: Vec<&NodeMetadata> = metadata.nodes.iter().collect();
+        jobs::report_phase("nodes", None);
+        loop {
+            let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|meta| {
+                meta.parent_id
+                    .as_ref()
+                    .is_none_or(|parent| ids.contains_key(parent))
+            });
+            if ready.is_empty() {
+                report.errors.extend(
+                    rest.iter()
+                        .map(|meta| format!("{}: parent layer was not imported", meta.name)),
+                );
+                break;
+            }
+            pending = rest;
+            for meta in ready {
+                let path = resolve_workspace_path(paths.root(), &meta.rel_path);
+                if !path.exists() {
+                    report.missing.push(meta.rel_path.clone());
+                    continue;
+                }
+                let path = path.to_string_lossy().to_string();
+                let parent_id = meta.parent_id.as_ref().map(|parent| ids[parent].clone());
+                let tracked = existing
+                    .iter()
+                    .find(|n| normalize_path(&n.path) == normalize_path(&path));
+                let id = match tracked {
+                    Some(node) => {
+                        db.update_node_parent(&node.id, parent_id.as_deref())?;
+                        db.update_node_metadata(
+                            &node.id,
+                            &meta.name,
+                            meta.desc.as_deref(),
+                            meta.generalized,
+                        )?;
+                        report.updated.push(node.id.clone());
+                        node.id.clone()
+                    }
+                    None => {
+                        let id = if existing.iter().any(|n| n.id == meta.id) {
+                            Uuid::new_v4().to_string()
+                        } else {
+                            meta.id.clone()
+                        };
+                        db.insert_node(&Node {
+                            id: id.clone(),
+                            parent_id,
+                            name: meta.name.clone(),
+                            path,
+                            desc: meta.desc.clone(),
+                            created_at: meta.created_at,
+                            generalized: meta.generalized,
+                            sort_key: meta.sort_key,
+                            ..Default::default()
+                        })?;
+                        report.adopted.push(id.clone());
+                        id
+                    }
+                };
+                db.update_node_appearance(&id, meta.color.as_deref(), meta.icon.as_deref())?;
+                if let Err(err) = self.set_node_tags(&id, meta.tags.clone()) {
+                    report.errors.push(format!("{}: {err}", meta.name));
+                }
+                db.set_node_scripts(&id, &meta.scripts)?;
+                ids.insert(meta.id.clone(), id);
+            }
+        }
+
+        jobs::report_phase("boot_entries", None);
+        for meta in &metadata.nodes {
+            let (Some(id), Some(description)) = (ids.get(&meta.id), &meta.bcd_description) else {
+                continue;
+            };
+            let has_entry = db.fetch_node(id)?.is_some_and(|n| n.bcd_guid.is_some());
+            let res = if has_entry {
+                self.update_bcd_description(id, description)
+            } else {
+                self.add_bcd_entry(id, Some(description.clone()))
+                    .map(|_| ())
+            };
+            if let Err(err) = res {
+                report
+                    .errors
+                    .push(format!("{} boot entry: {err}", meta.name));
+            }
+        }
+
+        for recipe in &metadata.recipes {
+            match db.upsert_recipe(recipe) {
+                Ok(()) => report.recipes += 1,
+                Err(err) => report.errors.push(format!("recipe {}: {err}", recipe.name)),
+            }
+        }
+
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            None,
+            "import_metadata",
+            if report.errors.is_empty() {
+                "ok"
+            } else {
+                "partial"
+            },
+            &format!(
+                "file={file} machine={} adopted={} updated={} missing={} recipes={}",
+                metadata.machine.as_deref().unwrap_or("unknown"),
+                report.adopted.len(),
+                report.updated.len(),
+                report.missing.len(),
+                report.recipes
+            ),
+            started.elapsed(),
+        )?;
+        Ok(report)
+    }
+
+    /// The operations log of the current workspace.
+    pub fn ops_log_path(&self) -> Result<PathBuf> {
+        Ok(self.paths()?.ops_log_path())
+    }
+
+    /// Copy of the script behind the most recent failed command in this session.
+    pub fn last_failed_script(&self) -> Result<PathBuf> {
+        LAST_FAILED_SCRIPT
+            .lock()
+            .ok()
+            .and_then(|last| last.clone())
+            .filter(|path| path.exists())
+            .ok_or_else(|| AppError::Message("no failed script recorded in this session".into()))
+    }
+
+    pub fn get_attach_history(&self, node_id: &str) -> Result<Vec<AttachEvent>> {
+        let db = self.db()?;
+        db.fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        db.fetch_attach_history(node_id)
+    }
+
+    /// Append to the node's attach history; failures are only logged so they never abort the caller.
+    fn record_attach(&self, node_id: &str, op_id: &str, action: &str, context: &str, ok: bool) {
+        let event = AttachEvent {
+            node_id: node_id.to_string(),
+            op_id: op_id.to_string(),
+            action: action.to_string(),
+            context: context.to_string(),
+            result: if ok { "ok" } else { "failed" }.to_string(),
+            ts: Utc::now(),
+        };
+        let res = self.db().and_then(|db| db.insert_attach_event(&event));
+        if let Err(err) = res {
+            warn!("record attach history failed node={node_id} action={action} err={err}");
+        }
+    }
+
+    /// Attach a layer, expose its system partition on a free drive letter, run `f` against the
+    /// mounted root (e.g. `U:\`) and detach again regardless of the outcome.
+    /// Attach history is recorded under `op_id`, the id of the caller's operation.
+    ///
+    /// The layer is attached read-write; callers writing to a layer that may have children
+    /// check `ensure_no_children` first, callers that only read use
+    /// `with_read_only_layer`.
+    fn with_mounted_layer<T>(
+        &self,
+        node: &Node,
+        tag: &str,
+        op_id: &str,
+        f: impl FnOnce(&Path) -> Result<T>,
+    ) -> Result<T> {
+        self.mount_layer(node, tag, op_id, false, f)
+    }
+
+    /// `with_mounted_layer` for queries: the layer is attached read-only, so parent layers can
+    /// be inspected without invalidating their children.
+    fn with_read_only_layer<T>(
+        &self,
+        node: &Node,
+        tag: &str,
+        op_id: &str,
+        f: impl FnOnce(&Path) -> Result<T>,
+    ) -> Result<T> {
+        self.mount_layer(node, tag, op_id, true, f)
+    }
+
+    fn mount_layer<T>(
+        &self,
+        node: &Node,
+        tag: &str,
+        op_id: &str,
+        read_only: bool,
+        f: impl FnOnce(&Path) -> Result<T>,
+    ) -> Result<T> {
+        let paths = self.paths()?;
+        let temp = TempManager::new(paths.tmp_dir())?;
+        let vhd_path = Path::new(&node.path);
+        let sys_letter = pick_free_letter().ok_or_else(|| {
+            AppError::Message("no free drive letter available between S: and Z:".into())
+        })?;
+
+        let detach =
+            |letters: &[char]| self.detach_quietly(&temp, vhd_path, letters, tag, &node.id, op_id);
+
+        jobs::report_phase("attach", None);
+        let attach_script = attach_list_vdisk_script(vhd_path, read_only)?;
+        let attach_path = temp.write_script(&format!("attach_{tag}.txt"), &attach_script)?;
+        log_diskpart_script(&attach_path);
+        let attach_res = run_diskpart_script(&attach_path).inspect_err(|_| detach(&[]))?;
+        log_command(
+            &format!("diskpart attach {tag}"),
+            &attach_res,
+            Some(&attach_path),
+        );
+        self.record_attach(
+            &node.id,
+            op_id,
+            "attach",
+            tag,
+            attach_res.exit_code == Some(0),
+        );
+        if !attach_res.succeeded() {
+            return Err(command_error(
+                "diskpart attach",
                 &attach_res,
                 Some(&attach_path),
             ));
         }
 
-        let parts = parse_list_partition(&attach_res.stdout);
-        let sys_part = parts
+        let parts = attached_partitions(vhd_path, &attach_res.stdout);
+        let Some(sys_part) = system_partition_index(&parts) else {
+            detach(&[]);
+            return Err(AppError::Message(
+                "failed to detect system partition from list partition".into(),
+            ));
+        };
+
+        let assign_script = assign_partitions_script(vhd_path, &[(sys_part, sys_letter)])?;
+        let assign_path = temp.write_script(&format!("assign_{tag}.txt"), &assign_script)?;
+        log_diskpart_script(&assign_path);
+        let assign_res = run_diskpart_script(&assign_path).inspect_err(|_| detach(&[]))?;
+        log_command(
+            &format!("diskpart assign {tag}"),
+            &assign_res,
+            Some(&assign_path),
+        );
+        if !assign_res.succeeded() {
+            detach(&[]);
+            return Err(command_error(
+                "diskpart assign",
+                &assign_res,
+                Some(&assign_path),
+            ));
+        }
+
+        if let Ok(Some(status)) = bitlocker::volume_status(&format!("{sys_letter}:")) {
+            let encrypted = status.is_encrypted();
+            info!(
+                "bitlocker node={} volume={} protection={} lock={}",
+                node.id, status.volume_status, status.protection_status, status.lock_status
+            );
+            if encrypted != node.bitlocker {
+                if let Ok(db) = self.db() {
+                    let _ = db.set_node_bitlocker(&node.id, encrypted);
+                }
+            }
+            if status.lock_status.eq_ignore_ascii_case("Locked") {
+                detach(&[sys_letter]);
+                return Err(AppError::Message(format!(
+                    "layer volume is locked by BitLocker: {}",
+                    node.path
+                )));
+            }
+        }
+
+        let sys_mount = PathBuf::from(format!("{sys_letter}:\\"));
+        jobs::report_phase(tag, None);
+        let result = f(&sys_mount);
+        jobs::report_phase("detach", None);
+        detach(&[sys_letter]);
+        result
+    }
+
+    /// Detach `vhd_path`, releasing `letters`, and record the outcome. Failures are only logged
+    /// so this can also clean up after a failed or cancelled step.
+    fn detach_quietly(
+        &self,
+        temp: &TempManager,
+        vhd_path: &Path,
+        letters: &[char],
+        tag: &str,
+        node_id: &str,
+        op_id: &str,
+    ) {
+        let detach_path = detach_vdisk_script(vhd_path, letters)
+            .and_then(|script| temp.write_script(&format!("detach_{tag}.txt"), &script));
+        if let Ok(detach_path) = detach_path {
+            log_diskpart_script(&detach_path);
+            let ok = match run_diskpart_script(&detach_path) {
+                Ok(o) => {
+                    log_command(&format!("diskpart detach {tag}"), &o, Some(&detach_path));
+                    o.exit_code == Some(0)
+                }
+                Err(_) => false,
+            };
+            self.record_attach(node_id, op_id, "detach", tag, ok);
+            if !ok {
+                log_detach_holders(vhd_path, letters);
+            }
+        }
+    }
+
+    /// Rewrite every layer's `parent_id` from the parent recorded in its VHDX, for trees that
+    /// drifted after files were moved or re-parented by hand. A parent file that is missing or
+    /// untracked leaves the link alone and marks the layer MissingParent.
+    pub fn repair_links(&self) -> Result<LinkRepair> {
+        let db = self.db()?;
+        let nodes = db.fetch_nodes()?;
+        let id_by_path: HashMap<String, String> = nodes
+            .iter()
+            .map(|n| (normalize_path(&n.path), n.id.clone()))
+            .collect();
+        let mut report = LinkRepair::default();
+        for node in &nodes {
+            if node.status.in_progress() || node.status == NodeStatus::Archived {
+                continue;
+            }
+            if !Path::new(&node.path).exists() {
+                report
+                    .errors
+                    .push(format!("{}: file not found {}", node.name, node.path));
+                continue;
+            }
+            let detail = match self.detail_vdisk(&node.path) {
+                Ok(detail) => detail,
+                Err(err) => {
+                    report.errors.push(format!("{}: {err}", node.name));
+                    continue;
+                }
+            };
+            report.checked += 1;
+            let parent = match detail.parent.as_deref().map(normalize_path) {
+                None => None,
+                Some(parent_path) => match id_by_path.get(&parent_path) {
+                    Some(id) if id != &node.id => Some(id.clone()),
+                    _ => {
+                        warn!(
+                            "repair_links parent not tracked node={} parent={parent_path}",
+                            node.id
+                        );
+                        report.orphaned.push(node.id.clone());
+                        db.update_node_status(&node.id, NodeStatus::MissingParent)?;
+                        continue;
+                    }
+                },
+            };
+            if node.parent_id != parent {
+                db.update_node_parent(&node.id, parent.as_deref())?;
+                info!(
+                    "repair_links node={} from={:?} to={:?}",
+                    node.id, node.parent_id, parent
+                );
+                report.changed.push(LinkChange {
+                    node_id: node.id.clone(),
+                    from: node.parent_id.clone(),
+                    to: parent,
+                });
+            }
+            if node.status == NodeStatus::MissingParent {
+                db.update_node_status(&node.id, NodeStatus::Normal)?;
+            }
+        }
+        db.insert_op(
+            &jobs::next_op_id(),
+            None,
+            "repair_links",
+            if report.errors.is_empty() {
+                "ok"
+            } else {
+                "partial"
+            },
+            &format!(
+                "checked={} changed={} orphaned={} errors={}",
+                report.checked,
+                report.changed.len(),
+                report.orphaned.len(),
+                report.errors.len()
+            ),
+        )?;
+        Ok(report)
+    }
+
+    /// Cross-check tracked layers against their files, the boot store and the attach state.
+    /// Nothing is changed; every problem becomes a finding, so all of them can be shown at once.
+    pub fn health_check(&self) -> Result<Vec<HealthFinding>> {
+        let paths = self.paths()?;
+        let nodes = self.db()?.fetch_nodes()?;
+        let by_id: HashMap<&str, &Node> = nodes.iter().map(|n| (n.id.as_str(), n)).collect();
+        let mut findings = Vec::new();
+        let mut report =
+            |category, node: Option<&Node>, bcd_guid: Option<&str>, message: String| {
+                findings.push(HealthFinding {
+                    category,
+                    node_id: node.map(|n| n.id.clone()),
+                    bcd_guid: bcd_guid.map(str::to_string),
+                    message,
+                })
+            };
+
+        let bcd_objects = match bcdedit_enum_all() {
+            Ok(res) if res.succeeded() => Some(parse_bcd_objects(&res.stdout)),
+            Ok(res) => {
+                log_command("bcdedit enum", &res, None);
+                None
+            }
+            Err(err) => {
+                warn!("health_check bcdedit enum failed: {err}");
+                None
+            }
+        };
+        // The layer the system booted from and its ancestors are attached for good reason.
+        let in_use = booted_chain(&nodes);
+        // While a job runs, its layer being attached is expected.
+        let idle = jobs::list().is_empty();
+
+        for node in &nodes {
+            if node.status.in_progress() || node.status == NodeStatus::Archived {
+                continue;
+            }
+            if !Path::new(&node.path).exists() {
+                report(
+                    HealthCategory::MissingFile,
+                    Some(node),
+                    None,
+                    format!("{}: {} not found", node.name, node.path),
+                );
+                continue;
+            }
+            let parent = match node.parent_id.as_deref() {
+                Some(parent_id) => match by_id.get(parent_id) {
+                    Some(parent) => Some(*parent),
+                    None => {
+                        report(
+                            HealthCategory::BrokenParent,
+                            Some(node),
+                            None,
+                            format!("{}: parent record missing", node.name),
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
+            if let (Some(guid), Some(objects)) = (node.bcd_guid.as_deref(), &bcd_objects) {
+                let exists = objects.iter().any(|o| {
+                    o.get("identifier")
+                        .is_some_and(|id| id.eq_ignore_ascii_case(guid))
+                });
+                if !exists {
+                    report(
+                        HealthCategory::OrphanEntry,
+                        Some(node),
+                        Some(guid),
+                        format!("{}: boot entry {guid} no longer exists", node.name),
+                    );
+                }
+            }
+            let detail = match self.detail_vdisk(&node.path) {
+                Ok(detail) => detail,
+                Err(err) => {
+                    warn!(
+                        "health_check detail_vdisk failed node={} err={err}",
+                        node.id
+                    );
+                    continue;
+                }
+            };
+            if let Some(parent) = parent {
+                match detail.parent.as_deref() {
+                    Some(actual) if normalize_path(actual) == normalize_path(&parent.path) => {
+                        if !Path::new(&parent.path).exists() {
+                            report(
+                                HealthCategory::BrokenParent,
+                                Some(node),
+                                None,
+                                format!("{}: parent file {} not found", node.name, parent.path),
+                            );
+                        }
+                    }
+                    Some(actual) => report(
+                        HealthCategory::BrokenParent,
+                        Some(node),
+                        None,
+                        format!(
+                            "{}: parent is {actual}, expected {}",
+                            node.name, parent.path
+                        ),
+                    ),
+                    None => report(
+                        HealthCategory::BrokenParent,
+                        Some(node),
+                        None,
+                        format!("{}: not a differencing disk", node.name),
+                    ),
+                }
+            }
+            if idle && detail.attached == Some(true) && !in_use.contains(node.id.as_str()) {
+                report(
+                    HealthCategory::StaleMount,
+                    Some(node),
+                    None,
+                    format!("{}: attached although no operation is running", node.name),
+                );
+            }
+        }
+
+        if let Some(objects) = &bcd_objects {
+            let tracked: HashSet<String> = nodes.iter().map(|n| normalize_path(&n.path)).collect();
+            let roots: Vec<String> = paths
+                .layer_roots()
+                .iter()
+                .map(|dir| {
+                    let dir = normalize_path(&dir.to_string_lossy());
+                    format!("{}\\", dir.trim_end_matches('\\'))
+                })
+                .collect();
+            for (guid, vhd) in vhd_boot_entries(objects) {
+                if !roots.iter().any(|root| vhd.starts_with(root.as_str())) {
+                    continue;
+                }
+                if !Path::new(&vhd).exists() {
+                    report(
+                        HealthCategory::OrphanEntry,
+                        None,
+                        Some(&guid),
+                        format!("boot entry {guid} points at missing {vhd}"),
+                    );
+                } else if !tracked.contains(&vhd) {
+                    report(
+                        HealthCategory::OrphanEntry,
+                        None,
+                        Some(&guid),
+                        format!("boot entry {guid} points at untracked {vhd}"),
+                    );
+                }
+            }
+        }
+
+        info!("health_check findings={}", findings.len());
+        Ok(findings)
+    }
+
+    /// Layers whose last scan found enough fragmentation that compacting them is worth it,
+    /// largest estimated savings first.
+    pub fn get_compaction_advice(&self) -> Result<Vec<CompactionHint>> {
+        let db = self.db()?;
+        let nodes = db.fetch_nodes()?;
+        let mut hints: Vec<CompactionHint> = db
+            .fetch_vhd_metrics()?
+            .into_iter()
+            .filter(|m| m.fragmentation >= COMPACT_MIN_FRAGMENTATION)
+            .filter_map(|m| {
+                let node = nodes
+                    .iter()
+                    .find(|n| n.id == m.node_id && n.status == NodeStatus::Normal)?;
+                let file_bytes = fs::metadata(&node.path).ok()?.len();
+                let estimated_savings = file_bytes / 100 * u64::from(m.fragmentation.min(100));
+                if estimated_savings < COMPACT_MIN_SAVINGS {
+                    return None;
+                }
+                Some(CompactionHint {
+                    compactable: !nodes
+                        .iter()
+                        .any(|c| c.parent_id.as_deref() == Some(&node.id)),
+                    node_id: m.node_id,
+                    file_bytes,
+                    fragmentation: m.fragmentation,
+                    estimated_savings,
+                    measured_at: m.measured_at,
+                })
+            })
+            .collect();
+        hints.sort_by_key(|h| std::cmp::Reverse(h.estimated_savings));
+        Ok(hints)
+    }
+
+    /// Compact a detached leaf layer and measure it again.
+    pub fn compact_layer(&self, node_id: &str) -> Result<ReclaimResult> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let node = db
+            .fetch_node(node_id)?
+            .ok_or_else(|| AppError::Message("node not found".into()))?;
+        if node.status != NodeStatus::Normal {
+            return Err(AppError::Message(format!(
+                "{} is {:?}; only normal layers can be compacted",
+                node.name, node.status
+            )));
+        }
+        self.ensure_no_children(&node, "compacting", false)?;
+        let before_bytes = fs::metadata(&node.path)?.len();
+        self.compact_vhd(&node)?;
+        let after_bytes = fs::metadata(&node.path)?.len();
+        match self.detail_vdisk(&node.path).map(|d| d.fragmentation) {
+            Ok(Some(fragmentation)) => db.upsert_vhd_metrics(&VhdMetrics {
+                node_id: node.id.clone(),
+                file_bytes: after_bytes,
+                fragmentation,
+                measured_at: Utc::now(),
+            })?,
+            Ok(None) => {}
+            Err(err) => warn!("compact_layer remeasure failed node={node_id} err={err}"),
+        }
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            Some(node_id),
+            "compact",
+            "ok",
+            &format!("before={before_bytes} after={after_bytes}"),
+            started.elapsed(),
+        )?;
+        info!("compact_layer node={node_id} before={before_bytes} after={after_bytes}");
+        Ok(ReclaimResult {
+            node_id: node_id.to_string(),
+            before_bytes,
+            after_bytes,
+        })
+    }
+
+    /// Hash base layers that share their size with another base, for `find_duplicate_bases`.
+    /// Bases whose hash is still current are skipped, so repeated runs only read changed files.
+    pub fn hash_bases(&self) -> Result<String> {
+        let db = self.db()?;
+        let known = db.fetch_node_hashes()?;
+        let mut hashed = 0;
+        let mut failed = 0;
+        for (node, file_bytes, modified) in duplicate_candidates(&db.fetch_nodes()?) {
+            let current = known
+                .get(&node.id)
+                .is_some_and(|h| h.file_bytes == file_bytes && h.modified == modified);
+            if current {
+                continue;
+            }
+            jobs::report_phase(&format!("hash {}", node.name), None);
+            match file_sha256(Path::new(&node.path)) {
+                Ok(sha256) => {
+                    db.upsert_node_hash(&NodeHash {
+                        node_id: node.id.clone(),
+                        file_bytes,
+                        modified,
+                        sha256,
+                        hashed_at: Utc::now(),
+                    })?;
+                    hashed += 1;
+                }
+                Err(err) => {
+                    warn!("hash_bases failed node={} err={err}", node.id);
+                    failed += 1;
+                }
+            }
+        }
+        info!("hash_bases hashed={hashed} failed={failed}");
+        Ok(format!("hashed={hashed} failed={failed}"))
+    }
+
+    /// Group base layers with identical content, from the hashes `hash_bases` recorded, and
+    /// suggest which copy to keep.
+    pub fn find_duplicate_bases(&self) -> Result<DuplicateReport> {
+        let db = self.db()?;
+        let nodes = db.fetch_nodes()?;
+        let known = db.fetch_node_hashes()?;
+        let mut report = DuplicateReport::default();
+        let mut by_hash: BTreeMap<&str, Vec<(&Node, u64)>> = BTreeMap::new();
+        for (node, file_bytes, modified) in duplicate_candidates(&nodes) {
+            match known.get(&node.id) {
+                Some(h) if h.file_bytes == file_bytes && h.modified == modified => {
+                    by_hash
+                        .entry(&h.sha256)
+                        .or_default()
+                        .push((node, file_bytes));
+                }
+                _ => report.unhashed.push(node.id.clone()),
+            }
+        }
+        let children = |id: &str| {
+            nodes
+                .iter()
+                .filter(|n| n.parent_id.as_deref() == Some(id))
+                .count()
+        };
+        for (sha256, mut bases) in by_hash {
+            if bases.len() < 2 {
+                continue;
+            }
+            bases.sort_by(|(a, _), (b, _)| {
+                children(&b.id)
+                    .cmp(&children(&a.id))
+                    .then(a.created_at.cmp(&b.created_at))
+            });
+            let file_bytes = bases[0].1;
+            report.groups.push(DuplicateBases {
+                sha256: sha256.to_string(),
+                file_bytes,
+                keep: bases[0].0.id.clone(),
+                duplicates: bases[1..].iter().map(|(n, _)| n.id.clone()).collect(),
+                reclaimable_bytes: file_bytes * (bases.len() as u64 - 1),
+            });
+        }
+        Ok(report)
+    }
+
+    /// Fix the selected `health_check` findings in dependency order: detach stale disks so their
+    /// files are free, drop the rows of layers whose file is gone, relink parents from the VHDX
+    /// headers, then re-create missing boot entries and delete entries pointing at missing
+    /// files. A failing repair is reported and does not stop the others. `force_detach` forces
+    /// stale disks off as `detach_stale` describes.
+    pub fn repair_all(
+        &self,
+        findings: &[HealthFinding],
+        force_detach: bool,
+    ) -> Result<RepairReport> {
+        let started = Instant::now();
+        let db = self.db()?;
+        let mut report = RepairReport::default();
+        let mut record = |finding: &HealthFinding, outcome: Result<String>| match outcome {
+            Ok(done) => report.repaired.push(done),
+            Err(err) => report.failed.push(format!("{}: {err}", finding.message)),
+        };
+        let selected = |category| findings.iter().filter(move |f| f.category == category);
+        let node_of = |finding: &HealthFinding| -> Result<Node> {
+            let id = finding
+                .node_id
+                .as_deref()
+                .ok_or_else(|| AppError::Message("finding names no node".into()))?;
+            db.fetch_node(id)?
+                .ok_or_else(|| AppError::Message("node not found".into()))
+        };
+
+        jobs::report_phase("detach", None);
+        for finding in selected(HealthCategory::StaleMount) {
+            record(
+                finding,
+                node_of(finding).and_then(|n| self.detach_stale(&n, force_detach)),
+            );
+        }
+
+        jobs::report_phase("prune", None);
+        for finding in selected(HealthCategory::MissingFile) {
+            let outcome = node_of(finding).and_then(|node| {
+                if Path::new(&node.path).exists() {
+                    return Err(AppError::Message(format!(
+                        "{} exists again; rescan instead",
+                        node.path
+                    )));
+                }
+                if node.protected || node.status.in_progress() {
+                    return Err(AppError::Message(format!(
+                        "{} is protected or busy",
+                        node.name
+                    )));
+                }
+                if let Some(guid) = node.bcd_guid.as_deref() {
+                    if let Ok(res) = bcdedit_delete(guid) {
+                        log_command("bcdedit delete", &res, None);
+                    }
+                }
+                let ids = [node.id.clone()];
+                db.delete_ops_for_nodes(&ids)?;
+                db.delete_nodes(&ids)?;
+                Ok(format!(
+                    "{}: removed the record of {}",
+                    node.name, node.path
+                ))
+            });
+            record(finding, outcome);
+        }
+
+        let broken: Vec<&HealthFinding> = selected(HealthCategory::BrokenParent).collect();
+        if !broken.is_empty() {
+            jobs::report_phase("relink", None);
+            match self.repair_links() {
+                Ok(links) => {
+                    for finding in broken {
+                        let outcome = node_of(finding).and_then(|node| {
+                            if links.orphaned.contains(&node.id) {
+                                return Err(AppError::Message(
+                                    "parent file is missing or not tracked".into(),
+                                ));
+                            }
+                            Ok(format!("{}: parent relinked", node.name))
+                        });
+                        record(finding, outcome);
+                    }
+                }
+                Err(err) => {
+                    for finding in broken {
+                        record(finding, Err(AppError::Message(err.to_string())));
+                    }
+                }
+            }
+        }
+
+        jobs::report_phase("bcd", None);
+        for finding in selected(HealthCategory::OrphanEntry) {
+            let outcome = match (finding.node_id.as_deref(), finding.bcd_guid.as_deref()) {
+                (Some(node_id), _) => match self.repair_bcd(node_id) {
+                    Ok(Some(guid)) => Ok(format!("{}: boot entry re-created as {guid}", node_id)),
+                    Ok(None) => Err(AppError::Message(
+                        "bcdboot created no entry for the layer".into(),
+                    )),
+                    Err(err) => Err(err),
+                },
+                (None, Some(guid)) => self.delete_orphan_entry(guid),
+                (None, None) => Err(AppError::Message(
+                    "finding names no node or boot entry".into(),
+                )),
+            };
+            record(finding, outcome);
+        }
+
+        db.insert_timed_op(
+            &jobs::next_op_id(),
+            None,
+            "repair_all",
+            if report.failed.is_empty() {
+                "ok"
+            } else {
+                "partial"
+            },
+            &format!(
+                "repaired={} failed={}",
+                report.repaired.len(),
+                report.failed.len()
+            ),
+            started.elapsed(),
+        )?;
+        info!(
+            "repair_all repaired={} failed={}",
+            report.repaired.len(),
+            report.failed.len()
+        );
+        Ok(report)
+    }
+
+    /// Detach a layer left attached, unless a job might be using it. With `force`, a refused
+    /// detach is retried with backoff after dismounting the disk's volumes, then done by disk
+    /// number, for when an antivirus scan or Explorer keeps the volumes busy.
+    fn detach_stale(&self, node: &Node, force: bool) -> Result<String> {
+        if !jobs::list().is_empty() {
+            return Err(AppError::Message(
+                "a job is running; retry once it finished".into(),
+            ));
+        }
+        let vhd_path = Path::new(&node.path);
+        let temp = TempManager::new(self.paths()?.tmp_dir())?;
+        let script = detach_vdisk_script(vhd_path, &[])?;
+        let script_path = temp.write_script("detach_repair.txt", &script)?;
+        let detach = || -> Result<CommandOutput> {
+            log_diskpart_script(&script_path);
+            let res = run_diskpart_script(&script_path)?;
+            log_command("diskpart detach repair", &res, Some(&script_path));
+            Ok(res)
+        };
+        let mut res = detach()?;
+        let mut outcome = (res.exit_code == Some(0)).then_some("detached");
+        if force && outcome.is_none() {
+            jobs::report_phase("force_detach", None);
+            for delay in FORCE_DETACH_BACKOFF {
+                let Some(disk) = storage::attached_disk(vhd_path)? else {
+                    outcome = Some("detached");
+                    break;
+                };
+                for volume in &disk.volumes {
+                    if let Err(err) = handles::dismount_volume(volume) {
+                        warn!("force detach node={}: {err}", node.id);
+                    }
+                }
+                thread::sleep(delay);
+                res = detach()?;
+                if res.exit_code == Some(0) {
+                    outcome = Some("detached after dismounting its volumes");
+                    break;
+                }
+            }
+            if outcome.is_none() {
+                if let Some(disk) = storage::attached_disk(vhd_path)? {
+                    match storage::dismount_disk_number(disk.number) {
+                        Ok(()) => outcome = Some("detached by disk number"),
+                        Err(err) => warn!(
+                            "detach node={} by disk number {} failed: {err}",
+                            node.id, disk.number
+                        ),
+                    }
+                }
+            }
+        }
+        self.record_attach(
+            &node.id,
+            &Uuid::new_v4().to_string(),
+            "detach",
+            if force { "force_repair" } else { "repair" },
+            outcome.is_some(),
+        );
+        let Some(outcome) = outcome else {
+            return Err(detach_error(&res, Some(&script_path), vhd_path, &[]));
+        };
+        Ok(format!("{}: {outcome}", node.name))
+    }
+
+    /// Delete a boot entry that points at a missing VHDX in the workspace. Entries whose file
+    /// exists are left for a rescan to adopt.
+    fn delete_orphan_entry(&self, guid: &str) -> Result<String> {
+        let bcd_enum = bcdedit_enum_all()?;
+        if !bcd_enum.succeeded() {
+            return Err(command_error("bcdedit enum", &bcd_enum, None));
+        }
+        let objects = parse_bcd_objects(&bcd_enum.stdout);
+        let Some((_, vhd)) = vhd_boot_entries(&objects)
+            .into_iter()
+            .find(|(id, _)| id.eq_ignore_ascii_case(guid))
+        else {
+            return Ok(format!("boot entry {guid} is already gone"));
+        };
+        if Path::new(&vhd).exists() {
+            return Err(AppError::Message(format!(
+                "{vhd} exists; rescan to adopt it instead"
+            )));
+        }
+        let res = bcdedit_delete(guid)?;
+        log_command("bcdedit delete", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("bcdedit delete", &res, None));
+        }
+        Ok(format!("boot entry {guid} deleted"))
+    }
+
+    /// Parent and attach state of a VHDX, from `Get-VHD` where available and diskpart otherwise.
+    pub fn detail_vdisk(&self, vhd_path: &str) -> Result<crate::diskpart::VhdDetail> {
+        match storage::vhd_detail(Path::new(vhd_path)) {
+            Ok(Some(detail)) => return Ok(detail),
+            Ok(None) => {}
+            Err(err) => warn!("Get-VHD failed path={vhd_path} err={err}"),
+        }
+        let paths = self.paths()?;
+        let temp = TempManager::new(paths.tmp_dir())?;
+        let script = detail_vdisk_script(Path::new(vhd_path))?;
+        let script_path = temp.write_script("detail_vdisk.txt", &script)?;
+        log_diskpart_script(&script_path);
+        let res = run_diskpart_script(&script_path)?;
+        log_command("diskpart detail", &res, Some(&script_path));
+        if !res.succeeded() {
+            return Err(command_error("diskpart detail", &res, Some(&script_path)));
+        }
+        Ok(parse_detail_vdisk(&res.stdout))
+    }
+}
+
+/// Marks a node as owned by a running job. Dropped without `finish` (the job failed), a node
+/// that was being created is removed again and any other node gets its previous status back.
+struct BusyNode {
+    db: Arc<Database>,
+    id: String,
+    previous: Option<NodeStatus>,
+    finished: bool,
+}
+
+impl BusyNode {
+    /// Insert `node` as `Creating` before its file exists, which also reserves its path and name.
+    fn creating(db: &Arc<Database>, node: Node) -> Result<Self> {
+        let id = node.id.clone();
+        db.insert_node(&Node {
+            status: NodeStatus::Creating,
+            ..node
+        })?;
+        Ok(Self {
+            db: db.clone(),
+            id,
+            previous: None,
+            finished: false,
+        })
+    }
+
+    fn mark(db: &Arc<Database>, node: &Node, status: NodeStatus) -> Result<Self> {
+        db.update_node_status(&node.id, status)?;
+        Ok(Self {
+            db: db.clone(),
+            id: node.id.clone(),
+            previous: Some(node.status.clone()),
+            finished: false,
+        })
+    }
+
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for BusyNode {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let reset = match &self.previous {
+            Some(status) => self.db.update_node_status(&self.id, status.clone()),
+            None => self.db.delete_nodes(std::slice::from_ref(&self.id)),
+        };
+        if let Err(err) = reset {
+            warn!("reset busy node failed node={} err={err}", self.id);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ScannedVhd {
+    path: String,
+    normalized: String,
+    parent_normalized: Option<String>,
+    detail_ok: bool,
+    fragmentation: Option<u32>,
+    created_at: DateTime<Utc>,
+    bcd_guid: Option<String>,
+}
+
+fn collect_vhdx_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut stack = vec![root.to_path_buf()];
+    let mut files = Vec::new();
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.is_dir() {
+                // Copies being restored (`restore_node_from_backup`) are not layers yet.
+                if !entry.file_name().to_string_lossy().starts_with(".restore-") {
+                    stack.push(path);
+                }
+            } else if path
+                .extension()
+                .and_then(|s| s.to_str())
+                .map(|s| s.eq_ignore_ascii_case("vhdx"))
+                .unwrap_or(false)
+            {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn derive_name_from_path(path: &str) -> String {
+    let stem = Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("vhdx");
+    if let Some((prefix, rest)) = stem.split_once('-') {
+        if prefix.chars().all(|c| c.is_ascii_digit()) && !rest.is_empty() {
+            return rest.to_string();
+        }
+    }
+    stem.to_string()
+}
+
+fn file_time_or_now(path: &Path) -> DateTime<Utc> {
+    fs::metadata(path)
+        .ok()
+        .and_then(|m| m.created().or_else(|_| m.modified()).ok())
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(Utc::now)
+}
+
+fn bcdedit_boot_sequence_and_reboot(guid: &str) -> Result<CommandOutput> {
+    let res = bcdedit_boot_sequence(guid)?;
+    // Reboot immediately
+    let _ = run_elevated_command("shutdown", &["/r", "/t", "0"], None);
+    Ok(res)
+}
+
+/// Verify the boot files of a freshly created entry. Creation keeps the layer when this fails
+/// and only leaves `boot_files_ready` unset, so the user is pointed at repair.
+fn boot_files_verified(guid: &str) -> bool {
+    if guid.is_empty() {
+        return false;
+    }
+    let Some(letter) = pick_free_letter() else {
+        warn!("no free drive letter to verify boot files guid={guid}");
+        return false;
+    };
+    match verify_boot_files(guid, letter) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("boot file verification failed guid={guid} err={err}");
+            false
+        }
+    }
+}
+
+/// Drive letters in use as a bitmask (bit 0 = `A:`); 0 when the query failed.
+fn logical_drives() -> u32 {
+    #[cfg(feature = "simulate")]
+    {
+        crate::simulate::logical_drives()
+    }
+    #[cfg(not(feature = "simulate"))]
+    {
+        unsafe { windows_sys::Win32::Storage::FileSystem::GetLogicalDrives() }
+    }
+}
+
+fn pick_free_letter() -> Option<char> {
+    let mask = logical_drives();
+    if mask == 0 {
+        return None;
+    }
+    for letter in b'S'..=b'Z' {
+        let idx = (letter - b'A') as u32;
+        let in_use = (mask & (1 << idx)) != 0;
+        if !in_use {
+            return Some(letter as char);
+        }
+    }
+    None
+}
+
+fn pick_free_letters(count: usize) -> Option<Vec<char>> {
+    let mask = logical_drives();
+    if mask == 0 {
+        return None;
+    }
+    let mut letters = Vec::new();
+    for letter in b'S'..=b'Z' {
+        let idx = (letter - b'A') as u32;
+        let in_use = (mask & (1 << idx)) != 0;
+        if !in_use {
+            letters.push(letter as char);
+            if letters.len() == count {
+                return Some(letters);
+            }
+        }
+    }
+    None
+}
+
+/// Copy the image's WinRE into the recovery partition and register it offline with the
+/// image's own reagentc, so recovery and reset work once the layer boots.
+fn stage_winre(sys_letter: char, recovery_letter: char) -> Result<()> {
+    let source = PathBuf::from(format!(
+        r"{sys_letter}:\Windows\System32\Recovery\Winre.wim"
+    ));
+    if !source.is_file() {
+        return Err(AppError::Message(format!(
+            "the applied image has no WinRE at {}",
+            source.display()
+        )));
+    }
+    let dest_dir = format!(r"{recovery_letter}:\Recovery\WindowsRE");
+    fs::create_dir_all(&dest_dir)?;
+    fs::copy(&source, Path::new(&dest_dir).join("Winre.wim"))?;
+    let reagentc = format!(r"{sys_letter}:\Windows\System32\reagentc.exe");
+    let target = format!(r"{sys_letter}:\Windows");
+    let res = run_elevated_command(
+        &reagentc,
+        &["/setreimage", "/path", &dest_dir, "/target", &target],
+        None,
+    )?;
+    log_command("reagentc setreimage", &res, None);
+    if !res.succeeded() {
+        return Err(command_error("reagentc setreimage", &res, None));
+    }
+    Ok(())
+}
+
+/// Replace the inherited ACL of `dir` and anything already in it with full control for
+/// SYSTEM and Administrators only, so users of the layer cannot read what is staged there.
+fn restrict_to_system_and_admins(dir: &Path) -> Result<()> {
+    let dir_arg = dir.to_string_lossy();
+    let res = run_elevated_command(
+        "icacls",
+        &[
+            &dir_arg,
+            "/inheritance:r",
+            "/grant:r",
+            "*S-1-5-18:(OI)(CI)F",
+            "*S-1-5-32-544:(OI)(CI)F",
+            "/T",
+        ],
+        None,
+    )?;
+    log_command("icacls", &res, None);
+    if !res.succeeded() {
+        return Err(command_error("icacls", &res, None));
+    }
+    Ok(())
+}
+
+/// Copy scripts/installers into `ProgramData\LayeredSystem\provision` of a mounted layer and
+/// register a runner that executes them in order on first logon.
+fn install_provisioning_scripts(sys_mount: &Path, scripts: &[ProvisioningScript]) -> Result<()> {
+    let dir = sys_mount
+        .join("ProgramData")
+        .join("LayeredSystem")
+        .join("provision");
+    fs::create_dir_all(&dir)?;
+
+    let mut lines = vec![
+        "@echo off".to_string(),
+        "chcp 65001 >nul".to_string(),
+        r#"set LOG="%~dp0provision.log""#.to_string(),
+    ];
+    for (idx, script) in scripts.iter().enumerate() {
+        let source = Path::new(&script.path);
+        let file_name = source
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| AppError::Message(format!("invalid script path: {}", script.path)))?;
+        let staged = format!("{idx:02}-{file_name}");
+        fs::copy(source, dir.join(&staged))?;
+
+        let args = script.args.as_deref().unwrap_or("");
+        let target = format!(r#""%~dp0{staged}""#);
+        let ext = source
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let invoke = match ext.as_str() {
+            "ps1" => {
+                format!("powershell.exe -NoProfile -ExecutionPolicy Bypass -File {target} {args}")
+            }
+            "msi" => format!("msiexec.exe /i {target} /qn /norestart {args}"),
+            "cmd" | "bat" => format!("call {target} {args}"),
+            _ => format!("{target} {args}"),
+        };
+        lines.push(format!("echo [%DATE% %TIME%] {staged} >> %LOG%"));
+        lines.push(format!("{} >> %LOG% 2>&1", invoke.trim_end()));
+        lines.push("echo exit=%ERRORLEVEL% >> %LOG%".to_string());
+    }
+    fs::write(dir.join("provision.cmd"), lines.join("\r\n"))?;
+
+    add_run_once(
+        sys_mount,
+        "LayeredSystemProvision",
+        r#"cmd.exe /c "%ProgramData%\LayeredSystem\provision\provision.cmd""#,
+    )
+}
+
+impl NetworkProfile {
+    /// Check addresses and the domain-join blob before anything is written to a layer.
+    pub fn validate(&self) -> Result<()> {
+        if let NetworkAddressing::Static {
+            address,
+            prefix_length,
+            gateway,
+            dns,
+        } = &self.addressing
+        {
+            let ip = |value: &str| {
+                value
+                    .trim()
+                    .parse::<IpAddr>()
+                    .map_err(|_| AppError::Message(format!("invalid IP address: {value}")))
+            };
+            let max_prefix = if ip(address)?.is_ipv4() { 32 } else { 128 };
+            if *prefix_length == 0 || *prefix_length > max_prefix {
+                return Err(AppError::Message(format!(
+                    "invalid prefix length: {prefix_length}"
+                )));
+            }
+            for value in gateway.iter().chain(dns) {
+                ip(value)?;
+            }
+        }
+        if let Some(blob) = self.djoin_blob.as_deref() {
+            if !Path::new(blob).is_file() {
+                return Err(AppError::Message(format!(
+                    "domain join blob not found: {blob}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SwapFileOptions {
+    /// Check the pagefile volume before a layer is created with these options.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(drive) = self.pagefile_drive.as_deref() {
+            normalize_volume(drive).ok_or_else(|| {
+                AppError::Message(format!(
+                    "invalid pagefile volume: {drive}; use a letter like D:"
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn presets(&self) -> Vec<RegistryPreset> {
+        let pagefile = match self.pagefile_drive.as_deref().and_then(normalize_volume) {
+            Some(drive) => RegistryPreset::MovePagefile { drive },
+            None => RegistryPreset::DisablePagefile,
+        };
+        vec![RegistryPreset::DisableHibernation, pagefile]
+    }
+}
+
+/// Write the first-logon network script and provision the offline domain join, if any.
+fn install_network_profile(sys_mount: &Path, profile: &NetworkProfile) -> Result<()> {
+    let dir = sys_mount
+        .join("ProgramData")
+        .join("LayeredSystem")
+        .join("network");
+    fs::create_dir_all(&dir)?;
+
+    let adapter = match profile.interface_alias.as_deref() {
+        Some(alias) => format!(
+            "Get-NetAdapter -Name '{}' -ErrorAction SilentlyContinue",
+            ps_escape_single(alias)
+        ),
+        None => "Get-NetAdapter -Physical -ErrorAction SilentlyContinue | Where-Object Status -eq 'Up' | Sort-Object ifIndex | Select-Object -First 1".to_string(),
+    };
+    let configure = match &profile.addressing {
+        NetworkAddressing::Dhcp => "Set-NetIPInterface -InterfaceIndex $nic.ifIndex -Dhcp Enabled
+Set-DnsClientServerAddress -InterfaceIndex $nic.ifIndex -ResetServerAddresses"
+            .to_string(),
+        NetworkAddressing::Static {
+            address,
+            prefix_length,
+            gateway,
+            dns,
+        } => {
+            let mut lines = vec![
+                "Set-NetIPInterface -InterfaceIndex $nic.ifIndex -Dhcp Disabled".to_string(),
+                "Get-NetIPAddress -InterfaceIndex $nic.ifIndex -ErrorAction SilentlyContinue | Remove-NetIPAddress -Confirm:$false".to_string(),
+                "Get-NetRoute -InterfaceIndex $nic.ifIndex -DestinationPrefix '0.0.0.0/0' -ErrorAction SilentlyContinue | Remove-NetRoute -Confirm:$false".to_string(),
+            ];
+            let gateway = gateway
+                .as_deref()
+                .map(|g| format!(" -DefaultGateway '{}'", g.trim()))
+                .unwrap_or_default();
+            lines.push(format!(
+                "New-NetIPAddress -InterfaceIndex $nic.ifIndex -IPAddress '{}' -PrefixLength {prefix_length}{gateway} | Out-Null",
+                address.trim()
+            ));
+            if !dns.is_empty() {
+                let servers: Vec<String> = dns.iter().map(|d| format!("'{}'", d.trim())).collect();
+                lines.push(format!(
+                    "Set-DnsClientServerAddress -InterfaceIndex $nic.ifIndex -ServerAddresses @({})",
+                    servers.join(", ")
+                ));
+            }
+            lines.join("\r\n")
+        }
+    };
+    // The adapter may still be coming up on the first logon after new hardware was detected.
+    let script = format!(
+        "$ErrorActionPreference = 'Stop'
+Start-Transcript -Path \"$PSScriptRoot\\network.log\" -Append | Out-Null
+$nic = $null
+for ($i = 0; $i -lt 30 -and -not $nic; $i++) {{
+    $nic = {adapter}
+    if (-not $nic) {{ Start-Sleep -Seconds 2 }}
+}}
+if (-not $nic) {{ throw 'no network adapter found' }}
+{configure}
+Stop-Transcript | Out-Null
+"
+    );
+    fs::write(dir.join("network.ps1"), script.replace('\n', "\r\n"))?;
+
+    if let Some(blob) = profile.djoin_blob.as_deref() {
+        let windows = sys_mount.join("Windows");
+        let res = run_elevated_command(
+            "djoin.exe",
+            &[
+                "/requestodj",
+                "/loadfile",
+                blob,
+                "/windowspath",
+                &windows.to_string_lossy(),
+            ],
+            None,
+        )?;
+        log_command("djoin requestodj", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("djoin requestodj", &res, None));
+        }
+    }
+
+    add_run_once(
+        sys_mount,
+        "LayeredSystemNetwork",
+        r#"cmd.exe /c powershell.exe -NoProfile -ExecutionPolicy Bypass -File "%ProgramData%\LayeredSystem\network\network.ps1""#,
+    )
+}
+
+/// Check that the files a recipe references exist and its registry tweaks are valid.
+fn validate_recipe(recipe: &Recipe) -> Result<()> {
+    if recipe.wim_index == 0 || recipe.size_gb == 0 {
+        return Err(AppError::Message(
+            "recipe needs an image index and a disk size".into(),
+        ));
+    }
+    let files = std::iter::once(&recipe.wim_file)
+        .chain(&recipe.drivers)
+        .chain(&recipe.unattend)
+        .chain(recipe.scripts.iter().map(|s| &s.path));
+    for file in files {
+        if !Path::new(file).exists() {
+            return Err(AppError::Message(format!("recipe file not found: {file}")));
+        }
+    }
+    let mut tweaks: Vec<RegistryTweak> = recipe.presets.iter().flat_map(|p| p.tweaks()).collect();
+    tweaks.extend(recipe.tweaks.iter().cloned());
+    validate_tweaks(&tweaks)
+}
+
+/// Apply the offline customizations of a recipe to a mounted layer.
+fn customize_from_recipe(sys_mount: &Path, recipe: &Recipe) -> Result<()> {
+    let image = sys_mount.to_string_lossy().to_string();
+    if !recipe.drivers.is_empty() {
+        jobs::report_phase("add_drivers", None);
+    }
+    for driver in &recipe.drivers {
+        let res = add_driver(&image, driver)?;
+        log_command("dism add-driver", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("dism add-driver", &res, None));
+        }
+    }
+
+    if let Some(unattend) = &recipe.unattend {
+        let panther = sys_mount.join("Windows").join("Panther");
+        fs::create_dir_all(&panther)?;
+        fs::copy(unattend, panther.join("unattend.xml"))?;
+    }
+
+    if let Some(preset) = recipe.debloat {
+        jobs::report_phase("debloat", None);
+        let res = get_provisioned_appx(&image)?;
+        log_command("dism get-provisionedappx", &res, None);
+        if !res.succeeded() {
+            return Err(command_error("dism get-provisionedappx", &res, None));
+        }
+        let apps = preset.apps();
+        for (display_name, package_name) in parse_provisioned_appx(&res.stdout) {
+            if !apps
+                .iter()
+                .any(|app| app.eq_ignore_ascii_case(&display_name))
+            {
+                continue;
+            }
+            let res = remove_provisioned_appx(&image, &package_name)?;
+            log_command("dism remove-provisionedappx", &res, None);
+            // A package that cannot be removed is not worth failing the whole layer over.
+            if !res.succeeded() {
+                warn!("remove provisioned appx failed package={package_name}");
+            }
+        }
+    }
+
+    let mut tweaks: Vec<RegistryTweak> = recipe.presets.iter().flat_map(|p| p.tweaks()).collect();
+    tweaks.extend(recipe.tweaks.iter().cloned());
+    if !tweaks.is_empty() {
+        jobs::report_phase("registry", None);
+        apply_tweaks(sys_mount, &tweaks)?;
+    }
+    Ok(())
+}
+
+fn record_provisioning(
+    db: &Database,
+    node_id: &str,
+    scripts: &[ProvisioningScript],
+    res: Result<()>,
+) -> Result<()> {
+    let detail = format!("count={}", scripts.len());
+    match res {
+        Ok(()) => {
+            db.insert_op(
+                &jobs::next_op_id(),
+                Some(node_id),
+                "provision_scripts",
+                "ok",
+                &detail,
+            )?;
+            info!("provision_scripts node={node_id} {detail}");
+            Ok(())
+        }
+        Err(err) => {
+            db.insert_op(
+                &jobs::next_op_id(),
+                Some(node_id),
+                "provision_scripts",
+                "error",
+                &format!("{detail} err={err}"),
+            )?;
+            Err(err)
+        }
+    }
+}
+
+/// Set `expired` on nodes past their `expires_at`, or idle longer than the shortest
+/// `tag_idle_days` rule of their tags (judged by the VHDX write time).
+fn annotate_expiry(nodes: &mut [Node], tag_idle_days: &BTreeMap<String, u32>) {
+    let now = Utc::now();
+    for node in nodes.iter_mut() {
+        let idle_days = node
+            .tags
             .iter()
-            .find(|p| p.kind.eq_ignore_ascii_case("Primary"))
-            .map(|p| p.index)
-            .or_else(|| {
-                parts
-                    .iter()
-                    .find(|p| p.kind.eq_ignore_ascii_case("Basic"))
-                    .map(|p| p.index)
-            })
-            .ok_or_else(|| {
-                AppError::Message("failed to detect system partition from list partition".into())
-            })?;
+            .filter_map(|tag| tag_idle_days.get(tag))
+            .min();
+        let idle = idle_days.is_some_and(|&days| {
+            fs::metadata(&node.path)
+                .and_then(|m| m.modified())
+                .map(DateTime::<Utc>::from)
+                .is_ok_and(|modified| now - modified > chrono::Duration::days(i64::from(days)))
+        });
+        node.expired = idle || node.expires_at.is_some_and(|at| at <= now);
+    }
+}
 
-        let assign_script =
-            assign_partitions_script(Path::new(&node.path), &[(sys_part, sys_letter)]);
-        let assign_path = temp.write_script("assign_repair.txt", &assign_script)?;
-        log_diskpart_script(&assign_path);
-        let assign_res = run_diskpart_script(&assign_path)?;
-        log_command("diskpart assign repair", &assign_res, Some(&assign_path));
-        if assign_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error(
-                "diskpart assign",
-                &assign_res,
-                Some(&assign_path),
-            ));
+/// Ids of the layer the system booted from and its ancestors; empty when it did not boot from
+/// a tracked layer.
+fn booted_chain(nodes: &[Node]) -> HashSet<&str> {
+    let boot_vhd = storage::boot_vhd_path().unwrap_or_else(|err| {
+        warn!("boot disk lookup failed: {err}");
+        None
+    });
+    let mut in_use = HashSet::new();
+    let mut current = boot_vhd.and_then(|vhd| {
+        let vhd = normalize_path(&vhd);
+        nodes.iter().find(|n| normalize_path(&n.path) == vhd)
+    });
+    while let Some(node) = current {
+        if !in_use.insert(node.id.as_str()) {
+            break;
         }
+        current = node
+            .parent_id
+            .as_deref()
+            .and_then(|id| nodes.iter().find(|n| n.id == id));
+    }
+    in_use
+}
 
-        let sys_mount = PathBuf::from(format!("{sys_letter}:"));
-        let bcd_res = run_bcdboot(&sys_mount)?;
-        log_command("bcdboot", &bcd_res, None);
-        if bcd_res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("bcdboot", &bcd_res, None));
+/// `node_id` followed by all its descendants, breadth first.
+fn subtree_ids(nodes: &[Node], node_id: &str) -> Vec<String> {
+    let mut graph: HashMap<String, Vec<String>> = HashMap::new();
+    for n in nodes.iter() {
+        if let Some(pid) = &n.parent_id {
+            graph.entry(pid.clone()).or_default().push(n.id.clone());
         }
-        let bcd_enum = bcdedit_enum_all()?;
-        log_command("bcdedit enum", &bcd_enum, None);
-        let guid = extract_guid_for_vhd(&bcd_enum.stdout, &node.path)
-            .or_else(|| extract_guid_for_partition_letter(&bcd_enum.stdout, sys_letter));
-        if let Some(guid) = &guid {
-            db.update_node_bcd(&node.id, guid)?;
-            if let Some(desc) = description {
-                let res = bcdedit_set_description(guid, desc)?;
-                log_command("bcdedit set description", &res, None);
+    }
+    let mut order = Vec::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(node_id.to_string());
+    while let Some(id) = queue.pop_front() {
+        order.push(id.clone());
+        if let Some(children) = graph.get(&id) {
+            for c in children {
+                queue.push_back(c.clone());
             }
         }
+    }
+    order
+}
 
-        let detach_script = detach_vdisk_script(Path::new(&node.path), &[sys_letter]);
-        let detach_path = temp.write_script("detach_repair.txt", &detach_script)?;
-        log_diskpart_script(&detach_path);
-        if let Ok(o) = run_diskpart_script(&detach_path) {
-            log_command("diskpart detach repair", &o, Some(&detach_path));
+/// Fill `depth`, `root_id`, `ancestor_ids` and `depth_exceeded` from the parent links.
+fn annotate_chains(nodes: &mut [Node], max_depth: u32) {
+    let parents: HashMap<String, Option<String>> = nodes
+        .iter()
+        .map(|n| (n.id.clone(), n.parent_id.clone()))
+        .collect();
+    for node in nodes.iter_mut() {
+        let mut ancestors = Vec::new();
+        let mut current = node.parent_id.clone();
+        while let Some(pid) = current {
+            // Guard against cycles and dangling links left by manual edits.
+            if pid == node.id || ancestors.contains(&pid) || !parents.contains_key(&pid) {
+                break;
+            }
+            current = parents.get(&pid).cloned().flatten();
+            ancestors.push(pid);
         }
+        node.depth = ancestors.len() as u32;
+        node.root_id = ancestors.last().cloned().unwrap_or_else(|| node.id.clone());
+        node.depth_exceeded = node.depth > max_depth;
+        node.ancestor_ids = ancestors;
+    }
+}
 
-        db.insert_op(
-            &Uuid::new_v4().to_string(),
-            Some(&node.id),
-            "repair_bcd",
-            "ok",
-            description.unwrap_or(""),
-        )?;
-        info!(
-            "repair_bcd node={} guid={}",
-            node.id,
-            guid.clone().unwrap_or_default()
+/// Log the layers deeper than `max_chain_depth`. Called after a scan rather than from
+/// `annotate_chains`, which runs on every list and would repeat the warning on each refresh.
+/// New layers cannot exceed the limit (`ensure_quota`), so only imported or re-linked chains and
+/// a lowered limit end up here.
+fn warn_deep_chains(nodes: &[Node]) {
+    for node in nodes.iter().filter(|n| n.depth_exceeded) {
+        warn!(
+            "node={} depth={} exceeds max_chain_depth",
+            node.id, node.depth
         );
-        Ok(guid)
     }
+}
 
-    pub fn detail_vdisk(&self, vhd_path: &str) -> Result<crate::diskpart::VhdDetail> {
-        let paths = self.paths()?;
-        let temp = TempManager::new(paths.tmp_dir())?;
-        let script = detail_vdisk_script(Path::new(vhd_path));
-        let script_path = temp.write_script("detail_vdisk.txt", &script)?;
-        log_diskpart_script(&script_path);
-        let res = run_diskpart_script(&script_path)?;
-        log_command("diskpart detail", &res, Some(&script_path));
-        if res.exit_code.unwrap_or(-1) != 0 {
-            return Err(command_error("diskpart detail", &res, Some(&script_path)));
+/// Partitions of a just-attached VHDX from the Storage cmdlets, falling back to the
+/// `list partition` section of the diskpart session that attached it.
+fn attached_partitions(vhd_path: &Path, diskpart_output: &str) -> Vec<PartitionInfo> {
+    match storage::vhd_partitions(vhd_path) {
+        Ok(parts) if !parts.is_empty() => parts,
+        Ok(_) => parse_list_partition(diskpart_output),
+        Err(err) => {
+            warn!("Get-Partition failed path={} err={err}", vhd_path.display());
+            parse_list_partition(diskpart_output)
         }
-        Ok(parse_detail_vdisk_parent(&res.stdout))
     }
 }
 
-#[derive(Debug)]
-struct ScannedVhd {
-    path: String,
-    normalized: String,
-    parent_normalized: Option<String>,
-    detail_ok: bool,
-    created_at: DateTime<Utc>,
-    bcd_guid: Option<String>,
+/// Pick the Windows partition of a layer from its partition list. When the partition
+/// types are localized the largest partition is taken, which is Windows in every layout we create.
+fn system_partition_index(parts: &[PartitionInfo]) -> Option<u32> {
+    parts
+        .iter()
+        .find(|p| p.kind.eq_ignore_ascii_case("Primary"))
+        .or_else(|| parts.iter().find(|p| p.kind.eq_ignore_ascii_case("Basic")))
+        .or_else(|| {
+            parts
+                .iter()
+                .filter(|p| p.size_mb.is_some())
+                .max_by_key(|p| p.size_mb)
+        })
+        .map(|p| p.index)
 }
 
-fn collect_vhdx_files(root: &Path) -> Result<Vec<PathBuf>> {
-    let mut stack = vec![root.to_path_buf()];
-    let mut files = Vec::new();
-    while let Some(dir) = stack.pop() {
-        for entry in fs::read_dir(&dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                stack.push(path);
-            } else if path
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.eq_ignore_ascii_case("vhdx"))
-                .unwrap_or(false)
-            {
-                files.push(path);
-            }
+/// Normalize user input such as `e`, `E:` or `E:\\` to `E:`.
+fn normalize_volume(input: &str) -> Option<String> {
+    let trimmed = input
+        .trim()
+        .trim_end_matches(['\\', '/'])
+        .trim_end_matches(':');
+    let mut chars = trimmed.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Some(format!("{}:", c.to_ascii_uppercase())),
+        _ => None,
+    }
+}
+
+/// DISM configuration list for capturing the live system volume: the files DISM skips by
+/// default plus every workspace folder on that volume, so no VHDX ends up in the new base.
+fn capture_config(paths: &AppPaths, system_drive: &str) -> String {
+    let mut lines: Vec<String> = [
+        "[ExclusionList]",
+        "\\$ntfs.log",
+        "\\hiberfil.sys",
+        "\\pagefile.sys",
+        "\\swapfile.sys",
+        "\\System Volume Information",
+        "\\RECYCLER",
+        "\\$Recycle.Bin",
+        "\\Windows\\CSC",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect();
+    let system_letter = system_drive.chars().next();
+    let mut dirs = paths.layer_roots();
+    dirs.push(paths.tmp_dir());
+    for dir in dirs {
+        let on_system = drive_letter_of(&dir)
+            .zip(system_letter)
+            .is_some_and(|(a, b)| a.eq_ignore_ascii_case(&b));
+        let rel = relative_to_drive(&dir);
+        if on_system && !rel.is_empty() {
+            lines.push(format!("\\{rel}"));
         }
     }
-    Ok(files)
+    lines.join("\r\n")
 }
 
-fn normalize_path(path: &str) -> String {
-    let trimmed = path.trim().trim_start_matches("\\\\?\\");
-    let adjusted = device_path_to_drive(trimmed).unwrap_or_else(|| trimmed.to_string());
-    adjusted.replace('/', "\\").to_ascii_lowercase()
+/// Strip the drive (or UNC) prefix, e.g. `D:\ls\meta` -> `ls\meta`.
+fn relative_to_drive(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\\")
 }
 
-fn derive_name_from_path(path: &str) -> String {
-    let stem = Path::new(path)
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("vhdx");
-    if let Some((prefix, rest)) = stem.split_once('-') {
-        if prefix.chars().all(|c| c.is_ascii_digit()) && !rest.is_empty() {
-            return rest.to_string();
+/// Drive letter of a mount root such as `U:\`.
+fn drive_letter_of(mount: &Path) -> Option<char> {
+    mount
+        .to_str()
+        .and_then(|s| s.chars().next())
+        .filter(|c| c.is_ascii_alphabetic())
+}
+
+/// Expand `{name}`, `{parent}` and `{date}` in a boot menu description template.
+fn render_description_template(template: &str, name: &str, parent: Option<&str>) -> String {
+    let rendered = template
+        .replace("{name}", name)
+        .replace("{parent}", parent.unwrap_or("base"))
+        .replace("{date}", &Local::now().format("%Y-%m-%d").to_string());
+    rendered.trim().to_string()
+}
+
+/// Names reserved by Windows for devices; unusable as file names on any volume.
+const RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Validate a layer name and derive the file name slug from it.
+///
+/// Names end up in diskpart scripts (inside double quotes) and in file names, so characters
+/// that are invalid in paths or break quoting are rejected rather than escaped.
+fn node_slug(name: &str) -> Result<String> {
+    if name.is_empty() {
+        return Err(AppError::Message("name must not be empty".into()));
+    }
+    if name.chars().count() > 64 {
+        return Err(AppError::Message(format!(
+            "name is longer than 64 characters: {name}"
+        )));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| c.is_control() || r#"<>:"/\|?*"#.contains(*c))
+    {
+        return Err(AppError::Message(format!(
+            "name contains a reserved character {c:?}: {name}"
+        )));
+    }
+    let mut slug = String::new();
+    for c in name.to_lowercase().chars() {
+        if c.is_alphanumeric() || c == '_' || c == '.' {
+            slug.push(c);
+        } else if !slug.ends_with('-') {
+            slug.push('-');
         }
     }
-    stem.to_string()
+    let slug = slug.trim_matches(|c| c == '-' || c == '.').to_string();
+    if slug.is_empty() {
+        return Err(AppError::Message(format!(
+            "name has no usable file name characters: {name}"
+        )));
+    }
+    let stem = slug.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.contains(&stem) {
+        return Err(AppError::Message(format!(
+            "name is reserved by Windows: {name}"
+        )));
+    }
+    Ok(slug)
 }
 
-fn file_time_or_now(path: &Path) -> DateTime<Utc> {
-    fs::metadata(path)
-        .ok()
-        .and_then(|m| m.created().or_else(|_| m.modified()).ok())
-        .map(DateTime::<Utc>::from)
-        .unwrap_or_else(Utc::now)
+/// Upper bound on sequence numbers skipped while looking for a free file name.
+const MAX_SEQ_PROBES: usize = 1000;
+
+/// Format version written by `export_metadata`.
+const METADATA_VERSION: u32 = 1;
+
+/// Reserve the next sequence number whose `{seq:04}-*.vhdx` is not already present in `dir`,
+/// e.g. because a file was restored from a backup or copied in by hand.
+fn next_vhd_path(db: &Database, dir: &Path, slug: &str) -> Result<PathBuf> {
+    let existing: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().to_lowercase())
+                .collect()
+        })
+        .unwrap_or_default();
+    for _ in 0..MAX_SEQ_PROBES {
+        let seq = db.next_seq()?;
+        let prefix = format!("{seq:04}-");
+        if !existing.iter().any(|name| name.starts_with(&prefix)) {
+            return Ok(dir.join(format!("{prefix}{slug}.vhdx")));
+        }
+        warn!("sequence {seq} already used in {}, skipping", dir.display());
+    }
+    Err(AppError::Message(format!(
+        "no free sequence number for a new disk in {}",
+        dir.display()
+    )))
 }
 
-fn bcdedit_boot_sequence_and_reboot(guid: &str) -> Result<CommandOutput> {
-    let res = bcdedit_boot_sequence(guid)?;
-    // Reboot immediately
-    let _ = run_elevated_command("shutdown", &["/r", "/t", "0"], None);
-    Ok(res)
+/// `<name> <yyyy-mm-dd>`, numbered when refreshed more than once a day.
+fn refreshed_base_name(db: &Database, name: &str) -> Result<String> {
+    let stem = format!("{name} {}", Local::now().format("%Y-%m-%d"));
+    let roots: Vec<String> = db
+        .fetch_nodes()?
+        .into_iter()
+        .filter(|n| n.parent_id.is_none())
+        .map(|n| n.name.trim().to_lowercase())
+        .collect();
+    (1..=MAX_SEQ_PROBES)
+        .map(|n| match n {
+            1 => stem.clone(),
+            n => format!("{stem} ({n})"),
+        })
+        .find(|candidate| !roots.contains(&candidate.to_lowercase()))
+        .ok_or_else(|| AppError::Message(format!("no free name for refreshed base {name}")))
 }
 
-fn pick_free_letter() -> Option<char> {
-    let mask = unsafe { GetLogicalDrives() };
-    if mask == 0 {
-        return None;
+/// `<file>.json` next to a published VHDX; every field is optional.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct LibraryManifest {
+    name: Option<String>,
+    desc: Option<String>,
+    sha256: Option<String>,
+    publisher: Option<String>,
+    published_at: Option<DateTime<Utc>>,
+    os_edition: Option<String>,
+    os_version: Option<String>,
+    os_build: Option<String>,
+    /// File name of the parent VHDX; set for backed-up differencing layers.
+    parent: Option<String>,
+    node_id: Option<String>,
+}
+
+fn read_library_manifest(vhd_path: &Path) -> Result<Option<LibraryManifest>> {
+    let mut manifest_path = vhd_path.as_os_str().to_owned();
+    manifest_path.push(".json");
+    match fs::read_to_string(&manifest_path) {
+        Ok(text) => Ok(Some(serde_json::from_str(&text)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
     }
-    for letter in b'S'..=b'Z' {
-        let idx = (letter - b'A') as u32;
-        let in_use = (mask & (1 << idx)) != 0;
-        if !in_use {
-            return Some(letter as char);
-        }
+}
+
+/// Copy a file into `dest_dir` with robocopy in restartable mode and check that the copy has
+/// the same SHA-256 as the source, which is returned.
+fn copy_verified(source: &Path, dest_dir: &Path) -> Result<String> {
+    let (Some(src_dir), Some(file_name)) = (source.parent(), source.file_name()) else {
+        return Err(AppError::Message(format!(
+            "invalid source {}",
+            source.display()
+        )));
+    };
+    fs::create_dir_all(dest_dir)?;
+    jobs::report_phase("copy", Some(5));
+    let src_arg = src_dir.to_string_lossy();
+    let dest_arg = dest_dir.to_string_lossy();
+    let file_arg = file_name.to_string_lossy();
+    let res = run_elevated_command(
+        "robocopy",
+        &[&src_arg, &dest_arg, &file_arg, "/Z", "/R:3", "/W:10", "/NP"],
+        None,
+    )?;
+    log_command("robocopy", &res, None);
+    // Exit codes below 8 mean the file was copied or already identical.
+    if !matches!(res.exit_code, Some(0..=7)) {
+        return Err(command_error("robocopy", &res, None));
     }
-    None
+    jobs::report_phase("verify_hash", Some(70));
+    let expected = file_sha256(source)?;
+    let actual = file_sha256(&dest_dir.join(file_name))?;
+    if !actual.eq_ignore_ascii_case(&expected) {
+        return Err(AppError::Message(format!(
+            "hash mismatch after copying {}: source {expected}, copy {actual}",
+            source.display()
+        )));
+    }
+    Ok(expected)
 }
 
-fn pick_free_letters(count: usize) -> Option<Vec<char>> {
-    let mask = unsafe { GetLogicalDrives() };
-    if mask == 0 {
-        return None;
+/// SHA-256 of a file as uppercase hex, computed by `Get-FileHash`.
+fn file_sha256(path: &Path) -> Result<String> {
+    let script = format!(
+        "$ErrorActionPreference = 'Stop'\n(Get-FileHash -LiteralPath '{}' -Algorithm SHA256).Hash",
+        ps_escape_single(&path.to_string_lossy())
+    );
+    let res = run_powershell(&script)?;
+    log_command("get-filehash", &res, None);
+    if res.exit_code != Some(0) {
+        return Err(command_error("get-filehash", &res, None));
     }
-    let mut letters = Vec::new();
-    for letter in b'S'..=b'Z' {
-        let idx = (letter - b'A') as u32;
-        let in_use = (mask & (1 << idx)) != 0;
-        if !in_use {
-            letters.push(letter as char);
-            if letters.len() == count {
-                return Some(letters);
-            }
+    Ok(res.stdout.trim().to_string())
+}
+
+/// Base layers that share their file size with another base, with that size and the file's
+/// modification time; only these can be duplicates, so only these are worth hashing.
+fn duplicate_candidates(nodes: &[Node]) -> Vec<(&Node, u64, Option<DateTime<Utc>>)> {
+    let bases: Vec<(&Node, u64, Option<DateTime<Utc>>)> = nodes
+        .iter()
+        .filter(|n| n.parent_id.is_none() && n.status == NodeStatus::Normal)
+        .filter_map(|n| {
+            let meta = fs::metadata(&n.path).ok()?;
+            let modified = meta.modified().ok().map(DateTime::<Utc>::from);
+            Some((n, meta.len(), modified))
+        })
+        .collect();
+    let mut sizes: HashMap<u64, usize> = HashMap::new();
+    for (_, len, _) in &bases {
+        *sizes.entry(*len).or_default() += 1;
+    }
+    bases
+        .into_iter()
+        .filter(|(_, len, _)| sizes[len] > 1)
+        .collect()
+}
+
+/// VHDXs in `dir` with the details of their manifests, as listed by the library and the
+/// template folder.
+fn list_vhdx_entries(dir: &Path) -> Result<Vec<LibraryEntry>> {
+    let mut entries = Vec::new();
+    for item in fs::read_dir(dir)? {
+        let path = item?.path();
+        let is_vhdx = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("vhdx"));
+        if !is_vhdx || !path.is_file() {
+            continue;
         }
+        let file = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let manifest = read_library_manifest(&path).unwrap_or_else(|err| {
+            warn!("invalid library manifest file={file} err={err}");
+            None
+        });
+        let manifest = manifest.unwrap_or_default();
+        entries.push(LibraryEntry {
+            size_bytes: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+            path: path.to_string_lossy().to_string(),
+            name: manifest
+                .name
+                .unwrap_or_else(|| file.trim_end_matches(".vhdx").to_string()),
+            file,
+            desc: manifest.desc,
+            sha256: manifest.sha256,
+            publisher: manifest.publisher,
+            published_at: manifest.published_at,
+            os_edition: manifest.os_edition,
+            os_version: manifest.os_version,
+            os_build: manifest.os_build,
+        });
     }
-    None
+    entries.sort_by_key(|e| e.name.to_lowercase());
+    Ok(entries)
 }
 
-/// Convert a device path (e.g. `\Device\HarddiskVolume10\foo`) to a drive path if possible.
-fn device_path_to_drive(path: &str) -> Option<String> {
-    let lower = path.to_ascii_lowercase();
-    let mut cleaned: &str = &lower;
-    if let Some(rest) = cleaned.strip_prefix("\\??\\") {
-        cleaned = rest;
+/// Path of a layer relative to the workspace root, or the full path when it lies outside.
+fn workspace_relative(root: &Path, path: &str) -> String {
+    let root_key = normalize_path(&root.to_string_lossy());
+    let prefix = format!("{}\\", root_key.trim_end_matches('\\'));
+    let plain = path.trim().trim_start_matches(r"\\?\").replace('/', "\\");
+    if plain.to_ascii_lowercase().starts_with(&prefix) {
+        plain[prefix.len()..].to_string()
+    } else {
+        path.to_string()
     }
-    if let Some(rest) = cleaned.strip_prefix("\\globalroot\\") {
-        cleaned = rest;
+}
+
+fn resolve_workspace_path(root: &Path, rel_path: &str) -> PathBuf {
+    let path = Path::new(rel_path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        root.join(path)
     }
-    if !cleaned.starts_with("\\device\\") {
-        return None;
+}
+
+/// Confirm `wim_file` opens and holds image `wim_index`, as DISM reads it.
+fn check_wim(wim_file: Option<&str>, wim_index: Option<u32>) -> Result<String> {
+    let Some(wim_file) = wim_file.filter(|w| !w.trim().is_empty()) else {
+        return Err(AppError::Message("no WIM/ESD file selected".into()));
+    };
+    fs::File::open(wim_file)
+        .map_err(|err| AppError::Message(format!("cannot read {wim_file}: {err}")))?;
+    let images = list_images(wim_file)?;
+    let index = wim_index.unwrap_or(1);
+    let image = images
+        .iter()
+        .find(|image| image.index == index)
+        .ok_or_else(|| {
+            AppError::Message(format!(
+                "{wim_file} has no image {index} ({} images)",
+                images.len()
+            ))
+        })?;
+    Ok(format!("image {index}: {}", image.name))
+}
+
+/// Refuse a name already used by a sibling (case-insensitive), which would make boot menu
+/// entries and the tree indistinguishable.
+fn ensure_unique_name(db: &Database, parent_id: Option<&str>, name: &str) -> Result<()> {
+    let taken = db
+        .fetch_nodes()?
+        .into_iter()
+        .any(|n| n.parent_id.as_deref() == parent_id && n.name.trim().eq_ignore_ascii_case(name));
+    if taken {
+        return Err(AppError::Message(format!(
+            "a layer named \"{name}\" already exists at this level"
+        )));
     }
+    Ok(())
+}
 
-    for letter in b'A'..=b'Z' {
-        let drive = format!("{}:", letter as char);
-        if let Some(prefix) = query_dos_device(&drive) {
-            let prefix_lower = prefix.to_ascii_lowercase();
-            if cleaned.starts_with(&prefix_lower) && cleaned.len() >= prefix_lower.len() {
-                let rest = cleaned[prefix_lower.len()..].trim_start_matches(['\\', '/']);
-                return if rest.is_empty() {
-                    Some(format!("{drive}\\"))
-                } else {
-                    Some(format!(r"{drive}\{rest}"))
-                };
-            }
+/// Refuse a new layer under `parent_id` (or a new base when `None`) that would break the
+/// configured chain depth, children-per-parent or total size limits. `incoming` is the size the
+/// new layer is expected to occupy right away.
+fn ensure_quota(db: &Database, parent_id: Option<&str>, incoming: u64) -> Result<()> {
+    let settings = db.get_settings()?;
+    let mut nodes = db.fetch_nodes()?;
+    annotate_chains(&mut nodes, settings.max_chain_depth);
+    if let Some(parent_id) = parent_id {
+        let parent = nodes.iter().find(|n| n.id == parent_id);
+        let depth = parent.map(|n| n.depth).unwrap_or(0) + 1;
+        if depth > settings.max_chain_depth {
+            return Err(AppError::Message(format!(
+                "chain would be {depth} layers deep; the limit is {} (max_chain_depth)",
+                settings.max_chain_depth
+            )));
+        }
+        let children = nodes
+            .iter()
+            .filter(|n| n.parent_id.as_deref() == Some(parent_id))
+            .count() as u32;
+        if settings.max_children_per_parent > 0 && children >= settings.max_children_per_parent {
+            return Err(AppError::Message(format!(
+                "{} already has {children} children; the limit is {} (max_children_per_parent)",
+                parent.map(|n| n.name.as_str()).unwrap_or(parent_id),
+                settings.max_children_per_parent
+            )));
         }
     }
-    None
+    if settings.max_total_gb > 0 {
+        let used: u64 = nodes
+            .iter()
+            .filter_map(|n| fs::metadata(&n.path).ok())
+            .map(|m| m.len())
+            .sum();
+        let limit = gb_to_bytes(settings.max_total_gb as u64);
+        if used + incoming > limit {
+            return Err(AppError::Message(format!(
+                "workspace layers use {} and the new layer needs {}; the limit is {} (max_total_gb)",
+                format_size(used),
+                format_size(incoming),
+                format_size(limit)
+            )));
+        }
+    }
+    Ok(())
 }
 
-fn query_dos_device(drive: &str) -> Option<String> {
-    let wide: Vec<u16> = OsStr::new(drive).encode_wide().chain(once(0)).collect();
-    let mut buffer = vec![0u16; 512];
-    let len = unsafe { QueryDosDeviceW(wide.as_ptr(), buffer.as_mut_ptr(), buffer.len() as u32) };
-    if len == 0 {
-        return None;
+fn archive_path(paths: &AppPaths, node_id: &str) -> PathBuf {
+    paths.archive_dir().join(format!("{node_id}.vhdx.zst"))
+}
+
+/// Level 3 keeps archiving at disk speed while still shrinking mostly-empty VHDXs a lot.
+const ARCHIVE_ZSTD_LEVEL: i32 = 3;
+
+fn compress_file(source: &Path, dest: &Path) -> Result<()> {
+    let reader = BufReader::new(fs::File::open(source)?);
+    let mut writer = BufWriter::new(fs::File::create(dest)?);
+    zstd::stream::copy_encode(reader, &mut writer, ARCHIVE_ZSTD_LEVEL)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn decompress_file(source: &Path, dest: &Path) -> Result<()> {
+    let reader = BufReader::new(fs::File::open(source)?);
+    let mut writer = BufWriter::new(fs::File::create(dest)?);
+    zstd::stream::copy_decode(reader, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Fail early with a readable message instead of letting diskpart/DISM run out of space mid-way.
+fn ensure_free_space(dir: &Path, required: u64) -> Result<()> {
+    let Some((free, _)) = volume_space(dir) else {
+        warn!("free space unknown dir={}", dir.display());
+        return Ok(());
+    };
+    if free < required {
+        return Err(AppError::Message(format!(
+            "insufficient space on {}: need {}, have {}",
+            dir.display(),
+            format_size(required),
+            format_size(free)
+        )));
     }
-    let slice = &buffer[..len as usize];
-    let end = slice.iter().position(|&c| c == 0).unwrap_or(slice.len());
-    Some(String::from_utf16_lossy(&slice[..end]))
+    Ok(())
+}
+
+fn gb_to_bytes(gb: u64) -> u64 {
+    gb * 1024 * 1024 * 1024
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} GB", bytes as f64 / (1024.0 * 1024.0 * 1024.0))
+}
+
+/// Free and total bytes of the volume holding `path`.
+#[cfg(windows)]
+fn volume_space(path: &Path) -> Option<(u64, u64)> {
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(once(0)).collect();
+    let mut free = 0u64;
+    let mut total = 0u64;
+    let ok =
+        unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut free, &mut total, std::ptr::null_mut()) };
+    (ok != 0).then_some((free, total))
+}
+
+/// Unknown on other hosts, where the simulated tools never run out of space.
+#[cfg(not(windows))]
+fn volume_space(_path: &Path) -> Option<(u64, u64)> {
+    None
 }
 
 fn log_diskpart_script(script: &Path) {
     let mut parts = Vec::new();
-    match fs::read_to_string(script) {
+    match read_script(script) {
         Ok(content) => {
+            transcript::record_script(script, &content);
             let trimmed = content.trim();
             if !trimmed.is_empty() {
                 parts.push(format!("script={trimmed}"));
@@ -902,16 +6790,16 @@ fn log_diskpart_script(script: &Path) {
 }
 
 fn log_command(name: &str, output: &CommandOutput, script: Option<&Path>) {
-    let mut parts = Vec::new();
-    if let Some(code) = output.exit_code {
-        parts.push(format!("exit={code}"));
-    }
+    let mut parts = vec![output.status_text()];
     if let Some(script) = script {
         parts.push(format!("script={}", script.display()));
     }
+    // The full output is in the transcript; without one (no workspace open) keep the gist here.
     let stderr = output.stderr.trim();
     let stdout = output.stdout.trim();
-    if !stderr.is_empty() {
+    if let Some(dir) = transcript::current_dir() {
+        parts.push(format!("transcript={}", dir.display()));
+    } else if !stderr.is_empty() {
         parts.push(format!("stderr={stderr}"));
     } else if !stdout.is_empty() {
         parts.push(format!("stdout={stdout}"));
@@ -919,13 +6807,46 @@ fn log_command(name: &str, output: &CommandOutput, script: Option<&Path>) {
     info!("{name}: {}", parts.join(" | "));
 }
 
-fn command_error(name: &str, output: &CommandOutput, script: Option<&Path>) -> AppError {
-    let mut parts = Vec::new();
-    if let Some(code) = output.exit_code {
-        parts.push(format!("exit={code}"));
+/// Find a language tag such as `de-de` or `zh-CN` in a language pack file name, e.g.
+/// `Microsoft-Windows-Client-Language-Pack_x64_de-de.cab` or `LanguageExperiencePack.zh-CN.Neutral.appx`.
+fn language_tag_from_package(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.split(['_', '.', ' '])
+        .find(|token| {
+            let mut parts = token.split('-');
+            let lang = parts.next().unwrap_or_default();
+            let region: Vec<&str> = parts.collect();
+            (2..=3).contains(&lang.len())
+                && lang.chars().all(|c| c.is_ascii_alphabetic())
+                && !region.is_empty()
+                && region.len() <= 2
+                && region.iter().all(|r| {
+                    (2..=4).contains(&r.len()) && r.chars().all(|c| c.is_ascii_alphabetic())
+                })
+        })
+        .map(|s| s.to_string())
+}
+
+/// Log an offline DISM call and turn a non-zero exit into an error.
+fn check_dism(step: &str, res: CommandOutput) -> Result<()> {
+    let name = format!("dism {step}");
+    log_command(&name, &res, None);
+    if !res.succeeded() {
+        return Err(command_error(&name, &res, None));
     }
+    Ok(())
+}
+
+fn command_error(name: &str, output: &CommandOutput, script: Option<&Path>) -> AppError {
+    let kind = output.classify();
+    let mut parts = vec![output.status_text()];
     if let Some(script) = script {
-        parts.push(format!("script={}", script.display()));
+        // The operation's temp folder is removed when it finishes, so keep a copy.
+        let kept = temp::preserve_failed(script).unwrap_or_else(|| script.to_path_buf());
+        parts.push(format!("script={}", kept.display()));
+        if let Ok(mut last) = LAST_FAILED_SCRIPT.lock() {
+            *last = Some(kept);
+        }
     }
     let stderr = output.stderr.trim();
     let stdout = output.stdout.trim();
@@ -936,9 +6857,335 @@ fn command_error(name: &str, output: &CommandOutput, script: Option<&Path>) -> A
     } else {
         parts.push("no output".into());
     }
-    AppError::Message(format!("{name} failed: {}", parts.join(" | ")))
+    AppError::Command {
+        kind,
+        message: format!("{name} failed ({kind}): {}", parts.join(" | ")),
+        hint: output.hint(),
+    }
+}
+
+/// `command_error` for a failed detach, naming what keeps the disk or its volumes open so the
+/// user can close it instead of rebooting.
+fn detach_error(
+    output: &CommandOutput,
+    script: Option<&Path>,
+    vhd_path: &Path,
+    letters: &[char],
+) -> AppError {
+    let err = command_error("diskpart detach", output, script);
+    let Some(holders) = handles::describe_holders(vhd_path, letters) else {
+        return err;
+    };
+    match err {
+        AppError::Command { kind, message, .. } => AppError::Command {
+            kind,
+            message: format!("{message} | held by: {holders}"),
+            hint: Some("Close the programs listed above (or wait for the antivirus scan to finish) and retry; a reboot is not needed."),
+        },
+        other => other,
+    }
+}
+
+/// Log what still holds a disk whose detach failed during cleanup.
+fn log_detach_holders(vhd_path: &Path, letters: &[char]) {
+    if let Some(holders) = handles::describe_holders(vhd_path, letters) {
+        warn!("{} is still held by: {holders}", vhd_path.display());
+    }
 }
 
 fn ps_escape_single(input: &str) -> String {
     input.replace('\'', "''")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use super::WorkspaceService;
+    use crate::diskpart::quote_path;
+    use crate::models::Node;
+    use crate::paths::normalize_path;
+    use crate::state::SharedState;
+    use crate::sys::{fake::ScriptedRunner, install_runner, run_command};
+
+    const FIRMWARE: &str = "\
+Firmware Boot Manager
+---------------------
+identifier              {fwbootmgr}
+displayorder            {bootmgr}
+                        {3a2b1c00-0000-0000-0000-000000000001}
+timeout                 2
+
+Windows Boot Manager
+--------------------
+identifier              {bootmgr}
+description             Windows Boot Manager
+
+Firmware Application (101fffff)
+-------------------------------
+identifier              {3a2b1c00-0000-0000-0000-000000000001}
+description             UEFI: PXE IPv4
+";
+
+    fn service(runner: &Arc<ScriptedRunner>) -> WorkspaceService {
+        WorkspaceService::with_runner(SharedState::default(), runner.clone())
+    }
+
+    /// A service over a fresh workspace in the temp folder; tools the test does not queue
+    /// fail, the way they do on a host without them.
+    fn workspace(runner: &Arc<ScriptedRunner>) -> (WorkspaceService, PathBuf) {
+        let root = std::env::temp_dir().join(format!("workspace-{}", Uuid::new_v4()));
+        let state = SharedState::default();
+        state.initialize(root.clone(), None).unwrap();
+        runner.otherwise(1, "");
+        (WorkspaceService::with_runner(state, runner.clone()), root)
+    }
+
+    fn layer(id: &str, parent_id: Option<&str>, path: &str, bcd_guid: Option<&str>) -> Node {
+        Node {
+            id: id.to_string(),
+            parent_id: parent_id.map(str::to_string),
+            name: id.to_string(),
+            path: path.to_string(),
+            bcd_guid: bcd_guid.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn firmware_entries_come_from_the_injected_runner() {
+        let runner = Arc::new(ScriptedRunner::default());
+        runner.expect("bcdedit", 0, FIRMWARE);
+        let info = service(&runner).list_firmware_entries().unwrap();
+        assert!(runner.is_done());
+        assert_eq!(runner.calls(), ["bcdedit /enum firmware /v"]);
+        assert!(info.boot_manager_first);
+        assert_eq!(info.timeout, Some(2));
+        assert_eq!(info.entries.len(), 2);
+        assert_eq!(info.entries[1].description, "UEFI: PXE IPv4");
+    }
+
+    #[test]
+    fn failed_bcdedit_is_reported() {
+        let runner = Arc::new(ScriptedRunner::default());
+        runner.expect("bcdedit", 1, "");
+        assert!(service(&runner).list_firmware_entries().is_err());
+        assert!(runner.is_done());
+    }
+
+    #[test]
+    fn wim_images_are_listed_through_dism() {
+        let runner = Arc::new(ScriptedRunner::default());
+        runner.expect(
+            "dism",
+            0,
+            "Index : 1\nName : Windows 11 Pro\nSize : 18,000 bytes\n\nIndex : 2\nName : Windows 11 Home\n",
+        );
+        let images = service(&runner)
+            .list_wim_images(r"D:\sources\install.wim")
+            .unwrap();
+        assert_eq!(
+            runner.calls(),
+            [r"dism /English /Get-WimInfo /WimFile:D:\sources\install.wim"]
+        );
+        let names: Vec<_> = images.iter().map(|i| (i.index, i.name.as_str())).collect();
+        assert_eq!(names, [(1, "Windows 11 Pro"), (2, "Windows 11 Home")]);
+    }
+
+    #[test]
+    fn scan_imports_layers_with_their_parent_and_boot_entry() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let (svc, root) = workspace(&runner);
+        // Files directly under the root are found before those in `disks`.
+        let base = root.join("base.vhdx");
+        let child = root.join("disks").join("child.vhdx");
+        fs::write(&base, b"").unwrap();
+        fs::write(&child, b"").unwrap();
+        // bcdedit shows the drive of a VHD device in brackets: `vhd=[C:]\path`.
+        let child_str = child.to_string_lossy().to_string();
+        let device = match child_str.split_once(':') {
+            Some((drive, rest)) => format!("[{drive}:]{rest}"),
+            None => child_str,
+        };
+        runner
+            .expect(
+                "bcdedit",
+                0,
+                &format!(
+                    "identifier              {{4d1a0000-0000-0000-0000-000000000001}}\n\
+                     device                  vhd={device}\n"
+                ),
+            )
+            .expect("diskpart", 0, "Is Child: No\nParent Filename: \n")
+            .expect(
+                "diskpart",
+                0,
+                &format!("Is Child: Yes\nParent Filename: {}\n", base.display()),
+            );
+
+        let nodes = svc.scan().unwrap();
+        assert!(runner.is_done());
+        assert_eq!(runner.calls()[0], "bcdedit /enum all /v");
+        let scripts = runner.scripts();
+        assert_eq!(scripts.len(), 2);
+        for (script, file) in scripts.iter().zip([&base, &child]) {
+            assert!(script.contains(&format!("select vdisk file={}", quote_path(file).unwrap())));
+            assert!(script.contains("detail vdisk"));
+        }
+        let find = |path: &PathBuf| {
+            let path = normalize_path(&path.to_string_lossy());
+            nodes
+                .iter()
+                .find(|n| normalize_path(&n.path) == path)
+                .cloned()
+                .unwrap()
+        };
+        let (base_node, child_node) = (find(&base), find(&child));
+        assert_eq!(base_node.parent_id, None);
+        assert_eq!(base_node.bcd_guid, None);
+        assert_eq!(child_node.parent_id.as_deref(), Some(base_node.id.as_str()));
+        assert_eq!(
+            child_node.bcd_guid.as_deref(),
+            Some("{4d1a0000-0000-0000-0000-000000000001}")
+        );
+        let db = svc.db().unwrap();
+        for node in [&base_node, &child_node] {
+            let ops = db.fetch_node_ops(&node.id).unwrap();
+            assert!(ops.iter().any(|op| op.action == "import_vhdx"));
+        }
+
+        drop(svc);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    // Writes the boot marker through the assigned drive letter, so it only runs where that
+    // letter is a real volume path.
+    #[cfg(windows)]
+    #[test]
+    fn create_diff_attaches_a_child_of_the_parent_and_registers_it() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let (svc, root) = workspace(&runner);
+        let letter = super::pick_free_letter().unwrap();
+        let parent = root.join("disks").join("base.vhdx");
+        fs::write(&parent, b"").unwrap();
+        let db = svc.db().unwrap();
+        db.insert_node(&layer("base", None, &parent.to_string_lossy(), None))
+            .unwrap();
+        runner
+            .expect(
+                "diskpart",
+                0,
+                "  Partition ###  Type              Size     Offset\n\
+                 \x20 -------------  ----------------  -------  -------\n\
+                 \x20 Partition 1    System             100 MB  1024 KB\n\
+                 \x20 Partition 2    Reserved            16 MB   101 MB\n\
+                 \x20 Partition 3    Primary             63 GB   117 MB\n",
+            )
+            .expect("diskpart", 0, "")
+            .expect("bcdboot", 0, "")
+            .expect(
+                "bcdedit",
+                0,
+                &format!(
+                    "identifier              {{4d1a0000-0000-0000-0000-000000000002}}\n\
+                     device                  partition={letter}:\n"
+                ),
+            )
+            .expect("diskpart", 0, "");
+
+        let node = svc.create_diff("base", "Office", None, Vec::new()).unwrap();
+        assert!(runner.is_done());
+        let scripts = runner.scripts();
+        assert_eq!(scripts.len(), 3);
+        assert!(scripts[0].contains(&format!(
+            "create vdisk file={} parent={}",
+            quote_path(std::path::Path::new(&node.path)).unwrap(),
+            quote_path(&parent).unwrap()
+        )));
+        assert!(scripts[0].contains("attach vdisk"));
+        assert!(scripts[1].contains("select partition 3"));
+        assert!(scripts[1].contains(&format!("assign letter={letter}")));
+        assert!(scripts[2].contains(&format!("remove letter={letter}")));
+        assert!(scripts[2].contains("detach vdisk"));
+        assert!(runner
+            .calls()
+            .iter()
+            .any(|c| c.starts_with(&format!("bcdboot {letter}:\\Windows"))));
+
+        let stored = db.fetch_node(&node.id).unwrap().unwrap();
+        assert_eq!(stored.parent_id.as_deref(), Some("base"));
+        assert_eq!(
+            stored.bcd_guid.as_deref(),
+            Some("{4d1a0000-0000-0000-0000-000000000002}")
+        );
+        assert_eq!(stored.status, crate::models::NodeStatus::Normal);
+        let ops = db.fetch_node_ops(&node.id).unwrap();
+        assert!(ops
+            .iter()
+            .any(|op| op.action == "create_diff" && op.result == "ok"));
+
+        drop(svc);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn delete_subtree_drops_boot_entries_detaches_and_removes_rows() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let (svc, root) = workspace(&runner);
+        let db = svc.db().unwrap();
+        let base = root.join("disks").join("base.vhdx");
+        let child = root.join("disks").join("child.vhdx");
+        db.insert_node(&layer("base", None, &base.to_string_lossy(), Some("{b}")))
+            .unwrap();
+        db.insert_node(&layer(
+            "child",
+            Some("base"),
+            &child.to_string_lossy(),
+            Some("{c}"),
+        ))
+        .unwrap();
+        runner
+            .expect("bcdedit", 0, "")
+            .expect("diskpart", 0, "")
+            .expect("bcdedit", 0, "")
+            .expect("diskpart", 0, "");
+
+        svc.delete_subtree("base").unwrap();
+        assert!(runner.is_done());
+        // Leaves go first.
+        let bcd: Vec<_> = runner
+            .calls()
+            .into_iter()
+            .filter(|c| c.starts_with("bcdedit"))
+            .collect();
+        assert_eq!(bcd, ["bcdedit /delete {c}", "bcdedit /delete {b}"]);
+        let scripts = runner.scripts();
+        assert_eq!(scripts.len(), 2);
+        for (script, file) in scripts.iter().zip([&child, &base]) {
+            assert!(script.contains(&format!("select vdisk file={}", quote_path(file).unwrap())));
+            assert!(script.contains("detach vdisk"));
+        }
+        assert!(db.fetch_nodes().unwrap().is_empty());
+        let ops = db.fetch_ops_between(None, None).unwrap();
+        assert!(ops.iter().any(
+            |op| op.action == "delete_subtree" && op.detail.as_deref() == Some("node_id=base")
+        ));
+
+        drop(svc);
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn dropping_the_service_restores_the_previous_runner() {
+        let outer = Arc::new(ScriptedRunner::default());
+        outer.expect("cmd", 0, "");
+        let _guard = install_runner(outer.clone());
+        drop(service(&Arc::new(ScriptedRunner::default())));
+        run_command("cmd", &["/c", "ver"], None).unwrap();
+        assert!(outer.is_done());
+    }
+}
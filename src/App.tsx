@@ -1,12 +1,27 @@
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import { useCallback, useEffect, useMemo, useState } from "react";
 import { useTranslation } from "react-i18next";
 import { NodeDetail } from "./components/NodeDetail";
 import { NodeTree } from "./components/NodeTree";
 import { WorkspaceGate } from "./components/WorkspaceGate";
-import { Node, RecentWorkspace, Settings, StatusLabels, TreeNode, WimImageInfo } from "./types";
+import {
+  ConfirmationRequest,
+  CreatePreflight,
+  HealthFinding,
+  JobNotification,
+  Node,
+  ReadOnlyStatus,
+  RecentWorkspace,
+  RepairReport,
+  Settings,
+  StatusLabels,
+  TreeNode,
+  WimImageInfo,
+} from "./types";
 import { Badge } from "./components/ui/Badge";
 import { Button } from "./components/ui/Button";
+import { Input } from "./components/ui/Input";
 import { Card } from "./components/ui/Card";
 import { useCommandRunner } from "./hooks/useCommandRunner";
 
@@ -21,14 +36,19 @@ function App() {
   const [recents, setRecents] = useState<RecentWorkspace[]>([]);
   const [baseName, setBaseName] = useState("base");
   const [baseSize, setBaseSize] = useState(60);
+  const [baseRecovery, setBaseRecovery] = useState(false);
   const [baseDesc, setBaseDesc] = useState("");
   const [wimPath, setWimPath] = useState("");
   const [wimIndex, setWimIndex] = useState(1);
   const [wimImages, setWimImages] = useState<WimImageInfo[]>([]);
   const [diffName, setDiffName] = useState("child");
   const [diffDesc, setDiffDesc] = useState("");
+  const [diffNoSwap, setDiffNoSwap] = useState(false);
   const [bcdName, setBcdName] = useState("");
   const [selectedNode, setSelectedNode] = useState("");
+  const [healthFindings, setHealthFindings] = useState<HealthFinding[]>([]);
+  const [readOnly, setReadOnly] = useState<ReadOnlyStatus | null>(null);
+  const [unlockPin, setUnlockPin] = useState("");
 
   const { run: runCommand, isBusy } = useCommandRunner({ setStatus, setMessage, t });
 
@@ -40,6 +60,10 @@ function App() {
       missing_bcd: t("node-status.missing-bcd"),
       mounted: t("node-status.mounted"),
       error: t("node-status.error"),
+      creating: t("node-status.creating"),
+      merging: t("node-status.merging"),
+      deleting: t("node-status.deleting"),
+      archived: t("node-status.archived"),
     }),
     [t],
   );
@@ -68,6 +92,44 @@ function App() {
     }
   }, [runCommand]);
 
+  const runHealthCheck = useCallback(async () => {
+    try {
+      const findings = await runCommand<HealthFinding[]>("health_check");
+      setHealthFindings(findings);
+    } catch {
+      // handled in runCommand
+    }
+  }, [runCommand]);
+
+  const refreshReadOnly = useCallback(async () => {
+    try {
+      setReadOnly(await runCommand<ReadOnlyStatus>("get_read_only_status"));
+    } catch {
+      // handled in runCommand
+    }
+  }, [runCommand]);
+
+  const handleUnlock = useCallback(async () => {
+    try {
+      await runCommand("unlock_read_only", { pin: unlockPin });
+      setUnlockPin("");
+      setMessage(t("message-unlocked"));
+    } catch {
+      // handled in runCommand
+    } finally {
+      await refreshReadOnly();
+    }
+  }, [runCommand, unlockPin, t, refreshReadOnly]);
+
+  const handleLock = useCallback(async () => {
+    try {
+      await runCommand("lock_read_only");
+      await refreshReadOnly();
+    } catch {
+      // handled in runCommand
+    }
+  }, [runCommand, refreshReadOnly]);
+
   useEffect(() => {
     const bootstrap = async () => {
       try {
@@ -92,6 +154,8 @@ function App() {
           i18n.changeLanguage(settings.locale || "zh-CN");
           setWorkspaceReady(true);
           await refreshNodes();
+          await refreshReadOnly();
+          await runHealthCheck();
         } else {
           setMessage(t("status-uninitialized"));
           setWorkspaceReady(false);
@@ -104,6 +168,20 @@ function App() {
     // eslint-disable-next-line react-hooks/exhaustive-deps
   }, []);
 
+  useEffect(() => {
+    const unlisten = listen<JobNotification>("job-notification-activated", ({ payload }) => {
+      setStatus(payload.ok ? "initialized" : "error");
+      setMessage(
+        payload.ok
+          ? t("message-job-finished", { kind: payload.kind })
+          : t("message-job-failed", { kind: payload.kind, msg: payload.detail ?? "" }),
+      );
+    });
+    return () => {
+      unlisten.then((fn) => fn());
+    };
+  }, [t]);
+
   useEffect(() => {
     if (!workspaceReady || !nodes.length) {
       setSelectedNode("");
@@ -136,7 +214,12 @@ function App() {
     });
 
     const sortRecursively = (list: TreeNode[]) => {
-      list.sort((a, b) => new Date(a.created_at).getTime() - new Date(b.created_at).getTime());
+      list.sort((a, b) => {
+        const ak = a.sort_key ?? Number.MAX_SAFE_INTEGER;
+        const bk = b.sort_key ?? Number.MAX_SAFE_INTEGER;
+        if (ak !== bk) return ak - bk;
+        return new Date(a.created_at).getTime() - new Date(b.created_at).getTime();
+      });
       list.forEach((child) => sortRecursively(child.children));
     };
     sortRecursively(roots);
@@ -193,13 +276,15 @@ function App() {
         setWorkspaceReady(true);
         setMessage(t("status-initialized", { path: result.settings.root_path }));
         await refreshNodes();
+        await refreshReadOnly();
+        await runHealthCheck();
       } catch {
         // handled in runCommand
       } finally {
         await refreshRecents();
       }
     },
-    [rootPath, runCommand, i18n.language, t, refreshNodes, refreshRecents],
+    [rootPath, runCommand, i18n.language, t, refreshNodes, refreshReadOnly, runHealthCheck, refreshRecents],
   );
 
   const handleCreateWorkspace = useCallback(async () => {
@@ -221,6 +306,7 @@ function App() {
         wimFile: wimPath,
         wimIndex,
         sizeGb: baseSize,
+        recovery: baseRecovery,
       });
       setStatus("initialized");
       setWorkspaceReady(true);
@@ -231,22 +317,35 @@ function App() {
     } finally {
       await refreshRecents();
     }
-  }, [rootPath, runCommand, i18n.language, baseName, baseDesc, wimPath, wimIndex, baseSize, t, refreshNodes, refreshRecents]);
+  }, [rootPath, runCommand, i18n.language, baseName, baseDesc, wimPath, wimIndex, baseSize, baseRecovery, t, refreshNodes, refreshRecents]);
 
   const handleCreateDiff = useCallback(async () => {
     if (!selectedNode) return;
     try {
+      const preflight = await runCommand<CreatePreflight>("preflight_create", {
+        params: { name: diffName, parent_id: selectedNode },
+      });
+      if (!preflight.ready) {
+        const failed = preflight.checks
+          .filter((check) => check.severity === "error")
+          .map((check) => check.message);
+        setMessage(t("status-error", { msg: failed.join("; ") }));
+        setStatus("error");
+        return;
+      }
       const res = await runCommand<{ node: Node }>("create_diff_vhd", {
         parentId: selectedNode,
+        parentVersion: selectedDetail?.version,
         name: diffName,
         desc: diffDesc || null,
+        swapFiles: diffNoSwap ? {} : null,
       });
       setMessage(t("message-diff-created", { name: res.node.name }));
       await refreshNodes();
     } catch {
       // handled in runCommand
     }
-  }, [selectedNode, runCommand, diffName, diffDesc, t, refreshNodes]);
+  }, [selectedNode, selectedDetail?.version, runCommand, diffName, diffDesc, diffNoSwap, t, refreshNodes]);
 
   const handleCheck = useCallback(async () => {
     try {
@@ -258,43 +357,81 @@ function App() {
     }
   }, [runCommand, t]);
 
+  const handleRepairAll = useCallback(async (forceDetach = false) => {
+    try {
+      const report = await runCommand<RepairReport>("repair_all", {
+        findings: healthFindings,
+        forceDetach,
+      });
+      setMessage(
+        t("message-health-repaired", {
+          repaired: report.repaired.length,
+          failed: report.failed.length,
+        }),
+      );
+      await refreshNodes();
+      await runHealthCheck();
+    } catch {
+      // handled in runCommand
+    }
+  }, [runCommand, healthFindings, t, refreshNodes, runHealthCheck]);
+
   const handleBootReboot = useCallback(async () => {
     if (!selectedNode) return;
     try {
-      await runCommand("set_bootsequence_and_reboot", { nodeId: selectedNode });
+      await runCommand("set_bootsequence_and_reboot", {
+        nodeId: selectedNode,
+        version: selectedDetail?.version,
+      });
       setMessage(t("message-boot-set"));
     } catch {
       // handled in runCommand
     }
-  }, [selectedNode, runCommand, t]);
+  }, [selectedNode, selectedDetail?.version, runCommand, t]);
 
   const handleStartVm = useCallback(async () => {
     if (!selectedNode) return;
     try {
-      const res = await runCommand<{ vm_name: string }>("start_vm", { nodeId: selectedNode });
+      const res = await runCommand<{ vm_name: string }>("start_vm", {
+        nodeId: selectedNode,
+        version: selectedDetail?.version,
+      });
       const label = res?.vm_name || selectedDetail?.name || selectedNode;
       setMessage(t("message-vm-started", { name: label }));
     } catch {
       // handled in runCommand
     }
-  }, [selectedNode, selectedDetail?.name, runCommand, t]);
+  }, [selectedNode, selectedDetail?.name, selectedDetail?.version, runCommand, t]);
 
   const handleDelete = useCallback(async () => {
     if (!selectedNode) return;
     try {
-      await runCommand("delete_subtree", { nodeId: selectedNode });
+      const version = selectedDetail?.version;
+      const request = await runCommand<ConfirmationRequest | null>("delete_subtree", { nodeId: selectedNode, version });
+      if (request) {
+        const confirmed = window.confirm(
+          t("confirm-delete-subtree", {
+            count: request.affected.length,
+            names: request.affected.join(", "),
+            gb: (request.total_bytes / 1024 ** 3).toFixed(1),
+          }),
+        );
+        if (!confirmed) return;
+        await runCommand("delete_subtree", { nodeId: selectedNode, version, confirmToken: request.token });
+      }
       setMessage(t("message-deleted"));
       await refreshNodes();
     } catch {
       // handled in runCommand
     }
-  }, [selectedNode, runCommand, refreshNodes, t]);
+  }, [selectedNode, selectedDetail?.version, runCommand, refreshNodes, t]);
 
   const handleAddBcd = useCallback(async () => {
     if (!selectedNode) return;
     try {
       const guid = await runCommand<string | null>("add_bcd_entry", {
         nodeId: selectedNode,
+        version: selectedDetail?.version,
         description: bcdName || null,
       });
       setMessage(t("message-repaired-bcd", { guid: guid ?? t("message-no-guid") }));
@@ -302,39 +439,45 @@ function App() {
     } catch {
       // handled in runCommand
     }
-  }, [selectedNode, runCommand, refreshNodes, bcdName, t]);
+  }, [selectedNode, selectedDetail?.version, runCommand, refreshNodes, bcdName, t]);
 
   const handleUpdateBcdDesc = useCallback(async () => {
     if (!selectedNode) return;
     try {
-      await runCommand("update_bcd_description", { nodeId: selectedNode, description: bcdName });
+      await runCommand("update_bcd_description", {
+        nodeId: selectedNode,
+        version: selectedDetail?.version,
+        description: bcdName,
+      });
       setMessage(t("message-updated-bcd"));
       await refreshNodes();
     } catch {
       // handled in runCommand
     }
-  }, [selectedNode, runCommand, refreshNodes, bcdName, t]);
+  }, [selectedNode, selectedDetail?.version, runCommand, refreshNodes, bcdName, t]);
 
   const handleDeleteBcd = useCallback(async () => {
     if (!selectedNode) return;
     try {
-      await runCommand("delete_bcd", { nodeId: selectedNode });
+      await runCommand("delete_bcd", { nodeId: selectedNode, version: selectedDetail?.version });
       setMessage(t("message-deleted-bcd"));
       await refreshNodes();
     } catch {
       // handled in runCommand
     }
-  }, [selectedNode, runCommand, refreshNodes, t]);
+  }, [selectedNode, selectedDetail?.version, runCommand, refreshNodes, t]);
 
   const handleCloseWorkspace = useCallback(() => {
     setWorkspaceReady(false);
     setNodes([]);
     setSelectedNode("");
+    setHealthFindings([]);
     setWimImages([]);
     setWimPath("");
     setWimIndex(1);
     setBaseName("base");
     setBaseSize(60);
+    setBaseRecovery(false);
     setBaseDesc("");
     setDiffName("child");
     setDiffDesc("");
@@ -412,6 +555,8 @@ function App() {
               setWimIndex={setWimIndex}
               baseSize={baseSize}
               setBaseSize={setBaseSize}
+              baseRecovery={baseRecovery}
+              setBaseRecovery={setBaseRecovery}
               baseName={baseName}
               setBaseName={setBaseName}
               baseDesc={baseDesc}
@@ -476,6 +621,77 @@ function App() {
               </div>
             </Card>
 
+            {readOnly?.read_only && (
+              <Card className="flex flex-wrap items-center justify-between gap-3 p-4 shadow-md shadow-peach-300/25">
+                <span className="text-sm font-semibold text-ink-900">
+                  {readOnly.unlocked ? t("read-only-unlocked") : t("read-only-title")}
+                </span>
+                {readOnly.unlocked ? (
+                  <Button variant="secondary" onClick={handleLock}>
+                    {t("read-only-lock")}
+                  </Button>
+                ) : (
+                  <div className="flex items-center gap-2">
+                    <Input
+                      type="password"
+                      inputMode="numeric"
+                      value={unlockPin}
+                      placeholder={t("read-only-pin")}
+                      onChange={(e) => setUnlockPin(e.target.value)}
+                    />
+                    <Button
+                      onClick={handleUnlock}
+                      disabled={!unlockPin || isBusy("unlock_read_only")}
+                      loading={isBusy("unlock_read_only")}
+                    >
+                      {t("read-only-unlock")}
+                    </Button>
+                  </div>
+                )}
+              </Card>
+            )}
+
+            {healthFindings.length > 0 && (
+              <Card className="flex flex-col gap-2 p-4 shadow-md shadow-peach-300/25">
+                <div className="flex items-center justify-between gap-3">
+                  <h2 className="text-sm font-semibold text-ink-900">
+                    {t("health-title", { count: healthFindings.length })}
+                  </h2>
+                  <div className="flex items-center gap-2">
+                    <Button
+                      onClick={() => handleRepairAll()}
+                      disabled={isBusy("repair_all")}
+                      loading={isBusy("repair_all")}
+                    >
+                      {t("health-repair")}
+                    </Button>
+                    {healthFindings.some((f) => f.category === "stale_mount") && (
+                      <Button
+                        variant="secondary"
+                        onClick={() => handleRepairAll(true)}
+                        disabled={isBusy("repair_all")}
+                      >
+                        {t("health-force-detach")}
+                      </Button>
+                    )}
+                    <Button variant="secondary" onClick={() => setHealthFindings([])}>
+                      {t("health-dismiss")}
+                    </Button>
+                  </div>
+                </div>
+                <ul className="max-h-40 space-y-1 overflow-auto text-sm text-ink-700">
+                  {healthFindings.map((finding, idx) => (
+                    <li key={idx} className="flex items-start gap-2">
+                      <Badge tone="warn" className="shrink-0 px-2 py-0.5">
+                        {t(`health-category.${finding.category}`)}
+                      </Badge>
+                      <span className="break-all">{finding.message}</span>
+                    </li>
+                  ))}
+                </ul>
+              </Card>
+            )}
+
             <div className="grid min-h-0 flex-1 grid-cols-1 gap-4 overflow-hidden lg:grid-cols-[340px_minmax(0,1fr)]">
               <NodeTree
                 data={treeData}
@@ -492,6 +708,8 @@ function App() {
                 diffDesc={diffDesc}
                 setDiffName={setDiffName}
                 setDiffDesc={setDiffDesc}
+                diffNoSwap={diffNoSwap}
+                setDiffNoSwap={setDiffNoSwap}
                 bcdName={bcdName}
                 setBcdName={setBcdName}
                 onAddBcd={handleAddBcd}
@@ -14,6 +14,8 @@ type Props = {
   diffDesc: string;
   setDiffName: (v: string) => void;
   setDiffDesc: (v: string) => void;
+  diffNoSwap: boolean;
+  setDiffNoSwap: (v: boolean) => void;
   bcdName: string;
   setBcdName: (v: string) => void;
   onAddBcd: () => void;
@@ -35,6 +37,8 @@ export function NodeDetail({
   diffDesc,
   setDiffName,
   setDiffDesc,
+  diffNoSwap,
+  setDiffNoSwap,
   bcdName,
   setBcdName,
   onAddBcd,
@@ -124,7 +128,15 @@ export function NodeDetail({
                 placeholder={t("diff-desc-placeholder")}
               />
             </div>
-            <div className="mt-3 flex justify-end">
+            <div className="mt-3 flex items-center justify-between gap-3">
+              <label className="flex items-center gap-2 text-sm text-ink-700">
+                <input
+                  type="checkbox"
+                  checked={diffNoSwap}
+                  onChange={(e) => setDiffNoSwap(e.target.checked)}
+                />
+                {t("diff-no-swap-label")}
+              </label>
               <Button
                 onClick={onCreateDiff}
                 disabled={!diffName.trim() || isBusy("create_diff_vhd")}
@@ -13,6 +13,8 @@ type Props = {
   setWimIndex: (v: number) => void;
   baseSize: number;
   setBaseSize: (v: number) => void;
+  baseRecovery: boolean;
+  setBaseRecovery: (v: boolean) => void;
   baseName: string;
   setBaseName: (v: string) => void;
   baseDesc: string;
@@ -44,6 +46,8 @@ export function WorkspaceGate(props: Props) {
     setWimIndex,
     baseSize,
     setBaseSize,
+    baseRecovery,
+    setBaseRecovery,
     baseName,
     setBaseName,
     baseDesc,
@@ -228,6 +232,14 @@ export function WorkspaceGate(props: Props) {
               />
             </label>
           </div>
+          <label className="flex items-center gap-2 text-sm font-semibold text-ink-700">
+            <input
+              type="checkbox"
+              checked={baseRecovery}
+              onChange={(e) => setBaseRecovery(e.target.checked)}
+            />
+            {t("base-recovery-label")}
+          </label>
           <div className="grid grid-cols-1 gap-3 sm:grid-cols-2">
             <Input
               value={baseName}
@@ -9,6 +9,10 @@ const statusToneMap: Record<NodeStatus, BadgeTone> = {
   missing_bcd: "warn",
   mounted: "info",
   error: "danger",
+  creating: "muted",
+  merging: "muted",
+  deleting: "muted",
+  archived: "muted",
 };
 
 export function statusToneFor(status: NodeStatus): BadgeTone {
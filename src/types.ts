@@ -3,6 +3,84 @@ export type Settings = {
   locale: string;
   seq_counter: number;
   last_boot_guid?: string | null;
+  tool_paths: ToolPaths;
+  rescue_guid?: string | null;
+  max_chain_depth: number;
+  max_children_per_parent: number;
+  max_total_gb: number;
+  free_space_headroom_gb: number;
+  bcd_description_template?: string | null;
+  bcd_target: BcdTarget;
+  maintenance: MaintenanceSchedule;
+  agent: AgentConfig;
+  library_path?: string | null;
+  temp_path?: string | null;
+  redact_logs: boolean;
+  base_path?: string | null;
+  diff_path?: string | null;
+  wim_cache: boolean;
+  template_path?: string | null;
+  confirm_destructive: boolean;
+  read_only: boolean;
+};
+
+export type ReadOnlyStatus = {
+  read_only: boolean;
+  unlocked: boolean;
+};
+
+export type AgentConfig = {
+  enabled: boolean;
+  endpoint: string;
+  key: string;
+  agent_name?: string | null;
+  poll_seconds: number;
+};
+
+export type MaintenanceTask =
+  | "rescan"
+  | "compact_idle"
+  | "prune_ops"
+  | "rotate_logs"
+  | "verify_chains"
+  | "backup"
+  | "clean_temp"
+  | "expire_layers"
+  | "hash_bases";
+
+export type MaintenanceSchedule = {
+  enabled: boolean;
+  interval_hours: number;
+  tasks: MaintenanceTask[];
+  idle_days: number;
+  ops_retention_days: number;
+  transcript_retention_days: number;
+  temp_retention_days: number;
+  log_max_mb: number;
+  log_keep: number;
+  backup_dest: string | null;
+  tag_idle_days: Record<string, number>;
+  delete_expired: boolean;
+};
+
+export type BcdTarget = {
+  esp?: string | null;
+  store?: string | null;
+};
+
+export type ToolPaths = {
+  dism?: string | null;
+  bcdboot?: string | null;
+  bcdedit?: string | null;
+  diskpart?: string | null;
+};
+
+export type ToolStatus = {
+  name: string;
+  path: string;
+  source: "override" | "system" | "adk" | "path";
+  exists: boolean;
+  candidates: string[];
 };
 
 export type NodeStatus =
@@ -11,7 +89,11 @@ export type NodeStatus =
   | "missing_parent"
   | "missing_bcd"
   | "mounted"
-  | "error";
+  | "error"
+  | "creating"
+  | "merging"
+  | "deleting"
+  | "archived";
 
 export type Node = {
   id: string;
@@ -23,6 +105,405 @@ export type Node = {
   created_at: string;
   status: NodeStatus;
   boot_files_ready: boolean;
+  generalized: boolean;
+  boot_verified_at?: string | null;
+  bitlocker: boolean;
+  color?: string | null;
+  icon?: string | null;
+  os_edition?: string | null;
+  os_version?: string | null;
+  os_build?: string | null;
+  sort_key?: number | null;
+  tags: string[];
+  version: number;
+  expires_at?: string | null;
+  protected: boolean;
+  network?: NetworkProfile | null;
+  expired: boolean;
+  depth: number;
+  root_id: string;
+  ancestor_ids: string[];
+  depth_exceeded: boolean;
+};
+
+export type NodeQuery = {
+  statuses?: NodeStatus[] | null;
+  exclude_statuses?: NodeStatus[] | null;
+  tag?: string | null;
+  name?: string | null;
+  idle_days?: number | null;
+  offset?: number;
+  limit?: number | null;
+};
+
+export type SavedView = {
+  id: string;
+  name: string;
+  query: NodeQuery;
+  created_at: string;
+};
+
+export type NodePage = {
+  nodes: Node[];
+  total: number;
+  offset: number;
+};
+
+export type ProvisioningScript = {
+  path: string;
+  args?: string | null;
+};
+
+export type ChainStats = {
+  root_id: string;
+  root_name: string;
+  node_count: number;
+  file_bytes: number;
+  virtual_bytes: number;
+};
+
+export type WorkspaceStats = {
+  node_count: number;
+  status_counts: Record<string, number>;
+  file_bytes: number;
+  virtual_bytes: number;
+  chains: ChainStats[];
+  volume_free_bytes?: number | null;
+  volume_total_bytes?: number | null;
+  recent_ops: {
+    since: string;
+    total: number;
+    failed: number;
+    by_action: Record<string, number>;
+  };
+};
+
+export type FirmwareEntry = {
+  identifier: string;
+  description: string;
+  kind: string;
+  device?: string | null;
+  path?: string | null;
+  position?: number | null;
+  is_windows_boot_manager: boolean;
+};
+
+export type FirmwareBootInfo = {
+  entries: FirmwareEntry[];
+  boot_manager_first: boolean;
+  timeout?: number | null;
+};
+
+export type BootMenuPolicy = "standard" | "legacy";
+
+export type BootStatusPolicy =
+  | "display_all"
+  | "ignore_all"
+  | "ignore_shutdown"
+  | "ignore_boot";
+
+export type BootEntryFlags = {
+  recovery_enabled?: boolean | null;
+  boot_status_policy?: BootStatusPolicy | null;
+  testsigning?: boolean | null;
+  nointegritychecks?: boolean | null;
+};
+
+export type SecureBootState = "enabled" | "disabled" | "unsupported" | "unknown";
+
+export type EstimateOp = "merge" | "clone" | "flatten";
+
+export type SpaceEstimate = {
+  op: EstimateOp;
+  node_id: string;
+  required_bytes: number;
+  free_bytes?: number | null;
+  sufficient?: boolean | null;
+};
+
+export type ReclaimResult = {
+  node_id: string;
+  before_bytes: number;
+  after_bytes: number;
+};
+
+export type BcdObject = {
+  title: string;
+  fields: [string, string[]][];
+};
+
+export type VhdState = {
+  parent: string | null;
+  attached: boolean | null;
+};
+
+export type NodeMetadata = {
+  id: string;
+  parent_id: string | null;
+  name: string;
+  rel_path: string;
+  desc: string | null;
+  created_at: string;
+  generalized: boolean;
+  color: string | null;
+  icon: string | null;
+  sort_key: number | null;
+  tags: string[];
+  scripts: ProvisioningScript[];
+  bcd_description: string | null;
+};
+
+export type WorkspaceMetadata = {
+  version: number;
+  exported_at: string;
+  machine: string | null;
+  nodes: NodeMetadata[];
+  recipes: Recipe[];
+};
+
+export type MetadataImport = {
+  adopted: string[];
+  updated: string[];
+  missing: string[];
+  recipes: number;
+  errors: string[];
+};
+
+export type LinkChange = {
+  node_id: string;
+  from: string | null;
+  to: string | null;
+};
+
+export type CompactionHint = {
+  node_id: string;
+  file_bytes: number;
+  fragmentation: number;
+  estimated_savings: number;
+  measured_at: string;
+  compactable: boolean;
+};
+
+export type DuplicateBases = {
+  sha256: string;
+  file_bytes: number;
+  keep: string;
+  duplicates: string[];
+  reclaimable_bytes: number;
+};
+
+export type DuplicateReport = {
+  groups: DuplicateBases[];
+  unhashed: string[];
+};
+
+export type HealthCategory = "missing_file" | "orphan_entry" | "broken_parent" | "stale_mount";
+
+export type HealthFinding = {
+  category: HealthCategory;
+  node_id?: string | null;
+  bcd_guid?: string | null;
+  message: string;
+};
+
+export type ChainIssueKind =
+  | "missing_file"
+  | "missing_parent_record"
+  | "parent_mismatch"
+  | "parent_modified"
+  | "attached";
+
+export type ChainIssue = {
+  kind: ChainIssueKind;
+  node_id: string;
+  message: string;
+};
+
+export type ConfirmationRequest = {
+  action: string;
+  node_id: string;
+  affected: string[];
+  total_bytes: number;
+  token: string;
+  expires_at: string;
+};
+
+export type RepairReport = {
+  repaired: string[];
+  failed: string[];
+};
+
+export type LinkRepair = {
+  checked: number;
+  changed: LinkChange[];
+  orphaned: string[];
+  errors: string[];
+};
+
+export type LibraryEntry = {
+  file: string;
+  path: string;
+  size_bytes: number;
+  name: string;
+  desc: string | null;
+  sha256: string | null;
+  publisher: string | null;
+  published_at: string | null;
+  os_edition: string | null;
+  os_version: string | null;
+  os_build: string | null;
+};
+
+export type BackupStatus = {
+  id: string;
+  file: string;
+  node_id: string;
+  dest: string;
+  backed_up_at: string;
+  result: string;
+  file_bytes: number;
+  source_modified: string | null;
+  sha256: string | null;
+  detail: string | null;
+};
+
+export type RecreatedLayer = {
+  old_id: string;
+  node: Node;
+};
+
+export type RefreshFailure = {
+  old_id: string;
+  error: string;
+};
+
+export type RefreshResult = {
+  base: Node;
+  recreated: RecreatedLayer[];
+  failed: RefreshFailure[];
+  retired: string[];
+};
+
+export type NodeDetails = {
+  node: Node;
+  chain: Node[];
+  children: Node[];
+  file_bytes: number | null;
+  virtual_bytes: number | null;
+  vhd: VhdState | null;
+  bcd_entry: BcdObject | null;
+  errors: string[];
+};
+
+export type NodeOperation = {
+  id: string;
+  node_id: string;
+  ts: string;
+  action: string;
+  result: string;
+  detail: string | null;
+  duration_ms: number | null;
+  transcript: string | null;
+};
+
+export type AuditRange = {
+  from?: string | null;
+  to?: string | null;
+};
+
+export type GraphFormat = "dot" | "mermaid";
+
+export type AuditFormat = "csv" | "markdown";
+
+export type AttachEvent = {
+  node_id: string;
+  op_id: string;
+  action: "attach" | "detach";
+  context: string;
+  result: "ok" | "failed";
+  ts: string;
+};
+
+export type LayerDriver = {
+  published_name: string;
+  original_file_name: string;
+  inbox: boolean;
+  class_name: string;
+  provider_name: string;
+  date: string;
+  version: string;
+};
+
+export type LayerPackage = {
+  identity: string;
+  state: string;
+  release_type: string;
+  install_time: string;
+};
+
+export type LayerFeature = {
+  name: string;
+  state: string;
+};
+
+export type RegistryTweak = {
+  hive: "SYSTEM" | "SOFTWARE" | "DEFAULT";
+  key: string;
+  name: string;
+  value_type: string;
+  data: string;
+};
+
+export type NetworkAddressing =
+  | { mode: "dhcp" }
+  | {
+      mode: "static";
+      address: string;
+      prefix_length: number;
+      gateway?: string | null;
+      dns: string[];
+    };
+
+export type NetworkProfile = {
+  addressing: NetworkAddressing;
+  interface_alias?: string | null;
+  djoin_blob?: string | null;
+};
+
+export type LocalAdmin = {
+  user_name: string;
+  password: string;
+  autologon: boolean;
+};
+
+export type RegistryPreset =
+  | { preset: "disable_hibernation" }
+  | { preset: "set_timezone"; timezone: string }
+  | { preset: "computer_name"; name: string }
+  | { preset: "disable_pagefile" }
+  | { preset: "move_pagefile"; drive: string };
+
+export type SwapFileOptions = {
+  pagefile_drive?: string | null;
+};
+
+export type DebloatPreset = "basic" | "full";
+
+export type Recipe = {
+  id?: string;
+  name: string;
+  desc?: string | null;
+  wim_file: string;
+  wim_index: number;
+  size_gb: number;
+  recovery?: boolean;
+  drivers?: string[];
+  unattend?: string | null;
+  debloat?: DebloatPreset | null;
+  tweaks?: RegistryTweak[];
+  presets?: RegistryPreset[];
+  scripts?: ProvisioningScript[];
+  updated_at?: string;
 };
 
 export type WimImageInfo = {
@@ -32,6 +513,94 @@ export type WimImageInfo = {
   size?: string;
 };
 
+export type WimSourceStatus = "ok" | "changed" | "missing";
+
+export type WimSource = {
+  id: string;
+  path: string;
+  size_bytes: number;
+  modified_at: string | null;
+  sha256: string;
+  images: WimImageInfo[];
+  added_at: string;
+  status: WimSourceStatus;
+};
+
+export type IssueSeverity = "warning" | "error";
+
+export type VolumeIssue = {
+  severity: IssueSeverity;
+  code: string;
+  message: string;
+};
+
+export type InitResult = {
+  settings: Settings;
+  volume_issues: VolumeIssue[];
+};
+
+export type CreatePreflightParams = {
+  name: string;
+  parent_id?: string | null;
+  wim_file?: string | null;
+  wim_index?: number | null;
+  recovery?: boolean;
+};
+
+export type PreflightCheck = {
+  code: string;
+  severity: IssueSeverity | null;
+  message: string;
+};
+
+export type CreatePreflight = {
+  ready: boolean;
+  checks: PreflightCheck[];
+};
+
+export type JobInfo = {
+  id: string;
+  program: string;
+  args: string[];
+  started_at: string;
+};
+
+export type JobRecord = {
+  id: string;
+  kind: string;
+  started_at: string;
+  finished_at: string;
+  result: string;
+  detail: string | null;
+};
+
+export type TempCleanup = {
+  removed: number;
+  freed_bytes: number;
+};
+
+export type OutputStream = "stdout" | "stderr";
+
+export type JobOutputLine = {
+  job_id: string;
+  stream: OutputStream;
+  line: string;
+};
+
+export type JobProgress = {
+  job_id: string;
+  phase: string;
+  percent: number | null;
+  command: string | null;
+};
+
+export type JobNotification = {
+  job_id: string | null;
+  kind: string;
+  ok: boolean;
+  detail: string | null;
+};
+
 export type TreeNode = Node & { children: TreeNode[] };
 export type StatusLabels = Record<NodeStatus, string>;
 